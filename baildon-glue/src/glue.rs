@@ -1,44 +1,117 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::io::ErrorKind;
-use std::ops::ControlFlow;
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicI64, Ordering};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::StreamExt;
-use gluesql::core::data::Schema;
+use gluesql::core::ast::{ColumnUniqueOption, Expr, IndexOperator, OrderByExpr};
+use gluesql::core::chrono::Utc;
+use gluesql::core::data::{Row, Schema, SchemaIndex, SchemaIndexOrd};
+use gluesql::core::executor::{evaluate_stateless, RowContext};
 // use gluesql::core::result::Result;
 use gluesql::core::store::{
-    AlterTable, CustomFunction, CustomFunctionMut, DataRow, Index, IndexMut, Metadata, RowIter,
-    Store, StoreMut, Transaction,
+    AlterTable, CustomFunction, CustomFunctionMut, DataRow, Index, IndexError, IndexMut, Metadata,
+    RowIter, Store, StoreMut, Transaction,
 };
-use gluesql::prelude::{Error, Key};
+use gluesql::prelude::{Error, Key, Value};
 use serde::{Deserialize, Serialize};
-use tokio::fs::OpenOptions;
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
 use tokio::sync::Mutex;
+use tokio::sync::OwnedMutexGuard;
 
+use baildon::btree::baildon::{BaildonKey, BaildonValue};
 use baildon::btree::Baildon;
+use baildon::btree::BaildonFamilies;
+use baildon::btree::ColumnFamily;
 use baildon::btree::Direction;
+use baildon::btree::TreeStats;
+
+use crate::Migration;
+use crate::QueryStats;
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
-pub(crate) struct BaildonGlue {
-    pub schemas: Baildon<String, Schema>,
+/// Maps the indexed values of a table to the keys of the rows which have them
+type IndexTree = Baildon<Key, Vec<Key>>;
+
+/// Open index trees, named by (table, index)
+type OpenIndexes = Vec<((String, String), Arc<IndexTree>)>;
+
+/// Locks which serialize changes to each table, by name
+type TableLocks = std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>;
+
+/// Number of index trees kept open, unless configured otherwise
+pub const DEFAULT_MAX_OPEN_INDEXES: usize = 64;
+
+/// Key of our config in its tree
+const CONFIG_KEY: &str = "config";
+
+/// GlueSQL storage in Baildon trees, which are kept in a directory.
+///
+/// Use it with a [`gluesql::prelude::Glue`] to execute SQL, and close it once done.
+///
+/// Clones share their trees, so each of several sessions can have a clone of its own. Changes to
+/// a table are made by one session at a time, and changes to different tables can be interleaved.
+pub struct BaildonGlue {
+    /// The schemas of our tables, by name
+    pub schemas: Arc<Baildon<String, Schema>>,
     config: BaildonConfig,
-    tables: Mutex<HashMap<String, Arc<Baildon<Key, DataRow>>>>,
+    // Our config is kept in a tree of its own, so changes to it are as durable as our data
+    configs: Arc<Baildon<String, BaildonConfig>>,
+    // The version of each table's schema, which starts at 1 and counts its changes
+    versions: Arc<Baildon<String, u64>>,
+    // The migration scripts which have been applied, by name
+    migrations: Arc<Baildon<String, Migration>>,
+    // Held while migrations are applied, so each is applied once
+    migrating: Arc<Mutex<()>>,
+    // Each table is a column family, so all tables share one data file
+    tables: Arc<BaildonFamilies<Key, DataRow>>,
+    // Each index is a tree of its own, opened when first used. Open trees are ordered from least
+    // to most recently used, so the least recently used can be closed once too many are open.
+    indexes: Arc<Mutex<OpenIndexes>>,
+    max_open_indexes: usize,
+    // The next key of each table without a primary key, found from its last key when first needed
+    sequences: Arc<Mutex<HashMap<String, i64>>>,
+    locks: Arc<TableLocks>,
+    // Rows returned by the scans of this session
+    rows_scanned: AtomicU64,
+    // Our trees are shared with other processes, which may change them, and we may not
+    read_only: bool,
+}
+
+impl Clone for BaildonGlue {
+    // Clones count their own scans
+    fn clone(&self) -> Self {
+        BaildonGlue {
+            schemas: self.schemas.clone(),
+            config: self.config.clone(),
+            configs: self.configs.clone(),
+            versions: self.versions.clone(),
+            migrations: self.migrations.clone(),
+            migrating: self.migrating.clone(),
+            tables: self.tables.clone(),
+            indexes: self.indexes.clone(),
+            max_open_indexes: self.max_open_indexes,
+            sequences: self.sequences.clone(),
+            locks: self.locks.clone(),
+            rows_scanned: AtomicU64::new(0),
+            read_only: self.read_only,
+        }
+    }
 }
 
-#[derive(Default, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub(crate) struct BaildonConfig {
-    pub index: AtomicI64,
     pub name: String,
     pub path: String,
 }
 
 impl BaildonGlue {
-    pub(crate) async fn new(path: &str) -> Result<Self> {
+    /// Create a database in a directory, which is created if necessary. Fails if the directory
+    /// already contains a database.
+    pub async fn new(path: &str) -> Result<Self> {
         // Create our path
         tokio::fs::create_dir_all(path)
             .await
@@ -70,108 +143,523 @@ impl BaildonGlue {
         let schemas: Baildon<String, Schema> = Baildon::try_new(&canonical_path, 13)
             .await
             .map_err(|e| Error::StorageMsg(e.to_string()))?;
+        canonical_path.set_file_name("data.db");
+        let tables: BaildonFamilies<Key, DataRow> = BaildonFamilies::try_new(&canonical_path, 13)
+            .await
+            .map_err(|e| Error::StorageMsg(e.to_string()))?;
         let config = BaildonConfig {
             path: config_path,
             name: config_name,
-            index: AtomicI64::new(0),
         };
+        canonical_path.set_file_name("config.db");
+        let configs: Baildon<String, BaildonConfig> =
+            Baildon::try_new(&canonical_path, 13)
+                .await
+                .map_err(|e| Error::StorageMsg(e.to_string()))?;
+        configs
+            .insert(CONFIG_KEY.to_string(), config.clone())
+            .await
+            .map_err(|e| Error::StorageMsg(e.to_string()))?;
+        canonical_path.set_file_name("versions.db");
+        let versions: Baildon<String, u64> = Baildon::try_new(&canonical_path, 13)
+            .await
+            .map_err(|e| Error::StorageMsg(e.to_string()))?;
+        canonical_path.set_file_name("migrations.db");
+        let migrations: Baildon<String, Migration> = Baildon::try_new(&canonical_path, 13)
+            .await
+            .map_err(|e| Error::StorageMsg(e.to_string()))?;
 
         Ok(BaildonGlue {
-            schemas,
+            schemas: Arc::new(schemas),
             config,
-            tables: Mutex::new(HashMap::new()),
+            configs: Arc::new(configs),
+            versions: Arc::new(versions),
+            migrations: Arc::new(migrations),
+            migrating: Arc::new(Mutex::new(())),
+            tables: Arc::new(tables),
+            indexes: Arc::new(Mutex::new(vec![])),
+            max_open_indexes: DEFAULT_MAX_OPEN_INDEXES,
+            sequences: Arc::new(Mutex::new(HashMap::new())),
+            locks: Arc::new(TableLocks::default()),
+            rows_scanned: AtomicU64::new(0),
+            read_only: false,
         })
     }
 
-    pub(crate) async fn open(path: &str) -> Result<Self> {
+    /// Open the database in a directory.
+    pub async fn open(path: &str) -> Result<Self> {
+        Self::open_with(path, false).await
+    }
+
+    /// Open the database in a directory for reading, while other processes may be changing it.
+    /// Statements which would change the database fail.
+    pub async fn open_read_only(path: &str) -> Result<Self> {
+        Self::open_with(path, true).await
+    }
+
+    async fn open_with(path: &str, read_only: bool) -> Result<Self> {
         let mut db_file = PathBuf::from(path);
         db_file.push("schema");
         db_file.set_extension("db");
-        let schemas: Baildon<String, Schema> = Baildon::try_open(&db_file)
+        let schemas: Baildon<String, Schema> = open_tree(&db_file, read_only, false).await?;
+
+        db_file.set_file_name("config.db");
+        let configs = if tokio::fs::try_exists(&db_file)
             .await
-            .map_err(|e| Error::StorageMsg(e.to_string()))?;
+            .map_err(|e| Error::StorageMsg(e.to_string()))?
+        {
+            open_tree(&db_file, read_only, false).await?
+        } else if read_only {
+            return Err(Error::StorageMsg(format!(
+                "database '{path}' must be opened for writing once, to upgrade it"
+            )));
+        } else {
+            migrate_config(&db_file).await?
+        };
+        let config = configs
+            .get(&CONFIG_KEY.to_string())
+            .await
+            .ok_or_else(|| Error::StorageMsg(format!("database '{path}' has no config")))?;
+
+        db_file.set_file_name("data.db");
+        let tables: BaildonFamilies<Key, DataRow> =
+            BaildonFamilies::from(open_tree(&db_file, read_only, true).await?);
+        // Older databases have no versions or migrations, so they start with none
+        db_file.set_file_name("versions.db");
+        let versions: Baildon<String, u64> = open_tree(&db_file, read_only, true).await?;
+        db_file.set_file_name("migrations.db");
+        let migrations: Baildon<String, Migration> = open_tree(&db_file, read_only, true).await?;
+        let glue = BaildonGlue {
+            schemas: Arc::new(schemas),
+            config,
+            configs: Arc::new(configs),
+            versions: Arc::new(versions),
+            migrations: Arc::new(migrations),
+            migrating: Arc::new(Mutex::new(())),
+            tables: Arc::new(tables),
+            indexes: Arc::new(Mutex::new(vec![])),
+            max_open_indexes: DEFAULT_MAX_OPEN_INDEXES,
+            sequences: Arc::new(Mutex::new(HashMap::new())),
+            locks: Arc::new(TableLocks::default()),
+            rows_scanned: AtomicU64::new(0),
+            read_only,
+        };
+        // Tables of older databases stay where they are until opened for writing
+        if !read_only {
+            glue.migrate_tables().await?;
+        }
+        Ok(glue)
+    }
+
+    /// Set the number of index trees which may be open at once (at least one).
+    pub fn set_max_open_indexes(&mut self, max_open_indexes: usize) {
+        self.max_open_indexes = max_open_indexes.max(1);
+    }
+
+    fn index_file(&self, table_name: &str, index_name: &str) -> PathBuf {
+        let mut index_file = PathBuf::from(&self.config.path);
+        index_file.push("indexes");
+        index_file.push(table_name);
+        index_file.push(index_name);
+        index_file.set_extension("db");
+        index_file
+    }
+
+    // Return an index, opening it if it isn't open
+    async fn get_index(&self, table_name: &str, index_name: &str) -> Result<Arc<IndexTree>> {
+        let name = (table_name.to_string(), index_name.to_string());
+        let mut indexes = self.indexes.lock().await;
+        if let Some(position) = indexes.iter().position(|(open, _tree)| *open == name) {
+            let index = indexes.remove(position);
+            let tree = index.1.clone();
+            indexes.push(index);
+            return Ok(tree);
+        }
+        let tree: IndexTree = open_tree(
+            &self.index_file(table_name, index_name),
+            self.read_only,
+            false,
+        )
+        .await?;
+        let tree = Arc::new(tree);
+        self.add_open_index(&mut indexes, name, tree.clone())
+            .await?;
+        Ok(tree)
+    }
+
+    // Add an index to our open indexes, closing the least recently used if too many are open
+    async fn add_open_index(
+        &self,
+        indexes: &mut OpenIndexes,
+        name: (String, String),
+        tree: Arc<IndexTree>,
+    ) -> Result<()> {
+        indexes.push((name, tree));
+        while indexes.len() > self.max_open_indexes {
+            let (_name, tree) = indexes.remove(0);
+            close_tree(tree).await?;
+        }
+        Ok(())
+    }
+
+    // Record a row in each of the indexes of its table
+    async fn index_row(&self, schema: &Schema, key: &Key, row: &DataRow) -> Result<()> {
+        for index in &schema.indexes {
+            let tree = self.get_index(&schema.table_name, &index.name).await?;
+            add_to_index(&tree, schema, &index.expr, key, row).await?;
+        }
+        Ok(())
+    }
+
+    // Remove a row from each of the indexes of its table
+    async fn unindex_row(&self, schema: &Schema, key: &Key, row: &DataRow) -> Result<()> {
+        for index in &schema.indexes {
+            let tree = self.get_index(&schema.table_name, &index.name).await?;
+            let value = index_key(schema, &index.expr, row).await?;
+            let Some(mut keys) = tree.get(&value).await else {
+                continue;
+            };
+            keys.retain(|k| k != key);
+            let result = if keys.is_empty() {
+                tree.delete(&value).await.map(|_v| ())
+            } else {
+                tree.insert(value, keys).await.map(|_v| ())
+            };
+            result.map_err(|e| Error::StorageMsg(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    // Return the next key of a table without a primary key.
+    //
+    // Keys follow the last key of the table, which is as durable as the rows themselves, so keys
+    // aren't reused after a restart, even if we weren't saved.
+    async fn next_key(&self, table_name: &str) -> i64 {
+        let mut sequences = self.sequences.lock().await;
+        let next = match sequences.get(table_name) {
+            Some(next) => *next,
+            None => {
+                let table = self.tables.open_cf(table_name);
+                let mut keys = table.keys(Direction::Descending).await;
+                match keys.next().await {
+                    Some(Key::I64(last)) => last + 1,
+                    _ => 0,
+                }
+            }
+        };
+        sequences.insert(table_name.to_string(), next + 1);
+        next
+    }
 
-        // let mut f_path = PathBuf::from(db_file);
-        db_file.set_extension("cfg");
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(false)
-            .create(false)
-            .open(&db_file)
+    // Close an index and remove its files
+    async fn remove_index(&self, table_name: &str, index_name: &str) -> Result<()> {
+        self.close_index(table_name, index_name).await?;
+        remove_table_files(&self.index_file(table_name, index_name)).await
+    }
+
+    async fn close_index(&self, table_name: &str, index_name: &str) -> Result<()> {
+        let name = (table_name.to_string(), index_name.to_string());
+        let mut indexes = self.indexes.lock().await;
+        match indexes.iter().position(|(open, _tree)| *open == name) {
+            Some(position) => close_tree(indexes.remove(position).1).await,
+            None => Ok(()),
+        }
+    }
+
+    // Insert or replace a schema, once its table is locked
+    async fn put_schema(&self, schema: &Schema) -> Result<()> {
+        let t_name = schema.table_name.clone();
+        let s = schema.clone();
+        // A table which was dropped may have left rows or indexes behind, if dropping failed
+        let version = if self.fetch_schema(&t_name).await?.is_none() {
+            self.remove_table_data(&t_name).await?;
+            1
+        } else {
+            self.versions.get(&t_name).await.unwrap_or_default() + 1
+        };
+        // Insert it into our schemas table
+        self.schemas
+            .insert(t_name.clone(), s)
             .await
             .map_err(|e| Error::StorageMsg(e.to_string()))?;
-        let mut s_cfg = String::new();
-        let _ = file
-            .read_to_string(&mut s_cfg)
+        self.versions
+            .insert(t_name, version)
             .await
             .map_err(|e| Error::StorageMsg(e.to_string()))?;
-        let config: BaildonConfig =
-            serde_json::from_str(&s_cfg).map_err(|e| Error::StorageMsg(e.to_string()))?;
-        Ok(BaildonGlue {
-            schemas,
-            config,
-            tables: Mutex::new(HashMap::new()),
-        })
+        Ok(())
     }
 
-    pub(crate) async fn save(&self) -> Result<()> {
-        let mut f_path = PathBuf::from(&self.config.path);
-        f_path.push("schema");
-        f_path.set_extension("cfg");
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&f_path)
+    // Insert or replace rows, once their table is locked
+    async fn put_rows(&self, table_name: &str, rows: Vec<(Key, DataRow)>) -> Result<()> {
+        let (table, schema) = self.get_table_and_schema(table_name).await?;
+        for (key, row) in rows {
+            // Updated rows replace their old values in our indexes
+            if let Some(old) = table.get(&key).await {
+                self.unindex_row(&schema, &key, &old).await?;
+            }
+            self.index_row(&schema, &key, &row).await?;
+            table
+                .insert(key, row)
+                .await
+                .map_err(|e| Error::StorageMsg(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    // Remove the rows and index files of a table which has no schema. Both are found from the
+    // name of the table, so this is safe to repeat.
+    async fn remove_table_data(&self, table_name: &str) -> Result<()> {
+        self.tables
+            .open_cf(table_name)
+            .clear()
             .await
             .map_err(|e| Error::StorageMsg(e.to_string()))?;
-        let s_config =
-            serde_json::to_string(&self.config).map_err(|e| Error::StorageMsg(e.to_string()))?;
-        file.write_all(s_config.as_bytes())
-            .await
-            .map_err(|e| Error::StorageMsg(e.to_string()))
+        let mut index_dir = PathBuf::from(&self.config.path);
+        index_dir.push("indexes");
+        index_dir.push(table_name);
+        match tokio::fs::remove_dir_all(&index_dir).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(Error::StorageMsg(e.to_string()))
+            }
+            _ => Ok(()),
+        }
     }
 
-    async fn get_table(&self, name: &str) -> Result<Arc<Baildon<Key, DataRow>>> {
-        let mut table_lock = self.tables.lock().await;
+    // Older databases stored each table in its own file. Move their rows into our data file.
+    async fn migrate_tables(&self) -> Result<()> {
+        let names = self
+            .schemas
+            .keys(Direction::Ascending)
+            .await
+            .collect::<Vec<String>>()
+            .await;
+        for name in names {
+            let mut table_file = PathBuf::from(&self.config.path);
+            table_file.push(&name);
+            table_file.set_extension("db");
+            if !tokio::fs::try_exists(&table_file)
+                .await
+                .map_err(|e| Error::StorageMsg(e.to_string()))?
+            {
+                continue;
+            }
+            let table: Baildon<Key, DataRow> = Baildon::try_open(&table_file)
+                .await
+                .map_err(|e| Error::StorageMsg(e.to_string()))?;
+            let family = self.tables.open_cf(&name);
+            let mut entries = table.entries(Direction::Ascending).await;
+            while let Some((key, row)) = entries.next().await {
+                family
+                    .insert(key, row)
+                    .await
+                    .map_err(|e| Error::StorageMsg(e.to_string()))?;
+            }
+            drop(entries);
+            table
+                .close()
+                .await
+                .map_err(|e| Error::StorageMsg(e.to_string()))?;
+            // Only remove the old file once its rows are safely in our data file
+            self.tables
+                .tree()
+                .flush_to_disk()
+                .await
+                .map_err(|e| Error::StorageMsg(e.to_string()))?;
+            remove_table_files(&table_file).await?;
+        }
+        Ok(())
+    }
 
-        let t_name = name.to_string();
-        match table_lock.get(&t_name) {
-            Some(db) => Ok(db.clone()),
-            None => {
-                if self.fetch_schema(&t_name).await?.is_none() {
-                    return Err(Error::StorageMsg(format!(
-                        "schema '{t_name}' does not exist"
-                    )));
+    /// Flush and close the files of the database. While other clones are open, the files are
+    /// only flushed, and they are closed with the last clone.
+    pub async fn close(self) -> Result<()> {
+        match Arc::try_unwrap(self.indexes) {
+            Ok(indexes) => {
+                for (_name, tree) in indexes.into_inner() {
+                    close_tree(tree).await?;
                 }
-                let mut table_file = PathBuf::from(self.config.path.clone());
-                table_file.push(&t_name);
-                table_file.set_extension("db");
-                // First try to open, if we can open add it to the HashMap and return
-                let table: Baildon<Key, DataRow> = match Baildon::try_open(table_file.clone()).await
-                {
-                    Ok(tbl) => tbl,
-                    Err(err) => {
-                        if let Some(io_error) = err.downcast_ref::<std::io::Error>() {
-                            if io_error.kind() == ErrorKind::NotFound {
-                                Baildon::try_new(table_file, 13)
-                                    .await
-                                    .map_err(|e| Error::StorageMsg(e.to_string()))?
-                            } else {
-                                return Err(Error::StorageMsg(err.to_string()));
-                            }
-                        } else {
-                            return Err(Error::StorageMsg(err.to_string()));
-                        }
-                    }
-                };
-                table_lock.insert(t_name.clone(), Arc::new(table));
-                Ok(table_lock.get(&t_name).expect("MUST BE THERE").clone())
+            }
+            Err(indexes) => {
+                for (_name, tree) in indexes.lock().await.iter() {
+                    tree.flush_to_disk()
+                        .await
+                        .map_err(|e| Error::StorageMsg(e.to_string()))?;
+                }
+            }
+        }
+        match Arc::try_unwrap(self.tables) {
+            Ok(tables) => tables.close().await,
+            Err(tables) => tables.tree().flush_to_disk().await,
+        }
+        .map_err(|e| Error::StorageMsg(e.to_string()))?;
+        close_tree(self.schemas).await?;
+        close_tree(self.versions).await?;
+        close_tree(self.migrations).await?;
+        close_tree(self.configs).await
+    }
+
+    // Lock a table, so no other session changes it until the guard is dropped. Fails if we may
+    // not change it.
+    async fn lock_table(&self, table_name: &str) -> Result<OwnedMutexGuard<()>> {
+        if self.read_only {
+            return Err(Error::StorageMsg("database is read only".to_string()));
+        }
+        let lock = self
+            .locks
+            .lock()
+            .expect("table locks aren't poisoned")
+            .entry(table_name.to_string())
+            .or_default()
+            .clone();
+        Ok(lock.lock_owned().await)
+    }
+
+    async fn get_table(&self, name: &str) -> Result<ColumnFamily<'_, Key, DataRow>> {
+        self.get_table_and_schema(name)
+            .await
+            .map(|(table, _schema)| table)
+    }
+
+    async fn get_table_and_schema(
+        &self,
+        name: &str,
+    ) -> Result<(ColumnFamily<'_, Key, DataRow>, Schema)> {
+        match self.fetch_schema(name).await? {
+            Some(schema) => Ok((self.tables.open_cf(name), schema)),
+            None => Err(Error::StorageMsg(format!("schema '{name}' does not exist"))),
+        }
+    }
+
+    /// Compact the data files of a table, or of the whole database, returning the number of bytes
+    /// reclaimed from each file. The rows of every table share one data file, which is compacted
+    /// either way.
+    pub async fn vacuum(&self, table_name: Option<&str>) -> Result<Vec<(String, u64)>> {
+        let schemas = match table_name {
+            Some(table_name) => vec![self.get_table_and_schema(table_name).await?.1],
+            None => self.fetch_all_schemas().await?,
+        };
+        let mut reclaimed = vec![];
+        if table_name.is_none() {
+            let bytes = self
+                .schemas
+                .compact()
+                .await
+                .map_err(|e| Error::StorageMsg(e.to_string()))?;
+            reclaimed.push(("schema".to_string(), bytes));
+        }
+        let bytes = self
+            .tables
+            .tree()
+            .compact()
+            .await
+            .map_err(|e| Error::StorageMsg(e.to_string()))?;
+        reclaimed.push(("data".to_string(), bytes));
+        for schema in schemas {
+            for index in &schema.indexes {
+                let bytes = self
+                    .get_index(&schema.table_name, &index.name)
+                    .await?
+                    .compact()
+                    .await
+                    .map_err(|e| Error::StorageMsg(e.to_string()))?;
+                reclaimed.push((format!("{}.{}", schema.table_name, index.name), bytes));
+            }
+        }
+        Ok(reclaimed)
+    }
+
+    /// Return the statistics of the trees of a table, or of the whole database, as for vacuum.
+    pub async fn stats(&self, table_name: Option<&str>) -> Result<Vec<(String, TreeStats)>> {
+        let schemas = match table_name {
+            Some(table_name) => vec![self.get_table_and_schema(table_name).await?.1],
+            None => self.fetch_all_schemas().await?,
+        };
+        let mut stats = vec![];
+        if table_name.is_none() {
+            let tree_stats = self
+                .schemas
+                .stats()
+                .await
+                .map_err(|e| Error::StorageMsg(e.to_string()))?;
+            stats.push(("schema".to_string(), tree_stats));
+        }
+        let tree_stats = self
+            .tables
+            .tree()
+            .stats()
+            .await
+            .map_err(|e| Error::StorageMsg(e.to_string()))?;
+        stats.push(("data".to_string(), tree_stats));
+        for schema in schemas {
+            for index in &schema.indexes {
+                let tree_stats = self
+                    .get_index(&schema.table_name, &index.name)
+                    .await?
+                    .stats()
+                    .await
+                    .map_err(|e| Error::StorageMsg(e.to_string()))?;
+                stats.push((format!("{}.{}", schema.table_name, index.name), tree_stats));
             }
         }
+        Ok(stats)
+    }
+
+    /// Return the work done since we were opened. Rows are counted by this session, and nodes by
+    /// our trees, which may be shared with other sessions.
+    pub async fn query_stats(&self) -> QueryStats {
+        let mut metrics = vec![self.schemas.metrics(), self.tables.tree().metrics()];
+        for (_name, tree) in self.indexes.lock().await.iter() {
+            metrics.push(tree.metrics());
+        }
+        QueryStats {
+            rows_scanned: self.rows_scanned.load(Ordering::Relaxed),
+            nodes_read: metrics.iter().map(|metrics| metrics.cache_misses).sum(),
+            cache_hits: metrics.iter().map(|metrics| metrics.cache_hits).sum(),
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    // Count rows returned by a scan
+    fn scanned(&self, rows: usize) {
+        self.rows_scanned.fetch_add(rows as u64, Ordering::Relaxed);
+    }
+
+    /// Return the version of the schema of a table, which is 1 when it is created and counts the
+    /// changes to it. Tables of older databases have no version until they are changed.
+    pub async fn schema_version(&self, table_name: &str) -> Option<u64> {
+        self.versions.get(&table_name.to_string()).await
+    }
+
+    /// Return the migration scripts which have been applied, in order of name.
+    pub async fn migrations(&self) -> Vec<Migration> {
+        self.migrations
+            .values(Direction::Ascending)
+            .await
+            .collect::<Vec<Migration>>()
+            .await
+    }
+
+    // Record that a migration script was applied
+    pub(crate) async fn record_migration(&self, migration: Migration) -> Result<()> {
+        self.migrations
+            .insert(migration.name.clone(), migration)
+            .await
+            .map(|_old| ())
+            .map_err(|e| Error::StorageMsg(e.to_string()))
+    }
+
+    // Lock our migrations, so no other session applies them until the guard is dropped
+    pub(crate) async fn lock_migrations(&self) -> OwnedMutexGuard<()> {
+        self.migrating.clone().lock_owned().await
     }
 
-    pub(crate) async fn print_tables(&self) -> Result<()> {
+    /// Return the number of rows in a table.
+    pub async fn row_count(&self, table_name: &str) -> Result<usize> {
+        Ok(self.get_table(table_name).await?.len().await)
+    }
+
+    /// Print the rows of every table, for debugging.
+    pub async fn print_tables(&self) -> Result<()> {
         let mut streamer = self.schemas.keys(Direction::Ascending).await;
         while let Some(table) = streamer.next().await {
             self.print_table(&table).await?;
@@ -179,16 +667,16 @@ impl BaildonGlue {
         Ok(())
     }
 
-    pub(crate) async fn print_table(&self, table_name: &str) -> Result<()> {
+    /// Print the rows of a table, for debugging.
+    pub async fn print_table(&self, table_name: &str) -> Result<()> {
         let table = self.get_table(table_name).await?;
         let mut sep = "";
-        let callback = |(key, value)| {
+        let mut entries = table.entries(Direction::Ascending).await;
+        while let Some((key, value)) = entries.next().await {
             print!("{sep}{key:?}:{value:?}");
             sep = ", ";
-            ControlFlow::Continue(())
-        };
-        table.traverse_entries(Direction::Ascending, callback).await;
-        println!("\nutilization: {}", table.utilization().await);
+        }
+        println!("\nutilization: {}", self.tables.tree().utilization().await);
         println!();
         Ok(())
     }
@@ -212,61 +700,82 @@ impl Store for BaildonGlue {
 
     async fn fetch_data(&self, table_name: &str, key: &Key) -> Result<Option<DataRow>> {
         let table = self.get_table(table_name).await?;
-        table.get(key).await.map(Ok).transpose()
+        let row = table.get(key).await;
+        self.scanned(row.iter().count());
+        Ok(row)
     }
 
     async fn scan_data(&self, table_name: &str) -> Result<RowIter> {
         let table = self.get_table(table_name).await?;
-        Ok(Box::new(
-            table
-                .entries(Direction::Ascending)
-                .await
-                .collect::<Vec<(Key, DataRow)>>()
-                .await
-                .into_iter()
-                .map(Ok),
-        ))
+        let rows = table
+            .entries(Direction::Ascending)
+            .await
+            .collect::<Vec<(Key, DataRow)>>()
+            .await;
+        self.scanned(rows.len());
+        Ok(Box::new(rows.into_iter().map(Ok)))
     }
 }
 
 #[async_trait::async_trait(?Send)]
 impl StoreMut for BaildonGlue {
     async fn insert_schema(&mut self, schema: &Schema) -> Result<()> {
-        let t_name = schema.table_name.clone();
-        let s = schema.clone();
-        // Insert it into our schemas table
-        self.schemas
-            .insert(t_name, s)
-            .await
-            .map_err(|e| Error::StorageMsg(e.to_string()))?;
-        Ok(())
+        let _lock = self.lock_table(&schema.table_name).await?;
+        self.put_schema(schema).await
     }
 
     async fn delete_schema(&mut self, table_name: &str) -> Result<()> {
-        let mut db_file = PathBuf::from(table_name);
+        let _lock = self.lock_table(table_name).await?;
         let t_name = table_name.to_string();
-        db_file.set_extension("db");
-        let _ = tokio::fs::remove_file(&db_file)
-            .await
-            .map_err(|e| Error::StorageMsg(e.to_string()));
-        db_file.set_extension("wal");
-        let _ = tokio::fs::remove_file(&db_file)
+        // The schema goes first, so the table is dropped as soon as it is gone. If removing its
+        // data fails, the remains are removed when its name is next used.
+        let schema = self
+            .schemas
+            .delete(&t_name)
             .await
-            .map_err(|e| Error::StorageMsg(e.to_string()));
-        self.schemas
+            .map_err(|e| Error::StorageMsg(e.to_string()))?;
+        self.sequences.lock().await.remove(table_name);
+        self.versions
             .delete(&t_name)
             .await
-            .map(|_v| ())
             .map_err(|e| Error::StorageMsg(e.to_string()))?;
-        Ok(())
+        for index in schema.iter().flat_map(|schema| &schema.indexes) {
+            self.close_index(table_name, &index.name).await?;
+        }
+        self.remove_table_data(table_name).await
     }
 
     async fn append_data(&mut self, table_name: &str, rows: Vec<DataRow>) -> Result<()> {
-        let table = self.get_table(table_name).await?;
+        let _lock = self.lock_table(table_name).await?;
+        let schema = self.get_table_and_schema(table_name).await?.1;
+        let primary_key = schema.column_defs.iter().flatten().position(|column_def| {
+            column_def.unique == Some(ColumnUniqueOption { is_primary: true })
+        });
+        // Rows with a primary key are keyed by it
+        if let Some(i) = primary_key {
+            let rows = rows
+                .into_iter()
+                .map(|row| {
+                    let key = match &row {
+                        DataRow::Vec(values) => values.get(i).map(Key::try_from),
+                        DataRow::Map(_) => None,
+                    };
+                    match key {
+                        Some(key) => Ok((key?, row)),
+                        None => Err(Error::StorageMsg(format!(
+                            "row of '{table_name}' has no primary key"
+                        ))),
+                    }
+                })
+                .collect::<Result<Vec<(Key, DataRow)>>>()?;
+            return self.put_rows(table_name, rows).await;
+        }
         for row in rows {
-            let idx = self.config.index.fetch_add(1, Ordering::SeqCst);
-            table
-                .insert(Key::I64(idx), row)
+            let key = Key::I64(self.next_key(table_name).await);
+            self.index_row(&schema, &key, &row).await?;
+            self.tables
+                .open_cf(table_name)
+                .insert(key, row)
                 .await
                 .map_err(|e| Error::StorageMsg(e.to_string()))?;
         }
@@ -274,31 +783,257 @@ impl StoreMut for BaildonGlue {
     }
 
     async fn insert_data(&mut self, table_name: &str, rows: Vec<(Key, DataRow)>) -> Result<()> {
-        let table = self.get_table(table_name).await?;
-        for (key, row) in rows {
-            table
-                .insert(key, row)
-                .await
-                .map_err(|e| Error::StorageMsg(e.to_string()))?;
-        }
-        Ok(())
+        let _lock = self.lock_table(table_name).await?;
+        self.put_rows(table_name, rows).await
     }
 
     async fn delete_data(&mut self, table_name: &str, keys: Vec<Key>) -> Result<()> {
-        let table = self.get_table(table_name).await?;
+        let _lock = self.lock_table(table_name).await?;
+        let (table, schema) = self.get_table_and_schema(table_name).await?;
         for key in keys {
-            table
+            let old = table
                 .delete(&key)
                 .await
                 .map_err(|e| Error::StorageMsg(e.to_string()))?;
+            if let Some(old) = old {
+                self.unindex_row(&schema, &key, &old).await?;
+            }
         }
         Ok(())
     }
 }
 
-impl Index for BaildonGlue {}
+// Open a tree, for reading only if required. Missing trees are created if required, unless we are
+// reading only, when they are empty trees of our own.
+async fn open_tree<K: BaildonKey, V: BaildonValue>(
+    file: &Path,
+    read_only: bool,
+    create: bool,
+) -> Result<Baildon<K, V>> {
+    let result = if read_only {
+        let exists = tokio::fs::try_exists(file)
+            .await
+            .map_err(|e| Error::StorageMsg(e.to_string()))?;
+        if create && !exists {
+            Baildon::temporary(13).await
+        } else {
+            Baildon::try_open_shared(file).await
+        }
+    } else if create {
+        Baildon::open_or_create(file, 13).await
+    } else {
+        Baildon::try_open(file).await
+    };
+    result.map_err(|e| Error::StorageMsg(e.to_string()))
+}
+
+// Close a tree, or if it's still in use, flush it, so it's closed cleanly once dropped
+async fn close_tree<K: BaildonKey, V: BaildonValue>(tree: Arc<Baildon<K, V>>) -> Result<()> {
+    match Arc::try_unwrap(tree) {
+        Ok(tree) => tree.close().await,
+        Err(tree) => tree.flush_to_disk().await,
+    }
+    .map_err(|e| Error::StorageMsg(e.to_string()))
+}
+
+// Databases created by older versions keep their config in a JSON file, which is only written
+// when they are saved. Move it into a tree of its own.
+async fn migrate_config(config_file: &Path) -> Result<Baildon<String, BaildonConfig>> {
+    let json_file = config_file.with_file_name("schema.cfg");
+    let s_cfg = tokio::fs::read_to_string(&json_file)
+        .await
+        .map_err(|e| Error::StorageMsg(e.to_string()))?;
+    let config: BaildonConfig =
+        serde_json::from_str(&s_cfg).map_err(|e| Error::StorageMsg(e.to_string()))?;
+    let configs = Baildon::try_new(config_file, 13)
+        .await
+        .map_err(|e| Error::StorageMsg(e.to_string()))?;
+    configs
+        .insert(CONFIG_KEY.to_string(), config)
+        .await
+        .map_err(|e| Error::StorageMsg(e.to_string()))?;
+    configs
+        .flush_to_disk()
+        .await
+        .map_err(|e| Error::StorageMsg(e.to_string()))?;
+    tokio::fs::remove_file(&json_file)
+        .await
+        .map_err(|e| Error::StorageMsg(e.to_string()))?;
+    Ok(configs)
+}
 
-impl IndexMut for BaildonGlue {}
+async fn remove_table_files(table_file: &Path) -> Result<()> {
+    let mut table_file = table_file.to_path_buf();
+    tokio::fs::remove_file(&table_file)
+        .await
+        .map_err(|e| Error::StorageMsg(e.to_string()))?;
+    table_file.set_extension("wal");
+    match tokio::fs::remove_file(&table_file).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::StorageMsg(e.to_string())),
+        _ => Ok(()),
+    }
+}
+
+// Evaluate an index expression against a row
+async fn index_key(schema: &Schema, expr: &Expr, row: &DataRow) -> Result<Key> {
+    let row = match row {
+        DataRow::Vec(values) => Row::Vec {
+            columns: schema
+                .column_defs
+                .iter()
+                .flatten()
+                .map(|column_def| column_def.name.clone())
+                .collect(),
+            values: values.clone(),
+        },
+        DataRow::Map(values) => Row::Map(values.clone()),
+    };
+    let context = RowContext::new(&schema.table_name, Cow::Owned(row), None);
+    evaluate_stateless(Some(context), expr).await?.try_into()
+}
+
+async fn add_to_index(
+    tree: &IndexTree,
+    schema: &Schema,
+    expr: &Expr,
+    key: &Key,
+    row: &DataRow,
+) -> Result<()> {
+    let value = index_key(schema, expr, row).await?;
+    let mut keys = tree.get(&value).await.unwrap_or_default();
+    keys.push(key.clone());
+    tree.insert(value, keys)
+        .await
+        .map(|_v| ())
+        .map_err(|e| Error::StorageMsg(e.to_string()))
+}
+
+#[async_trait::async_trait(?Send)]
+impl Index for BaildonGlue {
+    async fn scan_indexed_data(
+        &self,
+        table_name: &str,
+        index_name: &str,
+        asc: Option<bool>,
+        cmp_value: Option<(&IndexOperator, Value)>,
+    ) -> Result<RowIter> {
+        let table = self.get_table(table_name).await?;
+        let tree = self.get_index(table_name, index_name).await?;
+        let direction = if asc == Some(false) {
+            Direction::Descending
+        } else {
+            Direction::Ascending
+        };
+        let range = match cmp_value {
+            Some((op, value)) => {
+                let value = Key::try_from(value)?;
+                // Nothing compares with NULL
+                if value == Key::None {
+                    return Ok(Box::new(std::iter::empty()));
+                }
+                match op {
+                    IndexOperator::Gt => (Bound::Excluded(value), Bound::Unbounded),
+                    IndexOperator::GtEq => (Bound::Included(value), Bound::Unbounded),
+                    IndexOperator::Lt => (Bound::Unbounded, Bound::Excluded(value)),
+                    IndexOperator::LtEq => (Bound::Unbounded, Bound::Included(value)),
+                    IndexOperator::Eq => (Bound::Included(value.clone()), Bound::Included(value)),
+                }
+            }
+            None => (Bound::Unbounded, Bound::Unbounded),
+        };
+        // NULLs are ordered last, but are only part of unbounded scans
+        let comparing = range != (Bound::Unbounded, Bound::Unbounded);
+        let entries = tree
+            .range(range, direction)
+            .await
+            .collect::<Vec<(Key, Vec<Key>)>>()
+            .await;
+        let mut rows = vec![];
+        for (value, keys) in entries {
+            if comparing && value == Key::None {
+                continue;
+            }
+            for key in keys {
+                let row = table.get(&key).await.ok_or_else(|| {
+                    Error::StorageMsg(format!(
+                        "index '{index_name}' refers to a missing row: {key:?}"
+                    ))
+                });
+                rows.push(row.map(|row| (key, row)));
+            }
+        }
+        self.scanned(rows.len());
+        Ok(Box::new(rows.into_iter()))
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl IndexMut for BaildonGlue {
+    async fn create_index(
+        &mut self,
+        table_name: &str,
+        index_name: &str,
+        column: &OrderByExpr,
+    ) -> Result<()> {
+        let _lock = self.lock_table(table_name).await?;
+        let mut schema = self
+            .fetch_schema(table_name)
+            .await?
+            .ok_or_else(|| IndexError::TableNotFound(table_name.to_string()))?;
+        if schema.indexes.iter().any(|index| index.name == index_name) {
+            return Err(IndexError::IndexNameAlreadyExists(index_name.to_string()).into());
+        }
+
+        let index_file = self.index_file(table_name, index_name);
+        if let Some(index_dir) = index_file.parent() {
+            tokio::fs::create_dir_all(index_dir)
+                .await
+                .map_err(|e| Error::StorageMsg(e.to_string()))?;
+        }
+        let tree: IndexTree = Baildon::try_new(&index_file, 13)
+            .await
+            .map_err(|e| Error::StorageMsg(e.to_string()))?;
+        // Index the existing rows of the table
+        let rows = self
+            .tables
+            .open_cf(table_name)
+            .entries(Direction::Ascending)
+            .await
+            .collect::<Vec<(Key, DataRow)>>()
+            .await;
+        for (key, row) in rows {
+            add_to_index(&tree, &schema, &column.expr, &key, &row).await?;
+        }
+
+        // Our trees can be scanned in either direction
+        schema.indexes.push(SchemaIndex {
+            name: index_name.to_string(),
+            expr: column.expr.clone(),
+            order: SchemaIndexOrd::Both,
+            created: Utc::now().naive_utc(),
+        });
+        let name = (table_name.to_string(), index_name.to_string());
+        let mut indexes = self.indexes.lock().await;
+        self.add_open_index(&mut indexes, name, Arc::new(tree))
+            .await?;
+        drop(indexes);
+        self.put_schema(&schema).await
+    }
+
+    async fn drop_index(&mut self, table_name: &str, index_name: &str) -> Result<()> {
+        let _lock = self.lock_table(table_name).await?;
+        let mut schema = self
+            .fetch_schema(table_name)
+            .await?
+            .ok_or_else(|| IndexError::TableNotFound(table_name.to_string()))?;
+        if !schema.indexes.iter().any(|index| index.name == index_name) {
+            return Err(IndexError::IndexNameDoesNotExist(index_name.to_string()).into());
+        }
+        schema.indexes.retain(|index| index.name != index_name);
+        self.put_schema(&schema).await?;
+        self.remove_index(table_name, index_name).await
+    }
+}
 
 impl AlterTable for BaildonGlue {}
 
@@ -0,0 +1,36 @@
+#![warn(missing_docs)]
+//! GlueSQL storage in Baildon B+Trees
+//!
+//! [`BaildonGlue`] implements the storage traits of [GlueSQL](https://gluesql.org), so SQL
+//! databases can be embedded in applications:
+//!
+//! ```no_run
+//! use baildon_glue::gluesql::prelude::Glue;
+//! use baildon_glue::BaildonGlue;
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let storage = BaildonGlue::new("shop").await?;
+//! let mut glue = Glue::new(storage);
+//! glue.execute_async("CREATE TABLE fruit (name TEXT PRIMARY KEY, price FLOAT)")
+//!     .await?;
+//! glue.execute_async("INSERT INTO fruit VALUES ('apple', 0.5)")
+//!     .await?;
+//! glue.storage.close().await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The tables of a database share one tree, as column families. The schemas of tables, and
+//! each of their indexes, are kept in trees of their own.
+
+pub use gluesql;
+
+pub use glue::BaildonGlue;
+pub use glue::DEFAULT_MAX_OPEN_INDEXES;
+pub use migrate::migrate;
+pub use migrate::Migration;
+pub use stats::QueryStats;
+
+mod glue;
+mod migrate;
+mod stats;
@@ -1,16 +1,38 @@
 use std::env;
 use std::fs::metadata;
 use std::ops::ControlFlow;
+use std::path::Path;
 use std::path::PathBuf;
+use std::time::Instant;
 
+use anyhow::bail;
+use anyhow::Context;
 use anyhow::Result;
 use baildon::btree::Direction;
+use baildon_glue::BaildonGlue;
+use baildon_glue::QueryStats;
 use clap::Parser;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use gluesql::core::ast::Expr;
+use gluesql::core::ast::ToSql;
+use gluesql::core::sqlparser::ast::Statement as SqlStatement;
+use gluesql::core::store::DataRow;
+use gluesql::core::store::Store;
 use gluesql::prelude::*;
 use rustyline::error::ReadlineError;
 use rustyline::DefaultEditor;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
 
-mod glue;
+mod output;
+mod pgwire;
+
+use output::OutputMode;
+use pgwire::Reply;
+use pgwire::Request;
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
@@ -21,6 +43,30 @@ struct Cli {
     /// Create a new database (will overwrite existing file)
     #[arg(short, long, default_value_t = false)]
     create: bool,
+
+    /// Open the database for reading only, while other processes may be changing it
+    #[arg(long, default_value_t = false, conflicts_with = "create")]
+    read_only: bool,
+
+    /// Execute the SQL statements in a file, rather than interactively
+    #[arg(short, long)]
+    file: Option<PathBuf>,
+
+    /// Output mode for the rows of queries (Table, Csv or Json)
+    #[arg(short, long, default_value = "Table")]
+    output: OutputMode,
+
+    /// Serve queries on an address with the PostgreSQL protocol, rather than interactively
+    #[arg(long)]
+    serve: Option<String>,
+
+    /// Print SQL statements which recreate the database, rather than running interactively
+    #[arg(long, default_value_t = false, conflicts_with_all = ["file", "serve"])]
+    dump: bool,
+
+    /// Maximum number of index trees kept open at once
+    #[arg(long, default_value_t = baildon_glue::DEFAULT_MAX_OPEN_INDEXES)]
+    max_open_indexes: usize,
 }
 
 fn get_history_file() -> Option<PathBuf> {
@@ -40,30 +86,232 @@ fn get_history_file() -> Option<PathBuf> {
         })
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let isatty = unsafe { libc::isatty(0) };
+/// Execute the semicolon separated statements in a file, stopping at the first which fails.
+async fn read_file(glue: &mut Glue<BaildonGlue>, file: &Path, output: OutputMode) -> Result<()> {
+    let sql = tokio::fs::read_to_string(file)
+        .await
+        .with_context(|| format!("could not read: {}", file.display()))?;
+    let statements = parse(&sql)?;
+    for (number, statement) in statements.iter().enumerate() {
+        let payload = execute_statement(glue, statement)
+            .await
+            .with_context(|| format!("statement: {}: {statement}", number + 1))?;
+        output::print_payload(&payload, output, false)?;
+    }
+    Ok(())
+}
 
-    let cli = Cli::parse();
+async fn execute_statement(
+    glue: &mut Glue<BaildonGlue>,
+    statement: &SqlStatement,
+) -> std::result::Result<Payload, Error> {
+    let statement = translate(statement)?;
+    let statement = plan(&glue.storage, statement).await?;
+    glue.execute_stmt_async(&statement).await
+}
 
-    let log_dir = match env::var("TMPDIR") {
-        Ok(d) => d,
-        Err(_e) => ".".to_string(),
-    };
+/// Process a REPL meta-command, which starts with a `.`. `last` is the work done by the last SQL
+/// we executed.
+async fn dot_command(
+    glue: &mut Glue<BaildonGlue>,
+    line: &str,
+    output: OutputMode,
+    last: &QueryStats,
+) -> Result<()> {
+    let line = line.trim();
+    let (command, argument) = line
+        .split_once(char::is_whitespace)
+        .map_or((line, ""), |(command, argument)| (command, argument.trim()));
+    match command {
+        ".read" if !argument.is_empty() => read_file(glue, Path::new(argument), output).await,
+        ".read" => bail!("usage: .read <file>"),
+        ".vacuum" => {
+            let table_name = Some(argument).filter(|argument| !argument.is_empty());
+            for (name, bytes) in glue.storage.vacuum(table_name).await? {
+                println!("{name}: {bytes} bytes reclaimed");
+            }
+            Ok(())
+        }
+        ".tables" => {
+            for schema in glue.storage.fetch_all_schemas().await? {
+                println!("{}", schema.table_name);
+            }
+            Ok(())
+        }
+        ".schema" => {
+            let schemas = match argument {
+                "" => glue.storage.fetch_all_schemas().await?,
+                table_name => match glue.storage.fetch_schema(table_name).await? {
+                    Some(schema) => vec![schema],
+                    None => bail!("table does not exist: {table_name}"),
+                },
+            };
+            for schema in schemas {
+                if let Some(version) = glue.storage.schema_version(&schema.table_name).await {
+                    println!("-- version: {version}");
+                }
+                println!("{}", schema.to_ddl());
+            }
+            Ok(())
+        }
+        ".import" => match argument.split_whitespace().collect::<Vec<&str>>()[..] {
+            [file, table_name] => {
+                let count = import_csv(glue, Path::new(file), table_name).await?;
+                println!("imported: {count} rows into {table_name}");
+                Ok(())
+            }
+            _ => bail!("usage: .import <csv> <table>"),
+        },
+        ".stats" => {
+            let table_name = Some(argument).filter(|argument| !argument.is_empty());
+            if let Some(table_name) = table_name {
+                println!("rows: {}", glue.storage.row_count(table_name).await?);
+            }
+            for (name, stats) in glue.storage.stats(table_name).await? {
+                println!("{name}:\n{stats}");
+            }
+            Ok(())
+        }
+        ".dump" => dump(glue).await,
+        ".migrate" if !argument.is_empty() => {
+            for name in baildon_glue::migrate(glue, Path::new(argument)).await? {
+                println!("applied: {name}");
+            }
+            Ok(())
+        }
+        ".migrate" => bail!("usage: .migrate <dir>"),
+        ".migrations" => {
+            for migration in glue.storage.migrations().await {
+                println!("{}: applied {}", migration.name, migration.applied);
+            }
+            Ok(())
+        }
+        ".explain" => {
+            println!("{last}");
+            Ok(())
+        }
+        _ => bail!("unknown command: {command}"),
+    }
+}
 
-    let file_appender = tracing_appender::rolling::daily(log_dir, "baildon-glue.log");
-    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
-    tracing_subscriber::fmt().with_writer(non_blocking).init();
+/// Insert the rows of a CSV file, which has a header row, into a table. If the table doesn't
+/// exist, it is created with a TEXT column for each field. Empty fields are inserted as NULL.
+/// Returns the number of rows inserted.
+async fn import_csv(glue: &mut Glue<BaildonGlue>, file: &Path, table_name: &str) -> Result<usize> {
+    // Rows are inserted in batches, to limit the size of each statement
+    const BATCH_SIZE: usize = 100;
 
-    let storage: glue::BaildonGlue = if cli.create {
-        glue::BaildonGlue::new(&cli.database).await?
-    } else {
-        glue::BaildonGlue::open(&cli.database).await?
-    };
+    let mut reader = csv::Reader::from_path(file)
+        .with_context(|| format!("could not read: {}", file.display()))?;
+    let columns = reader
+        .headers()?
+        .iter()
+        .map(quote_identifier)
+        .collect::<Vec<String>>();
+    let table = quote_identifier(table_name);
+    if glue.storage.fetch_schema(table_name).await?.is_none() {
+        let definitions = columns
+            .iter()
+            .map(|column| format!("{column} TEXT"))
+            .collect::<Vec<String>>()
+            .join(", ");
+        glue.execute_async(format!("CREATE TABLE {table} ({definitions})"))
+            .await?;
+    }
 
-    // let storage = SharedMemoryStorage::new();
-    let mut glue = Glue::new(storage);
+    let prefix = format!("INSERT INTO {table} ({}) VALUES ", columns.join(", "));
+    let mut count = 0;
+    let mut values = vec![];
+    for record in reader.records() {
+        let literals = record?
+            .iter()
+            .map(|field| match field {
+                "" => "NULL".to_string(),
+                field => quote_literal(field),
+            })
+            .collect::<Vec<String>>();
+        values.push(format!("({})", literals.join(", ")));
+        if values.len() == BATCH_SIZE {
+            count += insert_values(glue, &prefix, &mut values, count).await?;
+        }
+    }
+    if !values.is_empty() {
+        count += insert_values(glue, &prefix, &mut values, count).await?;
+    }
+    Ok(count)
+}
 
+// Execute an INSERT of the supplied values, which follow `count` rows already inserted
+async fn insert_values(
+    glue: &mut Glue<BaildonGlue>,
+    prefix: &str,
+    values: &mut Vec<String>,
+    count: usize,
+) -> Result<usize> {
+    let len = values.len();
+    let sql = format!("{prefix}{}", values.join(", "));
+    values.clear();
+    glue.execute_async(sql)
+        .await
+        .with_context(|| format!("rows: {}..{}", count + 1, count + len))?;
+    Ok(len)
+}
+
+/// Print the statements which recreate every table, with its indexes and rows.
+async fn dump(glue: &Glue<BaildonGlue>) -> Result<()> {
+    for schema in glue.storage.fetch_all_schemas().await? {
+        println!("{}", schema.to_ddl());
+        let table = quote_identifier(&schema.table_name);
+        let columns = match &schema.column_defs {
+            Some(column_defs) => {
+                let names = column_defs
+                    .iter()
+                    .map(|column_def| quote_identifier(&column_def.name))
+                    .collect::<Vec<String>>();
+                format!(" ({})", names.join(", "))
+            }
+            None => String::new(),
+        };
+        for row in glue.storage.scan_data(&schema.table_name).await? {
+            let literals = match row?.1 {
+                DataRow::Vec(values) => values
+                    .into_iter()
+                    .map(sql_literal)
+                    .collect::<Result<Vec<String>>>()?,
+                // Rows of schemaless tables are inserted as JSON objects
+                DataRow::Map(map) => vec![sql_literal(Value::Map(map))?],
+            };
+            println!(
+                "INSERT INTO {table}{columns} VALUES ({});",
+                literals.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+// A literal which is converted back to the value when inserted into a column of its type
+fn sql_literal(value: Value) -> Result<String> {
+    Ok(match value {
+        Value::Null => "NULL".to_string(),
+        Value::Str(value) => quote_literal(&value),
+        value @ (Value::Map(_) | Value::List(_)) => {
+            quote_literal(&serde_json::Value::try_from(value)?.to_string())
+        }
+        value @ Value::Interval(_) => Expr::try_from(value)?.to_sql(),
+        value => quote_literal(&String::from(&value)),
+    })
+}
+
+fn quote_literal(literal: &str) -> String {
+    format!("'{}'", literal.replace('\'', "''"))
+}
+
+fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+async fn interactive(glue: &mut Glue<BaildonGlue>, isatty: i32, output: OutputMode) -> Result<()> {
     // `()` can be used when no completer is required
     let mut rl = DefaultEditor::new()?;
     if isatty == 1 {
@@ -75,6 +323,7 @@ async fn main() -> Result<()> {
     }
 
     println!("terminate with ctrl-c or ctrl-d");
+    let mut last = QueryStats::default();
     loop {
         let readline = rl.readline("sql> ");
         match readline {
@@ -82,17 +331,33 @@ async fn main() -> Result<()> {
                 if line.is_empty() {
                     continue;
                 }
-                let output = match glue.execute_async(&line).await {
-                    Ok(out) => {
-                        if isatty == 0 {
-                            format!("{out:?}")
-                        } else {
-                            format!("out> {out:?}")
+                if line.trim_start().starts_with('.') {
+                    if let Err(err) = dot_command(glue, &line, output, &last).await {
+                        println!("err> {err:#}");
+                    }
+                    if isatty == 1 {
+                        rl.add_history_entry(line.as_str())?;
+                    }
+                    continue;
+                }
+                let start = glue.storage.query_stats().await;
+                let started = Instant::now();
+                let result = glue.execute_async(&line).await;
+                last = glue
+                    .storage
+                    .query_stats()
+                    .await
+                    .since(&start, started.elapsed());
+                match result {
+                    Ok(payloads) => {
+                        for payload in &payloads {
+                            if let Err(err) = output::print_payload(payload, output, isatty == 1) {
+                                println!("err> {err}");
+                            }
                         }
                     }
-                    Err(err) => format!("err> {err}"),
-                };
-                println!("{output}");
+                    Err(err) => println!("err> {err}"),
+                }
                 if isatty == 1 {
                     rl.add_history_entry(line.as_str())?;
                 }
@@ -119,20 +384,181 @@ async fn main() -> Result<()> {
             }
         }
     }
+    Ok(())
+}
 
-    glue.storage.save().await.expect("It will save");
+/// Serve PostgreSQL clients until we are interrupted.
+async fn serve(glue: &Glue<BaildonGlue>, address: &str) -> Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    println!("listening on {address}, terminate with ctrl-c");
+    // Our storage can't be shared between threads, so all of our connections are served by this
+    // task. Each has a session of its own, so their queries are interleaved.
+    let mut connections = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = accepted?;
+                let glue = Glue::new(glue.storage.clone());
+                connections.push(async move {
+                    if let Err(e) = handle_connection(glue, stream).await {
+                        tracing::warn!(%peer, error = %e, "connection failed");
+                    }
+                });
+            }
+            Some(()) = connections.next() => {}
+            _ = tokio::signal::ctrl_c() => {
+                println!("terminating...");
+                break;
+            }
+        }
+    }
+    Ok(())
+}
 
-    glue.storage.print_tables().await?;
+async fn handle_connection(mut glue: Glue<BaildonGlue>, mut stream: TcpStream) -> Result<()> {
+    if !pgwire::read_startup(&mut stream).await? {
+        return Ok(());
+    }
+    let mut buf = vec![];
+    Reply::AuthenticationOk.encode(&mut buf);
+    for (name, value) in [
+        ("server_version", "14.0"),
+        ("server_encoding", "UTF8"),
+        ("client_encoding", "UTF8"),
+        ("DateStyle", "ISO, MDY"),
+        ("integer_datetimes", "on"),
+        ("standard_conforming_strings", "on"),
+    ] {
+        Reply::ParameterStatus(name, value).encode(&mut buf);
+    }
+    Reply::ReadyForQuery.encode(&mut buf);
+    stream.write_all(&buf).await?;
+
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    // Once we have rejected a message of the extended query protocol, the rest of its messages
+    // are skipped until the client syncs
+    let mut skipping = false;
+    loop {
+        buf.clear();
+        match pgwire::read_request(&mut reader).await? {
+            None | Some(Request::Terminate) => break,
+            Some(Request::Query(sql)) => {
+                skipping = false;
+                query(&mut glue, &sql, &mut buf).await;
+                Reply::ReadyForQuery.encode(&mut buf);
+            }
+            Some(Request::Sync) => {
+                skipping = false;
+                Reply::ReadyForQuery.encode(&mut buf);
+            }
+            Some(Request::Other(_)) if skipping => continue,
+            Some(Request::Other(tag)) => {
+                skipping = true;
+                Reply::Error {
+                    code: pgwire::FEATURE_NOT_SUPPORTED,
+                    message: format!("unsupported message: '{}', use simple queries", tag as char),
+                }
+                .encode(&mut buf);
+            }
+        }
+        writer.write_all(&buf).await?;
+    }
+    Ok(())
+}
 
-    let mut sep = "";
-    let callback = |(key, value)| {
-        print!("{sep}{key:?}:{value:?}");
-        sep = ", ";
-        ControlFlow::Continue(())
+/// Execute the statements of a simple query, stopping at the first which fails, as PostgreSQL
+/// does.
+async fn query(glue: &mut Glue<BaildonGlue>, sql: &str, buf: &mut Vec<u8>) {
+    let statements = match parse(sql) {
+        Ok(statements) => statements,
+        Err(e) => {
+            Reply::Error {
+                code: pgwire::SYNTAX_ERROR,
+                message: e.to_string(),
+            }
+            .encode(buf);
+            return;
+        }
     };
-    glue.storage.schemas.traverse_entries(Direction::Ascending, callback).await;
-    println!("\nutilization: {}", glue.storage.schemas.utilization().await);
-    println!();
+    if statements.is_empty() {
+        Reply::EmptyQueryResponse.encode(buf);
+    }
+    for statement in &statements {
+        match execute_statement(glue, statement).await {
+            Ok(payload) => {
+                for reply in pgwire::replies(payload) {
+                    reply.encode(buf);
+                }
+            }
+            Err(e) => {
+                Reply::Error {
+                    code: pgwire::INTERNAL_ERROR,
+                    message: e.to_string(),
+                }
+                .encode(buf);
+                return;
+            }
+        }
+    }
+}
 
-    Ok(())
+#[tokio::main]
+async fn main() -> Result<()> {
+    let isatty = unsafe { libc::isatty(0) };
+
+    let cli = Cli::parse();
+
+    let log_dir = match env::var("TMPDIR") {
+        Ok(d) => d,
+        Err(_e) => ".".to_string(),
+    };
+
+    let file_appender = tracing_appender::rolling::daily(log_dir, "baildon-glue.log");
+    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
+    tracing_subscriber::fmt().with_writer(non_blocking).init();
+
+    let mut storage: BaildonGlue = if cli.create {
+        BaildonGlue::new(&cli.database).await?
+    } else if cli.read_only {
+        BaildonGlue::open_read_only(&cli.database).await?
+    } else {
+        BaildonGlue::open(&cli.database).await?
+    };
+    storage.set_max_open_indexes(cli.max_open_indexes);
+
+    // let storage = SharedMemoryStorage::new();
+    let mut glue = Glue::new(storage);
+
+    let result = match (&cli.file, &cli.serve) {
+        (Some(_), Some(_)) => Err(anyhow::anyhow!("a file can't be executed while serving")),
+        (Some(file), None) => read_file(&mut glue, file, cli.output).await,
+        (None, Some(address)) => serve(&glue, address).await,
+        (None, None) if cli.dump => dump(&glue).await,
+        (None, None) => interactive(&mut glue, isatty, cli.output).await,
+    };
+
+    // A dump is only useful if nothing else is printed
+    if !cli.dump {
+        glue.storage.print_tables().await?;
+
+        let mut sep = "";
+        let callback = |(key, value)| {
+            print!("{sep}{key:?}:{value:?}");
+            sep = ", ";
+            ControlFlow::Continue(())
+        };
+        glue.storage
+            .schemas
+            .traverse_entries(Direction::Ascending, callback)
+            .await;
+        println!(
+            "\nutilization: {}",
+            glue.storage.schemas.utilization().await
+        );
+        println!();
+    }
+
+    glue.storage.close().await?;
+    result
 }
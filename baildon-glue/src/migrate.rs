@@ -0,0 +1,80 @@
+//! Migrations
+//!
+//! Migration scripts are files of SQL statements, which are applied in order of name, and each
+//! only once. The scripts which have been applied are recorded in the database, with their SQL,
+//! so a script which is changed once applied is detected.
+
+use std::path::Path;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use gluesql::core::chrono::{NaiveDateTime, Utc};
+use gluesql::prelude::Glue;
+use serde::{Deserialize, Serialize};
+
+use crate::BaildonGlue;
+
+/// A migration script which was applied.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Migration {
+    /// The name of the script.
+    pub name: String,
+    /// The SQL of the script.
+    pub sql: String,
+    /// When the script was applied.
+    pub applied: NaiveDateTime,
+}
+
+/// Apply the `.sql` scripts in a directory which haven't been applied, in order of name, returning
+/// the names of those which were applied.
+///
+/// Scripts are applied until one fails. A script which fails isn't recorded, but statements which
+/// preceded the failure are not undone, so it should be fixed to apply the rest.
+pub async fn migrate(glue: &mut Glue<BaildonGlue>, dir: &Path) -> Result<Vec<String>> {
+    let mut scripts = vec![];
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .with_context(|| format!("could not read: {}", dir.display()))?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if entry.file_type().await?.is_file()
+            && path.extension().is_some_and(|extension| extension == "sql")
+        {
+            scripts.push(path);
+        }
+    }
+    scripts.sort();
+
+    let _lock = glue.storage.lock_migrations().await;
+    let applied = glue.storage.migrations().await;
+    let mut names = vec![];
+    for script in scripts {
+        let name = script
+            .file_name()
+            .expect("scripts have names")
+            .to_string_lossy()
+            .to_string();
+        let sql = tokio::fs::read_to_string(&script)
+            .await
+            .with_context(|| format!("could not read: {}", script.display()))?;
+        if let Some(migration) = applied.iter().find(|migration| migration.name == name) {
+            if migration.sql != sql {
+                bail!("migration {name} has changed since it was applied");
+            }
+            continue;
+        }
+        glue.execute_async(&sql)
+            .await
+            .with_context(|| format!("migration {name} failed"))?;
+        glue.storage
+            .record_migration(Migration {
+                name: name.clone(),
+                sql,
+                applied: Utc::now().naive_utc(),
+            })
+            .await?;
+        names.push(name);
+    }
+    Ok(names)
+}
@@ -0,0 +1,135 @@
+//! Render the results of queries, for people or for scripts.
+
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+
+use anyhow::Result;
+use gluesql::prelude::{Payload, Value};
+use serde_json::{Map as JsonMap, Value as JsonValue};
+use strum::EnumString;
+
+#[derive(Clone, Copy, Debug, Default, EnumString)]
+#[strum(ascii_case_insensitive)]
+pub(crate) enum OutputMode {
+    /// As an aligned table
+    #[default]
+    Table,
+    /// As CSV, with a header row
+    Csv,
+    /// As a JSON array of objects, one per row
+    Json,
+}
+
+/// Print the result of a statement. The rows of queries are rendered in our mode, and other
+/// results are printed as they are, prefixed by `out> ` if we are interactive.
+pub(crate) fn print_payload(payload: &Payload, mode: OutputMode, interactive: bool) -> Result<()> {
+    match payload {
+        Payload::Select { labels, rows } => println!("{}", mode.render(labels, rows)?),
+        Payload::SelectMap(maps) => {
+            let (labels, rows) = map_rows(maps);
+            println!("{}", mode.render(&labels, &rows)?);
+        }
+        _ if interactive => println!("out> {payload:?}"),
+        _ => println!("{payload:?}"),
+    }
+    Ok(())
+}
+
+/// Return the labels and rows of schemaless rows. Rows may have different columns, so every
+/// column is included, and rows are NULL in the columns they don't have.
+pub(crate) fn map_rows(maps: &[HashMap<String, Value>]) -> (Vec<String>, Vec<Vec<Value>>) {
+    let labels = maps
+        .iter()
+        .flat_map(|map| map.keys().cloned())
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect::<Vec<String>>();
+    let rows = maps
+        .iter()
+        .map(|map| {
+            labels
+                .iter()
+                .map(|label| map.get(label).cloned().unwrap_or(Value::Null))
+                .collect()
+        })
+        .collect();
+    (labels, rows)
+}
+
+impl OutputMode {
+    fn render(self, labels: &[String], rows: &[Vec<Value>]) -> Result<String> {
+        match self {
+            OutputMode::Table => Ok(render_table(labels, rows)),
+            OutputMode::Csv => render_csv(labels, rows),
+            OutputMode::Json => render_json(labels, rows),
+        }
+    }
+}
+
+fn render_table(labels: &[String], rows: &[Vec<Value>]) -> String {
+    let cells = rows
+        .iter()
+        .map(|row| row.iter().map(String::from).collect())
+        .collect::<Vec<Vec<String>>>();
+    let mut widths = labels
+        .iter()
+        .map(|label| label.chars().count())
+        .collect::<Vec<usize>>();
+    for row in &cells {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let border = widths
+        .iter()
+        .map(|width| format!("+{}", "-".repeat(width + 2)))
+        .collect::<String>()
+        + "+";
+    let line = |row: &[String]| {
+        row.iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("| {cell:width$} "))
+            .collect::<String>()
+            + "|"
+    };
+    let mut table = vec![border.clone(), line(labels), border.clone()];
+    table.extend(cells.iter().map(|row| line(row)));
+    if !cells.is_empty() {
+        table.push(border);
+    }
+    match cells.len() {
+        1 => table.push("(1 row)".to_string()),
+        count => table.push(format!("({count} rows)")),
+    }
+    table.join("\n")
+}
+
+fn render_csv(labels: &[String], rows: &[Vec<Value>]) -> Result<String> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(labels)?;
+    for row in rows {
+        writer.write_record(row.iter().map(|value| match value {
+            // An empty field is clearer than "NULL", which could be a string
+            Value::Null => String::new(),
+            value => String::from(value),
+        }))?;
+    }
+    let csv = String::from_utf8(writer.into_inner()?)?;
+    Ok(csv.trim_end_matches('\n').to_string())
+}
+
+fn render_json(labels: &[String], rows: &[Vec<Value>]) -> Result<String> {
+    let objects = rows
+        .iter()
+        .map(|row| {
+            labels
+                .iter()
+                .zip(row)
+                .map(|(label, value)| Ok((label.clone(), JsonValue::try_from(value.clone())?)))
+                .collect::<Result<JsonMap<String, JsonValue>>>()
+                .map(JsonValue::Object)
+        })
+        .collect::<Result<Vec<JsonValue>>>()?;
+    Ok(serde_json::to_string_pretty(&objects)?)
+}
@@ -0,0 +1,295 @@
+//! PostgreSQL frontend/backend protocol
+//!
+//! Just enough of version 3.0 of the protocol to accept connections, without authentication or
+//! encryption, and to answer simple queries. Values are always sent as text, and the columns of
+//! rows are typed by their first non-NULL value.
+
+use anyhow::bail;
+use anyhow::Result;
+use gluesql::prelude::{Payload, PayloadVariable, Value};
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+
+use crate::output;
+
+/// Maximum length of a message
+const MAX_MESSAGE_LEN: usize = 64 * 1024 * 1024;
+
+/// Codes which start a startup packet
+const PROTOCOL_VERSION: i32 = 196608;
+const CANCEL_REQUEST: i32 = 80877102;
+const SSL_REQUEST: i32 = 80877103;
+const GSSENC_REQUEST: i32 = 80877104;
+
+/// SQLSTATE codes of our errors
+pub(crate) const SYNTAX_ERROR: &str = "42601";
+pub(crate) const FEATURE_NOT_SUPPORTED: &str = "0A000";
+pub(crate) const INTERNAL_ERROR: &str = "XX000";
+
+/// Type OIDs of our columns
+const BOOL: i32 = 16;
+const BYTEA: i32 = 17;
+const INT8: i32 = 20;
+const INT2: i32 = 21;
+const INT4: i32 = 23;
+const TEXT: i32 = 25;
+const FLOAT4: i32 = 700;
+const FLOAT8: i32 = 701;
+const DATE: i32 = 1082;
+const TIME: i32 = 1083;
+const TIMESTAMP: i32 = 1114;
+const NUMERIC: i32 = 1700;
+const UUID: i32 = 2950;
+
+/// A message from a client, once it has started up
+#[derive(Debug)]
+pub(crate) enum Request {
+    Query(String),
+    Sync,
+    Terminate,
+    /// A message we don't support, such as those of the extended query protocol
+    Other(u8),
+}
+
+/// A message to a client
+#[derive(Debug)]
+pub(crate) enum Reply {
+    AuthenticationOk,
+    ParameterStatus(&'static str, &'static str),
+    ReadyForQuery,
+    /// The names and type OIDs of the columns of the rows which follow
+    RowDescription(Vec<(String, i32)>),
+    DataRow(Vec<Option<String>>),
+    CommandComplete(String),
+    EmptyQueryResponse,
+    Error {
+        code: &'static str,
+        message: String,
+    },
+}
+
+impl Reply {
+    /// Append our encoding to the supplied buffer.
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        let tag = match self {
+            Reply::AuthenticationOk => b'R',
+            Reply::ParameterStatus(..) => b'S',
+            Reply::ReadyForQuery => b'Z',
+            Reply::RowDescription(_) => b'T',
+            Reply::DataRow(_) => b'D',
+            Reply::CommandComplete(_) => b'C',
+            Reply::EmptyQueryResponse => b'I',
+            Reply::Error { .. } => b'E',
+        };
+        buf.push(tag);
+        // Our length is filled in once our body is encoded
+        buf.extend_from_slice(&[0; 4]);
+        match self {
+            Reply::AuthenticationOk => buf.extend_from_slice(&0i32.to_be_bytes()),
+            Reply::ParameterStatus(name, value) => {
+                put_str(buf, name);
+                put_str(buf, value);
+            }
+            // We never start transactions which span queries, so we are always idle
+            Reply::ReadyForQuery => buf.push(b'I'),
+            Reply::RowDescription(columns) => {
+                buf.extend_from_slice(&(columns.len() as i16).to_be_bytes());
+                for (name, oid) in columns {
+                    put_str(buf, name);
+                    // Table OID and attribute number, which we don't have
+                    buf.extend_from_slice(&0i32.to_be_bytes());
+                    buf.extend_from_slice(&0i16.to_be_bytes());
+                    buf.extend_from_slice(&oid.to_be_bytes());
+                    // Variable length, with no type modifier, in text format
+                    buf.extend_from_slice(&(-1i16).to_be_bytes());
+                    buf.extend_from_slice(&(-1i32).to_be_bytes());
+                    buf.extend_from_slice(&0i16.to_be_bytes());
+                }
+            }
+            Reply::DataRow(values) => {
+                buf.extend_from_slice(&(values.len() as i16).to_be_bytes());
+                for value in values {
+                    match value {
+                        Some(value) => {
+                            buf.extend_from_slice(&(value.len() as i32).to_be_bytes());
+                            buf.extend_from_slice(value.as_bytes());
+                        }
+                        None => buf.extend_from_slice(&(-1i32).to_be_bytes()),
+                    }
+                }
+            }
+            Reply::CommandComplete(tag) => put_str(buf, tag),
+            Reply::EmptyQueryResponse => (),
+            Reply::Error { code, message } => {
+                for (field, value) in [
+                    (b'S', "ERROR"),
+                    (b'V', "ERROR"),
+                    (b'C', *code),
+                    (b'M', message.as_str()),
+                ] {
+                    buf.push(field);
+                    put_str(buf, value);
+                }
+                buf.push(0);
+            }
+        }
+        let len = (buf.len() - start - 1) as i32;
+        buf[start + 1..start + 5].copy_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+}
+
+/// Read the startup packet of a client, declining any request for encryption. Returns false if
+/// the client only wanted to cancel a query, which we can't do.
+pub(crate) async fn read_startup<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+) -> Result<bool> {
+    loop {
+        let body = read_body(stream).await?;
+        let Some(code) = body.get(..4) else {
+            bail!("startup packet is too short");
+        };
+        let code = i32::from_be_bytes(code.try_into()?);
+        match code {
+            PROTOCOL_VERSION => return Ok(true),
+            SSL_REQUEST | GSSENC_REQUEST => stream.write_all(b"N").await?,
+            CANCEL_REQUEST => return Ok(false),
+            _ => bail!(
+                "unsupported protocol version: {}.{}",
+                code >> 16,
+                code & 0xffff
+            ),
+        }
+    }
+}
+
+/// Read a message from a client, returning None if the client closed its connection.
+pub(crate) async fn read_request<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Request>> {
+    let tag = match reader.read_u8().await {
+        Ok(tag) => tag,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let body = read_body(reader).await?;
+    let request = match tag {
+        b'Q' => {
+            let Some(sql) = body.strip_suffix(&[0]) else {
+                bail!("query is not terminated");
+            };
+            Request::Query(String::from_utf8(sql.to_vec())?)
+        }
+        b'S' => Request::Sync,
+        b'X' => Request::Terminate,
+        tag => Request::Other(tag),
+    };
+    Ok(Some(request))
+}
+
+// Read the length of a message, which includes itself, and then the rest of the message
+async fn read_body<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let len = reader.read_i32().await?;
+    let len = match usize::try_from(len) {
+        Ok(len) if (4..=MAX_MESSAGE_LEN).contains(&len) => len,
+        _ => bail!("invalid message length: {len}"),
+    };
+    let mut body = vec![0; len - 4];
+    reader.read_exact(&mut body).await?;
+    Ok(body)
+}
+
+/// Return the replies which describe the result of a statement.
+pub(crate) fn replies(payload: Payload) -> Vec<Reply> {
+    let tag = match payload {
+        Payload::Select { labels, rows } => return row_replies(labels, rows),
+        Payload::SelectMap(maps) => {
+            let (labels, rows) = output::map_rows(&maps);
+            return row_replies(labels, rows);
+        }
+        Payload::ShowColumns(columns) => {
+            let rows = columns
+                .into_iter()
+                .map(|(name, data_type)| vec![Value::Str(name), Value::Str(data_type.to_string())])
+                .collect();
+            return row_replies(vec!["field".to_string(), "type".to_string()], rows);
+        }
+        Payload::ShowVariable(variable) => {
+            let (label, values) = match variable {
+                PayloadVariable::Tables(names) => ("tables", names),
+                PayloadVariable::Functions(names) => ("functions", names),
+                PayloadVariable::Version(version) => ("version", vec![version]),
+            };
+            let rows = values
+                .into_iter()
+                .map(|value| vec![Value::Str(value)])
+                .collect();
+            return row_replies(vec![label.to_string()], rows);
+        }
+        Payload::Create => "CREATE TABLE".to_string(),
+        Payload::Insert(count) => format!("INSERT 0 {count}"),
+        Payload::Delete(count) => format!("DELETE {count}"),
+        Payload::Update(count) => format!("UPDATE {count}"),
+        Payload::DropTable => "DROP TABLE".to_string(),
+        Payload::DropFunction => "DROP FUNCTION".to_string(),
+        Payload::AlterTable => "ALTER TABLE".to_string(),
+        Payload::CreateIndex => "CREATE INDEX".to_string(),
+        Payload::DropIndex => "DROP INDEX".to_string(),
+        Payload::StartTransaction => "BEGIN".to_string(),
+        Payload::Commit => "COMMIT".to_string(),
+        Payload::Rollback => "ROLLBACK".to_string(),
+    };
+    vec![Reply::CommandComplete(tag)]
+}
+
+fn row_replies(labels: Vec<String>, rows: Vec<Vec<Value>>) -> Vec<Reply> {
+    let types = (0..labels.len()).map(|i| {
+        rows.iter()
+            .filter_map(|row| row.get(i))
+            .find(|value| !value.is_null())
+            .map_or(TEXT, type_oid)
+    });
+    let mut replies = vec![Reply::RowDescription(
+        labels.into_iter().zip(types).collect(),
+    )];
+    let count = rows.len();
+    replies.extend(
+        rows.iter()
+            .map(|row| Reply::DataRow(row.iter().map(text).collect())),
+    );
+    replies.push(Reply::CommandComplete(format!("SELECT {count}")));
+    replies
+}
+
+fn type_oid(value: &Value) -> i32 {
+    match value {
+        Value::Bool(_) => BOOL,
+        Value::Bytea(_) => BYTEA,
+        Value::I8(_) | Value::I16(_) | Value::U8(_) => INT2,
+        Value::I32(_) | Value::U16(_) => INT4,
+        Value::I64(_) | Value::U32(_) => INT8,
+        Value::I128(_) | Value::U64(_) | Value::U128(_) | Value::Decimal(_) => NUMERIC,
+        Value::F32(_) => FLOAT4,
+        Value::F64(_) => FLOAT8,
+        Value::Date(_) => DATE,
+        Value::Time(_) => TIME,
+        Value::Timestamp(_) => TIMESTAMP,
+        Value::Uuid(_) => UUID,
+        _ => TEXT,
+    }
+}
+
+// The text format of a value, as PostgreSQL would send it
+fn text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::Bool(value) => Some((if *value { "t" } else { "f" }).to_string()),
+        Value::Bytea(_) => Some(format!("\\x{}", String::from(value))),
+        value => Some(String::from(value)),
+    }
+}
@@ -0,0 +1,39 @@
+//! Statistics of the work done to execute statements
+
+use std::fmt;
+use std::time::Duration;
+
+/// The work done by a database, since it was opened or over a period.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueryStats {
+    /// Number of rows returned by scans and lookups of tables.
+    pub rows_scanned: u64,
+    /// Number of nodes read from disk, because they weren't cached.
+    pub nodes_read: u64,
+    /// Number of nodes found in the caches of our trees.
+    pub cache_hits: u64,
+    /// How long the work took, if it was measured.
+    pub elapsed: Duration,
+}
+
+impl QueryStats {
+    /// Return the work done since an earlier snapshot, which took the supplied time. Counts of
+    /// index trees which were closed in the meantime are lost, so counts never go below zero.
+    pub fn since(&self, earlier: &QueryStats, elapsed: Duration) -> QueryStats {
+        QueryStats {
+            rows_scanned: self.rows_scanned.saturating_sub(earlier.rows_scanned),
+            nodes_read: self.nodes_read.saturating_sub(earlier.nodes_read),
+            cache_hits: self.cache_hits.saturating_sub(earlier.cache_hits),
+            elapsed,
+        }
+    }
+}
+
+impl fmt::Display for QueryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "rows scanned: {}", self.rows_scanned)?;
+        writeln!(f, "nodes read: {}", self.nodes_read)?;
+        writeln!(f, "cache hits: {}", self.cache_hits)?;
+        write!(f, "elapsed: {:?}", self.elapsed)
+    }
+}
@@ -0,0 +1,306 @@
+use std::env;
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::extract::Path;
+use axum::extract::Query;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::routing::get;
+use axum::Json;
+use axum::Router;
+use baildon::btree::Baildon;
+use baildon::btree::Direction;
+use baildon::btree::Recovery;
+use clap::Parser;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Values are stored as JSON text
+type Store = Baildon<String, String>;
+
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Store location
+    store: String,
+
+    /// Address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:8080")]
+    address: SocketAddr,
+
+    /// Create a new store (will overwrite existing file)
+    #[arg(short, long, default_value_t = false)]
+    create: bool,
+
+    /// What to do with a WAL left by a failed writer (ReplayWal, IgnoreWal or FailIfWalPresent)
+    #[arg(long)]
+    recovery: Option<Recovery>,
+}
+
+/// Most entries returned by a single range request
+const MAX_RANGE_ENTRIES: usize = 1000;
+
+/// Keys from start (inclusive) to end (exclusive). Missing bounds are unbounded. At most limit
+/// entries (capped at MAX_RANGE_ENTRIES) are returned, so continue from the last key returned.
+#[derive(Debug, Deserialize)]
+struct Range {
+    start: Option<String>,
+    end: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    key: String,
+    value: Value,
+}
+
+/// Failures of our store, reported as internal server errors.
+struct StoreError(anyhow::Error);
+
+impl IntoResponse for StoreError {
+    fn into_response(self) -> Response {
+        (StatusCode::INTERNAL_SERVER_ERROR, self.0.to_string()).into_response()
+    }
+}
+
+impl From<anyhow::Error> for StoreError {
+    fn from(err: anyhow::Error) -> Self {
+        Self(err)
+    }
+}
+
+/// Stores which weren't written by us may contain values which aren't JSON, so return them as
+/// JSON strings.
+fn to_json(value: String) -> Value {
+    serde_json::from_str(&value).unwrap_or(Value::String(value))
+}
+
+async fn get_key(State(btree): State<Arc<Store>>, Path(key): Path<String>) -> Response {
+    match btree.get(&key).await {
+        Some(value) => Json(to_json(value)).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn put_key(
+    State(btree): State<Arc<Store>>,
+    Path(key): Path<String>,
+    Json(value): Json<Value>,
+) -> Result<StatusCode, StoreError> {
+    match btree.insert(key, value.to_string()).await? {
+        Some(_old) => Ok(StatusCode::NO_CONTENT),
+        None => Ok(StatusCode::CREATED),
+    }
+}
+
+async fn delete_key(
+    State(btree): State<Arc<Store>>,
+    Path(key): Path<String>,
+) -> Result<Response, StoreError> {
+    match btree.delete(&key).await? {
+        Some(value) => Ok(Json(to_json(value)).into_response()),
+        None => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
+async fn get_range(
+    State(btree): State<Arc<Store>>,
+    Query(range): Query<Range>,
+) -> Json<Vec<Entry>> {
+    let limit = range
+        .limit
+        .map_or(MAX_RANGE_ENTRIES, |limit| limit.min(MAX_RANGE_ENTRIES));
+    let range = (
+        range.start.map_or(Bound::Unbounded, Bound::Included),
+        range.end.map_or(Bound::Unbounded, Bound::Excluded),
+    );
+    let entries = btree
+        .range(range, Direction::Ascending)
+        .await
+        .take(limit)
+        .map(|(key, value)| Entry {
+            key,
+            value: to_json(value),
+        })
+        .collect()
+        .await;
+    Json(entries)
+}
+
+fn router(btree: Arc<Store>) -> Router {
+    Router::new()
+        .route("/keys/:key", get(get_key).put(put_key).delete(delete_key))
+        .route("/range", get(get_range))
+        .with_state(btree)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    let log_dir = match env::var("TMPDIR") {
+        Ok(d) => d,
+        Err(_e) => ".".to_string(),
+    };
+
+    let file_appender = tracing_appender::rolling::daily(log_dir, "baildon.log");
+    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
+    tracing_subscriber::fmt().with_writer(non_blocking).init();
+
+    let btree: Store = if cli.create {
+        Store::try_new(&cli.store, 13).await?
+    } else {
+        Store::try_open_with_recovery(&cli.store, cli.recovery.unwrap_or_default()).await?
+    };
+    let btree = Arc::new(btree);
+
+    println!("listening on {}, terminate with ctrl-c", cli.address);
+    axum::Server::bind(&cli.address)
+        .serve(router(btree.clone()).into_make_service())
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.ok();
+            println!("terminating...");
+        })
+        .await?;
+
+    // Our router has been dropped, so we should be the only owner of our tree
+    match Arc::try_unwrap(btree) {
+        Ok(btree) => btree.close().await,
+        Err(btree) => btree.flush_to_disk().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::body::Body;
+    use axum::http::Method;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    async fn store() -> Arc<Store> {
+        Arc::new(Store::temporary(4).await.expect("creates tree"))
+    }
+
+    async fn send(
+        btree: &Arc<Store>,
+        method: Method,
+        uri: &str,
+        body: Option<&str>,
+    ) -> (StatusCode, Option<Value>) {
+        let mut request = Request::builder().method(method).uri(uri);
+        let body = match body {
+            Some(body) => {
+                request = request.header("content-type", "application/json");
+                Body::from(body.to_string())
+            }
+            None => Body::empty(),
+        };
+        let response = router(btree.clone())
+            .oneshot(request.body(body).expect("builds request"))
+            .await
+            .expect("routes request");
+        let status = response.status();
+        let bytes = hyper::body::to_bytes(response.into_body())
+            .await
+            .expect("reads body");
+        let value = (!bytes.is_empty()).then(|| serde_json::from_slice(&bytes).expect("is json"));
+        (status, value)
+    }
+
+    async fn range(btree: &Arc<Store>, uri: &str) -> Vec<String> {
+        let (status, value) = send(btree, Method::GET, uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        value
+            .expect("has body")
+            .as_array()
+            .expect("is array")
+            .iter()
+            .map(|entry| entry["key"].as_str().expect("has key").to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn it_puts_gets_and_deletes_keys() {
+        let btree = store().await;
+        assert_eq!(
+            send(&btree, Method::PUT, "/keys/a", Some(r#"{"n":1}"#)).await,
+            (StatusCode::CREATED, None)
+        );
+        assert_eq!(
+            send(&btree, Method::PUT, "/keys/a", Some(r#"{"n":2}"#)).await,
+            (StatusCode::NO_CONTENT, None)
+        );
+        assert_eq!(
+            send(&btree, Method::GET, "/keys/a", None).await,
+            (StatusCode::OK, Some(serde_json::json!({"n": 2})))
+        );
+        assert_eq!(
+            send(&btree, Method::DELETE, "/keys/a", None).await,
+            (StatusCode::OK, Some(serde_json::json!({"n": 2})))
+        );
+        assert_eq!(
+            send(&btree, Method::GET, "/keys/a", None).await,
+            (StatusCode::NOT_FOUND, None)
+        );
+        assert_eq!(
+            send(&btree, Method::DELETE, "/keys/a", None).await,
+            (StatusCode::NOT_FOUND, None)
+        );
+    }
+
+    #[tokio::test]
+    async fn it_returns_values_which_arent_json_as_strings() {
+        let btree = store().await;
+        btree
+            .insert("a".to_string(), "not json".to_string())
+            .await
+            .expect("insert worked");
+        assert_eq!(
+            send(&btree, Method::GET, "/keys/a", None).await,
+            (StatusCode::OK, Some(Value::String("not json".to_string())))
+        );
+    }
+
+    #[tokio::test]
+    async fn it_gets_ranges() {
+        let btree = store().await;
+        for key in ["a", "b", "c", "d"] {
+            btree
+                .insert(key.to_string(), "1".to_string())
+                .await
+                .expect("insert worked");
+        }
+        assert_eq!(range(&btree, "/range").await, ["a", "b", "c", "d"]);
+        assert_eq!(range(&btree, "/range?start=b").await, ["b", "c", "d"]);
+        assert_eq!(range(&btree, "/range?end=c").await, ["a", "b"]);
+        assert_eq!(range(&btree, "/range?start=b&end=d").await, ["b", "c"]);
+        assert_eq!(range(&btree, "/range?start=b&limit=1").await, ["b"]);
+        let (status, value) = send(&btree, Method::GET, "/range", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(value.expect("has body")[0]["value"], serde_json::json!(1));
+    }
+
+    #[tokio::test]
+    async fn it_caps_unbounded_ranges() {
+        let btree = store().await;
+        for i in 0..MAX_RANGE_ENTRIES + 10 {
+            btree
+                .insert(format!("{i:05}"), "1".to_string())
+                .await
+                .expect("insert worked");
+        }
+        assert_eq!(range(&btree, "/range").await.len(), MAX_RANGE_ENTRIES);
+        let uri = format!("/range?limit={}", MAX_RANGE_ENTRIES + 10);
+        assert_eq!(range(&btree, &uri).await.len(), MAX_RANGE_ENTRIES);
+    }
+}
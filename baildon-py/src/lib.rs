@@ -0,0 +1,313 @@
+//! Python bindings for baildon
+//!
+//! Keys and values are bytes. Strings and byte vectors have the same encoding, so stores of
+//! `String` or `Vec<u8>` keys and values, written by Rust services, may be read and written.
+//!
+//! ```python
+//! from baildon_py import Baildon
+//!
+//! tree = Baildon.create("example.db")
+//! tree.insert(b"key", b"value")
+//! for key, value in tree.range(start=b"k"):
+//!     print(key, value)
+//! tree.close()
+//! ```
+//!
+//! Calls block until complete, without holding the GIL.
+
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::sync::LazyLock;
+
+use baildon::btree::Baildon;
+use baildon::btree::Direction;
+use futures::StreamExt;
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use tokio::runtime::Runtime;
+
+type Store = Baildon<Vec<u8>, Vec<u8>>;
+
+/// Branching factor of created trees, if none is specified
+const DEFAULT_BRANCH: u64 = 13;
+
+/// Number of entries read by an iterator at a time
+const PAGE_SIZE: usize = 100;
+
+// All of our trees share a runtime
+static RUNTIME: LazyLock<Runtime> = LazyLock::new(|| {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("creates runtime")
+});
+
+fn to_py_err(err: impl Display) -> PyErr {
+    PyRuntimeError::new_err(err.to_string())
+}
+
+fn to_py_bytes(py: Python<'_>, bytes: Vec<u8>) -> Py<PyBytes> {
+    PyBytes::new(py, &bytes).into()
+}
+
+/// A persistent B+Tree of bytes.
+#[pyclass(name = "Baildon")]
+struct Tree {
+    // None once we are closed
+    btree: Option<Arc<Store>>,
+}
+
+impl Tree {
+    fn btree(&self) -> PyResult<Arc<Store>> {
+        self.btree
+            .clone()
+            .ok_or_else(|| PyRuntimeError::new_err("tree is closed"))
+    }
+}
+
+#[pymethods]
+impl Tree {
+    /// Create a new tree at the specified path (will overwrite existing file).
+    #[staticmethod]
+    #[pyo3(signature = (path, branch = DEFAULT_BRANCH))]
+    fn create(py: Python<'_>, path: &str, branch: u64) -> PyResult<Self> {
+        let btree = py
+            .allow_threads(|| RUNTIME.block_on(Store::try_new(path, branch)))
+            .map_err(to_py_err)?;
+        Ok(Self {
+            btree: Some(Arc::new(btree)),
+        })
+    }
+
+    /// Open an existing tree at the specified path.
+    #[staticmethod]
+    fn open(py: Python<'_>, path: &str) -> PyResult<Self> {
+        let btree = py
+            .allow_threads(|| RUNTIME.block_on(Store::try_open(path)))
+            .map_err(to_py_err)?;
+        Ok(Self {
+            btree: Some(Arc::new(btree)),
+        })
+    }
+
+    /// Return the value of a key, or None.
+    fn get(&self, py: Python<'_>, key: &[u8]) -> PyResult<Option<Py<PyBytes>>> {
+        let btree = self.btree()?;
+        let key = key.to_vec();
+        let value = py.allow_threads(|| RUNTIME.block_on(btree.get(&key)));
+        Ok(value.map(|value| to_py_bytes(py, value)))
+    }
+
+    /// Insert a key and value, returning the previous value of the key, or None.
+    fn insert(&self, py: Python<'_>, key: &[u8], value: &[u8]) -> PyResult<Option<Py<PyBytes>>> {
+        let btree = self.btree()?;
+        let (key, value) = (key.to_vec(), value.to_vec());
+        let old = py
+            .allow_threads(|| RUNTIME.block_on(btree.insert(key, value)))
+            .map_err(to_py_err)?;
+        Ok(old.map(|old| to_py_bytes(py, old)))
+    }
+
+    /// Delete a key, returning its value, or None.
+    fn delete(&self, py: Python<'_>, key: &[u8]) -> PyResult<Option<Py<PyBytes>>> {
+        let btree = self.btree()?;
+        let key = key.to_vec();
+        let value = py
+            .allow_threads(|| RUNTIME.block_on(btree.delete(&key)))
+            .map_err(to_py_err)?;
+        Ok(value.map(|value| to_py_bytes(py, value)))
+    }
+
+    /// Return an iterator over (key, value) tuples, in ascending order, from start (inclusive)
+    /// to end (exclusive). Missing bounds are unbounded.
+    ///
+    /// Entries are read a page at a time, each page starting from the leaf containing the end of
+    /// the previous page, so entries before start aren't visited.
+    #[pyo3(signature = (start = None, end = None))]
+    fn range(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> PyResult<EntryIterator> {
+        let bound = |key: Option<&[u8]>, bound: fn(Vec<u8>) -> Bound<Vec<u8>>| {
+            key.map_or(Bound::Unbounded, |key| bound(key.to_vec()))
+        };
+        Ok(EntryIterator {
+            btree: self.btree()?,
+            page: VecDeque::new(),
+            start: Some(bound(start, Bound::Included)),
+            end: bound(end, Bound::Excluded),
+        })
+    }
+
+    /// Flush all our updated nodes to disk.
+    fn flush(&self, py: Python<'_>) -> PyResult<()> {
+        let btree = self.btree()?;
+        py.allow_threads(|| RUNTIME.block_on(btree.flush_to_disk()))
+            .map_err(to_py_err)
+    }
+
+    /// Close our tree, flushing all our updated nodes to disk. Closing a closed tree does
+    /// nothing.
+    fn close(&mut self, py: Python<'_>) -> PyResult<()> {
+        let Some(btree) = self.btree.take() else {
+            return Ok(());
+        };
+        // Iterators may still refer to our tree, in which case we can only flush
+        py.allow_threads(|| match Arc::try_unwrap(btree) {
+            Ok(btree) => RUNTIME.block_on(btree.close()),
+            Err(btree) => RUNTIME.block_on(btree.flush_to_disk()),
+        })
+        .map_err(to_py_err)
+    }
+
+    fn __contains__(&self, py: Python<'_>, key: &[u8]) -> PyResult<bool> {
+        let btree = self.btree()?;
+        let key = key.to_vec();
+        Ok(py.allow_threads(|| RUNTIME.block_on(btree.contains(&key))))
+    }
+
+    fn __len__(&self, py: Python<'_>) -> PyResult<usize> {
+        let btree = self.btree()?;
+        Ok(py.allow_threads(|| RUNTIME.block_on(btree.count())))
+    }
+}
+
+/// An iterator over a range of entries.
+#[pyclass]
+struct EntryIterator {
+    btree: Arc<Store>,
+    page: VecDeque<(Vec<u8>, Vec<u8>)>,
+    // The start of our next page, None once we are exhausted
+    start: Option<Bound<Vec<u8>>>,
+    end: Bound<Vec<u8>>,
+}
+
+impl EntryIterator {
+    fn next_page(&mut self, py: Python<'_>) {
+        let Some(start) = self.start.take() else {
+            return;
+        };
+        let (btree, end) = (&self.btree, &self.end);
+        let page = py.allow_threads(|| {
+            RUNTIME.block_on(async {
+                btree
+                    .range((start, end.clone()), Direction::Ascending)
+                    .await
+                    .take(PAGE_SIZE)
+                    .collect::<VecDeque<(Vec<u8>, Vec<u8>)>>()
+                    .await
+            })
+        });
+        // A short page is our last
+        if page.len() == PAGE_SIZE {
+            self.start = page
+                .back()
+                .map(|(key, _value)| Bound::Excluded(key.clone()));
+        }
+        self.page = page;
+    }
+}
+
+#[pymethods]
+impl EntryIterator {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> Option<(Py<PyBytes>, Py<PyBytes>)> {
+        if self.page.is_empty() {
+            self.next_page(py);
+        }
+        let (key, value) = self.page.pop_front()?;
+        Some((to_py_bytes(py, key), to_py_bytes(py, value)))
+    }
+}
+
+#[pymodule]
+fn baildon_py(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
+    m.add_class::<Tree>()?;
+    m.add_class::<EntryIterator>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(py: Python<'_>, mut iterator: EntryIterator) -> Vec<(Vec<u8>, Vec<u8>)> {
+        std::iter::from_fn(|| iterator.__next__(py))
+            .map(|(key, value)| {
+                (
+                    key.as_ref(py).as_bytes().to_vec(),
+                    value.as_ref(py).as_bytes().to_vec(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn it_wraps_trees() {
+        pyo3::prepare_freethreaded_python();
+        let path = std::env::temp_dir().join("baildon_py_tree.db");
+        let path = path.to_str().expect("utf-8 path");
+        Python::with_gil(|py| {
+            let mut tree = Tree::create(py, path, 4).expect("creates tree");
+            for i in 0..50u8 {
+                let old = tree.insert(py, &[i], &[i, i]).expect("inserts");
+                assert!(old.is_none());
+            }
+            let old = tree.insert(py, &[7], b"seven").expect("inserts");
+            assert_eq!(old.expect("replaced").as_ref(py).as_bytes(), &[7, 7]);
+            assert_eq!(
+                tree.get(py, &[7])
+                    .expect("gets")
+                    .expect("found")
+                    .as_ref(py)
+                    .as_bytes(),
+                b"seven"
+            );
+            assert!(tree.get(py, &[99]).expect("gets").is_none());
+            assert!(tree.delete(py, &[8]).expect("deletes").is_some());
+            assert!(!tree.__contains__(py, &[8]).expect("checks"));
+
+            let range = tree.range(Some(&[5]), Some(&[10])).expect("ranges");
+            let keys = entries(py, range)
+                .into_iter()
+                .map(|(key, _value)| key[0])
+                .collect::<Vec<u8>>();
+            assert_eq!(keys, vec![5, 6, 7, 9]);
+            let range = tree.range(Some(&[45]), None).expect("ranges");
+            assert_eq!(
+                entries(py, range),
+                (45..50u8)
+                    .map(|i| (vec![i], vec![i, i]))
+                    .collect::<Vec<_>>()
+            );
+            let range = tree.range(None, None).expect("ranges");
+            assert_eq!(entries(py, range).len(), 49);
+
+            // Ranges span several pages
+            for i in 0..=255u8 {
+                tree.insert(py, &[i, 0], &[i]).expect("inserts");
+            }
+            let range = tree.range(Some(&[1]), None).expect("ranges");
+            let keys = entries(py, range)
+                .into_iter()
+                .map(|(key, _value)| key)
+                .collect::<Vec<Vec<u8>>>();
+            assert_eq!(keys.len(), 255 + 48);
+            assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+            for i in 0..=255u8 {
+                tree.delete(py, &[i, 0]).expect("deletes");
+            }
+            tree.close(py).expect("closes");
+            assert!(tree.get(py, &[7]).is_err());
+
+            let mut tree = Tree::open(py, path).expect("opens tree");
+            assert_eq!(tree.__len__(py).expect("counts"), 49);
+            assert!(tree.__contains__(py, &[7]).expect("checks"));
+            tree.close(py).expect("closes");
+        });
+        std::fs::remove_file(path).expect("cleanup");
+    }
+}
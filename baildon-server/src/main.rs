@@ -0,0 +1,360 @@
+use std::env;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use anyhow::Result;
+use baildon::btree::Baildon;
+use baildon::btree::Direction;
+use baildon::btree::Recovery;
+use clap::Parser;
+use futures::StreamExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+
+use resp::Frame;
+
+mod resp;
+
+/// Number of keys returned by SCAN, if no COUNT is specified
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+type Store = Baildon<Vec<u8>, Vec<u8>>;
+
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Store location
+    store: String,
+
+    /// Address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:6379")]
+    address: String,
+
+    /// Create a new store (will overwrite existing file)
+    #[arg(short, long, default_value_t = false)]
+    create: bool,
+
+    /// What to do with a WAL left by a failed writer (ReplayWal, IgnoreWal or FailIfWalPresent)
+    #[arg(long)]
+    recovery: Option<Recovery>,
+
+    /// Maximum length of a key or value sent by a client, in bytes
+    #[arg(long, default_value_t = resp::DEFAULT_MAX_BULK_LEN)]
+    max_bulk_len: usize,
+}
+
+async fn handle_connection(
+    btree: Arc<Store>,
+    stream: TcpStream,
+    max_bulk_len: usize,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut buf = vec![];
+    loop {
+        let (reply, quit) = match resp::read_command(&mut reader, max_bulk_len).await {
+            Ok(Some(args)) if args.is_empty() => continue,
+            Ok(Some(args)) => {
+                let quit = args[0].eq_ignore_ascii_case(b"quit");
+                (process_command(&btree, &args).await, quit)
+            }
+            Ok(None) => break,
+            // We can't find the start of the next command, so give up on this client
+            Err(e) => (Frame::Error(format!("ERR Protocol error: {e}")), true),
+        };
+        buf.clear();
+        reply.encode(&mut buf);
+        writer.write_all(&buf).await?;
+        if quit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+async fn process_command(btree: &Store, args: &[Vec<u8>]) -> Frame {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+    let params = &args[1..];
+    match (name.as_str(), params.len()) {
+        // redis-cli asks about our commands when it starts, we don't provide any details
+        ("command", _) => Frame::Array(vec![]),
+        ("del", 1..) => {
+            let mut deleted = 0;
+            for key in params {
+                match btree.delete(key).await {
+                    Ok(Some(_value)) => deleted += 1,
+                    Ok(None) => (),
+                    Err(e) => return Frame::Error(format!("ERR {e}")),
+                }
+            }
+            Frame::Integer(deleted)
+        }
+        ("exists", 1..) => {
+            let mut found = 0;
+            for key in params {
+                if btree.contains(key).await {
+                    found += 1;
+                }
+            }
+            Frame::Integer(found)
+        }
+        ("get", 1) => Frame::Bulk(btree.get(&params[0]).await),
+        ("ping", 0) => Frame::Simple("PONG".to_string()),
+        ("ping", 1) => Frame::Bulk(Some(params[0].clone())),
+        ("quit", 0) => Frame::Simple("OK".to_string()),
+        ("scan", 1..) => scan(btree, params).await,
+        ("set", 2) => match btree.insert(params[0].clone(), params[1].clone()).await {
+            Ok(_old) => Frame::Simple("OK".to_string()),
+            Err(e) => Frame::Error(format!("ERR {e}")),
+        },
+        // Options, such as expiry, aren't supported
+        ("set", 3..) => Frame::Error("ERR syntax error".to_string()),
+        ("command" | "del" | "exists" | "get" | "ping" | "quit" | "scan" | "set", _) => {
+            Frame::Error(format!(
+                "ERR wrong number of arguments for '{name}' command"
+            ))
+        }
+        _ => Frame::Error(format!("ERR unknown command '{name}'")),
+    }
+}
+
+/// SCAN cursor [MATCH pattern] [COUNT count]
+///
+/// Our cursor encodes the last key which was scanned, and scanning resumes from the leaf which
+/// contains it, so each page costs the same. Keys inserted or deleted during a scan may be
+/// missed, as with Redis, but keys are never returned twice.
+async fn scan(btree: &Store, params: &[Vec<u8>]) -> Frame {
+    let start = match decode_cursor(&params[0]) {
+        Some(start) => start,
+        None => return Frame::Error("ERR invalid cursor".to_string()),
+    };
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in params[1..].chunks(2) {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case(b"match") => pattern = Some(value),
+            [name, value] if name.eq_ignore_ascii_case(b"count") => match parse_number(value) {
+                Some(n) if n > 0 => count = n,
+                _ => return Frame::Error("ERR value is out of range".to_string()),
+            },
+            _ => return Frame::Error("ERR syntax error".to_string()),
+        }
+    }
+
+    let keys = btree
+        .range_keys((start, Bound::Unbounded), Direction::Ascending)
+        .await
+        .take(count)
+        .collect::<Vec<Vec<u8>>>()
+        .await;
+    // A short page means that we've reached the end of our keys
+    let next = match keys.last() {
+        Some(key) if keys.len() == count => encode_cursor(key),
+        _ => "0".to_string(),
+    };
+    let keys = keys
+        .into_iter()
+        .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+        .map(|key| Frame::Bulk(Some(key)))
+        .collect();
+    Frame::Array(vec![
+        Frame::Bulk(Some(next.into_bytes())),
+        Frame::Array(keys),
+    ])
+}
+
+fn parse_number(bytes: &[u8]) -> Option<usize> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+/// Encode the last key of a page as a cursor.
+///
+/// Clients expect cursors to be numbers, so keys are encoded as a `1` followed by three decimal
+/// digits for each byte. `0` is the cursor of the first page.
+fn encode_cursor(key: &[u8]) -> String {
+    key.iter().fold("1".to_string(), |mut cursor, byte| {
+        cursor.push_str(&format!("{byte:03}"));
+        cursor
+    })
+}
+
+/// Decode a cursor into the bound of the keys still to be scanned.
+fn decode_cursor(cursor: &[u8]) -> Option<Bound<Vec<u8>>> {
+    match cursor {
+        b"0" => Some(Bound::Unbounded),
+        [b'1', digits @ ..] if digits.len() % 3 == 0 => digits
+            .chunks(3)
+            .map(|byte| std::str::from_utf8(byte).ok()?.parse::<u8>().ok())
+            .collect::<Option<Vec<u8>>>()
+            .map(Bound::Excluded),
+        _ => None,
+    }
+}
+
+/// Match a glob style pattern, supporting `*`, `?` and `\` escapes.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where to resume if we need to match more text with our last `*`
+    let mut resume = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                resume = Some((p, t));
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            Some(b'\\') if pattern.get(p + 1) == Some(&text[t]) => {
+                p += 2;
+                t += 1;
+                continue;
+            }
+            Some(c) if *c != b'\\' && *c == text[t] => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            _ => (),
+        }
+        match resume {
+            Some((star_p, star_t)) => {
+                p = star_p;
+                t = star_t + 1;
+                resume = Some((star_p, t));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    let log_dir = match env::var("TMPDIR") {
+        Ok(d) => d,
+        Err(_e) => ".".to_string(),
+    };
+
+    let file_appender = tracing_appender::rolling::daily(log_dir, "baildon.log");
+    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
+    tracing_subscriber::fmt().with_writer(non_blocking).init();
+
+    let btree: Store = if cli.create {
+        Store::try_new(&cli.store, 13).await?
+    } else {
+        Store::try_open_with_recovery(&cli.store, cli.recovery.unwrap_or_default()).await?
+    };
+    let btree = Arc::new(btree);
+
+    let listener = TcpListener::bind(&cli.address).await?;
+    println!("listening on {}, terminate with ctrl-c", cli.address);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = accepted?;
+                let btree = btree.clone();
+                let max_bulk_len = cli.max_bulk_len;
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(btree, stream, max_bulk_len).await {
+                        tracing::warn!(%peer, error = %e, "connection failed");
+                    }
+                });
+            }
+            _ = tokio::signal::ctrl_c() => {
+                println!("terminating...");
+                break;
+            }
+        }
+    }
+
+    // Connections may still be in progress, in which case we can only flush
+    match Arc::try_unwrap(btree) {
+        Ok(btree) => btree.close().await,
+        Err(btree) => btree.flush_to_disk().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_matches_globs() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"*", b"anything"));
+        assert!(glob_match(b"", b""));
+        assert!(!glob_match(b"", b"a"));
+        assert!(glob_match(b"user:*", b"user:42"));
+        assert!(!glob_match(b"user:*", b"users:42"));
+        assert!(glob_match(b"*:42", b"user:42"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"a*b*c", b"axxbyyc"));
+        assert!(glob_match(b"a*b*c", b"abbbc"));
+        assert!(!glob_match(b"a*b*c", b"axxbyy"));
+        assert!(glob_match(b"**", b"ab"));
+        // Escaped characters match themselves
+        assert!(glob_match(b"a\\*", b"a*"));
+        assert!(!glob_match(b"a\\*", b"ab"));
+        assert!(glob_match(b"\\?", b"?"));
+        assert!(!glob_match(b"\\?", b"x"));
+    }
+
+    #[test]
+    fn it_encodes_cursors() {
+        for key in [&b""[..], b"a", b"\x00\xff", b"user:42"] {
+            let cursor = encode_cursor(key);
+            assert!(cursor.bytes().all(|byte| byte.is_ascii_digit()));
+            assert_eq!(
+                decode_cursor(cursor.as_bytes()),
+                Some(Bound::Excluded(key.to_vec()))
+            );
+        }
+        assert_eq!(encode_cursor(b"a"), "1097");
+        assert_eq!(decode_cursor(b"0"), Some(Bound::Unbounded));
+        for cursor in [&b""[..], b"2097", b"109", b"1256", b"1x97", b"00"] {
+            assert_eq!(decode_cursor(cursor), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_scans_pages() {
+        let btree = Store::temporary(4).await.expect("creates tree");
+        for i in 0..25u8 {
+            btree.insert(vec![i], vec![i]).await.expect("inserts");
+        }
+        let param = |param: &str| param.as_bytes().to_vec();
+        let mut cursor = "0".to_string();
+        let mut keys = vec![];
+        loop {
+            let Frame::Array(reply) =
+                scan(&btree, &[param(&cursor), param("COUNT"), param("10")]).await
+            else {
+                panic!("expected an array");
+            };
+            let [Frame::Bulk(Some(next)), Frame::Array(page)] = &reply[..] else {
+                panic!("expected a cursor and keys");
+            };
+            for key in page {
+                let Frame::Bulk(Some(key)) = key else {
+                    panic!("expected a key");
+                };
+                keys.push(key[0]);
+            }
+            cursor = String::from_utf8(next.clone()).expect("utf-8 cursor");
+            if cursor == "0" {
+                break;
+            }
+        }
+        assert_eq!(keys, (0..25u8).collect::<Vec<u8>>());
+        assert!(matches!(scan(&btree, &[param("2")]).await, Frame::Error(_)));
+    }
+}
@@ -0,0 +1,238 @@
+//! RESP (REdis Serialization Protocol)
+//!
+//! Just enough of the protocol to read client commands and write replies. Commands are arrays
+//! of bulk strings, or inline commands (space separated words on a line) as typed into a telnet
+//! session.
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Result;
+use tokio::io::AsyncBufRead;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncReadExt;
+
+/// Maximum length of a line (array or bulk string header, or inline command), as for Redis
+const MAX_LINE_LEN: u64 = 64 * 1024;
+
+/// Maximum number of arguments in a command
+const MAX_ARGS: usize = 64 * 1024;
+
+/// Default maximum length of a bulk string
+pub(crate) const DEFAULT_MAX_BULK_LEN: usize = 64 * 1024 * 1024;
+
+/// Number of arguments allocated before any have been read, since headers can't be trusted
+const PREALLOCATED_ARGS: usize = 16;
+
+/// A reply to a client
+#[derive(Debug)]
+pub(crate) enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Frame>),
+}
+
+impl Frame {
+    /// Append our encoding to the supplied buffer.
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Frame::Simple(s) => {
+                buf.push(b'+');
+                buf.extend_from_slice(s.as_bytes());
+            }
+            Frame::Error(s) => {
+                buf.push(b'-');
+                buf.extend_from_slice(s.as_bytes());
+            }
+            Frame::Integer(i) => {
+                buf.push(b':');
+                buf.extend_from_slice(i.to_string().as_bytes());
+            }
+            Frame::Bulk(None) => buf.extend_from_slice(b"$-1"),
+            Frame::Bulk(Some(bytes)) => {
+                buf.push(b'$');
+                buf.extend_from_slice(bytes.len().to_string().as_bytes());
+                buf.extend_from_slice(b"\r\n");
+                buf.extend_from_slice(bytes);
+            }
+            Frame::Array(frames) => {
+                buf.push(b'*');
+                buf.extend_from_slice(frames.len().to_string().as_bytes());
+                buf.extend_from_slice(b"\r\n");
+                for frame in frames {
+                    frame.encode(buf);
+                }
+                // Our elements are terminated, so we are too
+                return;
+            }
+        }
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Read a command from a client, returning None if the client closed its connection.
+///
+/// Buffers grow as arguments are read, rather than being allocated from their headers, so
+/// clients can't make us allocate more than they send.
+pub(crate) async fn read_command<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_bulk_len: usize,
+) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader).await? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        // Inline command
+        let words = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_vec())
+            .collect();
+        return Ok(Some(words));
+    }
+    let count = parse_len(&line[1..], MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count.min(PREALLOCATED_ARGS));
+    for _ in 0..count {
+        let header = read_line(reader)
+            .await?
+            .ok_or_else(|| anyhow!("unexpected end of command"))?;
+        if header.first() != Some(&b'$') {
+            bail!("expected '$', got '{}'", String::from_utf8_lossy(&header));
+        }
+        let len = parse_len(&header[1..], max_bulk_len)?;
+        let mut arg = vec![];
+        let read = (&mut *reader)
+            .take(len as u64 + 2)
+            .read_to_end(&mut arg)
+            .await?;
+        if read < len + 2 {
+            bail!("unexpected end of command");
+        }
+        if !arg.ends_with(b"\r\n") {
+            bail!("bulk string is not terminated");
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Read a line, without its terminator, returning None at the end of our input.
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = vec![];
+    let read = (&mut *reader)
+        .take(MAX_LINE_LEN)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        bail!("line is too long, or not terminated");
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(bytes: &[u8], max: usize) -> Result<usize> {
+    let len = std::str::from_utf8(bytes)?
+        .parse::<usize>()
+        .map_err(|_e| anyhow!("invalid length: '{}'", String::from_utf8_lossy(bytes)))?;
+    if len > max {
+        bail!("length: {len} exceeds maximum: {max}");
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut input: &[u8]) -> Result<Option<Vec<Vec<u8>>>> {
+        read_command(&mut input, 16).await
+    }
+
+    fn args(args: &[&str]) -> Option<Vec<Vec<u8>>> {
+        Some(args.iter().map(|arg| arg.as_bytes().to_vec()).collect())
+    }
+
+    #[tokio::test]
+    async fn it_reads_commands() {
+        let command = read(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n").await;
+        assert_eq!(command.expect("reads"), args(&["GET", "key"]));
+        let command = read(b"*2\r\n$3\r\nSET\r\n$0\r\n\r\n").await;
+        assert_eq!(command.expect("reads"), args(&["SET", ""]));
+        let command = read(b"*0\r\n").await;
+        assert_eq!(command.expect("reads"), args(&[]));
+        assert_eq!(read(b"").await.expect("reads"), None);
+
+        // Commands may be read one after another
+        let mut input = &b"*1\r\n$4\r\nPING\r\nPING\r\n"[..];
+        for _ in 0..2 {
+            let command = read_command(&mut input, 16).await;
+            assert_eq!(command.expect("reads"), args(&["PING"]));
+        }
+    }
+
+    #[tokio::test]
+    async fn it_reads_inline_commands() {
+        let command = read(b"SET  key\tvalue\r\n").await;
+        assert_eq!(command.expect("reads"), args(&["SET", "key", "value"]));
+        let command = read(b"PING\n").await;
+        assert_eq!(command.expect("reads"), args(&["PING"]));
+        assert_eq!(read(b"\r\n").await.expect("reads"), args(&[]));
+        // Inline commands must be terminated
+        assert!(read(b"PING").await.is_err());
+        let line = vec![b'a'; MAX_LINE_LEN as usize + 1];
+        assert!(read(&line).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_truncated_commands() {
+        for input in [
+            &b"*2\r\n$3\r\nGET\r\n"[..],
+            b"*1\r\n$3\r\nGE",
+            b"*1\r\n$3\r\nGET",
+            b"*1\r\n$3",
+            b"*1\r\n",
+        ] {
+            assert!(read(input).await.is_err(), "{input:?}");
+        }
+        // Bulk strings must be terminated
+        assert!(read(b"*1\r\n$3\r\nGETXX").await.is_err());
+        assert!(read(b"*1\r\n3\r\nGET\r\n").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_oversized_lengths() {
+        assert!(read(b"*1\r\n$16\r\n0123456789abcdef\r\n").await.is_ok());
+        assert!(read(b"*1\r\n$17\r\n0123456789abcdefg\r\n").await.is_err());
+        let command = format!("*{}\r\n", MAX_ARGS + 1);
+        assert!(read(command.as_bytes()).await.is_err());
+        // Huge lengths fail when their data is missing, without being allocated
+        let command = format!("*{MAX_ARGS}\r\n$1\r\na\r\n");
+        assert!(read(command.as_bytes()).await.is_err());
+        let mut input = &b"*1\r\n$1073741824\r\nab\r\n"[..];
+        assert!(read_command(&mut input, usize::MAX).await.is_err());
+        assert!(read(b"*-1\r\n").await.is_err());
+        assert!(read(b"*1\r\n$x\r\n").await.is_err());
+    }
+
+    #[test]
+    fn it_encodes_frames() {
+        let mut buf = vec![];
+        Frame::Array(vec![
+            Frame::Simple("OK".to_string()),
+            Frame::Error("ERR".to_string()),
+            Frame::Integer(-3),
+            Frame::Bulk(Some(b"ab".to_vec())),
+            Frame::Bulk(None),
+        ])
+        .encode(&mut buf);
+        assert_eq!(buf, b"*5\r\n+OK\r\n-ERR\r\n:-3\r\n$2\r\nab\r\n$-1\r\n");
+    }
+}
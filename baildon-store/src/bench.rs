@@ -0,0 +1,129 @@
+//! A quick mixed workload, to compare branching factors and hardware.
+//!
+//! Benchmark keys are written with a prefix, so they don't replace existing entries, and are
+//! deleted once the workload is complete.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::bail;
+use anyhow::Result;
+use baildon::btree::Baildon;
+use rand::Rng;
+
+/// Prefix of benchmark keys
+const KEY_PREFIX: &str = "__bench__:";
+
+/// Parse the proportion of operations which are reads.
+pub(crate) fn parse_ratio(ratio: &str) -> Result<f64, String> {
+    match ratio.parse::<f64>() {
+        Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err(format!("{ratio} isn't a number from 0 to 1")),
+    }
+}
+
+/// Results of a benchmark.
+pub(crate) struct BenchReport {
+    elapsed: Duration,
+    reads: Latencies,
+    writes: Latencies,
+    hits: usize,
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ops = self.reads.len() + self.writes.len();
+        writeln!(
+            f,
+            "ops: {ops} ({} reads, {} writes) in {:.3?}: {:.0} ops/s",
+            self.reads.len(),
+            self.writes.len(),
+            self.elapsed,
+            ops as f64 / self.elapsed.as_secs_f64()
+        )?;
+        writeln!(f, "reads: {} (hits: {})", self.reads, self.hits)?;
+        write!(f, "writes: {}", self.writes)
+    }
+}
+
+/// Latencies of one kind of operation, sorted once complete.
+#[derive(Default)]
+struct Latencies(Vec<Duration>);
+
+impl Latencies {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn percentile(&self, percentile: usize) -> Duration {
+        let idx = (self.0.len() * percentile / 100).min(self.0.len() - 1);
+        self.0[idx]
+    }
+}
+
+impl fmt::Display for Latencies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "none");
+        }
+        write!(
+            f,
+            "p50: {:.1?}, p90: {:.1?}, p99: {:.1?}, max: {:.1?}",
+            self.percentile(50),
+            self.percentile(90),
+            self.percentile(99),
+            self.0[self.0.len() - 1]
+        )
+    }
+}
+
+/// Run a workload of reads and writes of random keys, returning the latency of each kind of
+/// operation. Keys are chosen from as many keys as there are operations, so reads may miss.
+pub(crate) async fn bench(
+    btree: &Baildon<String, String>,
+    ops: usize,
+    read_ratio: f64,
+    value_size: usize,
+) -> Result<BenchReport> {
+    if btree.read_only() && read_ratio < 1.0 {
+        bail!("a read only store can only run reads (--read-ratio 1)");
+    }
+    let value = "x".repeat(value_size);
+    let mut rng = rand::thread_rng();
+    let mut reads = Latencies::default();
+    let mut writes = Latencies::default();
+    let mut written = BTreeSet::new();
+    let mut hits = 0;
+
+    let start = Instant::now();
+    for _ in 0..ops {
+        let key = format!("{KEY_PREFIX}{:010}", rng.gen_range(0..ops.max(1)));
+        if rng.gen_bool(read_ratio) {
+            let op_start = Instant::now();
+            let found = btree.get(&key).await.is_some();
+            reads.0.push(op_start.elapsed());
+            hits += usize::from(found);
+        } else {
+            let op_start = Instant::now();
+            let result = btree.insert(key.clone(), value.clone()).await;
+            writes.0.push(op_start.elapsed());
+            result?;
+            written.insert(key);
+        }
+    }
+    let elapsed = start.elapsed();
+
+    for key in written {
+        btree.delete(&key).await?;
+    }
+    reads.0.sort_unstable();
+    writes.0.sort_unstable();
+    Ok(BenchReport {
+        elapsed,
+        reads,
+        writes,
+        hits,
+    })
+}
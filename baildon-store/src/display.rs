@@ -0,0 +1,35 @@
+//! Display keys and values which may contain control characters, without garbling the terminal.
+
+use std::borrow::Cow;
+use std::fmt::Write;
+
+use strum::EnumString;
+
+#[derive(Clone, Copy, Debug, Default, EnumString)]
+#[strum(ascii_case_insensitive)]
+pub(crate) enum DisplayMode {
+    /// As they are
+    #[default]
+    Utf8,
+    /// As the hex digits of their bytes
+    Hex,
+    /// With control characters (and quotes and backslashes) escaped, as in Rust strings
+    Escape,
+}
+
+impl DisplayMode {
+    /// Format a key or value for display.
+    pub(crate) fn show(self, text: &str) -> Cow<'_, str> {
+        match self {
+            DisplayMode::Utf8 => Cow::Borrowed(text),
+            DisplayMode::Hex => {
+                let mut hex = String::with_capacity(text.len() * 2);
+                for byte in text.bytes() {
+                    let _ = write!(hex, "{byte:02x}");
+                }
+                Cow::Owned(hex)
+            }
+            DisplayMode::Escape => Cow::Owned(text.escape_debug().to_string()),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Import and export entries as CSV or JSON Lines.
+//!
+//! CSV records have no header and two fields: key, then value. JSON Lines contain an object per
+//! entry: `{"key": "...", "value": "..."}`.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::ops::Bound;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use baildon::btree::Baildon;
+use baildon::btree::Direction;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use strum::EnumString;
+
+/// Branching factor of new stores
+pub(crate) const DEFAULT_BRANCH: u64 = 13;
+
+/// Largest branching factor chosen for imported stores
+const MAX_IMPORT_BRANCH: u64 = 256;
+
+/// Number of entries between progress reports
+const PROGRESS_INTERVAL: u64 = 10_000;
+
+#[derive(Clone, Copy, Debug, Default, EnumString)]
+#[strum(ascii_case_insensitive)]
+pub(crate) enum Format {
+    #[default]
+    Csv,
+    Jsonl,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    key: String,
+    value: String,
+}
+
+type Entries = Box<dyn Iterator<Item = Result<(String, String)>>>;
+
+fn read_entries(format: Format, file: &str) -> Result<Entries> {
+    match format {
+        Format::Csv => {
+            let reader = baildon::csv::ReaderBuilder::new()
+                .has_headers(false)
+                .from_path(file)?;
+            Ok(Box::new(reader.into_records().map(|record| {
+                let record = record?;
+                match (record.get(0), record.get(1)) {
+                    (Some(key), Some(value)) => Ok((key.to_string(), value.to_string())),
+                    _ => Err(anyhow!("record: {record:?} needs a key and a value")),
+                }
+            })))
+        }
+        Format::Jsonl => {
+            let reader = BufReader::new(File::open(file)?);
+            Ok(Box::new(
+                reader
+                    .lines()
+                    .filter(|line| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+                    .map(|line| {
+                        let entry: Entry = serde_json::from_str(&line?)?;
+                        Ok((entry.key, entry.value))
+                    }),
+            ))
+        }
+    }
+}
+
+fn report_progress(count: u64, action: &str) {
+    if count % PROGRESS_INTERVAL == 0 {
+        eprint!("\r{action}: {count} entries");
+        let _ = std::io::stderr().flush();
+    }
+}
+
+fn end_progress(count: u64) {
+    if count >= PROGRESS_INTERVAL {
+        eprintln!();
+    }
+}
+
+/// Insert the entries from a file, returning the number of entries inserted.
+pub(crate) async fn import(
+    btree: &Baildon<String, String>,
+    format: Format,
+    file: &str,
+) -> Result<u64> {
+    let mut count = 0;
+    for entry in read_entries(format, file)? {
+        let (key, value) = entry?;
+        btree.insert(key, value).await?;
+        count += 1;
+        report_progress(count, "imported");
+    }
+    end_progress(count);
+    Ok(count)
+}
+
+/// Create a new store, bulk loaded from the entries in a file.
+///
+/// The entries are sorted (later entries replacing earlier entries with the same key) before the
+/// store is built. Unless a branching factor is specified, it is chosen to suit the number of
+/// entries.
+pub(crate) async fn import_new(
+    store: &str,
+    branch: Option<u64>,
+    format: Format,
+    file: &str,
+) -> Result<Baildon<String, String>> {
+    let mut entries = BTreeMap::new();
+    let mut count = 0;
+    for entry in read_entries(format, file)? {
+        let (key, value) = entry?;
+        entries.insert(key, value);
+        count += 1;
+        report_progress(count, "read");
+    }
+    end_progress(count);
+    let branch = branch.unwrap_or_else(|| import_branch(entries.len()));
+    println!(
+        "bulk loading: {} entries, branching factor: {branch}",
+        entries.len()
+    );
+    Baildon::bulk_load(store, branch, entries).await
+}
+
+/// Write our entries to a file, or stdout if no file is specified, returning the number of entries
+/// written. If a range is specified, only keys from start (inclusive) to end (exclusive) are
+/// written.
+pub(crate) async fn export(
+    btree: &Baildon<String, String>,
+    format: Format,
+    range: Option<(&str, &str)>,
+    file: Option<&str>,
+) -> Result<u64> {
+    let writer: Box<dyn Write> = match file {
+        Some(file) => Box::new(BufWriter::new(File::create(file)?)),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let mut writer = match format {
+        Format::Csv => EntryWriter::Csv(
+            baildon::csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(writer),
+        ),
+        Format::Jsonl => EntryWriter::Jsonl(writer),
+    };
+
+    let range = match range {
+        Some((start, end)) => (
+            Bound::Included(start.to_string()),
+            Bound::Excluded(end.to_string()),
+        ),
+        None => (Bound::Unbounded, Bound::Unbounded),
+    };
+    let mut entries = btree.range(range, Direction::Ascending).await;
+    let mut count = 0;
+    let mut result = Ok(());
+    while let Some((key, value)) = entries.next().await {
+        result = writer.write(key, value);
+        if result.is_err() {
+            break;
+        }
+        count += 1;
+        report_progress(count, "exported");
+    }
+    end_progress(count);
+    result?;
+    writer.flush()?;
+    Ok(count)
+}
+
+enum EntryWriter {
+    Csv(baildon::csv::Writer<Box<dyn Write>>),
+    Jsonl(Box<dyn Write>),
+}
+
+impl EntryWriter {
+    fn write(&mut self, key: String, value: String) -> Result<()> {
+        match self {
+            EntryWriter::Csv(writer) => writer.write_record([key, value])?,
+            EntryWriter::Jsonl(writer) => {
+                serde_json::to_writer(&mut *writer, &Entry { key, value })?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            EntryWriter::Csv(writer) => writer.flush()?,
+            EntryWriter::Jsonl(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// Choose a branching factor which keeps large stores shallow. About the cube root of the number
+/// of entries keeps a store to around three levels of nodes.
+fn import_branch(entries: usize) -> u64 {
+    ((entries as f64).cbrt().ceil() as u64).clamp(DEFAULT_BRANCH, MAX_IMPORT_BRANCH)
+}
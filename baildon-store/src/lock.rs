@@ -0,0 +1,32 @@
+//! Find the process which holds the lock on a store.
+
+use std::path::Path;
+
+/// Describe the process which holds the lock on a file, if it can be found.
+///
+/// Locks are listed in /proc/locks, so holders can only be found on Linux.
+#[cfg(unix)]
+pub(crate) fn holder(path: &Path) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    let inode = std::fs::metadata(path).ok()?.ino().to_string();
+    let locks = std::fs::read_to_string("/proc/locks").ok()?;
+    // e.g.: 1: FLOCK  ADVISORY  WRITE 1234 08:01:5678 0 EOF
+    let pid = locks.lines().find_map(|line| {
+        let fields = line.split_whitespace().collect::<Vec<&str>>();
+        let (kind, pid, id) = (fields.get(1)?, fields.get(4)?, fields.get(5)?);
+        (*kind == "FLOCK" && id.rsplit(':').next()? == inode).then(|| pid.to_string())
+    })?;
+    match std::fs::read(format!("/proc/{pid}/cmdline")) {
+        Ok(cmdline) => {
+            let cmdline = String::from_utf8_lossy(&cmdline).replace('\0', " ");
+            Some(format!("process: {pid} ({})", cmdline.trim_end()))
+        }
+        Err(_) => Some(format!("process: {pid}")),
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn holder(_path: &Path) -> Option<String> {
+    None
+}
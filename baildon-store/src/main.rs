@@ -1,16 +1,35 @@
 use std::env;
 use std::fs::metadata;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::ops::Bound;
+use std::path::Path;
 use std::path::PathBuf;
-use std::str::FromStr;
+use std::process::ExitCode;
 
+use anyhow::bail;
+use anyhow::Context;
 use anyhow::Result;
+use baildon::btree::baildon::BaildonError;
 use baildon::btree::Baildon;
 use baildon::btree::Direction;
+use baildon::btree::Recovery;
 use clap::Parser;
 use clap::Subcommand;
+use futures::Stream;
+use futures::StreamExt;
 use rustyline::error::ReadlineError;
 use rustyline::DefaultEditor;
-use strum::EnumString;
+
+use display::DisplayMode;
+use interchange::Format;
+use interchange::DEFAULT_BRANCH;
+
+mod bench;
+mod display;
+mod interchange;
+mod lock;
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
@@ -22,13 +41,50 @@ struct Cli {
     #[arg(short, long, default_value_t = false)]
     create: bool,
 
+    /// Branching factor of a created store, at least 2 (default: 13, or to suit imported entries)
+    #[arg(short, long, requires = "create", value_parser = clap::value_parser!(u64).range(2..))]
+    branch: Option<u64>,
+
+    /// Open the store read only (another process may be writing to it)
+    #[arg(short, long, default_value_t = false, conflicts_with = "create")]
+    read_only: bool,
+
+    /// Open the store read only if another process has locked it for writing
+    #[arg(long, default_value_t = false, conflicts_with = "create")]
+    force: bool,
+
+    /// What to do with a WAL left by a failed writer (ReplayWal, IgnoreWal or FailIfWalPresent)
+    #[arg(long)]
+    recovery: Option<Recovery>,
+
+    /// How keys and values are displayed (Utf8, Hex or Escape)
+    #[arg(short, long, default_value = "utf8")]
+    display: DisplayMode,
+
+    /// Run the commands in a script, one per line (read from stdin if it isn't a terminal)
+    #[arg(short, long)]
+    script: Option<PathBuf>,
+
     #[command(subcommand)]
     parameter: Option<Parameter>,
 }
 
-#[derive(Debug, EnumString, Subcommand)]
-#[strum(ascii_case_insensitive)]
+#[derive(Debug, Subcommand)]
 enum Parameter {
+    /// Back up the store to a file (which may be restored)
+    Backup { target: String },
+    /// Run a mixed workload of reads and writes, reporting throughput and latencies
+    Bench {
+        /// Number of operations
+        #[arg(long, default_value_t = 10_000)]
+        ops: usize,
+        /// Proportion of operations which are reads (from 0 to 1)
+        #[arg(long, default_value_t = 0.8, value_parser = bench::parse_ratio)]
+        read_ratio: f64,
+        /// Size of written values, in bytes
+        #[arg(long, default_value_t = 100)]
+        value_size: usize,
+    },
     /// Does our store contain this key
     Contains { key: String },
     /// Clear store entries
@@ -42,8 +98,43 @@ enum Parameter {
         /// Direction (Descending or Ascending)
         direction: Option<Direction>,
     },
+    /// Export entries to a file, or stdout
+    Export {
+        /// Format of the file (Csv or Jsonl)
+        #[arg(short, long, default_value = "csv")]
+        format: Format,
+        /// Only export keys from start (inclusive) to end (exclusive)
+        #[arg(long, num_args = 2, value_names = ["START", "END"])]
+        range: Option<Vec<String>>,
+        /// File location (stdout if not specified)
+        file: Option<String>,
+    },
     /// Get this key
-    Get { key: String },
+    Get {
+        key: String,
+        /// Value to print if the key isn't found
+        #[arg(short, long)]
+        default: Option<String>,
+    },
+    /// Import entries from a file
+    Import {
+        /// Format of the file (Csv or Jsonl)
+        #[arg(short, long, default_value = "csv")]
+        format: Format,
+        /// File location
+        file: String,
+    },
+    /// Import the key and value columns of a SQLite table
+    ImportSqlite {
+        /// SQLite database location
+        database: String,
+        /// Table to import
+        table: String,
+        /// Key column (which must contain text)
+        key: String,
+        /// Value column (which must contain text)
+        value: String,
+    },
     /// Insert key value pair
     Insert { key: String, value: String },
     /// List store keys
@@ -56,6 +147,47 @@ enum Parameter {
         /// Direction (Descending or Ascending)
         direction: Option<Direction>,
     },
+    /// List store entries whose keys start with a prefix
+    Prefix { prefix: String },
+    /// Move the value of a key to a new key
+    Rename {
+        old: String,
+        new: String,
+        /// Replace the value of the new key, if it exists
+        #[arg(long)]
+        overwrite: bool,
+    },
+    /// Restore the store from a backup, replacing its contents
+    Restore { backup: String },
+    /// List keys chosen at random from the store
+    Sample {
+        /// Number of keys
+        #[arg(default_value_t = 5)]
+        count: usize,
+    },
+    /// List store entries within a range of keys
+    Scan {
+        /// First key (inclusive)
+        #[arg(long)]
+        start: Option<String>,
+        /// End key (exclusive)
+        #[arg(long)]
+        end: Option<String>,
+        /// Maximum number of entries
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Direction (Descending or Ascending)
+        direction: Option<Direction>,
+    },
+    /// Change a setting for the following commands
+    Set {
+        #[command(subcommand)]
+        setting: Setting,
+    },
+    /// Display B+Tree statistics
+    Stats,
+    /// Print the structure of the B+Tree: separator keys of nodes and key ranges of leaves
+    Tree,
     /// Node Utilization
     Utilization,
     /// List store values
@@ -65,6 +197,23 @@ enum Parameter {
     },
     /// Verify store
     Verify,
+    /// List the records of the store's WAL, which are replayed when it is opened
+    Wal,
+}
+
+#[derive(Debug, Subcommand)]
+enum Setting {
+    /// How keys and values are displayed (Utf8, Hex or Escape)
+    Display { mode: DisplayMode },
+    /// Direction of listings, unless one is specified (Descending or Ascending)
+    Direction { direction: Direction },
+}
+
+/// Settings used by our commands, which scripts (such as our init script) may change
+#[derive(Clone, Copy, Debug)]
+struct Settings {
+    display: DisplayMode,
+    direction: Direction,
 }
 
 fn get_history_file() -> Option<PathBuf> {
@@ -84,7 +233,14 @@ fn get_history_file() -> Option<PathBuf> {
         })
 }
 
-async fn interactive(btree: Baildon<String, String>) -> Result<()> {
+/// Our init script, run before any commands are entered interactively, if it exists
+fn get_init_file() -> Option<PathBuf> {
+    dirs::preference_dir()
+        .map(|base| base.join("baildon").join("init"))
+        .filter(|file| file.is_file())
+}
+
+async fn interactive(btree: &Baildon<String, String>, mut settings: Settings) -> Result<()> {
     // `()` can be used when no completer is required
     let mut rl = DefaultEditor::new()?;
     if let Some(file_location) = get_history_file() {
@@ -92,6 +248,17 @@ async fn interactive(btree: Baildon<String, String>) -> Result<()> {
             println!("error loading history: {e}");
         }
     }
+    if let Some(file_location) = get_init_file() {
+        // A failing init script shouldn't stop us from starting
+        match File::open(&file_location) {
+            Ok(file) => {
+                if let Err(e) = run_script(btree, BufReader::new(file), &mut settings).await {
+                    println!("error running {}: {e:#}", file_location.display());
+                }
+            }
+            Err(e) => println!("error opening {}: {e}", file_location.display()),
+        }
+    }
     println!("terminate with ctrl-c or ctrl-d");
     loop {
         let readline = rl.readline("word: ");
@@ -100,122 +267,14 @@ async fn interactive(btree: Baildon<String, String>) -> Result<()> {
                 if line.is_empty() {
                     continue;
                 }
-                // EnumString doesn't deal with variant parameters, so...
-                let words = line.split_whitespace().collect::<Vec<&str>>();
-                let parameter = match Parameter::from_str(words[0]) {
-                    Ok(p) => {
-                        // Can't think of a better way of doing this...
-                        match p {
-                            Parameter::Contains { key: _ } => {
-                                if words.len() != 2 {
-                                    println!("usage: contains <key>");
-                                    continue;
-                                }
-                                Parameter::Contains {
-                                    key: words[1].to_string(),
-                                }
-                            }
-                            Parameter::Delete { key: _ } => {
-                                if words.len() != 2 {
-                                    println!("usage: delete <key>");
-                                    continue;
-                                }
-                                Parameter::Delete {
-                                    key: words[1].to_string(),
-                                }
-                            }
-                            Parameter::Get { key: _ } => {
-                                if words.len() != 2 {
-                                    println!("usage: get <key>");
-                                    continue;
-                                }
-                                Parameter::Get {
-                                    key: words[1].to_string(),
-                                }
-                            }
-                            Parameter::Insert { key: _, value: _ } => {
-                                if words.len() != 3 {
-                                    println!("usage: insert <key> <value>");
-                                    continue;
-                                }
-                                Parameter::Insert {
-                                    key: words[1].to_string(),
-                                    value: words[2].to_string(),
-                                }
-                            }
-                            Parameter::Keys { direction: _ } => match words.len() {
-                                1 => Parameter::Keys { direction: None },
-                                2 => {
-                                    // Try to process the parameter
-                                    let direction = Direction::from_str(words[1]).ok();
-                                    if direction.is_none() {
-                                        println!("usage: keys [<direction>]");
-                                        continue;
-                                    }
-                                    Parameter::Keys { direction }
-                                }
-                                _ => {
-                                    println!("usage: keys [<direction>]");
-                                    continue;
-                                }
-                            },
-                            Parameter::Entries { direction: _ } => match words.len() {
-                                1 => Parameter::Entries { direction: None },
-                                2 => {
-                                    // Try to process the parameter
-                                    let direction = Direction::from_str(words[1]).ok();
-                                    if direction.is_none() {
-                                        println!("usage: entries [<direction>]");
-                                        continue;
-                                    }
-                                    Parameter::Entries { direction }
-                                }
-                                _ => {
-                                    println!("usage: entries [<direction>]");
-                                    continue;
-                                }
-                            },
-                            Parameter::Nodes { direction: _ } => match words.len() {
-                                1 => Parameter::Nodes { direction: None },
-                                2 => {
-                                    // Try to process the parameter
-                                    let direction = Direction::from_str(words[1]).ok();
-                                    if direction.is_none() {
-                                        println!("usage: nodes [<direction>]");
-                                        continue;
-                                    }
-                                    Parameter::Nodes { direction }
-                                }
-                                _ => {
-                                    println!("usage: nodes [<direction>]");
-                                    continue;
-                                }
-                            },
-                            Parameter::Values { direction: _ } => match words.len() {
-                                1 => Parameter::Values { direction: None },
-                                2 => {
-                                    // Try to process the parameter
-                                    let direction = Direction::from_str(words[1]).ok();
-                                    if direction.is_none() {
-                                        println!("usage: values [<direction>]");
-                                        continue;
-                                    }
-                                    Parameter::Values { direction }
-                                }
-                                _ => {
-                                    println!("usage: values [<direction>]");
-                                    continue;
-                                }
-                            },
-                            _ => p,
+                match parse_command(&line) {
+                    Ok(parameter) => {
+                        if let Err(e) = process_parameter(btree, &parameter, &mut settings).await {
+                            println!("{e:#}");
                         }
                     }
-                    Err(e) => {
-                        println!("error: {e}");
-                        continue;
-                    }
-                };
-                process_parameter(&btree, &parameter).await;
+                    Err(e) => println!("{e}"),
+                }
                 rl.add_history_entry(line.as_str())?;
             }
             Err(ReadlineError::Interrupted) => {
@@ -240,94 +299,304 @@ async fn interactive(btree: Baildon<String, String>) -> Result<()> {
     Ok(())
 }
 
-async fn process_parameter(btree: &Baildon<String, String>, parameter: &Parameter) {
+/// Run the commands in a script, one per line, stopping at the first command which fails. Blank
+/// lines and lines starting with `#` are skipped.
+async fn run_script(
+    btree: &Baildon<String, String>,
+    script: impl BufRead,
+    settings: &mut Settings,
+) -> Result<()> {
+    for (number, line) in script.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parameter = parse_command(line).with_context(|| format!("line: {}", number + 1))?;
+        process_parameter(btree, &parameter, settings)
+            .await
+            .with_context(|| format!("line: {}: {line}", number + 1))?;
+    }
+    Ok(())
+}
+
+/// A command, as entered interactively or in a script
+#[derive(Debug, Parser)]
+#[command(multicall = true)]
+struct Line {
+    #[command(subcommand)]
+    parameter: Parameter,
+}
+
+/// Parse a command, as entered interactively or in a script.
+///
+/// Words are split as a shell would split them, so they may be quoted or escaped, and are then
+/// parsed in the same way as our command line arguments.
+fn parse_command(line: &str) -> Result<Parameter> {
+    let Some(mut words) = shlex::split(line) else {
+        bail!("error: unbalanced quotes");
+    };
+    // Commands are case insensitive
+    if let Some(command) = words.first_mut() {
+        *command = command.to_lowercase();
+    }
+    Ok(Line::try_parse_from(words)?.parameter)
+}
+
+/// Whether a command found what it looked for, so scripts can tell missing keys from failures.
+enum Outcome {
+    Found,
+    NotFound,
+}
+
+/// Process a command, returning an error if it fails.
+async fn process_parameter(
+    btree: &Baildon<String, String>,
+    parameter: &Parameter,
+    settings: &mut Settings,
+) -> Result<Outcome> {
+    let Settings {
+        display,
+        direction: default_direction,
+    } = *settings;
+    let mut outcome = Outcome::Found;
     match parameter {
+        Parameter::Backup { target } => match btree.backup_to(target).await {
+            Ok(copied) => println!("backed up: {copied} bytes to {target}"),
+            Err(e) => return Err(e.context("backup failed")),
+        },
+        Parameter::Bench {
+            ops,
+            read_ratio,
+            value_size,
+        } => match bench::bench(btree, *ops, *read_ratio, *value_size).await {
+            Ok(report) => println!("{report}"),
+            Err(e) => return Err(e.context("bench failed")),
+        },
         Parameter::Contains { key } => {
             if btree.contains(key).await {
                 println!("true");
             } else {
                 println!("false");
+                outcome = Outcome::NotFound;
             }
         }
         Parameter::Clear => match btree.clear().await {
             Ok(_) => println!("cleared"),
-            Err(e) => println!("error: {e}"),
+            Err(e) => return Err(e),
         },
         Parameter::Count => println!("count: {}", btree.count().await),
         Parameter::Delete { key } => match btree.delete(key).await {
             Ok(opt_value) => match opt_value {
                 Some(value) => {
-                    println!("deleted: {key}: {value}");
+                    println!("deleted: {}: {}", display.show(key), display.show(&value));
                 }
                 None => {
                     println!("not found");
                 }
             },
-            Err(err) => {
-                println!("delete failed: {err}");
-            }
+            Err(err) => return Err(err.context("delete failed")),
         },
-        Parameter::Get { key } => match btree.get(key).await {
+        Parameter::Get { key, default } => match btree.get(key).await.or_else(|| default.clone()) {
             Some(value) => {
-                println!("{value}");
+                println!("{}", display.show(&value));
             }
             None => {
                 println!("not found");
+                outcome = Outcome::NotFound;
             }
         },
+        Parameter::Export {
+            format,
+            range,
+            file,
+        } => {
+            let range = range
+                .as_ref()
+                .map(|range| (range[0].as_str(), range[1].as_str()));
+            match interchange::export(btree, *format, range, file.as_deref()).await {
+                // Don't mix our report with exported entries
+                Ok(count) => eprintln!("exported: {count} entries"),
+                Err(e) => return Err(e.context("export failed")),
+            }
+        }
+        Parameter::Import { format, file } => {
+            match interchange::import(btree, *format, file).await {
+                Ok(count) => println!("imported: {count} entries"),
+                Err(e) => return Err(e.context("import failed")),
+            }
+        }
+        Parameter::ImportSqlite {
+            database,
+            table,
+            key,
+            value,
+        } => match btree.import_sqlite(database, table, key, value).await {
+            Ok(count) => println!("imported: {count} rows"),
+            Err(e) => return Err(e.context("import failed")),
+        },
         Parameter::Insert { key, value } => match btree.insert(key.clone(), value.clone()).await {
             Ok(opt_value) => match opt_value {
                 Some(old) => {
-                    println!("old value: {old}");
+                    println!("old value: {}", display.show(&old));
                 }
                 None => {
-                    println!("inserted: {key}: {value}");
+                    println!("inserted: {}: {}", display.show(key), display.show(value));
                 }
             },
-            Err(err) => {
-                println!("insert failed: {err}");
-            }
+            Err(err) => return Err(err.context("insert failed")),
         },
         Parameter::Keys { direction } => {
-            if let Some(dir) = direction {
-                btree.print_keys(*dir).await
-            } else {
-                btree.print_keys(Direction::Ascending).await
-            }
+            let keys = btree
+                .keys(direction.unwrap_or(default_direction))
+                .await
+                .map(|key| display.show(&key).into_owned());
+            print_all(keys).await
         }
         Parameter::Entries { direction } => {
-            if let Some(dir) = direction {
-                btree.print_entries(*dir).await
-            } else {
-                btree.print_entries(Direction::Ascending).await
-            }
+            let entries = btree
+                .entries(direction.unwrap_or(default_direction))
+                .await
+                .map(|entry| show_entry(display, entry));
+            print_all(entries).await
         }
         Parameter::Nodes { direction } => {
-            if let Some(dir) = direction {
-                btree.print_nodes(*dir).await
-            } else {
-                btree.print_nodes(Direction::Ascending).await
+            btree
+                .print_nodes(direction.unwrap_or(default_direction))
+                .await
+        }
+        Parameter::Prefix { prefix } => {
+            let entries = btree
+                .scan_prefix(prefix.clone())
+                .await
+                .map(|entry| show_entry(display, entry));
+            print_all(entries).await
+        }
+        Parameter::Rename {
+            old,
+            new,
+            overwrite,
+        } => match btree.rename(old, new.clone(), *overwrite).await {
+            Ok(_) => println!("renamed: {} to {}", display.show(old), display.show(new)),
+            Err(e) => return Err(e.context("rename failed")),
+        },
+        Parameter::Restore { .. } => {
+            // The store is replaced, so it mustn't be open
+            bail!("restore failed: the store is open, restore from the command line")
+        }
+        Parameter::Sample { count } => {
+            for key in btree.sample_keys(*count).await {
+                println!("{}", display.show(&key));
             }
         }
+        Parameter::Scan {
+            start,
+            end,
+            limit,
+            direction,
+        } => {
+            let range = (
+                start.clone().map_or(Bound::Unbounded, Bound::Included),
+                end.clone().map_or(Bound::Unbounded, Bound::Excluded),
+            );
+            let entries = btree
+                .range(range, direction.unwrap_or(default_direction))
+                .await
+                .take(limit.unwrap_or(usize::MAX))
+                .map(|entry| show_entry(display, entry));
+            print_all(entries).await
+        }
+        Parameter::Set { setting } => match setting {
+            Setting::Display { mode } => {
+                settings.display = *mode;
+                println!("display: {mode:?}");
+            }
+            Setting::Direction { direction } => {
+                settings.direction = *direction;
+                println!("direction: {direction:?}");
+            }
+        },
+        Parameter::Stats => match btree.stats().await {
+            Ok(stats) => println!("{stats}"),
+            Err(e) => return Err(e.context("Stats failed")),
+        },
+        Parameter::Tree => btree.print_tree().await.context("tree failed")?,
         Parameter::Utilization => {
             println!("Utilization: {:.1}%", 100.0 * btree.utilization().await);
         }
         Parameter::Verify => match btree.verify(Direction::Ascending).await {
-            Ok(_) => println!("Ok"),
-            Err(e) => println!("Verification failed: {e}"),
+            Ok(report) if report.is_ok() => println!("Ok"),
+            Ok(report) => bail!("Verification failed: {report}"),
+            Err(e) => return Err(e.context("Verification failed")),
         },
+        Parameter::Wal => print_wal(btree.path(), display).await?,
         Parameter::Values { direction } => {
-            if let Some(dir) = direction {
-                btree.print_values(*dir).await
-            } else {
-                btree.print_values(Direction::Ascending).await
-            }
+            let values = btree
+                .values(direction.unwrap_or(default_direction))
+                .await
+                .map(|value| display.show(&value).into_owned());
+            print_all(values).await
         }
     }
+    Ok(outcome)
+}
+
+/// Print the records of the WAL of a store.
+async fn print_wal(store: &Path, display: DisplayMode) -> Result<()> {
+    let records = Baildon::<String, String>::inspect_wal(store).await?;
+    for record in &records {
+        let key = match &record.new_key {
+            Some(new_key) => format!("{} -> {}", display.show(&record.key), display.show(new_key)),
+            None => display.show(&record.key).into_owned(),
+        };
+        println!(
+            "{}: {}: {key} ({} bytes)",
+            record.offset,
+            record.operation(),
+            record.size
+        );
+    }
+    println!("records: {}", records.len());
+    Ok(())
+}
+
+/// Format an entry for display.
+fn show_entry(display: DisplayMode, (key, value): (String, String)) -> String {
+    format!("{}:{}", display.show(&key), display.show(&value))
+}
+
+/// Print every item of a stream on one line, separated by commas.
+async fn print_all(mut items: impl Stream<Item = String> + Unpin) {
+    let mut sep = "";
+    while let Some(item) = items.next().await {
+        print!("{sep}{item}");
+        sep = ", ";
+    }
+    println!();
+}
+
+/// Restore a store from a backup, then verify the restored store.
+///
+/// The backup may be specified by the location of its copy, or its manifest.
+async fn restore(store: &str, backup: &str) -> Result<()> {
+    let manifest = Path::new(backup).with_extension("manifest");
+    let restored = Baildon::<String, String>::restore(&manifest, store).await?;
+    println!("restored: {restored} bytes from {}", manifest.display());
+    // Opening replays any backed up WAL
+    let btree = Baildon::<String, String>::try_open(store).await?;
+    let report = btree.verify(Direction::Ascending).await;
+    let count = btree.count().await;
+    btree.close().await?;
+    let report = report?;
+    if !report.is_ok() {
+        bail!("verification failed: {report}");
+    }
+    println!("verified: {count} entries");
+    Ok(())
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
     let cli = Cli::parse();
 
     let log_dir = match env::var("TMPDIR") {
@@ -339,15 +608,156 @@ async fn main() -> Result<()> {
     let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
     tracing_subscriber::fmt().with_writer(non_blocking).init();
 
+    // Scripts can tell keys which weren't found from failures
+    match run(cli).await {
+        Ok(Outcome::Found) => ExitCode::SUCCESS,
+        Ok(Outcome::NotFound) => ExitCode::from(1),
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<Outcome> {
+    if cli.parameter.is_some() && cli.script.is_some() {
+        bail!("a command can't be specified with a script");
+    }
+    // Open our script first, so a missing script doesn't leave our store open
+    let script: Option<Box<dyn BufRead>> = match &cli.script {
+        Some(path) => Some(Box::new(BufReader::new(File::open(path)?))),
+        None if cli.parameter.is_none() && unsafe { libc::isatty(0) } == 0 => {
+            Some(Box::new(std::io::stdin().lock()))
+        }
+        None => None,
+    };
+
+    // Restores replace the store, so they happen before it is opened
+    if let Some(Parameter::Restore { backup }) = &cli.parameter {
+        return restore(&cli.store, backup).await.map(|_| Outcome::Found);
+    }
+
+    // Opening a store replays and removes its WAL, so inspect it first
+    if let Some(Parameter::Wal) = &cli.parameter {
+        return print_wal(Path::new(&cli.store), cli.display)
+            .await
+            .map(|_| Outcome::Found);
+    }
+
+    // New stores are bulk loaded, rather than imported into
+    if cli.create {
+        let btree = match &cli.parameter {
+            Some(Parameter::Import { format, file }) => {
+                Some(interchange::import_new(&cli.store, cli.branch, *format, file).await?)
+            }
+            Some(Parameter::ImportSqlite {
+                database,
+                table,
+                key,
+                value,
+            }) => Some(
+                Baildon::<String, String>::from_sqlite(
+                    &cli.store,
+                    cli.branch.unwrap_or(DEFAULT_BRANCH),
+                    database,
+                    table,
+                    key,
+                    value,
+                )
+                .await?,
+            ),
+            _ => None,
+        };
+        if let Some(btree) = btree {
+            println!("imported: {} entries", btree.count().await);
+            return btree.close().await.map(|_| Outcome::Found);
+        }
+    }
+
     let btree: Baildon<String, String> = if cli.create {
-        Baildon::<String, String>::try_new(&cli.store, 13).await?
+        Baildon::<String, String>::try_new(&cli.store, cli.branch.unwrap_or(DEFAULT_BRANCH)).await?
+    } else if cli.read_only {
+        Baildon::<String, String>::try_open_shared(&cli.store).await?
     } else {
-        Baildon::<String, String>::try_open(&cli.store).await?
+        match Baildon::<String, String>::try_open_with_recovery(
+            &cli.store,
+            cli.recovery.unwrap_or_default(),
+        )
+        .await
+        {
+            Ok(btree) => btree,
+            Err(e) if matches!(e.downcast_ref(), Some(BaildonError::Locked(_))) => {
+                let holder = lock::holder(Path::new(&cli.store))
+                    .unwrap_or_else(|| "another process".to_string());
+                if !cli.force {
+                    bail!(
+                        "store: {} is locked by {holder}, use --force to open it read only",
+                        cli.store
+                    );
+                }
+                eprintln!(
+                    "store: {} is locked by {holder}, opening it read only",
+                    cli.store
+                );
+                Baildon::<String, String>::try_open_shared(&cli.store).await?
+            }
+            Err(e) => return Err(e),
+        }
+    };
+
+    let mut settings = Settings {
+        display: cli.display,
+        direction: Direction::Ascending,
+    };
+    let result = match (cli.parameter, script) {
+        (Some(parameter), _) => process_parameter(&btree, &parameter, &mut settings).await,
+        (None, Some(script)) => run_script(&btree, script, &mut settings)
+            .await
+            .map(|_| Outcome::Found),
+        (None, None) => interactive(&btree, settings).await.map(|_| Outcome::Found),
     };
+    let closed = btree.close().await;
+    let outcome = result?;
+    closed?;
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    match cli.parameter {
-        Some(parameter) => process_parameter(&btree, &parameter).await,
-        None => interactive(btree).await?,
+    #[tokio::test]
+    async fn it_changes_settings_in_scripts() {
+        let btree = Baildon::<String, String>::temporary(4)
+            .await
+            .expect("creates tree");
+        let mut settings = Settings {
+            display: DisplayMode::Utf8,
+            direction: Direction::Ascending,
+        };
+        let script = "# an init file\n\
+            set display hex\n\
+            SET direction Descending\n\
+            insert greeting \"hello world\"\n\
+            keys\n\
+            scan --limit 1\n";
+        run_script(&btree, script.as_bytes(), &mut settings)
+            .await
+            .expect("runs script");
+        assert!(matches!(settings.display, DisplayMode::Hex));
+        assert_eq!(settings.direction, Direction::Descending);
+        assert_eq!(
+            btree.get(&"greeting".to_string()).await.as_deref(),
+            Some("hello world")
+        );
+
+        // Invalid settings fail, and leave our settings unchanged
+        for line in ["set direction sideways", "set display", "set colour red"] {
+            assert!(run_script(&btree, line.as_bytes(), &mut settings)
+                .await
+                .is_err());
+        }
+        assert!(matches!(settings.display, DisplayMode::Hex));
+        assert_eq!(settings.direction, Direction::Descending);
     }
-    Ok(())
 }
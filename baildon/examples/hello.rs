@@ -26,6 +26,9 @@ async fn main() -> Result<()> {
     assert!(!tree.contains(&key).await);
     assert_eq!(tree.get(&key).await, None);
 
+    // Close our tree, flushing it to disk
+    tree.close().await?;
+
     std::fs::remove_file("hello.db")?;
     Ok(())
 }
@@ -17,6 +17,9 @@ async fn main() -> Result<()> {
     assert!(tree.contains(&"something".to_string()).await);
     assert_eq!(tree.get(&"something".to_string()).await, Some(3));
 
+    // Close our tree, flushing it to disk
+    tree.close().await?;
+
     std::fs::remove_file("hello.db").expect("cleanup");
     Ok(())
 }
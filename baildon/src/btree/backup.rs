@@ -0,0 +1,428 @@
+//! Backups
+//!
+//! A tree may be backed up while it is in use. Our data file is copied while our file lock is
+//! held, so nothing is flushed during the copy, and then our WAL is copied. Our WAL contains
+//! every modification since our tree was opened, so when the backup is opened, replaying the
+//! copied WAL recovers the state of our tree at the end of the backup.
+//!
+//! Modifications continue during a backup, but reading nodes which aren't cached waits until our
+//! data file has been copied.
+//!
+//! Each backup is described by a manifest, with a `.manifest` extension, which records the size
+//! and checksum of each of its files. The manifest is written last, so a backup without a manifest
+//! is incomplete. Backups are restored from their manifest, which verifies every file first.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::baildon::Baildon;
+use super::baildon::BaildonError;
+use super::baildon::BaildonKey;
+use super::baildon::BaildonValue;
+use super::bloom::bloom_path;
+use crate::BINCODER;
+
+const MANIFEST_VERSION_1: u8 = 1;
+
+/// Files are checksummed in chunks of this many bytes.
+const CHECKSUM_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Progress of a backup.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BackupProgress {
+    /// Number of bytes copied.
+    pub bytes_copied: u64,
+    /// Number of bytes to copy. Our WAL is only included once our data file has been copied.
+    pub bytes_total: u64,
+}
+
+/// Describes the files of a backup.
+#[derive(Debug, Deserialize, Serialize)]
+struct BackupManifest {
+    version: u8,
+    data: BackupFile,
+    wal: Option<BackupFile>,
+}
+
+/// A file within a backup. Files are named relative to their manifest.
+#[derive(Debug, Deserialize, Serialize)]
+struct BackupFile {
+    name: String,
+    len: u64,
+    checksum: u64,
+}
+
+impl BackupFile {
+    async fn describe(path: &Path, name: &Path) -> Result<Self> {
+        let (len, checksum) = checksum_file(path).await?;
+        Ok(Self {
+            name: name
+                .file_name()
+                .ok_or_else(|| BaildonError::InvalidBackupTarget(name.into()))?
+                .to_string_lossy()
+                .to_string(),
+            len,
+            checksum,
+        })
+    }
+
+    /// Return the path of our file, if it matches its description.
+    async fn verify(&self, manifest: &Path) -> Result<PathBuf> {
+        let path = manifest.with_file_name(&self.name);
+        self.verify_copy(&path).await?;
+        Ok(path)
+    }
+
+    /// Check that a copy of our file matches its description.
+    async fn verify_copy(&self, path: &Path) -> Result<()> {
+        let (len, checksum) = checksum_file(path).await?;
+        if len != self.len || checksum != self.checksum {
+            return Err(
+                BaildonError::InvalidBackup(format!("{} is corrupt", path.display())).into(),
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<K, V> Baildon<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    /// Write a consistent copy of our tree to the target path, returning the number of bytes
+    /// copied. Any existing tree at the target is replaced.
+    ///
+    /// A manifest is written alongside the copy, with a `.manifest` extension, so that the backup
+    /// can be restored with [`Baildon::restore`].
+    pub async fn backup_to<P: AsRef<Path>>(&self, target: P) -> Result<u64> {
+        self.backup_to_with_progress(target, |_progress| ()).await
+    }
+
+    /// Write a consistent copy of our tree to the target path, reporting progress as it is
+    /// copied.
+    pub async fn backup_to_with_progress<P, F>(&self, target: P, mut progress: F) -> Result<u64>
+    where
+        P: AsRef<Path>,
+        F: FnMut(BackupProgress),
+    {
+        let target = target.as_ref();
+        if target == self.path {
+            return Err(BaildonError::InvalidBackupTarget(target.into()).into());
+        }
+        let mut data_tmp = target.to_path_buf();
+        data_tmp.set_extension("backup");
+        let mut wal_tmp = target.to_path_buf();
+        wal_tmp.set_extension("wal.backup");
+
+        let result = async {
+            let copied = self
+                .write_backup(&data_tmp, &wal_tmp, &mut progress)
+                .await?;
+            let manifest = describe_backup(target, &data_tmp, &wal_tmp).await?;
+            Ok::<_, anyhow::Error>((copied, manifest))
+        }
+        .await;
+        let (copied, manifest) = match result {
+            Ok(described) => described,
+            Err(e) => {
+                for path in [&data_tmp, &wal_tmp] {
+                    let _ = tokio::fs::remove_file(path).await;
+                }
+                return Err(e);
+            }
+        };
+
+        let manifest_path = target.with_extension("manifest");
+        remove_if_present(&manifest_path).await?;
+        let target_wal = replace_tree(target).await?;
+        if tokio::fs::try_exists(&wal_tmp).await? {
+            tokio::fs::rename(&wal_tmp, &target_wal).await?;
+        }
+        tokio::fs::rename(&data_tmp, target).await?;
+        // Our backup is only complete once its manifest exists
+        tokio::fs::write(&manifest_path, BINCODER.serialize(&manifest)?).await?;
+        tracing::info!(copied, target = %target.display(), "backed up B+Tree");
+        Ok(copied)
+    }
+
+    /// Restore the backup described by a manifest to the target path, returning the number of
+    /// bytes restored. Any existing tree at the target is replaced.
+    ///
+    /// Every file is verified before anything is restored, and each copy is verified before it
+    /// replaces the target. The backed up WAL is replayed when the restored tree is opened.
+    pub async fn restore<P: AsRef<Path>, Q: AsRef<Path>>(manifest: P, target: Q) -> Result<u64> {
+        let manifest_path = manifest.as_ref();
+        let target = target.as_ref();
+        let manifest: BackupManifest = BINCODER
+            .deserialize(&tokio::fs::read(manifest_path).await?)
+            .map_err(|e| BaildonError::InvalidBackup(e.to_string()))?;
+        if manifest.version != MANIFEST_VERSION_1 {
+            return Err(BaildonError::InvalidBackup(format!(
+                "unsupported manifest version: {}",
+                manifest.version
+            ))
+            .into());
+        }
+        let data = manifest.data.verify(manifest_path).await?;
+        if data == target {
+            return Err(BaildonError::InvalidBackupTarget(target.into()).into());
+        }
+        let wal = match &manifest.wal {
+            Some(wal) => Some(wal.verify(manifest_path).await?),
+            None => None,
+        };
+
+        let mut data_tmp = target.to_path_buf();
+        data_tmp.set_extension("restore");
+        let mut restored = tokio::fs::copy(&data, &data_tmp).await?;
+        if let Err(e) = manifest.data.verify_copy(&data_tmp).await {
+            let _ = tokio::fs::remove_file(&data_tmp).await;
+            return Err(e);
+        }
+        let target_wal = replace_tree(target).await?;
+        if let (Some(wal), Some(description)) = (wal, &manifest.wal) {
+            restored += tokio::fs::copy(&wal, &target_wal).await?;
+            if let Err(e) = description.verify_copy(&target_wal).await {
+                for path in [&data_tmp, &target_wal] {
+                    let _ = tokio::fs::remove_file(path).await;
+                }
+                return Err(e);
+            }
+        }
+        tokio::fs::rename(&data_tmp, target).await?;
+        tracing::info!(restored, target = %target.display(), "restored B+Tree");
+        Ok(restored)
+    }
+
+    async fn write_backup<F>(
+        &self,
+        data_path: &Path,
+        wal_path: &Path,
+        progress: &mut F,
+    ) -> Result<u64>
+    where
+        F: FnMut(BackupProgress),
+    {
+        let mut data = File::create(data_path).await?;
+        let mut copied = {
+            // Hold our file lock, so that nothing is flushed while we copy
+            let mut file_lock = self.file.lock().await;
+            file_lock
+                .copy_to(&mut data, |bytes_copied, bytes_total| {
+                    progress(BackupProgress {
+                        bytes_copied,
+                        bytes_total,
+                    })
+                })
+                .await?
+        };
+        data.sync_all().await?;
+
+        // Read only trees don't have a WAL
+        let s_wal = match self.wal.lock().await.as_mut() {
+            Some(wal) => wal.read_all().await?,
+            None => vec![],
+        };
+        if !s_wal.is_empty() {
+            let mut wal = File::create(wal_path).await?;
+            wal.write_all(&s_wal).await?;
+            wal.sync_all().await?;
+            copied += s_wal.len() as u64;
+            progress(BackupProgress {
+                bytes_copied: copied,
+                bytes_total: copied,
+            });
+        }
+        Ok(copied)
+    }
+}
+
+/// Describe the files of a backup which will be moved to the target path.
+async fn describe_backup(target: &Path, data: &Path, wal: &Path) -> Result<BackupManifest> {
+    let wal = if tokio::fs::try_exists(wal).await? {
+        let mut target_wal = target.to_path_buf();
+        target_wal.set_extension("wal");
+        Some(BackupFile::describe(wal, &target_wal).await?)
+    } else {
+        None
+    };
+    Ok(BackupManifest {
+        version: MANIFEST_VERSION_1,
+        data: BackupFile::describe(data, target).await?,
+        wal,
+    })
+}
+
+/// Remove any existing tree at the target path, returning the path of its WAL. Trees are removed
+/// first, so that a WAL is never replayed over another data file.
+async fn replace_tree(target: &Path) -> Result<PathBuf> {
+    let mut target_wal = target.to_path_buf();
+    target_wal.set_extension("wal");
+    for path in [target, &target_wal, &bloom_path(target)] {
+        remove_if_present(path).await?;
+    }
+    Ok(target_wal)
+}
+
+/// Return the length and (FNV-1a) checksum of a file.
+async fn checksum_file(path: &Path) -> Result<(u64, u64)> {
+    let mut file = File::open(path).await?;
+    let mut buf = vec![0; CHECKSUM_CHUNK_SIZE];
+    let mut len = 0;
+    let mut checksum = 0xcbf2_9ce4_8422_2325_u64;
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            return Ok((len, checksum));
+        }
+        len += read as u64;
+        checksum = buf[..read].iter().fold(checksum, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+    }
+}
+
+async fn remove_if_present(path: &Path) -> Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_backs_up_tree() {
+        let tree = Baildon::<usize, usize>::temporary(5)
+            .await
+            .expect("creates tree file");
+        for i in 0..100 {
+            tree.insert(i, i).await.expect("insert worked");
+        }
+        tree.flush_to_disk().await.expect("flushes tree");
+        // Modifications which haven't been flushed are recovered from the copied WAL
+        for i in 100..200 {
+            tree.insert(i, i).await.expect("insert worked");
+        }
+        for i in 0..50 {
+            tree.delete(&i).await.expect("delete worked");
+        }
+
+        let mut reports = vec![];
+        let copied = tree
+            .backup_to_with_progress("backup_tree.db", |progress| reports.push(progress))
+            .await
+            .expect("backs up tree");
+        let last = reports.last().expect("reports progress");
+        assert_eq!(last.bytes_copied, copied);
+        assert_eq!(last.bytes_total, copied);
+        assert!(reports
+            .windows(2)
+            .all(|w| w[0].bytes_copied < w[1].bytes_copied));
+
+        let backup = Baildon::<usize, usize>::try_open("backup_tree.db")
+            .await
+            .expect("opens backup");
+        assert_eq!(backup.to_btreemap().await, tree.to_btreemap().await);
+        backup.close().await.expect("closes backup");
+
+        let err = tree
+            .backup_to(&tree.path)
+            .await
+            .err()
+            .expect("can't back up over our tree");
+        assert!(matches!(
+            err.downcast_ref::<BaildonError>(),
+            Some(BaildonError::InvalidBackupTarget(_))
+        ));
+        std::fs::remove_file("backup_tree.db").expect("cleanup");
+        std::fs::remove_file("backup_tree.manifest").expect("cleanup");
+    }
+
+    #[tokio::test]
+    async fn it_restores_backup() {
+        let tree = Baildon::<usize, usize>::temporary(5)
+            .await
+            .expect("creates tree file");
+        for i in 0..100 {
+            tree.insert(i, i).await.expect("insert worked");
+        }
+        tree.backup_to("restore_backup.db")
+            .await
+            .expect("backs up tree");
+
+        let restored = Baildon::<usize, usize>::restore("restore_backup.manifest", "restored.db")
+            .await
+            .expect("restores backup");
+        assert!(restored > 0);
+        let target = Baildon::<usize, usize>::try_open("restored.db")
+            .await
+            .expect("opens restored tree");
+        assert_eq!(target.to_btreemap().await, tree.to_btreemap().await);
+        target.close().await.expect("closes restored tree");
+
+        // Corrupt backups aren't restored
+        let mut data = std::fs::read("restore_backup.db").expect("reads backup");
+        data[0] ^= 0xff;
+        std::fs::write("restore_backup.db", data).expect("corrupts backup");
+        let err = Baildon::<usize, usize>::restore("restore_backup.manifest", "restored.db")
+            .await
+            .err()
+            .expect("backup is corrupt");
+        assert!(matches!(
+            err.downcast_ref::<BaildonError>(),
+            Some(BaildonError::InvalidBackup(_))
+        ));
+        for path in [
+            "restore_backup.db",
+            "restore_backup.wal",
+            "restore_backup.manifest",
+            "restored.db",
+        ] {
+            std::fs::remove_file(path).expect("cleanup");
+        }
+    }
+
+    #[tokio::test]
+    async fn it_backs_up_renames_of_overwritten_keys() {
+        let tree = Baildon::<usize, usize>::temporary(5)
+            .await
+            .expect("creates tree file");
+        tree.insert(1, 0).await.expect("insert worked");
+        tree.rename(&1, 2, false).await.expect("renames");
+        // Our WAL still holds the rename, which is replayed over a newer data file
+        tree.flush_to_disk().await.expect("flushes tree");
+        tree.insert(1, 5).await.expect("insert worked");
+        tree.backup_to("rename_backup.db")
+            .await
+            .expect("backs up tree");
+
+        Baildon::<usize, usize>::restore("rename_backup.manifest", "rename_restored.db")
+            .await
+            .expect("restores backup");
+        let target = Baildon::<usize, usize>::try_open("rename_restored.db")
+            .await
+            .expect("opens restored tree");
+        assert_eq!(target.get(&1).await, Some(5));
+        assert_eq!(target.get(&2).await, Some(0));
+        assert_eq!(target.to_btreemap().await, tree.to_btreemap().await);
+        target.close().await.expect("closes restored tree");
+        for path in [
+            "rename_backup.db",
+            "rename_backup.wal",
+            "rename_backup.manifest",
+            "rename_restored.db",
+        ] {
+            std::fs::remove_file(path).expect("cleanup");
+        }
+    }
+}
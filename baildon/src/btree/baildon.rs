@@ -3,28 +3,39 @@
 //! This is the main data structure exposed by the library.
 //!
 
-use std::collections::HashMap;
 use std::fmt::Display;
+use std::future::Future;
 use std::io::ErrorKind;
 use std::ops::ControlFlow;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::Ordering;
 use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
+use bincode::Options;
 use futures::StreamExt;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use strum::EnumString;
 use thiserror::Error;
 use tokio::io;
-use tokio::sync::{Mutex, MutexGuard};
-
+use tokio::sync::{Mutex, MutexGuard, RwLock};
+use tracing::field::Empty;
+use tracing::{Instrument, Span};
+
+use super::bloom::{bloom_path, remove_bloom_file, BloomFilter};
+use super::cache::NodeCache;
+use super::compaction::CompactionState;
+use super::crash::{CrashPoint, CrashPoints};
 use super::node::Node;
-use super::sparse::BuildIdentityHasher;
+use super::order::NATURAL_ORDER;
 use crate::command::Command;
-use crate::io::file::BTreeFile;
+use crate::io::file::{BTreeFile, BTreeFileError};
 use crate::io::wal::WalFile;
+use crate::metrics::MetricsRecorder;
+use crate::BINCODER;
 
 /// When accessing tree contents serially, ascending or descending order.
 #[derive(Clone, Copy, Debug, EnumString, PartialEq)]
@@ -36,8 +47,68 @@ pub enum Direction {
     Descending,
 }
 
+impl Direction {
+    /// Return the opposite direction.
+    pub fn reverse(self) -> Self {
+        match self {
+            Direction::Ascending => Direction::Descending,
+            Direction::Descending => Direction::Ascending,
+        }
+    }
+}
+
+/// When streaming tree contents, whether leaf nodes read from disk are added to the node cache.
+///
+/// Large analytical scans can use `Bypass` to avoid evicting the operational working set.
+#[derive(Clone, Copy, Debug, Default, EnumString, PartialEq)]
+#[strum(ascii_case_insensitive)]
+pub enum CachePolicy {
+    /// Add leaf nodes read from disk to the node cache.
+    #[default]
+    Populate,
+    /// Don't add leaf nodes read from disk to the node cache.
+    Bypass,
+}
+
+/// When opening a tree, what to do with a WAL left behind by a failed writer.
+#[derive(Clone, Copy, Debug, Default, EnumString, PartialEq)]
+#[strum(ascii_case_insensitive)]
+pub enum Recovery {
+    /// Replay the WAL, recovering any modifications which weren't flushed.
+    #[default]
+    ReplayWal,
+    /// Don't replay the WAL. A writable tree moves the WAL aside, with a `.quarantine`
+    /// extension, so it is preserved for inspection.
+    IgnoreWal,
+    /// Fail with [`BaildonError::WalPresent`] if there is a WAL.
+    FailIfWalPresent,
+}
+
+/// Limits on the serialized size of keys and values which may be inserted into a tree.
+///
+/// Use `usize::MAX` to remove a limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SizeLimits {
+    /// Maximum serialized size of a key, in bytes.
+    pub max_key_size: usize,
+    /// Maximum serialized size of a value, in bytes.
+    pub max_value_size: usize,
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        Self {
+            max_key_size: 16 * 1024,
+            max_value_size: 1024 * 1024,
+        }
+    }
+}
+
 const BAILDON_FILE_SIZE: u64 = 512_000;
 
+// Distinguishes temporary trees created by the same process
+static TEMPORARY_ID: AtomicUsize = AtomicUsize::new(0);
+
 /// Keys which we wish to store in a Baildon tree.
 pub trait BaildonKey: Clone + Ord + Serialize + DeserializeOwned + std::fmt::Debug {}
 
@@ -76,6 +147,82 @@ pub enum BaildonError {
     /// Could not find a node's parent
     #[error("could not find parent for node with index: {0}")]
     LostParent(usize),
+
+    /// Store is locked by another process
+    #[error("store: {0} is locked by another process")]
+    Locked(PathBuf),
+
+    /// Store was opened read only
+    #[error("store: {0} was opened read only")]
+    ReadOnly(PathBuf),
+
+    /// Store was updated by another process since it was last read
+    #[error("store: {0} was updated by another process")]
+    Stale(PathBuf),
+
+    /// Store has a WAL which would need to be replayed
+    #[error("store has a WAL: {0}")]
+    WalPresent(PathBuf),
+
+    /// Store has no recoverable data
+    #[error("store: {0} has no recoverable data")]
+    Unrecoverable(PathBuf),
+
+    /// Serialized key is larger than our limit
+    #[error("key size: {0} exceeds limit: {1}")]
+    KeyTooLarge(usize, usize),
+
+    /// Serialized value is larger than our limit
+    #[error("value size: {0} exceeds limit: {1}")]
+    ValueTooLarge(usize, usize),
+
+    /// Write queue is no longer processing writes
+    #[error("write queue closed")]
+    QueueClosed,
+
+    /// Store was created with a different ordering of keys
+    #[error("store: {0} was created with a different key ordering")]
+    KeyOrderMismatch(PathBuf),
+
+    /// Stored value can't be deserialized
+    #[error("corrupt value: {0}")]
+    CorruptValue(String),
+
+    /// Snapshot can't be imported
+    #[error("invalid snapshot: {0}")]
+    InvalidSnapshot(String),
+
+    /// A crash point was triggered
+    #[error("crashed at: {0}")]
+    Crashed(CrashPoint),
+
+    /// Bulk loaded entries must be in strictly ascending order of key
+    #[error("entry: {0} is out of order")]
+    UnsortedEntries(usize),
+
+    /// A tree can't be backed up over itself
+    #[error("can't back up to: {0}")]
+    InvalidBackupTarget(PathBuf),
+
+    /// Backup can't be restored
+    #[error("invalid backup: {0}")]
+    InvalidBackup(String),
+
+    /// Record can't be imported
+    #[error("invalid record: {0}")]
+    InvalidRecord(String),
+
+    /// A tree can't be merged into itself
+    #[error("store: {0} can't be merged into itself")]
+    SelfMerge(PathBuf),
+
+    /// Key to be renamed doesn't exist
+    #[error("key: {0} not found")]
+    KeyNotFound(String),
+
+    /// Key would be replaced by a rename
+    #[error("key: {0} already exists")]
+    KeyExists(String),
 }
 
 /// A B+Tree.
@@ -85,13 +232,23 @@ where
     K: BaildonKey + Send + Sync,
     V: BaildonValue + Send + Sync,
 {
-    file: Mutex<BTreeFile>,
-    path: PathBuf,
-    root: Mutex<usize>,
-    pub(crate) nodes: Mutex<HashMap<usize, Node<K, V>, BuildIdentityHasher>>,
+    pub(crate) file: Mutex<BTreeFile>,
+    pub(crate) path: PathBuf,
+    pub(crate) root: Mutex<usize>,
+    pub(crate) nodes: RwLock<NodeCache<K, V>>,
     branch: u64,
     pub(crate) index: AtomicUsize,
-    wal: Mutex<WalFile>,
+    entries: AtomicUsize,
+    depth: AtomicUsize,
+    pub(crate) wal: Mutex<Option<WalFile>>,
+    read_only: bool,
+    limits: SizeLimits,
+    pub(crate) compaction: std::sync::Mutex<CompactionState>,
+    pub(crate) bloom: std::sync::RwLock<Option<BloomFilter>>,
+    pub(crate) crash_points: CrashPoints,
+    pub(crate) metrics: MetricsRecorder,
+    temporary: bool,
+    closed: AtomicBool,
 }
 
 impl<K, V> Baildon<K, V>
@@ -101,14 +258,20 @@ where
 {
     /// Create a new store at the specified path with the specified branching factor.
     pub async fn try_new<P: AsRef<Path>>(origin: P, branch: u64) -> Result<Self> {
+        Self::create(origin.as_ref(), branch, NATURAL_ORDER).await
+    }
+
+    pub(crate) async fn create(path: &Path, branch: u64, key_order: u64) -> Result<Self> {
         if branch < 2 {
             return Err(BaildonError::BranchTooSmall(branch).into());
         }
-        let path: &Path = origin.as_ref();
 
         tracing::info!("Creating B+Tree at: {}", path.display());
 
-        let mut file = BTreeFile::try_new(path, BAILDON_FILE_SIZE).await?;
+        let mut file = BTreeFile::try_new(path, BAILDON_FILE_SIZE)
+            .await
+            .map_err(|e| locked_error(path, e))?;
+        file.set_key_order(key_order);
 
         let root = Node::<K, V>::root(branch);
 
@@ -116,142 +279,470 @@ where
 
         file.write_data(1, &s_root).await?;
 
-        let mut nodes: HashMap<_, _, BuildIdentityHasher> = HashMap::default();
+        let nodes = NodeCache::new();
         nodes.insert(1, root);
 
         // If we can't create a new WalFile, we should fail because we might be trying to create a
         // store over a failed WAL. That will require manual clean up first.
         let mut wal_path = PathBuf::new();
-        wal_path.push(origin.as_ref());
+        wal_path.push(path);
         wal_path.set_extension("wal");
         let wal = WalFile::try_new(&wal_path).await?;
+        // Any existing Bloom filter belongs to a previous store
+        remove_bloom_file(path).await?;
 
         let this = Self {
             file: Mutex::new(file),
             path: path.into(),
             root: Mutex::new(1),
-            nodes: Mutex::new(nodes),
+            nodes: RwLock::new(nodes),
             branch,
             index: AtomicUsize::new(2),
-            wal: Mutex::new(wal),
+            entries: AtomicUsize::new(0),
+            depth: AtomicUsize::new(1),
+            wal: Mutex::new(Some(wal)),
+            read_only: false,
+            compaction: std::sync::Mutex::new(CompactionState::default()),
+            bloom: std::sync::RwLock::new(None),
+            limits: SizeLimits::default(),
+            crash_points: CrashPoints::default(),
+            metrics: MetricsRecorder::default(),
+            temporary: false,
+            closed: AtomicBool::new(false),
         };
         this.inner_flush_to_disk(false).await?;
         Ok(this)
     }
 
+    /// Create a new store, with the specified branching factor, in the temporary directory.
+    ///
+    /// The store's files are removed when the tree is closed or dropped, so the tree doesn't need
+    /// to be closed.
+    pub async fn temporary(branch: u64) -> Result<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos());
+        let path = std::env::temp_dir().join(format!(
+            "baildon-{}-{}-{nanos}.db",
+            std::process::id(),
+            TEMPORARY_ID.fetch_add(1, Ordering::SeqCst)
+        ));
+        let mut this = Self::try_new(&path, branch).await?;
+        this.temporary = true;
+        Ok(this)
+    }
+
     /// Open an exisiting store at the specified path.
+    ///
+    /// The store is locked, so no other process may open it for writing while it is open.
     pub async fn try_open<P: AsRef<Path>>(origin: P) -> Result<Self> {
-        let path: &Path = origin.as_ref();
+        Self::open(origin.as_ref(), false, Recovery::ReplayWal, NATURAL_ORDER).await
+    }
+
+    /// Open an exisiting store at the specified path, choosing what to do with any WAL.
+    pub async fn try_open_with_recovery<P: AsRef<Path>>(
+        origin: P,
+        recovery: Recovery,
+    ) -> Result<Self> {
+        Self::open(origin.as_ref(), false, recovery, NATURAL_ORDER).await
+    }
+
+    /// Open an existing store at the specified path for read only access.
+    ///
+    /// Any number of processes may open a store read only at the same time, including while
+    /// another process has the store open for writing. Modifications return
+    /// [`BaildonError::ReadOnly`].
+    ///
+    /// Modifications made by the writer are picked up when the tree is refreshed. Searches refresh
+    /// automatically, but traversals and streams only refresh before they start, so call
+    /// [`Baildon::refresh`] to be sure of seeing the writer's latest changes.
+    ///
+    /// If a WAL is present, it is replayed in memory but the store is left unmodified.
+    pub async fn try_open_shared<P: AsRef<Path>>(origin: P) -> Result<Self> {
+        Self::open(origin.as_ref(), true, Recovery::ReplayWal, NATURAL_ORDER).await
+    }
+
+    /// Open an existing store at the specified path, or create a new store with the specified
+    /// branching factor if the store doesn't exist.
+    pub async fn open_or_create<P: AsRef<Path>>(origin: P, branch: u64) -> Result<Self> {
+        Self::open_or_create_with_order(origin.as_ref(), branch, NATURAL_ORDER).await
+    }
 
-        tracing::info!("Opening B+Tree at: {}", path.display());
+    pub(crate) async fn open_or_create_with_order(
+        path: &Path,
+        branch: u64,
+        key_order: u64,
+    ) -> Result<Self> {
+        match Self::open(path, false, Recovery::ReplayWal, key_order).await {
+            Err(err)
+                if err
+                    .downcast_ref::<std::io::Error>()
+                    .is_some_and(|io_error| io_error.kind() == ErrorKind::NotFound) =>
+            {
+                Self::create(path, branch, key_order).await
+            }
+            result => result,
+        }
+    }
 
-        let mut file = BTreeFile::try_open(path).await?;
+    pub(crate) async fn open(
+        path: &Path,
+        read_only: bool,
+        recovery: Recovery,
+        key_order: u64,
+    ) -> Result<Self> {
+        tracing::info!(
+            read_only,
+            ?recovery,
+            "Opening B+Tree at: {}",
+            path.display()
+        );
+
+        let mut file = if read_only {
+            BTreeFile::try_open_read_only(path).await
+        } else {
+            BTreeFile::try_open(path).await
+        }
+        .map_err(|e| locked_error(path, e))?;
+
+        // Our keys would be out of order
+        if file.get_key_order() != key_order {
+            return Err(BaildonError::KeyOrderMismatch(path.into()).into());
+        }
 
         let index = AtomicUsize::new(file.get_tree_index().await);
+        let stats = file.get_count_and_depth();
 
-        let buf = file.read_data(file.get_root_index().await).await?;
-        let root: Node<K, V> = Node::<K, V>::deserialize(&buf)?;
+        let root = Self::read_root(&mut file).await?;
         let branch = root.branch();
 
-        let mut nodes: HashMap<_, _, BuildIdentityHasher> = HashMap::default();
+        let nodes = NodeCache::new();
         let idx = root.index();
         nodes.insert(root.index(), root);
 
-        // If we can open a WalFile, then we should replay it before allowing the open to complete
-        // If not, last shutdown was fine, so create a new WalFile
         let mut wal_path = PathBuf::new();
-        wal_path.push(origin.as_ref());
+        wal_path.push(path);
         wal_path.set_extension("wal");
-        let mut recover = false;
-        let wal = match WalFile::try_open(&wal_path).await {
-            Ok(wal) => {
-                recover = true;
-                wal
+        match recovery {
+            Recovery::ReplayWal => (),
+            Recovery::IgnoreWal => {
+                // Read only trees don't create a WAL, so the WAL can stay where it is
+                if !read_only {
+                    Self::quarantine_wal(&wal_path).await?;
+                }
             }
-            Err(err) => {
-                // If the error is NotFound, we can ignore the error since this is the happy path
-                if let Some(io_error) = err.downcast_ref::<std::io::Error>() {
-                    if io_error.kind() != ErrorKind::NotFound {
-                        return Err(err);
-                    }
-                } else {
-                    return Err(err);
+            Recovery::FailIfWalPresent => {
+                if tokio::fs::try_exists(&wal_path).await? {
+                    return Err(BaildonError::WalPresent(wal_path).into());
                 }
-                WalFile::try_new(&wal_path).await?
             }
-        };
+        }
 
         let this = Self {
             file: Mutex::new(file),
             path: path.into(),
             root: Mutex::new(idx),
-            nodes: Mutex::new(nodes),
+            nodes: RwLock::new(nodes),
             branch,
             index,
-            wal: Mutex::new(wal),
+            entries: AtomicUsize::new(0),
+            depth: AtomicUsize::new(1),
+            wal: Mutex::new(None),
+            read_only,
+            compaction: std::sync::Mutex::new(CompactionState::default()),
+            bloom: std::sync::RwLock::new(None),
+            limits: SizeLimits::default(),
+            crash_points: CrashPoints::default(),
+            metrics: MetricsRecorder::default(),
+            temporary: false,
+            closed: AtomicBool::new(false),
         };
 
-        if recover {
-            let mut wal = this.wal.lock().await;
+        // Older files, or files which were being updated when the writer failed, don't record
+        // their count or depth, so we have to measure them
+        match stats {
+            Some((count, depth)) => this.set_stats(count, depth),
+            None => {
+                let (count, depth) = this.measure_stats().await?;
+                this.set_stats(count, depth);
+            }
+        }
+
+        // Files must be upgraded before any modifications are written
+        if !read_only && this.file.lock().await.needs_upgrade() {
+            this.upgrade_file().await?;
+        }
 
-            // Process wal file
-            tracing::info!("Recovering from wal...");
-            loop {
-                match wal.read_data().await {
-                    Ok(data) => {
-                        let cmd: Command<K, V> = Command::deserialize(&data)?;
-                        match cmd {
-                            Command::Upsert(key, value) => {
-                                // We don't care about the updated value, so ignore the
-                                // function result
-                                let _ = this.inner_insert(key, value).await;
-                            }
-                            Command::Delete(key) => {
-                                // We don't care about the deleted value, so ignore the
-                                // function result
-                                let _ = this.inner_delete(&key).await;
-                            }
+        // Our filter must be in place before any keys are inserted by replaying our WAL
+        if !read_only {
+            this.read_bloom_filter().await?;
+        }
+
+        // If we can open a WalFile, then we should replay it before allowing the open to complete
+        // If not, last shutdown was fine, so create a new WalFile
+        let recovered = recovery == Recovery::ReplayWal && this.recover(&wal_path).await?;
+
+        // Read only trees never write to a WAL
+        if !read_only {
+            if recovered {
+                std::fs::remove_file(&wal_path)?;
+            }
+            *this.wal.lock().await = Some(WalFile::try_new(&wal_path).await?);
+        }
+        Ok(this)
+    }
+
+    /// Move a WAL aside, so that it isn't replayed, but can still be inspected.
+    async fn quarantine_wal(wal_path: &Path) -> Result<()> {
+        if !tokio::fs::try_exists(wal_path).await? {
+            return Ok(());
+        }
+        let mut quarantine_path = wal_path.to_path_buf();
+        quarantine_path.set_extension("wal.quarantine");
+        // Never overwrite an earlier quarantined WAL
+        if tokio::fs::try_exists(&quarantine_path).await? {
+            return Err(std::io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} already exists", quarantine_path.display()),
+            )
+            .into());
+        }
+        tracing::warn!(
+            "Ignoring WAL, moving {} to {}",
+            wal_path.display(),
+            quarantine_path.display()
+        );
+        tokio::fs::rename(wal_path, &quarantine_path).await?;
+        Ok(())
+    }
+
+    async fn read_root(file: &mut BTreeFile) -> Result<Node<K, V>> {
+        let buf = file.read_data(file.get_root_index().await).await?;
+        Self::decode_node(file, &buf)
+    }
+
+    /// Deserialize a node, using the layout of the file it was read from.
+    fn decode_node(file: &BTreeFile, buf: &[u8]) -> Result<Node<K, V>> {
+        if file.has_leaf_links() {
+            Node::<K, V>::deserialize(buf)
+        } else {
+            Node::<K, V>::deserialize_unlinked(buf)
+        }
+    }
+
+    /// Upgrade a file written by an earlier version, so that earlier versions won't try to read
+    /// the nodes we write.
+    ///
+    /// Leaves are rewritten with lazy values as they are modified, so only files which don't link
+    /// their leaves have to rewrite their nodes.
+    async fn upgrade_file(&self) -> Result<()> {
+        if !self.file.lock().await.has_leaf_links() {
+            self.link_leaves().await?;
+        }
+        self.file.lock().await.upgrade();
+        self.inner_flush_to_disk(false).await
+    }
+
+    /// Link every leaf to its siblings, so that files written before leaves were linked can be
+    /// streamed by following links.
+    ///
+    /// Every leaf is loaded into our cache until they are flushed, so this is only done once.
+    async fn link_leaves(&self) -> Result<()> {
+        tracing::info!("Linking leaf nodes of: {}", self.path.display());
+        {
+            let nodes_lock = self.nodes.read().await;
+            let mut leaves = vec![];
+            let mut pending = vec![*self.root.lock().await];
+            while let Some(idx) = pending.pop() {
+                let node = self.find_node_with_lock(&nodes_lock, idx).await?;
+                if node.is_leaf() {
+                    leaves.push(idx);
+                } else {
+                    // Push in reverse, so that leaves are visited in ascending order
+                    pending.extend(node.children().rev());
+                }
+            }
+            for (position, idx) in leaves.iter().enumerate() {
+                let prev = position.checked_sub(1).map(|prev| leaves[prev]);
+                let next = leaves.get(position + 1).copied();
+                nodes_lock.update(*idx, |node| {
+                    node.set_sibling(Direction::Descending, prev);
+                    node.set_sibling(Direction::Ascending, next);
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Replay the WAL at the specified path, if there is one.
+    ///
+    /// Returns true if a WAL was replayed.
+    async fn recover(&self, wal_path: &Path) -> Result<bool> {
+        let mut wal = match WalFile::try_open(wal_path).await {
+            Ok(wal) => wal,
+            Err(err) => {
+                // If the error is NotFound, we can ignore the error since this is the happy path
+                if let Some(io_error) = err.downcast_ref::<std::io::Error>() {
+                    if io_error.kind() == ErrorKind::NotFound {
+                        return Ok(false);
+                    }
+                }
+                return Err(err);
+            }
+        };
+
+        // Process wal file
+        tracing::info!("Recovering from wal...");
+        loop {
+            match wal.read_data().await {
+                Ok(data) => {
+                    let cmd: Command<K, V> = Command::deserialize(&data)?;
+                    match cmd {
+                        Command::Upsert(key, value) => {
+                            // We don't care about the updated value, so ignore the
+                            // function result
+                            let _ = self.inner_insert(key, value).await;
+                        }
+                        Command::Delete(key) => {
+                            // We don't care about the deleted value, so ignore the
+                            // function result
+                            let _ = self.inner_delete(&key).await;
+                        }
+                        Command::Rename(from, to) => {
+                            let _ = self.inner_rename(&from, to).await;
+                        }
+                        Command::Move(from, to, value) => {
+                            let _ = self.inner_move(&from, to, value).await;
                         }
                     }
-                    Err(e) => {
-                        // XXX This is perhaps a bit sketchy...
-                        if let Some(down_e) = e.downcast_ref::<io::Error>() {
-                            if down_e.kind() == io::ErrorKind::UnexpectedEof {
-                                std::fs::remove_file(&wal_path)?;
-                                *wal = WalFile::try_new(&wal_path).await?;
-                                break;
-                            }
+                }
+                Err(e) => {
+                    // XXX This is perhaps a bit sketchy...
+                    if let Some(down_e) = e.downcast_ref::<io::Error>() {
+                        if down_e.kind() == io::ErrorKind::UnexpectedEof {
+                            break;
                         }
-                        tracing::info!("Recovering failed, data read error: {e:?}");
-                        return Err(e);
                     }
+                    tracing::info!("Recovering failed, data read error: {e:?}");
+                    return Err(e);
                 }
             }
-            tracing::info!("Recovered!");
         }
-        Ok(this)
+        tracing::info!("Recovered!");
+        Ok(true)
+    }
+
+    /// Re-read a read only tree, picking up any modifications flushed by the writer.
+    ///
+    /// Writable trees are always current, so this does nothing.
+    pub async fn refresh(&self) -> Result<()> {
+        if !self.read_only {
+            return Ok(());
+        }
+        let stats = {
+            let nodes_lock = self.nodes.write().await;
+            let mut file_lock = self.file.lock().await;
+
+            // The writer may have replaced our file (e.g.: compaction), so open it again
+            let mut file = BTreeFile::try_open_read_only(&self.path).await?;
+            let root = Self::read_root(&mut file).await?;
+            self.index
+                .store(file.get_tree_index().await, Ordering::SeqCst);
+            let stats = file.get_count_and_depth();
+            *self.root.lock().await = root.index();
+            nodes_lock.clear();
+            nodes_lock.insert(root.index(), root);
+            *file_lock = file;
+            stats
+        };
+        let (count, depth) = match stats {
+            Some(stats) => stats,
+            None => self.measure_stats().await?,
+        };
+        self.set_stats(count, depth);
+        let mut wal_path = self.path.clone();
+        wal_path.set_extension("wal");
+        self.recover(&wal_path).await?;
+        Ok(())
+    }
+
+    /// Refresh a read only tree if the writer has updated it.
+    async fn ensure_current(&self) -> Result<()> {
+        if !self.read_only {
+            return Ok(());
+        }
+        let current = self.file.lock().await.is_current().await?;
+        if !current {
+            self.refresh().await?;
+        }
+        Ok(())
+    }
+
+    /// Set the limits on the size of keys and values which may be inserted.
+    pub fn set_size_limits(&mut self, limits: SizeLimits) {
+        self.limits = limits;
+    }
+
+    /// Return the limits on the size of keys and values which may be inserted.
+    pub fn size_limits(&self) -> SizeLimits {
+        self.limits
+    }
+
+    /// Return our branching factor.
+    pub fn branch(&self) -> u64 {
+        self.branch
+    }
+
+    /// Was our tree opened read only?
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Return the path of our data file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Close our tree, flushing all our updated nodes to disk.
+    ///
+    /// Trees should always be closed. If a tree is dropped without being closed, any
+    /// modifications which weren't flushed will be recovered from the WAL when the tree is next
+    /// opened.
+    pub async fn close(self) -> Result<()> {
+        self.stop_compaction();
+        // Temporary trees are removed when dropped, so there's no point flushing them
+        if !self.read_only && !self.temporary {
+            self.flush_to_disk().await?;
+        }
+        self.closed.store(true, Ordering::SeqCst);
+        Ok(())
     }
 
     /// Clear our tree.
     pub async fn clear(&self) -> Result<()> {
+        self.check_writable()?;
         let mut file_lock = self.file.lock().await;
         file_lock.reset(BAILDON_FILE_SIZE).await?;
 
         // Can't fail from here
-        let mut nodes_lock = self.nodes.lock().await;
+        let nodes_lock = self.nodes.write().await;
         nodes_lock.clear();
         self.index.store(1, Ordering::SeqCst);
         let root = Node::<K, V>::root(self.branch);
-        self.add_node(&mut nodes_lock, root).await;
+        self.add_node(&nodes_lock, root).await;
         let mut root_lock = self.root.lock().await;
         *root_lock = 1;
+        self.set_stats(0, 1);
+        self.clear_bloom_filter();
         Ok(())
     }
 
     /// Does the tree contain this key?
     pub async fn contains(&self, key: &K) -> bool {
-        let mut nodes_lock = self.nodes.lock().await;
-        let node = match self.search_node_with_lock(&mut nodes_lock, key).await {
+        if !self.may_contain(key) {
+            return false;
+        }
+        let node = match self.search_node(key).await {
             Ok(v) => v,
             Err(_) => return false,
         };
@@ -260,29 +751,115 @@ where
 
     /// Return count of entries.
     pub async fn count(&self) -> usize {
-        let count = AtomicUsize::new(0);
-        let callback = |node: &Node<K, V>| {
-            count.fetch_add(node.len(), Ordering::SeqCst);
-            ControlFlow::Continue(())
-        };
-        self.traverse_leaf_nodes(Direction::Ascending, callback)
-            .await;
-        count.load(Ordering::SeqCst)
+        if let Err(e) = self.ensure_current().await {
+            tracing::error!("could not refresh tree: {e}");
+        }
+        self.entries.load(Ordering::SeqCst)
+    }
+
+    /// Return the depth of our tree. A tree which only has a root node has a depth of 1.
+    pub async fn depth(&self) -> usize {
+        if let Err(e) = self.ensure_current().await {
+            tracing::error!("could not refresh tree: {e}");
+        }
+        self.depth.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_stats(&self, count: usize, depth: usize) {
+        self.entries.store(count, Ordering::SeqCst);
+        self.depth.store(depth, Ordering::SeqCst);
     }
 
     /// Delete a Key and return an optional previous Value.
     pub async fn delete(&self, key: &K) -> Result<Option<V>, anyhow::Error> {
-        let cmd: Command<K, V> = Command::Delete(key.clone());
-        let s_cmd = cmd.serialize()?;
-        let mut wal_lock = self.wal.lock().await;
-        wal_lock.write_data(&s_cmd).await?;
+        let _timer = self.metrics.delete.time();
+        let span = tracing::debug_span!(
+            "baildon.delete",
+            key_size = Empty,
+            node = Empty,
+            duration_us = Empty
+        );
+        record_key_size(&span, key);
+        traced(span, self.logged_delete(key)).await
+    }
+
+    async fn logged_delete(&self, key: &K) -> Result<Option<V>> {
+        self.check_writable()?;
+        let s_cmd = Command::<K, V>::delete_ref(key)?;
+        let _wal_lock = self.append_to_wal(&s_cmd).await?;
+        self.crash_points.trigger(CrashPoint::AfterWalAppend)?;
         self.inner_delete(key).await
     }
 
+    /// Move the value of a key to a new key, returning the value.
+    ///
+    /// The move is a single WAL record, so after a crash it is recovered completely or not at
+    /// all. The record holds the moved value, so replaying it (e.g. when a backup is opened)
+    /// doesn't depend on the value `from` has by then. Fails if `from` doesn't exist, or if `to`
+    /// exists and `overwrite` is false.
+    pub async fn rename(&self, from: &K, to: K, overwrite: bool) -> Result<V> {
+        self.check_writable()?;
+        // Checked while our WAL is locked, so no other modification can intervene
+        let mut wal_lock = self.wal.lock().await;
+        let value = self
+            .lookup(from)
+            .await
+            .ok_or_else(|| BaildonError::KeyNotFound(format!("{from:?}")))?;
+        if from == &to {
+            return Ok(value);
+        }
+        if !overwrite && self.lookup(&to).await.is_some() {
+            return Err(BaildonError::KeyExists(format!("{to:?}")).into());
+        }
+        self.check_sizes(&to, &value)?;
+        let s_cmd = Command::move_ref(from, &to, &value)?;
+        self.write_to_wal(&mut wal_lock, &s_cmd).await?;
+        self.crash_points.trigger(CrashPoint::AfterWalAppend)?;
+        self.inner_move(from, to, value.clone()).await?;
+        Ok(value)
+    }
+
+    /// Move the value of a key, if it exists, to a new key. Only replayed from older WALs.
+    async fn inner_rename(&self, from: &K, to: K) -> Result<()> {
+        if let Some(value) = self.inner_delete(from).await? {
+            self.inner_insert(to, value).await;
+        }
+        Ok(())
+    }
+
+    /// Delete a key, and insert its moved value at a new key.
+    async fn inner_move(&self, from: &K, to: K, value: V) -> Result<()> {
+        self.inner_delete(from).await?;
+        self.inner_insert(to, value).await;
+        Ok(())
+    }
+
+    /// Append a command to our WAL. Our WAL stays locked until the returned guard is dropped, so
+    /// that commands are applied in the order they were logged.
+    async fn append_to_wal(&self, s_cmd: &[u8]) -> Result<MutexGuard<'_, Option<WalFile>>> {
+        let mut wal_lock = self.wal.lock().await;
+        self.write_to_wal(&mut wal_lock, s_cmd).await?;
+        Ok(wal_lock)
+    }
+
+    /// Append a command to our locked WAL.
+    async fn write_to_wal(&self, wal_lock: &mut Option<WalFile>, s_cmd: &[u8]) -> Result<()> {
+        let span = tracing::debug_span!("wal.append", bytes = s_cmd.len(), duration_us = Empty);
+        traced(span, async {
+            if let Some(wal) = wal_lock.as_mut() {
+                wal.write_data(s_cmd).await?;
+                MetricsRecorder::count(&self.metrics.wal_bytes, s_cmd.len() as u64);
+            }
+            Ok(())
+        })
+        .await
+    }
+
     async fn inner_delete(&self, key: &K) -> Result<Option<V>> {
-        let mut nodes_lock = self.nodes.lock().await;
+        let nodes_lock = self.nodes.write().await;
 
-        let mut node = self.search_node_with_lock(&mut nodes_lock, key).await?;
+        let mut node = Arc::unwrap_or_clone(self.search_node_with_lock(&nodes_lock, key).await?);
+        Span::current().record("node", node.index());
 
         // REMEMBER if search_node() finds a node, we still need to confirm
         // that our node contains the key we are looking for.
@@ -293,6 +870,11 @@ where
         // XXX: This will return None if the key can't be found. Arguably, that's not quite the correct
         // logic, but correct enough for now.
         let value = node.remove_value(key);
+        if value.is_some() {
+            self.entries.fetch_sub(1, Ordering::SeqCst);
+        }
+        // A corrupt value is still deleted
+        let value = value.and_then(|value| old_value(value, node.index()));
 
         loop {
             if !node.is_minimum() {
@@ -301,17 +883,13 @@ where
             // Process this node
             // Try to find a donor node
             let (neighbour_opt, direction) = match self
-                .neighbour_same_parent_with_lock(
-                    &mut nodes_lock,
-                    node.index(),
-                    Direction::Ascending,
-                )
+                .neighbour_same_parent_with_lock(&nodes_lock, node.index(), Direction::Ascending)
                 .await
             {
                 Some(n) => (Some(n), Direction::Ascending),
                 None => (
                     self.neighbour_same_parent_with_lock(
-                        &mut nodes_lock,
+                        &nodes_lock,
                         node.index(),
                         Direction::Descending,
                     )
@@ -320,7 +898,7 @@ where
                 ),
             };
             // Process this node
-            match neighbour_opt {
+            match neighbour_opt.map(Arc::unwrap_or_clone) {
                 Some(mut neighbour) => {
                     // If our neighbour isn't at minimum we can simply take a k/v pair
                     if !neighbour.is_minimum() {
@@ -355,17 +933,13 @@ where
                                     child.set_parent(Some(node.index()));
                                     None
                                 };
-                                self.update_node(&mut nodes_lock, child, closure).await;
+                                self.update_node(&nodes_lock, child, closure).await;
 
                                 // Update the parent:
-                                self.update_node(
-                                    &mut nodes_lock,
-                                    p_idx,
-                                    |parent: &mut Node<K, V>| {
-                                        parent.update_child_key(tgt_idx, k);
-                                        None
-                                    },
-                                )
+                                self.update_node(&nodes_lock, p_idx, |parent: &mut Node<K, V>| {
+                                    parent.update_child_key(tgt_idx, k);
+                                    None
+                                })
                                 .await;
                             }
                             Node::Leaf(data) => {
@@ -376,22 +950,18 @@ where
                                 };
 
                                 // Update our node
-                                node.set_value(&k, value);
+                                node.set_lazy_value(&k, value);
 
                                 // Update the parent:
-                                self.update_node(
-                                    &mut nodes_lock,
-                                    p_idx,
-                                    |parent: &mut Node<K, V>| {
-                                        parent.update_child_key(tgt_idx, k);
-                                        None
-                                    },
-                                )
+                                self.update_node(&nodes_lock, p_idx, |parent: &mut Node<K, V>| {
+                                    parent.update_child_key(tgt_idx, k);
+                                    None
+                                })
                                 .await;
                             }
                         }
                         // Replace our modified neighbour
-                        self.replace_node(&mut nodes_lock, neighbour);
+                        self.replace_node(&nodes_lock, neighbour);
                     } else {
                         // We need to merge our neighbour
                         assert_ne!(neighbour.index(), node.index());
@@ -406,9 +976,23 @@ where
                             };
                             for child in data.children() {
                                 let _ = self
-                                    .update_node(&mut nodes_lock, child, closure_update_parent)
+                                    .update_node(&nodes_lock, child, closure_update_parent)
+                                    .await;
+                            }
+                        }
+                        // Our neighbour is going away, so link past it
+                        if node.is_leaf() {
+                            let node_idx = node.index();
+                            let beyond = neighbour.sibling(direction);
+                            if let Some(beyond_idx) = beyond {
+                                let closure_relink = |beyond: &mut Node<K, V>| {
+                                    beyond.set_sibling(direction.reverse(), Some(node_idx));
+                                    None
+                                };
+                                self.update_node(&nodes_lock, beyond_idx, closure_relink)
                                     .await;
                             }
+                            node.set_sibling(direction, beyond);
                         }
                         // Capture various useful bits of data before the merge
                         let neighbour_idx = neighbour.index();
@@ -441,10 +1025,10 @@ where
                             .parent()
                             .ok_or(BaildonError::LostParent(node.index()))?;
                         let _ = self
-                            .update_node(&mut nodes_lock, p_idx, closure_cleanup_parent)
+                            .update_node(&nodes_lock, p_idx, closure_cleanup_parent)
                             .await;
                         // Remove the lost node
-                        nodes_lock.remove(&neighbour_idx);
+                        nodes_lock.remove(neighbour_idx);
                         // WE ARE VERY CAREFUL TO ONLY HOLD THE FILE LOCK BRIEFLY HERE
                         let mut file_lock = self.file.lock().await;
                         file_lock.free_data(neighbour_idx)?;
@@ -454,8 +1038,9 @@ where
                             let mut root_lock = self.root.lock().await;
                             *root_lock = node.index();
                             node.set_parent(None);
-                            nodes_lock.remove(&p_idx);
+                            nodes_lock.remove(p_idx);
                             file_lock.free_data(p_idx)?;
+                            self.depth.fetch_sub(1, Ordering::SeqCst);
                             break;
                         }
                     }
@@ -463,53 +1048,89 @@ where
                         .parent()
                         .ok_or(BaildonError::LostParent(node.index()))?;
                     // Replace our modified node
-                    self.replace_node(&mut nodes_lock, node);
+                    self.replace_node(&nodes_lock, node);
                     // Now, update our node for next loop
-                    node = self
-                        .find_node_with_lock(&mut nodes_lock, node_parent)
-                        .await?;
+                    node = Arc::unwrap_or_clone(
+                        self.find_node_with_lock(&nodes_lock, node_parent).await?,
+                    );
                 }
                 // If we don't have a neighbour, we can't have a parent, so job done
                 None => break,
             }
         }
         // Replace our modified node
-        self.replace_node(&mut nodes_lock, node);
+        self.replace_node(&nodes_lock, node);
         Ok(value)
     }
 
     /// Serialize and store all our updated nodes to disk.
     pub async fn flush_to_disk(&self) -> Result<()> {
+        self.check_writable()?;
+        self.refresh_full_bloom_filter().await?;
         self.inner_flush_to_disk(true).await
     }
 
     async fn inner_flush_to_disk(&self, remove_wal: bool) -> Result<()> {
-        let mut nodes_lock = self.nodes.lock().await;
+        let _timer = self.metrics.flush.time();
+        let span = tracing::debug_span!(
+            "baildon.flush",
+            nodes = Empty,
+            writes = Empty,
+            duration_us = Empty
+        );
+        traced(span, self.write_dirty_nodes(remove_wal)).await
+    }
+
+    async fn write_dirty_nodes(&self, remove_wal: bool) -> Result<()> {
+        let nodes_lock = self.nodes.write().await;
         let mut file_lock = self.file.lock().await;
+        let allocated = file_lock.blocks_allocated();
 
-        tracing::debug!("About to examine {} nodes", nodes_lock.len());
-        for node in nodes_lock.values_mut().filter(|n| !n.clean()) {
-            tracing::debug!("Storing node: {:?}", node);
+        let mut batch = vec![];
+        for node in nodes_lock.dirty_nodes() {
             // Update root offset if required.
             if node.parent().is_none() {
                 *self.root.lock().await = node.index();
-                // *root_lock = node.index();
             }
-            tracing::debug!("Storing dirty node {:?}", node);
-            node.set_clean(true);
-            let s_node = (*node).serialize()?;
-            file_lock.write_data(node.index(), &s_node).await?;
+            batch.push((node.index(), node.serialize()?));
+        }
+        Span::current().record("nodes", batch.len());
+        // Nodes in adjacent blocks are written together
+        let writes = file_lock.prepare_writes(batch).await?;
+        MetricsRecorder::count(
+            &self.metrics.blocks_allocated,
+            file_lock.blocks_allocated() - allocated,
+        );
+        Span::current().record("writes", writes.len());
+        for write in writes {
+            tracing::debug!(indices = ?write.indices(), "writing nodes");
+            file_lock.write_coalesced(&write).await?;
+            self.crash_at(&file_lock, CrashPoint::AfterNodeWrite)
+                .await?;
         }
+        self.crash_at(&file_lock, CrashPoint::BeforeFooterWrite)
+            .await?;
         // Update the file header
         let index = self.index.load(Ordering::SeqCst);
+        file_lock.set_count_and_depth(
+            self.entries.load(Ordering::SeqCst),
+            self.depth.load(Ordering::SeqCst),
+        );
         file_lock
             .write_header_with_indices(*self.root.lock().await, index)
             .await?;
 
-        tracing::debug!("Tree index: {}", self.index.load(Ordering::SeqCst));
         nodes_lock.clear();
 
         let result = file_lock.flush().await;
+        if result.is_ok() {
+            // A stale filter is rebuilt when our tree is next opened, so our tree is still usable
+            let generation = file_lock.get_generation();
+            let entries = self.entries.load(Ordering::SeqCst);
+            if let Err(e) = self.write_bloom_filter(generation, entries).await {
+                tracing::error!("Error when writing bloom filter: {e}");
+            }
+        }
         if result.is_ok() && remove_wal {
             let mut wal_path = self.path.clone();
             wal_path.set_extension("wal");
@@ -520,48 +1141,81 @@ where
         result
     }
 
+    /// Trigger a crash point during a flush.
+    async fn crash_at(&self, file: &BTreeFile, point: CrashPoint) -> Result<()> {
+        let result = self.crash_points.trigger(point);
+        if result.is_err() {
+            // Writes which completed before a crash would have reached the OS
+            file.flush().await?;
+        }
+        result
+    }
+
     /// Get the value.
     pub async fn get(&self, key: &K) -> Option<V> {
-        let mut nodes_lock = self.nodes.lock().await;
-        let node = self
-            .search_node_with_lock(&mut nodes_lock, key)
-            .await
-            .ok()?;
-        node.value(key)
+        let _timer = self.metrics.get.time();
+        let span = tracing::debug_span!(
+            "baildon.get",
+            key_size = Empty,
+            node = Empty,
+            duration_us = Empty
+        );
+        record_key_size(&span, key);
+        traced(span, self.lookup(key)).await
     }
 
-    /// Log basic information about our B+Tree.
-    pub async fn info(&self) {
-        tracing::info!(
-            path = %self.path.display(),
-            branching = self.branch,
-            node_count = self.count().await,
-            "B+Tree"
-        );
+    async fn lookup(&self, key: &K) -> Option<V> {
+        if !self.may_contain(key) {
+            return None;
+        }
+        let node = self.search_node(key).await.ok()?;
+        Span::current().record("node", node.index());
+        node.value(key)
+            .map_err(|e| tracing::error!("could not read value in node {}: {e}", node.index()))
+            .ok()?
     }
 
     /// Insert a Key and Value.
+    ///
+    /// Keys and values larger than our [`SizeLimits`] are rejected.
     pub async fn insert(&self, key: K, value: V) -> Result<Option<V>, anyhow::Error> {
-        let cmd = Command::Upsert(key.clone(), value.clone());
-        let s_cmd = cmd.serialize()?;
-        let mut wal_lock = self.wal.lock().await;
-        wal_lock.write_data(&s_cmd).await?;
+        let _timer = self.metrics.insert.time();
+        let span = tracing::debug_span!(
+            "baildon.insert",
+            key_size = Empty,
+            node = Empty,
+            duration_us = Empty
+        );
+        record_key_size(&span, &key);
+        traced(span, self.logged_insert(key, value)).await
+    }
+
+    async fn logged_insert(&self, key: K, value: V) -> Result<Option<V>> {
+        self.check_writable()?;
+        self.check_sizes(&key, &value)?;
+        let s_cmd = Command::upsert_ref(&key, &value)?;
+        let _wal_lock = self.append_to_wal(&s_cmd).await?;
+        self.crash_points.trigger(CrashPoint::AfterWalAppend)?;
         Ok(self.inner_insert(key, value).await)
     }
 
     /// Insert a Key and Value.
     async fn inner_insert(&self, mut key: K, value: V) -> Option<V> {
-        tracing::debug!("INSERTING: {:?}, {:?}", key, value);
-        let mut nodes_lock = self.nodes.lock().await;
+        let nodes_lock = self.nodes.write().await;
 
-        let mut node = self
-            .search_node_with_lock(&mut nodes_lock, &key)
-            .await
-            .ok()?;
+        let mut node =
+            Arc::unwrap_or_clone(self.search_node_with_lock(&nodes_lock, &key).await.ok()?);
+        Span::current().record("node", node.index());
 
         assert!(node.is_leaf());
 
         let value = node.set_value(&key, value);
+        if value.is_none() {
+            self.entries.fetch_add(1, Ordering::SeqCst);
+            self.add_to_bloom_filter(&key);
+        }
+        // A corrupt value is still replaced
+        let value = value.and_then(|value| old_value(value, node.index()));
 
         if node.is_full() {
             // Split the Node
@@ -569,7 +1223,16 @@ where
             key = node.max_key().clone();
             let mut new_key = new.max_key().clone();
             // Insert our new leaf node to the list of nodes
-            let mut new_idx = self.add_node(&mut nodes_lock, new).await;
+            let mut new_idx = self.add_node(&nodes_lock, new).await;
+            // Link our new leaf between our node and our node's old successor
+            if let Some(next_idx) = node.sibling(Direction::Ascending) {
+                self.update_node(&nodes_lock, next_idx, |next: &mut Node<K, V>| {
+                    next.set_sibling(Direction::Descending, Some(new_idx));
+                    None
+                })
+                .await;
+            }
+            node.set_sibling(Direction::Ascending, Some(new_idx));
             loop {
                 let p_opt = node.parent();
                 match p_opt {
@@ -577,11 +1240,12 @@ where
                         // Help the borrow check by ensuring tmp will drop
                         let tmp_idx = node.index();
                         // Sync out our node and get ready to loop
-                        self.replace_node(&mut nodes_lock, node);
+                        self.replace_node(&nodes_lock, node);
                         // Process this parent
-                        node = self
-                            .find_node_as_option_with_lock(&mut nodes_lock, p_idx)
-                            .await?;
+                        node = Arc::unwrap_or_clone(
+                            self.find_node_as_option_with_lock(&nodes_lock, p_idx)
+                                .await?,
+                        );
                         node.set_child(&key, tmp_idx);
                         node.set_child(&new_key, new_idx);
                         if node.is_full() {
@@ -590,7 +1254,7 @@ where
                             let new = node.split();
                             key = node.max_key().clone();
                             new_key = new.max_key().clone();
-                            new_idx = self.add_node(&mut nodes_lock, new).await;
+                            new_idx = self.add_node(&nodes_lock, new).await;
                         } else {
                             break;
                         }
@@ -598,14 +1262,14 @@ where
                     None => {
                         let keys = vec![key, new_key];
                         let children = vec![node.index(), new_idx];
-                        node.set_parent(Some(self.add_root(&mut nodes_lock, children, keys).await));
+                        node.set_parent(Some(self.add_root(&nodes_lock, children, keys).await));
                         break;
                     }
                 }
             }
         }
         // Finally, sync out our node and return our value
-        self.replace_node(&mut nodes_lock, node);
+        self.replace_node(&nodes_lock, node);
         value
     }
 
@@ -618,6 +1282,54 @@ where
         self.traverse_nodes(direction, callback).await
     }
 
+    /// Print to stdout an ASCII rendering of our tree, with the separator keys of each internal
+    /// node and the range of keys of each leaf.
+    ///
+    /// Leaves aren't added to our cache, so printing a large tree doesn't evict other nodes.
+    pub async fn print_tree(&self) -> Result<()> {
+        self.ensure_current().await?;
+        let nodes_lock = self.nodes.read().await;
+        // Each node is printed after its prefix, and its children after their prefix
+        let mut pending = vec![(*self.root.lock().await, String::new(), String::new())];
+        while let Some((idx, prefix, child_prefix)) = pending.pop() {
+            let node = self
+                .find_node_with_lock_and_policy(&nodes_lock, idx, CachePolicy::Bypass)
+                .await?;
+            if node.is_leaf() {
+                let mut keys = node.keys();
+                match (keys.next(), keys.next_back()) {
+                    (Some(first), last) => println!(
+                        "{prefix}leaf: {idx}: {first:?}..={:?} ({} entries)",
+                        last.unwrap_or(first),
+                        node.len()
+                    ),
+                    (None, _) => println!("{prefix}leaf: {idx}: empty"),
+                }
+            } else {
+                println!(
+                    "{prefix}node: {idx}: {:?}",
+                    node.keys().collect::<Vec<&K>>()
+                );
+                let children = node.children().collect::<Vec<usize>>();
+                let last = children.len().saturating_sub(1);
+                // Push in reverse, so that children are printed in ascending order
+                for (i, child) in children.into_iter().enumerate().rev() {
+                    let (branch, indent) = if i == last {
+                        ("`-- ", "    ")
+                    } else {
+                        ("+-- ", "|   ")
+                    };
+                    pending.push((
+                        child,
+                        format!("{child_prefix}{branch}"),
+                        format!("{child_prefix}{indent}"),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Traverse entries until stream exhausted or callback returns break.
     pub async fn traverse_entries(
         &self,
@@ -678,48 +1390,24 @@ where
         used.load(Ordering::SeqCst) as f64 / total.load(Ordering::SeqCst) as f64
     }
 
-    /// Verify all the nodes in the tree.
-    pub async fn verify(&self, direction: Direction) -> Result<()> {
-        let callback = |node: &Node<K, V>| {
-            let mut seen_keys: Vec<K> = vec![];
-            if node.is_leaf() {
-                for key in node.keys() {
-                    assert!(!seen_keys.contains(key));
-                }
-                seen_keys.extend(node.keys().cloned());
-            } else {
-                futures::executor::block_on(async {
-                    let mut nodes_lock = self.nodes.lock().await;
-                    for child in node.children() {
-                        let child = match self.find_node_with_lock(&mut nodes_lock, child).await {
-                            Ok(c) => c,
-                            Err(e) => {
-                                tracing::error!("could not find node: {e}");
-                                return ControlFlow::Break(());
-                            }
-                        };
-                        assert_eq!(Some(node.index()), child.parent());
-                    }
-                    ControlFlow::Continue(())
-                });
-            }
-            node.verify_keys();
-            ControlFlow::Continue(())
-        };
-        self.traverse_nodes(direction, callback).await;
-        Ok(())
-    }
-
     /// Return last key.
     #[allow(dead_code)]
     async fn last_key(&self) -> Option<K> {
-        self.last_leaf().await.keys().last().cloned()
+        self.last_leaf(CachePolicy::Populate)
+            .await
+            .keys()
+            .last()
+            .cloned()
     }
 
     /// Return first key.
     #[allow(dead_code)]
     async fn first_key(&self) -> Option<K> {
-        self.first_leaf().await.keys().next().cloned()
+        self.first_leaf(CachePolicy::Populate)
+            .await
+            .keys()
+            .next()
+            .cloned()
     }
 
     /// Traverse all nodes in a tree using the callback.
@@ -743,7 +1431,9 @@ where
         direction: Direction,
         mut f: impl FnMut(&Node<K, V>) -> ControlFlow<()>,
     ) {
-        let mut streamer = self.stream_all_leaf_nodes(direction).await;
+        let mut streamer = self
+            .stream_all_leaf_nodes(direction, CachePolicy::Populate)
+            .await;
         while let Some(leaf) = streamer.next().await {
             match f(&leaf) {
                 ControlFlow::Break(_) => break,
@@ -752,11 +1442,27 @@ where
         }
     }
 
-    async fn add_node(
-        &self,
-        nodes_lock: &mut MutexGuard<'_, HashMap<usize, Node<K, V>, BuildIdentityHasher>>,
-        mut node: Node<K, V>,
-    ) -> usize {
+    pub(crate) fn check_sizes(&self, key: &K, value: &V) -> Result<()> {
+        let key_size = BINCODER.serialized_size(key)? as usize;
+        if key_size > self.limits.max_key_size {
+            return Err(BaildonError::KeyTooLarge(key_size, self.limits.max_key_size).into());
+        }
+        let value_size = BINCODER.serialized_size(value)? as usize;
+        if value_size > self.limits.max_value_size {
+            return Err(BaildonError::ValueTooLarge(value_size, self.limits.max_value_size).into());
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            Err(BaildonError::ReadOnly(self.path.clone()).into())
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn add_node(&self, nodes_lock: &NodeCache<K, V>, mut node: Node<K, V>) -> usize {
         let idx = self.index.fetch_add(1, Ordering::SeqCst);
         node.set_index(idx);
         if let Node::Internal(data) = &node {
@@ -774,42 +1480,40 @@ where
 
     fn replace_node(
         &self,
-        nodes_lock: &mut MutexGuard<'_, HashMap<usize, Node<K, V>, BuildIdentityHasher>>,
+        nodes_lock: &NodeCache<K, V>,
         mut node: Node<K, V>,
-    ) -> Option<Node<K, V>> {
+    ) -> Option<Arc<Node<K, V>>> {
         node.set_clean(false);
         nodes_lock.insert(node.index(), node)
     }
 
     async fn update_node(
         &self,
-        nodes_lock: &mut MutexGuard<'_, HashMap<usize, Node<K, V>, BuildIdentityHasher>>,
+        nodes_lock: &NodeCache<K, V>,
         idx: usize,
         f: impl FnOnce(&mut Node<K, V>) -> Option<V>,
     ) -> Option<V> {
         // Add the node to our cache if it isn't already there
-        if nodes_lock.get(&idx).is_none() {
+        if !nodes_lock.contains(idx) {
             let node = self.read_node(idx).await.ok()?;
             nodes_lock.insert(idx, node);
         }
-        let node = nodes_lock.get_mut(&idx).unwrap();
-        tracing::debug!("Updating node: {:?}", node);
-        // Always mark an updated node as not clean
-        node.set_clean(false);
-        f(node)
+        nodes_lock
+            .update(idx, |node| {
+                // Always mark an updated node as not clean
+                node.set_clean(false);
+                f(node)
+            })
+            .flatten()
     }
 
-    async fn add_root<'a>(
+    async fn add_root(
         &self,
-        nodes_lock: &mut MutexGuard<'a, HashMap<usize, Node<K, V>, BuildIdentityHasher>>,
+        nodes_lock: &NodeCache<K, V>,
         children: Vec<usize>,
         keys: Vec<K>,
     ) -> usize {
-        tracing::debug!(
-            "Adding a new root: children: {:?}, keys: {:?}",
-            children,
-            keys
-        );
+        tracing::debug!(?children, "adding a new root");
         let root: Node<K, V> = Node::internal(self.branch, None, keys, children.clone());
         let root_idx = self.add_node(nodes_lock, root).await;
         let closure = |node: &mut Node<K, V>| {
@@ -820,9 +1524,54 @@ where
         self.update_node(nodes_lock, children[1], closure).await;
         let mut root_lock = self.root.lock().await;
         *root_lock = root_idx;
+        self.depth.fetch_add(1, Ordering::SeqCst);
         root_idx
     }
 
+    /// Measure the number of entries in, and depth of, our tree by visiting every node.
+    async fn measure_stats(&self) -> Result<(usize, usize)> {
+        let nodes_lock = self.nodes.read().await;
+        let mut count = 0;
+        let mut depth = 0;
+        let mut pending = vec![(*self.root.lock().await, 1)];
+        while let Some((idx, level)) = pending.pop() {
+            let node = self
+                .find_node_with_lock_and_policy(&nodes_lock, idx, CachePolicy::Bypass)
+                .await?;
+            if node.is_leaf() {
+                count += node.len();
+                depth = depth.max(level);
+            } else {
+                pending.extend(node.children().map(|child| (child, level + 1)));
+            }
+        }
+        Ok((count, depth))
+    }
+
+    /// Search our tree from the root for
+    ///  - a leaf node which may contain our key
+    ///
+    /// Only a read lock is required, so concurrent readers don't serialize. Readers may still add
+    /// nodes to our cache, since the nodes on disk can't change while the read lock is held.
+    ///
+    /// If a read only tree is updated by another process while we are searching, we refresh and
+    /// search again.
+    pub(crate) async fn search_node(&self, key: &K) -> Result<Arc<Node<K, V>>> {
+        self.ensure_current().await?;
+        loop {
+            let result = {
+                let nodes_lock = self.nodes.read().await;
+                self.search_node_with_lock(&nodes_lock, key).await
+            };
+            match result {
+                Err(e) if matches!(e.downcast_ref(), Some(BaildonError::Stale(_))) => {
+                    self.refresh().await?
+                }
+                result => return result,
+            }
+        }
+    }
+
     /// Search our tree from the root for
     ///  - a leaf node to which our key will be added
     ///
@@ -830,16 +1579,15 @@ where
     #[inline]
     async fn search_node_with_lock(
         &self,
-        nodes_lock: &'_ mut MutexGuard<'_, HashMap<usize, Node<K, V>, BuildIdentityHasher>>,
+        nodes_lock: &NodeCache<K, V>,
         key: &K,
-    ) -> Result<Node<K, V>> {
+    ) -> Result<Arc<Node<K, V>>> {
         let mut target_node = self
             .find_node_with_lock(nodes_lock, *self.root.lock().await)
             .await?;
         loop {
-            tracing::debug!("TARGET NODE: {:?}", target_node);
             if target_node.is_leaf() {
-                return Ok(target_node.clone());
+                return Ok(target_node);
             }
             let t_idx = target_node
                 .child(key)
@@ -851,9 +1599,9 @@ where
     /// Find a node from cache (or disk).
     pub(crate) async fn find_node_as_option_with_lock(
         &self,
-        nodes_lock: &'_ mut MutexGuard<'_, HashMap<usize, Node<K, V>, BuildIdentityHasher>>,
+        nodes_lock: &NodeCache<K, V>,
         idx: usize,
-    ) -> Option<Node<K, V>> {
+    ) -> Option<Arc<Node<K, V>>> {
         match self.find_node_with_lock(nodes_lock, idx).await {
             Ok(n) => Some(n),
             Err(e) => {
@@ -866,32 +1614,79 @@ where
     /// Find a node from cache (or disk).
     pub(crate) async fn find_node_with_lock(
         &self,
-        nodes_lock: &'_ mut MutexGuard<'_, HashMap<usize, Node<K, V>, BuildIdentityHasher>>,
+        nodes_lock: &NodeCache<K, V>,
+        idx: usize,
+    ) -> Result<Arc<Node<K, V>>> {
+        self.find_node_with_lock_and_policy(nodes_lock, idx, CachePolicy::Populate)
+            .await
+    }
+
+    /// Find a node from cache (or disk).
+    ///
+    /// Internal nodes are always cached, leaf nodes are only cached if the policy allows it.
+    pub(crate) async fn find_node_with_lock_and_policy(
+        &self,
+        nodes_lock: &NodeCache<K, V>,
         idx: usize,
-    ) -> Result<Node<K, V>> {
-        let child = match nodes_lock.get(&idx) {
-            Some(c) => c.clone(),
+        policy: CachePolicy,
+    ) -> Result<Arc<Node<K, V>>> {
+        let child = match nodes_lock.get(idx) {
+            Some(c) => {
+                MetricsRecorder::count(&self.metrics.cache_hits, 1);
+                c
+            }
             None => {
-                let node = self.read_node(idx).await?;
-                nodes_lock.insert(idx, node.clone());
+                MetricsRecorder::count(&self.metrics.cache_misses, 1);
+                let node = Arc::new(self.read_node(idx).await?);
+                if policy == CachePolicy::Populate || !node.is_leaf() {
+                    nodes_lock.insert_if_absent(idx, node.clone());
+                }
                 node
             }
         };
         Ok(child)
     }
 
+    /// Find a node from cache (or disk), using the supplied cache policy.
+    async fn find_node_as_option_with_lock_and_policy(
+        &self,
+        nodes_lock: &NodeCache<K, V>,
+        idx: usize,
+        policy: CachePolicy,
+    ) -> Option<Arc<Node<K, V>>> {
+        match self
+            .find_node_with_lock_and_policy(nodes_lock, idx, policy)
+            .await
+        {
+            Ok(n) => Some(n),
+            Err(e) => {
+                tracing::error!("could not find node with index {idx}: {e}");
+                None
+            }
+        }
+    }
+
     /// Read a node from disk.
     async fn read_node(&self, idx: usize) -> Result<Node<K, V>> {
         let mut file_lock = self.file.lock().await;
-        let buf = file_lock.read_data(idx).await?;
-        Node::<K, V>::deserialize(&buf)
+        let buf = file_lock
+            .read_data(idx)
+            .await
+            .map_err(|e| match e.downcast_ref() {
+                Some(BTreeFileError::Stale) => BaildonError::Stale(self.path.clone()).into(),
+                _ => e,
+            })?;
+        Self::decode_node(&file_lock, &buf)
     }
 
-    pub(crate) async fn first_leaf(&self) -> Node<K, V> {
-        let mut nodes_lock = self.nodes.lock().await;
+    pub(crate) async fn first_leaf(&self, policy: CachePolicy) -> Arc<Node<K, V>> {
+        if let Err(e) = self.ensure_current().await {
+            tracing::error!("could not refresh tree: {e}");
+        }
+        let nodes_lock = self.nodes.read().await;
         let root_lock = self.root.lock().await;
         let mut node = self
-            .find_node_with_lock(&mut nodes_lock, *root_lock)
+            .find_node_with_lock_and_policy(&nodes_lock, *root_lock, policy)
             .await
             .expect("FLUFFED NODE");
         loop {
@@ -900,17 +1695,20 @@ where
             }
             let idx = node.first_child();
             node = self
-                .find_node_with_lock(&mut nodes_lock, idx)
+                .find_node_with_lock_and_policy(&nodes_lock, idx, policy)
                 .await
                 .expect("FLUFFED NODE");
         }
     }
 
-    pub(crate) async fn last_leaf(&self) -> Node<K, V> {
-        let mut nodes_lock = self.nodes.lock().await;
+    pub(crate) async fn last_leaf(&self, policy: CachePolicy) -> Arc<Node<K, V>> {
+        if let Err(e) = self.ensure_current().await {
+            tracing::error!("could not refresh tree: {e}");
+        }
+        let nodes_lock = self.nodes.read().await;
         let root_lock = self.root.lock().await;
         let mut node = self
-            .find_node_with_lock(&mut nodes_lock, *root_lock)
+            .find_node_with_lock_and_policy(&nodes_lock, *root_lock, policy)
             .await
             .expect("FLUFFED NODE");
         loop {
@@ -919,24 +1717,46 @@ where
             }
             let idx = node.last_child();
             node = self
-                .find_node_with_lock(&mut nodes_lock, idx)
+                .find_node_with_lock_and_policy(&nodes_lock, idx, policy)
                 .await
                 .expect("FLUFFED NODE");
         }
     }
 
-    pub(crate) async fn neighbour(&self, idx: usize, direction: Direction) -> Option<Node<K, V>> {
-        let mut nodes_lock = self.nodes.lock().await;
-        self.neighbour_with_lock(&mut nodes_lock, idx, direction)
-            .await
+    /// Return the neighbouring leaf of a leaf.
+    ///
+    /// Leaves are linked to their siblings, unless they were read from an older file which
+    /// couldn't be upgraded (e.g.: it's read only), when we have to search via their parents.
+    pub(crate) async fn next_leaf(
+        &self,
+        leaf: &Node<K, V>,
+        direction: Direction,
+        policy: CachePolicy,
+    ) -> Option<Arc<Node<K, V>>> {
+        let linked = self.file.lock().await.has_leaf_links();
+        let nodes_lock = self.nodes.read().await;
+        if linked {
+            // Our leaf may have been split since it was read, so prefer any cached copy
+            let current = nodes_lock
+                .inspect(leaf.index(), |current| {
+                    current.is_leaf().then(|| current.sibling(direction))
+                })
+                .flatten();
+            let idx = current.unwrap_or_else(|| leaf.sibling(direction))?;
+            self.find_node_as_option_with_lock_and_policy(&nodes_lock, idx, policy)
+                .await
+        } else {
+            self.neighbour_with_lock(&nodes_lock, leaf.index(), direction, policy)
+                .await
+        }
     }
 
     async fn neighbour_same_parent_with_lock(
         &self,
-        nodes_lock: &'_ mut MutexGuard<'_, HashMap<usize, Node<K, V>, BuildIdentityHasher>>,
+        nodes_lock: &NodeCache<K, V>,
         idx: usize,
         direction: Direction,
-    ) -> Option<Node<K, V>> {
+    ) -> Option<Arc<Node<K, V>>> {
         let node = self.find_node_as_option_with_lock(nodes_lock, idx).await?;
         match node.parent() {
             Some(p_idx) => {
@@ -977,11 +1797,14 @@ where
 
     async fn neighbour_with_lock(
         &self,
-        nodes_lock: &'_ mut MutexGuard<'_, HashMap<usize, Node<K, V>, BuildIdentityHasher>>,
+        nodes_lock: &NodeCache<K, V>,
         mut idx: usize,
         direction: Direction,
-    ) -> Option<Node<K, V>> {
-        let mut node = self.find_node_as_option_with_lock(nodes_lock, idx).await?;
+        policy: CachePolicy,
+    ) -> Option<Arc<Node<K, V>>> {
+        let mut node = self
+            .find_node_as_option_with_lock_and_policy(nodes_lock, idx, policy)
+            .await?;
 
         let original_node = node.clone();
 
@@ -1019,7 +1842,9 @@ where
                         // Now keep looping down the children until we find a leaf node from c_pos
                         idx = node.children().nth(c_pos)?;
                         loop {
-                            node = self.find_node_as_option_with_lock(nodes_lock, idx).await?;
+                            node = self
+                                .find_node_as_option_with_lock_and_policy(nodes_lock, idx, policy)
+                                .await?;
                             if original_node.is_leaf() == node.is_leaf() {
                                 break;
                             }
@@ -1037,7 +1862,9 @@ where
                         // Now keep looping down the children until we find a node from c_pos
                         idx = node.children().nth(c_pos)?;
                         loop {
-                            node = self.find_node_as_option_with_lock(nodes_lock, idx).await?;
+                            node = self
+                                .find_node_as_option_with_lock_and_policy(nodes_lock, idx, policy)
+                                .await?;
                             if original_node.is_leaf() == node.is_leaf() {
                                 break;
                             }
@@ -1100,15 +1927,60 @@ where
     V: BaildonValue + Send + Sync,
 {
     fn drop(&mut self) {
-        std::thread::scope(|s| {
-            let hdl = s.spawn(|| {
-                let runtime = tokio::runtime::Builder::new_multi_thread().build().unwrap();
-                if let Err(e) = runtime.block_on(self.flush_to_disk()) {
-                    tracing::warn!("could not flush data file to disk: {}", e);
+        self.stop_compaction();
+        if self.temporary {
+            let mut wal_path = self.path.clone();
+            wal_path.set_extension("wal");
+            for path in [&self.path, &wal_path, &bloom_path(&self.path)] {
+                match std::fs::remove_file(path) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => {
+                        tracing::error!("Error when removing {}: {e}", path.display())
+                    }
+                    _ => (),
                 }
-            });
-            hdl.join().expect("thread finished");
-        });
+            }
+        } else if !self.read_only && !self.closed.load(Ordering::SeqCst) {
+            tracing::warn!(
+                path = %self.path.display(),
+                "B+Tree dropped without being closed, modifications will be recovered from the WAL"
+            );
+        }
+    }
+}
+
+/// Run an operation within a span, recording how long it took in the span's `duration_us` field.
+async fn traced<F: Future>(span: Span, operation: F) -> F::Output {
+    let start = Instant::now();
+    let output = operation.instrument(span.clone()).await;
+    span.record("duration_us", start.elapsed().as_micros() as u64);
+    output
+}
+
+/// Record the serialized size of a key in a span's `key_size` field, unless nothing is listening.
+fn record_key_size<K: Serialize>(span: &Span, key: &K) {
+    if span.is_disabled() {
+        return;
+    }
+    if let Ok(size) = BINCODER.serialized_size(key) {
+        span.record("key_size", size);
+    }
+}
+
+/// Report the old value of a key which was replaced or deleted. A corrupt old value is logged and
+/// reported as missing.
+fn old_value<V>(value: Result<V>, index: usize) -> Option<V> {
+    value
+        .map_err(|e| tracing::warn!("replaced corrupt value in node {index}: {e}"))
+        .ok()
+}
+
+/// Convert a failure to lock our store into a [`BaildonError::Locked`].
+pub(crate) fn locked_error(path: &Path, err: anyhow::Error) -> anyhow::Error {
+    match err.downcast_ref::<std::io::Error>() {
+        Some(io_error) if io_error.kind() == ErrorKind::WouldBlock => {
+            BaildonError::Locked(path.into()).into()
+        }
+        _ => err,
     }
 }
 
@@ -1,12 +1,18 @@
 use super::*;
 
+use std::sync::Arc;
+
+use crate::btree::CompactionOptions;
+use crate::btree::CrashPoint;
+
 use rand::Rng;
 
 #[tokio::test]
 async fn it_creates_tree() {
-    let _tree = Baildon::<String, usize>::try_new("create.db", 5)
+    let tree = Baildon::<String, usize>::try_new("create.db", 5)
         .await
         .expect("creates tree file");
+    tree.close().await.expect("closes tree");
     std::fs::remove_file("create.db").expect("cleanup");
 }
 
@@ -15,25 +21,354 @@ async fn it_opens_tree() {
     let tree = Baildon::<String, usize>::try_new("open.db", 5)
         .await
         .expect("creates tree file");
-    drop(tree);
-    let _tree = Baildon::<String, usize>::try_open("open.db")
+    tree.close().await.expect("closes tree");
+    let tree = Baildon::<String, usize>::try_open("open.db")
         .await
         .expect("opens tree file");
+    tree.close().await.expect("closes tree");
     std::fs::remove_file("open.db").expect("cleanup");
 }
 
+#[tokio::test]
+async fn it_opens_or_creates_tree() {
+    let tree = Baildon::<usize, usize>::open_or_create("open_or_create.db", 5)
+        .await
+        .expect("creates tree file");
+    tree.insert(1, 1).await.expect("insert worked");
+    tree.close().await.expect("closes tree");
+
+    let tree = Baildon::<usize, usize>::open_or_create("open_or_create.db", 5)
+        .await
+        .expect("opens tree file");
+    assert_eq!(tree.get(&1).await, Some(1));
+    tree.close().await.expect("closes tree");
+    std::fs::remove_file("open_or_create.db").expect("cleanup");
+}
+
+#[tokio::test]
+async fn it_opens_tree_shared() {
+    let tree = Baildon::<usize, usize>::try_new("open_shared.db", 5)
+        .await
+        .expect("creates tree file");
+    for i in 0..100 {
+        tree.insert(i, i).await.expect("insert worked");
+    }
+    tree.flush_to_disk().await.expect("flushes tree");
+
+    // Writers exclude other writers
+    let err = Baildon::<usize, usize>::try_open("open_shared.db")
+        .await
+        .err()
+        .expect("store is locked");
+    assert!(matches!(
+        err.downcast_ref::<BaildonError>(),
+        Some(BaildonError::Locked(_))
+    ));
+
+    // But not readers
+    let reader_1 = Baildon::<usize, usize>::try_open_shared("open_shared.db")
+        .await
+        .expect("opens tree file");
+    let reader_2 = Baildon::<usize, usize>::try_open_shared("open_shared.db")
+        .await
+        .expect("opens tree file");
+    assert!(reader_1.read_only());
+    assert_eq!(reader_1.count().await, 100);
+    assert_eq!(reader_2.get(&50).await, Some(50));
+    let err = reader_1.insert(100, 100).await.expect_err("read only");
+    assert!(matches!(
+        err.downcast_ref::<BaildonError>(),
+        Some(BaildonError::ReadOnly(_))
+    ));
+
+    // Readers pick up changes once the writer flushes
+    for i in 100..200 {
+        tree.insert(i, i).await.expect("insert worked");
+    }
+    tree.flush_to_disk().await.expect("flushes tree");
+    assert_eq!(reader_2.get(&150).await, Some(150));
+    reader_1.refresh().await.expect("refreshes tree");
+    assert_eq!(reader_1.count().await, 200);
+
+    // Even if the writer replaces the file
+    for i in (0..200).step_by(2) {
+        tree.delete(&i).await.expect("delete worked");
+    }
+    tree.flush_to_disk().await.expect("flushes tree");
+    tree.compact().await.expect("compacts tree");
+    assert_eq!(reader_1.get(&150).await, None);
+    assert_eq!(reader_1.count().await, 100);
+
+    reader_1.close().await.expect("closes tree");
+    reader_2.close().await.expect("closes tree");
+    tree.close().await.expect("closes tree");
+    std::fs::remove_file("open_shared.db").expect("cleanup");
+}
+
+#[tokio::test]
+async fn it_opens_tree_with_recovery_options() {
+    let tree = Baildon::<usize, usize>::try_new("open_recovery.db", 5)
+        .await
+        .expect("creates tree file");
+    for i in 0..100 {
+        tree.insert(i, i).await.expect("insert worked");
+    }
+    // Simulate a failure, leaving our modifications in the WAL
+    drop(tree);
+
+    let err = Baildon::<usize, usize>::try_open_with_recovery(
+        "open_recovery.db",
+        Recovery::FailIfWalPresent,
+    )
+    .await
+    .err()
+    .expect("WAL is present");
+    assert!(matches!(
+        err.downcast_ref::<BaildonError>(),
+        Some(BaildonError::WalPresent(_))
+    ));
+
+    let tree =
+        Baildon::<usize, usize>::try_open_with_recovery("open_recovery.db", Recovery::IgnoreWal)
+            .await
+            .expect("opens tree file");
+    assert_eq!(tree.count().await, 0);
+    tree.close().await.expect("closes tree");
+
+    // Our quarantined WAL can still be replayed
+    std::fs::rename("open_recovery.wal.quarantine", "open_recovery.wal").expect("restores WAL");
+    let tree = Baildon::<usize, usize>::try_open("open_recovery.db")
+        .await
+        .expect("opens tree file");
+    assert_eq!(tree.count().await, 100);
+    tree.close().await.expect("closes tree");
+    std::fs::remove_file("open_recovery.db").expect("cleanup");
+}
+
+#[tokio::test]
+async fn it_limits_entry_sizes() {
+    let mut tree = Baildon::<String, Vec<u8>>::try_new("limit_sizes.db", 5)
+        .await
+        .expect("creates tree file");
+    tree.set_size_limits(SizeLimits {
+        max_key_size: 32,
+        max_value_size: 1024,
+    });
+
+    let err = tree
+        .insert("k".repeat(64), vec![])
+        .await
+        .expect_err("key too large");
+    assert!(matches!(
+        err.downcast_ref::<BaildonError>(),
+        Some(BaildonError::KeyTooLarge(72, 32))
+    ));
+    let err = tree
+        .insert("key".to_string(), vec![0; 2048])
+        .await
+        .expect_err("value too large");
+    assert!(matches!(
+        err.downcast_ref::<BaildonError>(),
+        Some(BaildonError::ValueTooLarge(2056, 1024))
+    ));
+    tree.insert("key".to_string(), vec![0; 1000])
+        .await
+        .expect("insert worked");
+    assert_eq!(tree.count().await, 1);
+
+    tree.close().await.expect("closes tree");
+    std::fs::remove_file("limit_sizes.db").expect("cleanup");
+}
+
+#[tokio::test]
+async fn it_persists_count_and_depth() {
+    let tree = Baildon::<usize, usize>::try_new("count_depth.db", 5)
+        .await
+        .expect("creates tree file");
+    assert_eq!(tree.count().await, 0);
+    assert_eq!(tree.depth().await, 1);
+    for i in 0..100 {
+        tree.insert(i, i).await.expect("insert worked");
+    }
+    // Updating an entry doesn't change our count
+    tree.insert(0, 1).await.expect("insert worked");
+    for i in 0..25 {
+        tree.delete(&i).await.expect("delete worked");
+    }
+    let depth = tree.depth().await;
+    assert!(depth > 1);
+    assert_eq!(tree.count().await, 75);
+    tree.close().await.expect("closes tree");
+
+    let tree = Baildon::<usize, usize>::try_open("count_depth.db")
+        .await
+        .expect("opens tree file");
+    assert_eq!(tree.count().await, 75);
+    assert_eq!(tree.depth().await, depth);
+    let report = tree
+        .verify(Direction::Ascending)
+        .await
+        .expect("verifies tree");
+    assert!(report.is_ok(), "{report}");
+    tree.clear().await.expect("clears tree");
+    assert_eq!(tree.count().await, 0);
+    assert_eq!(tree.depth().await, 1);
+    tree.close().await.expect("closes tree");
+    std::fs::remove_file("count_depth.db").expect("cleanup");
+}
+
+fn crashed_at(err: &anyhow::Error, point: CrashPoint) -> bool {
+    matches!(err.downcast_ref::<BaildonError>(), Some(BaildonError::Crashed(p)) if *p == point)
+}
+
+#[tokio::test]
+async fn it_recovers_from_crash_after_wal_append() {
+    let tree = Baildon::<usize, usize>::try_new("crash_wal.db", 5)
+        .await
+        .expect("creates tree file");
+    for i in 0..100 {
+        tree.insert(i, i).await.expect("insert worked");
+    }
+    tree.arm_crash_point(CrashPoint::AfterWalAppend);
+    let err = tree.insert(100, 100).await.expect_err("crashed");
+    assert!(crashed_at(&err, CrashPoint::AfterWalAppend));
+    drop(tree);
+
+    // Everything appended to the WAL is recovered
+    let tree = Baildon::<usize, usize>::try_open("crash_wal.db")
+        .await
+        .expect("opens tree file");
+    assert_eq!(tree.count().await, 101);
+    for i in 0..=100 {
+        assert_eq!(tree.get(&i).await, Some(i));
+    }
+    let report = tree
+        .verify(Direction::Ascending)
+        .await
+        .expect("verifies tree");
+    assert!(report.is_ok(), "{report}");
+    tree.close().await.expect("closes tree");
+    std::fs::remove_file("crash_wal.db").expect("cleanup");
+}
+
+#[tokio::test]
+async fn it_renames_keys() {
+    let tree = Baildon::<usize, usize>::try_new("rename_tree.db", 5)
+        .await
+        .expect("creates tree file");
+    for i in 0..100 {
+        tree.insert(i, i).await.expect("insert worked");
+    }
+    assert_eq!(tree.rename(&1, 200, false).await.expect("renames"), 1);
+    assert_eq!(tree.get(&1).await, None);
+    assert_eq!(tree.get(&200).await, Some(1));
+    assert_eq!(tree.count().await, 100);
+
+    let err = tree.rename(&1, 201, false).await.expect_err("no key");
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(BaildonError::KeyNotFound(_))
+    ));
+    let err = tree.rename(&2, 3, false).await.expect_err("key exists");
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(BaildonError::KeyExists(_))
+    ));
+    assert_eq!(tree.rename(&2, 3, true).await.expect("renames"), 2);
+    assert_eq!(tree.get(&3).await, Some(2));
+    assert_eq!(tree.count().await, 99);
+
+    // Renames are recovered from our WAL
+    tree.arm_crash_point(CrashPoint::AfterWalAppend);
+    let err = tree.rename(&4, 4_000, false).await.expect_err("crashed");
+    assert!(crashed_at(&err, CrashPoint::AfterWalAppend));
+    drop(tree);
+    let tree = Baildon::<usize, usize>::try_open("rename_tree.db")
+        .await
+        .expect("opens tree file");
+    assert_eq!(tree.get(&4).await, None);
+    assert_eq!(tree.get(&4_000).await, Some(4));
+    assert_eq!(tree.get(&200).await, Some(1));
+    assert_eq!(tree.count().await, 99);
+    tree.close().await.expect("closes tree");
+    std::fs::remove_file("rename_tree.db").expect("cleanup");
+}
+
+#[tokio::test]
+async fn it_recovers_from_crash_during_flush() {
+    for point in [CrashPoint::AfterNodeWrite, CrashPoint::BeforeFooterWrite] {
+        let path = format!("crash_{point}.db");
+        let tree = Baildon::<usize, usize>::try_new(&path, 5)
+            .await
+            .expect("creates tree file");
+        for i in 0..100 {
+            tree.insert(i, i).await.expect("insert worked");
+        }
+        tree.close().await.expect("closes tree");
+
+        let tree = Baildon::<usize, usize>::try_open(&path)
+            .await
+            .expect("opens tree file");
+        for i in 0..100 {
+            tree.insert(i, i + 1).await.expect("insert worked");
+        }
+        tree.arm_crash_point(point);
+        let err = tree.flush_to_disk().await.expect_err("crashed");
+        assert!(crashed_at(&err, point));
+        drop(tree);
+
+        let tree = Baildon::<usize, usize>::try_open(&path)
+            .await
+            .expect("opens tree file");
+        assert_eq!(tree.count().await, 100);
+        for i in 0..100 {
+            assert_eq!(tree.get(&i).await, Some(i + 1));
+        }
+        let report = tree
+            .verify(Direction::Ascending)
+            .await
+            .expect("verifies tree");
+        assert!(report.is_ok(), "{report}");
+        tree.close().await.expect("closes tree");
+        std::fs::remove_file(&path).expect("cleanup");
+    }
+}
+
+#[tokio::test]
+async fn it_removes_temporary_tree() {
+    let tree = Baildon::<usize, usize>::temporary(5)
+        .await
+        .expect("creates tree file");
+    let path = tree.path.clone();
+    tree.insert(1, 1).await.expect("insert worked");
+    assert!(path.exists());
+    tree.close().await.expect("closes tree");
+    assert!(!path.exists());
+
+    let tree = Baildon::<usize, usize>::temporary(5)
+        .await
+        .expect("creates tree file");
+    let path = tree.path.clone();
+    let mut wal_path = path.clone();
+    wal_path.set_extension("wal");
+    tree.insert(1, 1).await.expect("insert worked");
+    drop(tree);
+    assert!(!path.exists());
+    assert!(!wal_path.exists());
+}
+
 #[tokio::test]
 async fn it_searches_empty_tree() {
-    let tree = Baildon::<String, usize>::try_new("search_empty.db", 5)
+    let tree = Baildon::<String, usize>::temporary(5)
         .await
         .expect("creates tree file");
     assert!(!tree.contains(&"something".to_string()).await);
-    std::fs::remove_file("search_empty.db").expect("cleanup");
+    tree.close().await.expect("closes tree");
 }
 
 #[test_log::test(tokio::test)]
 async fn it_inserts_into_empty_tree_random_usize() {
-    let tree = Baildon::<usize, usize>::try_new("insert_empty_random_usize.db", 8)
+    let tree = Baildon::<usize, usize>::temporary(8)
         .await
         .expect("creates tree file");
     let mut input = vec![];
@@ -45,8 +380,8 @@ async fn it_inserts_into_empty_tree_random_usize() {
     for i in input {
         assert!(tree.contains(&i).await);
     }
-    tree.info().await;
-    std::fs::remove_file("insert_empty_random_usize.db").expect("cleanup");
+    tracing::info!("{}", tree.stats().await.expect("reports stats"));
+    tree.close().await.expect("closes tree");
 }
 
 #[test_log::test(tokio::test)]
@@ -68,7 +403,8 @@ async fn it_inserts_into_empty_tree_usize() {
     for i in 0..4_000 {
         assert!(tree.contains(&i).await);
     }
-    tree.info().await;
+    tracing::info!("{}", tree.stats().await.expect("reports stats"));
+    tree.close().await.expect("closes tree");
     std::fs::remove_file("insert_empty_usize.db").expect("cleanup");
 }
 
@@ -83,7 +419,8 @@ async fn it_inserts_into_empty_tree_reverse_usize() {
     for i in 0..400 {
         assert!(tree.contains(&i).await);
     }
-    tree.info().await;
+    tracing::info!("{}", tree.stats().await.expect("reports stats"));
+    tree.close().await.expect("closes tree");
     std::fs::remove_file("insert_empty_reverse_usize.db").expect("cleanup");
 }
 
@@ -101,12 +438,13 @@ async fn it_inserts_and_clears_into_empty_tree_random_usize() {
     for i in &input {
         assert!(tree.contains(i).await);
     }
-    tree.info().await;
+    tracing::info!("{}", tree.stats().await.expect("reports stats"));
     tree.clear().await.expect("tree cleared");
     for i in input {
         assert!(!tree.contains(&i).await);
     }
-    tree.info().await;
+    tracing::info!("{}", tree.stats().await.expect("reports stats"));
+    tree.close().await.expect("closes tree");
     std::fs::remove_file("insert_and_clear_empty_random_usize.db").expect("cleanup");
 }
 
@@ -115,7 +453,7 @@ async fn it_inserts_into_empty_tree_string() {
     let tree = Baildon::<String, usize>::try_new("insert_empty_string.db", 30)
         .await
         .expect("creates tree file");
-    tree.info().await;
+    tracing::info!("{}", tree.stats().await.expect("reports stats"));
     for i in (0..400).rev() {
         let key = format!("something_{i}");
         let callback = |node: &Node<String, usize>| {
@@ -126,7 +464,7 @@ async fn it_inserts_into_empty_tree_string() {
         tree.insert(key, i).await.expect("insert worked");
         tree.traverse_nodes(Direction::Ascending, callback).await;
     }
-    tree.info().await;
+    tracing::info!("{}", tree.stats().await.expect("reports stats"));
     assert!(tree.contains(&"something_0".to_string()).await);
     assert!(tree.contains(&"something_13".to_string()).await);
     assert!(tree.contains(&"something_319".to_string()).await);
@@ -134,13 +472,13 @@ async fn it_inserts_into_empty_tree_string() {
         .await
         .expect("FLUSHING DATA");
 
-    drop(tree);
+    tree.close().await.expect("closes tree");
     tracing::debug!("ABOUT TO RE-OPEN");
     let new_tree = Baildon::<String, usize>::try_open("insert_empty_string.db")
         .await
         .expect("opens tree file");
     tracing::debug!("ABOUT TO PRINT NEW-INFO");
-    new_tree.info().await;
+    tracing::info!("{}", new_tree.stats().await.expect("reports stats"));
     assert!(new_tree.contains(&"something_0".to_string()).await);
     assert!(new_tree.contains(&"something_13".to_string()).await);
     assert!(new_tree.contains(&"something_319".to_string()).await);
@@ -162,7 +500,7 @@ async fn it_inserts_into_empty_tree_string() {
     assert!(new_tree.contains(&"something_400".to_string()).await);
     assert!(new_tree.contains(&"something_413".to_string()).await);
     assert!(new_tree.contains(&"something_719".to_string()).await);
-    drop(new_tree);
+    new_tree.close().await.expect("closes tree");
     std::fs::remove_file("insert_empty_string.db").expect("cleanup");
 }
 
@@ -184,7 +522,8 @@ async fn it_inserts_into_empty_tree_example_usize() {
     for i in &input {
         assert!(tree.contains(i).await);
     }
-    tree.info().await;
+    tracing::info!("{}", tree.stats().await.expect("reports stats"));
+    tree.close().await.expect("closes tree");
     std::fs::remove_file("insert_empty_example_usize.db").expect("cleanup");
 }
 
@@ -208,13 +547,14 @@ async fn it_deletes_from_populated_tree_example_usize() {
     }
     assert_eq!(tree.first_key().await.unwrap(), 5);
     assert_eq!(tree.last_key().await.unwrap(), 55);
-    tree.info().await;
+    tracing::info!("{}", tree.stats().await.expect("reports stats"));
     tree.inner_flush_to_disk(false)
         .await
         .expect("FLUSHING DATA");
     println!("What is 55: {:?}", tree.get(&55).await);
     tree.delete(&55).await.expect("delete worked");
     println!("What is 55: {:?}", tree.get(&55).await);
+    tree.close().await.expect("closes tree");
     std::fs::remove_file("delete_populated_example_usize.db").expect("cleanup");
 }
 
@@ -230,6 +570,7 @@ async fn it_deletes_from_populated_tree_you_tube_example_usize() {
         tree.insert(*i, *i).await.expect("insert worked");
     }
 
+    tree.close().await.expect("closes tree");
     std::fs::remove_file("delete_populated_you_tube_example_usize.db").expect("cleanup");
 }
 
@@ -246,6 +587,7 @@ async fn it_can_retrieve_keys_from_empty_tree() {
         .await;
     assert!(keys.is_empty());
 
+    tree.close().await.expect("closes tree");
     std::fs::remove_file("retrieve_keys_from_empty_tree.db").expect("cleanup");
 }
 
@@ -270,5 +612,109 @@ async fn it_can_retrieve_keys_from_tree() {
 
     assert_eq!(input, keys);
 
+    tree.close().await.expect("closes tree");
     std::fs::remove_file("retrieve_keys_from_tree.db").expect("cleanup");
 }
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn it_reads_from_tree_concurrently() {
+    let tree = Arc::new(
+        Baildon::<usize, usize>::try_new("read_concurrently.db", 7)
+            .await
+            .expect("creates tree file"),
+    );
+    for i in 0..400 {
+        tree.insert(i, i).await.expect("insert worked");
+    }
+
+    let mut readers = vec![];
+    for _ in 0..8 {
+        let reader = tree.clone();
+        readers.push(tokio::spawn(async move {
+            for i in 0..400 {
+                assert_eq!(reader.get(&i).await, Some(i));
+            }
+        }));
+    }
+    for reader in readers {
+        reader.await.expect("reader finished");
+    }
+
+    Arc::into_inner(tree)
+        .expect("only reference")
+        .close()
+        .await
+        .expect("closes tree");
+    std::fs::remove_file("read_concurrently.db").expect("cleanup");
+}
+
+#[test_log::test(tokio::test)]
+async fn it_compacts_tree() {
+    let tree = Baildon::<usize, usize>::try_new("compact.db", 5)
+        .await
+        .expect("creates tree file");
+    for i in 0..400 {
+        tree.insert(i, i).await.expect("insert worked");
+    }
+    tree.flush_to_disk().await.expect("flushes");
+    for i in 0..300 {
+        tree.delete(&i).await.expect("delete worked");
+    }
+    tree.flush_to_disk().await.expect("flushes");
+    assert!(tree.fragmentation().await > 0.0);
+    assert!(tree.compact().await.expect("compacts") > 0);
+    assert_eq!(tree.fragmentation().await, 0.0);
+    tree.close().await.expect("closes tree");
+
+    let tree = Baildon::<usize, usize>::try_open("compact.db")
+        .await
+        .expect("opens tree file");
+    for i in 0..400 {
+        assert_eq!(tree.contains(&i).await, i >= 300);
+    }
+    tree.close().await.expect("closes tree");
+    std::fs::remove_file("compact.db").expect("cleanup");
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread"))]
+async fn it_compacts_tree_in_the_background() {
+    let tree = Arc::new(
+        Baildon::<usize, usize>::try_new("compact_background.db", 5)
+            .await
+            .expect("creates tree file"),
+    );
+    for i in 0..400 {
+        tree.insert(i, i).await.expect("insert worked");
+    }
+    tree.flush_to_disk().await.expect("flushes");
+    for i in 0..300 {
+        tree.delete(&i).await.expect("delete worked");
+    }
+    tree.flush_to_disk().await.expect("flushes");
+
+    let options = CompactionOptions {
+        threshold: 0.0,
+        check_interval: std::time::Duration::from_millis(10),
+        min_interval: std::time::Duration::from_secs(60),
+    };
+    tree.start_compaction(options);
+    while tree.compaction_progress().compactions == 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    tree.stop_compaction();
+    let progress = tree.compaction_progress();
+    assert!(!progress.running);
+    assert_eq!(progress.compactions, 1);
+    assert!(progress.bytes_reclaimed > 0);
+
+    // Wait for our aborted task to release its reference to our tree
+    while Arc::strong_count(&tree) > 1 {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    Arc::into_inner(tree)
+        .expect("only reference")
+        .close()
+        .await
+        .expect("closes tree");
+    std::fs::remove_file("compact_background.db").expect("cleanup");
+}
@@ -0,0 +1,362 @@
+//! Bloom Filters
+//!
+//! A tree may maintain a Bloom filter of its keys, so that searches for keys which aren't present
+//! can usually return without reading any nodes. Filters are stored alongside our data file, with
+//! a `.bloom` extension, and are rewritten whenever our tree is flushed.
+//!
+//! Keys are never removed from a filter, so deleted keys remain until the filter is rebuilt.
+//! Filters are rebuilt when they contain more keys than they were sized for, when our tree is
+//! compacted and when a stored filter doesn't match our data file (e.g.: the writer crashed).
+//!
+//! Read only trees don't use filters, since the writer may add keys at any time.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+use super::baildon::Baildon;
+use super::baildon::BaildonKey;
+use super::baildon::BaildonValue;
+use super::baildon::CachePolicy;
+use crate::BINCODER;
+
+/// Number of bits per key used when a stored filter can't be read.
+const DEFAULT_BITS_PER_KEY: usize = 10;
+
+/// Filters are always sized for at least this many keys.
+const MIN_CAPACITY: usize = 1024;
+
+/// A Bloom filter of serialized keys.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+    bits_per_key: usize,
+    capacity: usize,
+    len: usize,
+}
+
+impl BloomFilter {
+    fn new(capacity: usize, bits_per_key: usize) -> Self {
+        let capacity = capacity.max(MIN_CAPACITY);
+        let bits_per_key = bits_per_key.max(1);
+        // ln(2) hashes per bit of each key minimises false positives
+        let hashes = (bits_per_key as f64 * std::f64::consts::LN_2).round() as u32;
+        Self {
+            bits: vec![0; (capacity * bits_per_key).div_ceil(64)],
+            hashes: hashes.max(1),
+            bits_per_key,
+            capacity,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn insert<K: Serialize>(&mut self, key: &K) {
+        let Some(probes) = self.probes(key) else {
+            return;
+        };
+        for bit in probes {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    pub(crate) fn may_contain<K: Serialize>(&self, key: &K) -> bool {
+        // Keys which can't be serialized were never inserted, but we can't tell
+        self.probes(key)
+            .is_none_or(|mut probes| probes.all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0))
+    }
+
+    /// Does this filter contain more keys than it was sized for?
+    fn is_full(&self) -> bool {
+        self.len > self.capacity
+    }
+
+    /// Return the bits which represent a key, using double hashing of our serialized key.
+    fn probes<K: Serialize>(&self, key: &K) -> Option<impl Iterator<Item = usize>> {
+        let s_key = BINCODER.serialize(key).ok()?;
+        let h1 = fnv1a(0xcbf2_9ce4_8422_2325, &s_key);
+        let h2 = fnv1a(0x6c62_272e_07bb_0142, &s_key) | 1;
+        let bits = self.bits.len() as u64 * 64;
+        Some(
+            (0..self.hashes as u64)
+                .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize),
+        )
+    }
+}
+
+// FNV-1a, so that stored filters are stable across builds
+fn fnv1a(basis: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(basis, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// A stored filter, along with the state of the data file it was written for.
+#[derive(Deserialize, Serialize)]
+struct BloomFile {
+    generation: u64,
+    entries: usize,
+    filter: BloomFilter,
+}
+
+/// Return the path of the filter for the data file at the specified path.
+pub(crate) fn bloom_path(path: &Path) -> PathBuf {
+    let mut bloom_path = path.to_path_buf();
+    bloom_path.set_extension("bloom");
+    bloom_path
+}
+
+/// Remove the filter for the data file at the specified path, if there is one.
+pub(crate) async fn remove_bloom_file(path: &Path) -> Result<()> {
+    match tokio::fs::remove_file(bloom_path(path)).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+impl<K, V> Baildon<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    /// Maintain a Bloom filter of our keys, using the specified number of bits per key, so that
+    /// searches for keys which aren't present can usually return without reading any nodes.
+    ///
+    /// Around 10 bits per key gives a 1% false positive rate. Our filter is built from all our
+    /// keys, so this is as expensive as a full traversal. Once enabled, our filter is maintained
+    /// whenever our tree is opened for writing, until it is disabled.
+    pub async fn enable_bloom_filter(&self, bits_per_key: usize) -> Result<()> {
+        self.check_writable()?;
+        self.rebuild_bloom_filter(bits_per_key).await?;
+        self.flush_to_disk().await
+    }
+
+    /// Stop maintaining a Bloom filter of our keys, removing any stored filter.
+    pub async fn disable_bloom_filter(&self) -> Result<()> {
+        self.check_writable()?;
+        *self.bloom.write().expect("bloom filter lock poisoned") = None;
+        remove_bloom_file(&self.path).await
+    }
+
+    /// Do we maintain a Bloom filter of our keys?
+    pub fn has_bloom_filter(&self) -> bool {
+        self.bloom
+            .read()
+            .expect("bloom filter lock poisoned")
+            .is_some()
+    }
+
+    /// Could our tree contain this key? Always true if we don't have a filter.
+    pub(crate) fn may_contain(&self, key: &K) -> bool {
+        self.bloom
+            .read()
+            .expect("bloom filter lock poisoned")
+            .as_ref()
+            .is_none_or(|filter| filter.may_contain(key))
+    }
+
+    /// Add a key to our filter, if we have one.
+    pub(crate) fn add_to_bloom_filter(&self, key: &K) {
+        if let Some(filter) = self
+            .bloom
+            .write()
+            .expect("bloom filter lock poisoned")
+            .as_mut()
+        {
+            filter.insert(key);
+        }
+    }
+
+    /// Empty our filter, if we have one.
+    pub(crate) fn clear_bloom_filter(&self) {
+        if let Some(filter) = self
+            .bloom
+            .write()
+            .expect("bloom filter lock poisoned")
+            .as_mut()
+        {
+            *filter = BloomFilter::new(0, filter.bits_per_key);
+        }
+    }
+
+    /// Rebuild our filter, if we have one, so that it no longer contains deleted keys and is
+    /// sized for our current keys.
+    pub(crate) async fn refresh_bloom_filter(&self) -> Result<()> {
+        let bits_per_key = self
+            .bloom
+            .read()
+            .expect("bloom filter lock poisoned")
+            .as_ref()
+            .map(|filter| filter.bits_per_key);
+        match bits_per_key {
+            Some(bits_per_key) => self.rebuild_bloom_filter(bits_per_key).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Rebuild our filter if it contains more keys than it was sized for.
+    pub(crate) async fn refresh_full_bloom_filter(&self) -> Result<()> {
+        let full = self
+            .bloom
+            .read()
+            .expect("bloom filter lock poisoned")
+            .as_ref()
+            .is_some_and(BloomFilter::is_full);
+        if full {
+            self.refresh_bloom_filter().await?;
+        }
+        Ok(())
+    }
+
+    /// Build a new filter from all our keys.
+    async fn rebuild_bloom_filter(&self, bits_per_key: usize) -> Result<()> {
+        tracing::info!("Building bloom filter for: {}", self.path.display());
+        // Holding our nodes lock stops keys being inserted until our new filter is in place
+        let nodes_lock = self.nodes.read().await;
+        let mut filter = BloomFilter::new(self.count().await, bits_per_key);
+        let mut pending = vec![*self.root.lock().await];
+        while let Some(idx) = pending.pop() {
+            let node = self
+                .find_node_with_lock_and_policy(&nodes_lock, idx, CachePolicy::Bypass)
+                .await?;
+            if node.is_leaf() {
+                node.keys().for_each(|key| filter.insert(key));
+            } else {
+                pending.extend(node.children());
+            }
+        }
+        *self.bloom.write().expect("bloom filter lock poisoned") = Some(filter);
+        Ok(())
+    }
+
+    /// Read our stored filter, if there is one, rebuilding it if it doesn't match our data file.
+    pub(crate) async fn read_bloom_filter(&self) -> Result<()> {
+        let s_file = match tokio::fs::read(bloom_path(&self.path)).await {
+            Ok(s_file) => s_file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let generation = self.file.lock().await.get_generation();
+        match BINCODER.deserialize::<BloomFile>(&s_file) {
+            Ok(file) if file.generation == generation && file.entries == self.count().await => {
+                *self.bloom.write().expect("bloom filter lock poisoned") = Some(file.filter);
+                Ok(())
+            }
+            Ok(file) => {
+                tracing::warn!("Bloom filter is out of date, rebuilding");
+                self.rebuild_bloom_filter(file.filter.bits_per_key).await
+            }
+            Err(e) => {
+                tracing::warn!("Could not read bloom filter, rebuilding: {e}");
+                self.rebuild_bloom_filter(DEFAULT_BITS_PER_KEY).await
+            }
+        }
+    }
+
+    /// Store our filter, if we have one, recording the state of our data file.
+    ///
+    /// Must only be called while our nodes match our data file, i.e.: straight after a flush,
+    /// while still holding our nodes lock.
+    pub(crate) async fn write_bloom_filter(&self, generation: u64, entries: usize) -> Result<()> {
+        let s_file = {
+            let bloom_lock = self.bloom.read().expect("bloom filter lock poisoned");
+            let Some(filter) = bloom_lock.as_ref() else {
+                return Ok(());
+            };
+            BINCODER.serialize(&BloomFile {
+                generation,
+                entries,
+                filter: filter.clone(),
+            })?
+        };
+        // Replace our stored filter atomically, so it is never partially written
+        let path = bloom_path(&self.path);
+        let mut tmp_path = path.clone();
+        tmp_path.set_extension("bloom.tmp");
+        tokio::fs::write(&tmp_path, s_file).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_filters_keys() {
+        let mut filter = BloomFilter::new(1000, 10);
+        for i in (0..2000).step_by(2) {
+            filter.insert(&i);
+        }
+        assert!(!filter.is_full());
+        assert!((0..2000).step_by(2).all(|i| filter.may_contain(&i)));
+        let false_positives = (1..2000)
+            .step_by(2)
+            .filter(|i| filter.may_contain(i))
+            .count();
+        assert!(false_positives < 50, "{false_positives} false positives");
+    }
+
+    #[tokio::test]
+    async fn it_stores_bloom_filter() {
+        let tree = Baildon::<usize, usize>::try_new("bloom_filter.db", 5)
+            .await
+            .expect("creates tree file");
+        for i in (0..200).step_by(2) {
+            tree.insert(i, i).await.expect("insert worked");
+        }
+        assert!(!tree.has_bloom_filter());
+        tree.enable_bloom_filter(10).await.expect("enables filter");
+        assert!(tree.has_bloom_filter());
+        for i in (200..2000).step_by(2) {
+            tree.insert(i, i).await.expect("insert worked");
+        }
+        for i in 0..2000 {
+            assert_eq!(tree.get(&i).await, (i % 2 == 0).then_some(i));
+        }
+        tree.close().await.expect("closes tree");
+
+        let tree = Baildon::<usize, usize>::try_open("bloom_filter.db")
+            .await
+            .expect("opens tree file");
+        assert!(tree.has_bloom_filter());
+        for i in 0..2000 {
+            assert_eq!(tree.contains(&i).await, i % 2 == 0);
+        }
+        // Keys which weren't flushed are added to our filter when our WAL is replayed
+        tree.insert(3, 3).await.expect("insert worked");
+        drop(tree);
+        let tree = Baildon::<usize, usize>::try_open("bloom_filter.db")
+            .await
+            .expect("opens tree file");
+        assert!(tree.contains(&3).await);
+        tree.close().await.expect("closes tree");
+
+        // Filters which can't be read are rebuilt
+        let path = bloom_path(Path::new("bloom_filter.db"));
+        std::fs::write(&path, b"noise").expect("overwrites filter");
+        let tree = Baildon::<usize, usize>::try_open("bloom_filter.db")
+            .await
+            .expect("opens tree file");
+        assert!(tree.has_bloom_filter());
+        for i in 0..2000 {
+            assert_eq!(tree.contains(&i).await, i % 2 == 0 || i == 3);
+        }
+        tree.disable_bloom_filter().await.expect("disables filter");
+        assert!(!path.exists());
+        tree.close().await.expect("closes tree");
+
+        let tree = Baildon::<usize, usize>::try_open("bloom_filter.db")
+            .await
+            .expect("opens tree file");
+        assert!(!tree.has_bloom_filter());
+        assert!(tree.contains(&3).await);
+        tree.close().await.expect("closes tree");
+        std::fs::remove_file("bloom_filter.db").expect("cleanup");
+    }
+}
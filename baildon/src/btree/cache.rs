@@ -0,0 +1,150 @@
+//! Node Cache
+//!
+//! Nodes are cached in a number of independently locked shards, keyed by node index, so
+//! concurrent operations on different nodes don't contend on a single lock.
+//!
+//! Shard locks are only ever held briefly and never across an await point.
+//!
+//! Nodes are shared, so reading a node doesn't copy it. Updating a node only copies it if a reader
+//! still holds it.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::baildon::BaildonKey;
+use super::baildon::BaildonValue;
+use super::node::Node;
+use super::sparse::BuildIdentityHasher;
+
+const SHARD_COUNT: usize = 16;
+
+type Shard<K, V> = HashMap<usize, Arc<Node<K, V>>, BuildIdentityHasher>;
+
+pub(crate) struct NodeCache<K, V> {
+    shards: Vec<Mutex<Shard<K, V>>>,
+}
+
+impl<K, V> NodeCache<K, V>
+where
+    K: BaildonKey,
+    V: BaildonValue,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            shards: (0..SHARD_COUNT)
+                .map(|_| Mutex::new(HashMap::default()))
+                .collect(),
+        }
+    }
+
+    fn shard(&self, idx: usize) -> MutexGuard<'_, Shard<K, V>> {
+        self.shards[idx % SHARD_COUNT]
+            .lock()
+            .expect("node cache shard lock poisoned")
+    }
+
+    fn shards(&self) -> impl Iterator<Item = MutexGuard<'_, Shard<K, V>>> {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().expect("node cache shard lock poisoned"))
+    }
+
+    pub(crate) fn contains(&self, idx: usize) -> bool {
+        self.shard(idx).contains_key(&idx)
+    }
+
+    pub(crate) fn get(&self, idx: usize) -> Option<Arc<Node<K, V>>> {
+        self.shard(idx).get(&idx).cloned()
+    }
+
+    pub(crate) fn insert(&self, idx: usize, node: Node<K, V>) -> Option<Arc<Node<K, V>>> {
+        self.shard(idx).insert(idx, Arc::new(node))
+    }
+
+    /// Insert a node, unless a node with that index is already cached.
+    pub(crate) fn insert_if_absent(&self, idx: usize, node: Arc<Node<K, V>>) {
+        self.shard(idx).entry(idx).or_insert(node);
+    }
+
+    pub(crate) fn remove(&self, idx: usize) -> Option<Arc<Node<K, V>>> {
+        self.shard(idx).remove(&idx)
+    }
+
+    /// Apply a function to a cached node, without copying it.
+    pub(crate) fn inspect<R>(&self, idx: usize, f: impl FnOnce(&Node<K, V>) -> R) -> Option<R> {
+        self.shard(idx).get(&idx).map(|node| f(node))
+    }
+
+    /// Apply a function to a cached node.
+    pub(crate) fn update<R>(&self, idx: usize, f: impl FnOnce(&mut Node<K, V>) -> R) -> Option<R> {
+        self.shard(idx)
+            .get_mut(&idx)
+            .map(|node| f(Arc::make_mut(node)))
+    }
+
+    pub(crate) fn clear(&self) {
+        for mut shard in self.shards() {
+            shard.clear();
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.shards().map(|shard| shard.len()).sum()
+    }
+
+    /// Mark all dirty nodes as clean and return them.
+    pub(crate) fn dirty_nodes(&self) -> Vec<Arc<Node<K, V>>> {
+        let mut dirty = vec![];
+        for mut shard in self.shards() {
+            for node in shard.values_mut().filter(|n| !n.clean()) {
+                Arc::make_mut(node).set_clean(true);
+                dirty.push(node.clone());
+            }
+        }
+        dirty
+    }
+
+    /// Return all cached nodes.
+    pub(crate) fn nodes(&self) -> Vec<Arc<Node<K, V>>> {
+        self.shards()
+            .flat_map(|shard| shard.values().cloned().collect::<Vec<_>>())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_tracks_dirty_nodes() {
+        let cache = NodeCache::<usize, usize>::new();
+        for idx in 1..40 {
+            let mut node = Node::root(4);
+            node.set_index(idx);
+            node.set_clean(idx % 2 == 0);
+            cache.insert(idx, node);
+        }
+        assert_eq!(cache.len(), 39);
+        assert_eq!(cache.dirty_nodes().len(), 20);
+        assert!(cache.dirty_nodes().is_empty());
+        assert!(cache.nodes().iter().all(|node| node.clean()));
+        cache.clear();
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn it_shares_nodes() {
+        let cache = NodeCache::<usize, usize>::new();
+        cache.insert(1, Node::root(4));
+        let first = cache.get(1).expect("cached");
+        assert!(Arc::ptr_eq(&first, &cache.get(1).expect("cached")));
+
+        // Updates don't affect readers
+        cache.update(1, |node| node.set_value(&1, 1));
+        let second = cache.get(1).expect("cached");
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(first.value(&1).expect("decodes"), None);
+        assert_eq!(second.value(&1).expect("decodes"), Some(1));
+    }
+}
@@ -0,0 +1,143 @@
+//! Compaction
+//!
+//! As nodes are freed, or migrated to larger blocks, unused space accumulates within our data
+//! file. Compaction rewrites the data file so that all the blocks are contiguous.
+//!
+//! Compaction may be performed manually or scheduled in the background whenever fragmentation
+//! crosses a threshold.
+
+use std::sync::{Arc, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tokio::task::JoinHandle;
+
+use super::baildon::Baildon;
+use super::baildon::BaildonKey;
+use super::baildon::BaildonValue;
+
+/// Options which control background compaction.
+#[derive(Clone, Copy, Debug)]
+pub struct CompactionOptions {
+    /// Compact when the fraction of unused space in the data file exceeds this threshold.
+    pub threshold: f64,
+    /// How often to check fragmentation.
+    pub check_interval: Duration,
+    /// Minimum time between compactions, so that foreground operations aren't starved.
+    pub min_interval: Duration,
+}
+
+impl Default for CompactionOptions {
+    fn default() -> Self {
+        Self {
+            threshold: 0.5,
+            check_interval: Duration::from_secs(10),
+            min_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Progress of background compaction.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CompactionProgress {
+    /// Is background compaction running.
+    pub running: bool,
+    /// Number of compactions performed.
+    pub compactions: u64,
+    /// Total number of bytes reclaimed.
+    pub bytes_reclaimed: u64,
+    /// Fragmentation at the last check.
+    pub fragmentation: f64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct CompactionState {
+    handle: Option<JoinHandle<()>>,
+    progress: CompactionProgress,
+}
+
+impl<K, V> Baildon<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    /// Return the fraction of our data file which is unused.
+    pub async fn fragmentation(&self) -> f64 {
+        self.file.lock().await.fragmentation()
+    }
+
+    /// Compact our data file, returning the number of bytes reclaimed.
+    pub async fn compact(&self) -> Result<u64> {
+        self.check_writable()?;
+        let reclaimed = self.file.lock().await.compact().await?;
+        tracing::info!(reclaimed, "compacted B+Tree");
+        // Our stored filter still matches our data file, so it is replaced at our next flush
+        self.refresh_bloom_filter().await?;
+        Ok(reclaimed)
+    }
+
+    /// Start compacting in the background whenever fragmentation exceeds our threshold.
+    ///
+    /// Any existing background compaction is stopped first.
+    pub fn start_compaction(self: &Arc<Self>, options: CompactionOptions)
+    where
+        K: 'static,
+        V: 'static,
+    {
+        let handle = tokio::spawn(Self::compaction_task(Arc::downgrade(self), options));
+        let mut state = self.compaction_state();
+        if let Some(old) = state.handle.replace(handle) {
+            old.abort();
+        }
+        state.progress.running = true;
+    }
+
+    /// Stop background compaction.
+    pub fn stop_compaction(&self) {
+        let mut state = self.compaction_state();
+        if let Some(handle) = state.handle.take() {
+            handle.abort();
+        }
+        state.progress.running = false;
+    }
+
+    /// Return background compaction progress.
+    pub fn compaction_progress(&self) -> CompactionProgress {
+        self.compaction_state().progress
+    }
+
+    fn compaction_state(&self) -> MutexGuard<'_, CompactionState> {
+        self.compaction
+            .lock()
+            .expect("compaction state lock poisoned")
+    }
+
+    async fn compaction_task(tree: Weak<Self>, options: CompactionOptions) {
+        let mut timer = tokio::time::interval(options.check_interval);
+        let mut last_compaction: Option<Instant> = None;
+        loop {
+            timer.tick().await;
+            // If our tree has gone away, there's nothing left to do
+            let Some(this) = tree.upgrade() else {
+                break;
+            };
+            let fragmentation = this.fragmentation().await;
+            this.compaction_state().progress.fragmentation = fragmentation;
+            if fragmentation < options.threshold {
+                continue;
+            }
+            if last_compaction.is_some_and(|last| last.elapsed() < options.min_interval) {
+                continue;
+            }
+            match this.compact().await {
+                Ok(reclaimed) => {
+                    let mut state = this.compaction_state();
+                    state.progress.compactions += 1;
+                    state.progress.bytes_reclaimed += reclaimed;
+                }
+                Err(e) => tracing::error!("background compaction failed: {e}"),
+            }
+            last_compaction = Some(Instant::now());
+        }
+    }
+}
@@ -0,0 +1,290 @@
+//! Conversion
+//!
+//! Move entries between in-memory collections and trees.
+
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use anyhow::Result;
+use futures::StreamExt;
+
+use super::baildon::Baildon;
+use super::baildon::BaildonError;
+use super::baildon::BaildonKey;
+use super::baildon::BaildonValue;
+use super::baildon::Direction;
+use super::bloom::remove_bloom_file;
+use super::node::Node;
+
+/// Remove the files of a tree which couldn't be loaded, so that its path can be used again.
+async fn remove_tree_files(path: &Path) -> Result<()> {
+    for path in [path.to_path_buf(), path.with_extension("wal")] {
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+    }
+    remove_bloom_file(path).await
+}
+
+/// Percentage of the capacity of each node which is filled by a bulk load, leaving room for
+/// later inserts.
+const BULK_LOAD_FILL: usize = 90;
+
+impl<K, V> Baildon<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    /// Create a new store at the specified path, with the specified branching factor, containing
+    /// the supplied entries.
+    ///
+    /// The entries are flushed to disk before the tree is returned.
+    pub async fn from_entries<P, I>(origin: P, branch: u64, entries: I) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let tree = Self::try_new(origin, branch).await?;
+        tree.extend(entries).await?;
+        tree.flush_to_disk().await?;
+        Ok(tree)
+    }
+
+    /// Create a new store at the specified path, with the specified branching factor, containing
+    /// the entries of a map.
+    pub async fn from_btreemap<P: AsRef<Path>>(
+        origin: P,
+        branch: u64,
+        map: BTreeMap<K, V>,
+    ) -> Result<Self> {
+        Self::from_entries(origin, branch, map).await
+    }
+
+    /// Create a new store at the specified path, with the specified branching factor, containing
+    /// the supplied entries, which must be in strictly ascending order of key.
+    ///
+    /// Rather than inserting entries one at a time, nodes are filled and the tree is built from
+    /// its leaves up, so this is much faster than [`Baildon::from_entries`]. Entries are not
+    /// written to our WAL, but the tree is flushed to disk before it is returned.
+    pub async fn bulk_load<P, I>(origin: P, branch: u64, entries: I) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        // Check our order before creating any files, and remove them if any entry is too large
+        let mut sorted: Vec<(K, V)> = vec![];
+        for (position, (key, value)) in entries.into_iter().enumerate() {
+            if sorted.last().is_some_and(|(last, _)| *last >= key) {
+                return Err(BaildonError::UnsortedEntries(position).into());
+            }
+            sorted.push((key, value));
+        }
+        let path = origin.as_ref();
+        let tree = Self::try_new(path, branch).await?;
+        if let Err(e) = sorted
+            .iter()
+            .try_for_each(|(key, value)| tree.check_sizes(key, value))
+        {
+            tree.close().await?;
+            remove_tree_files(path).await?;
+            return Err(e);
+        }
+        if !sorted.is_empty() {
+            tree.build(sorted).await;
+        }
+        tree.flush_to_disk().await?;
+        Ok(tree)
+    }
+
+    /// Replace our empty root with a tree built from sorted entries.
+    async fn build(&self, sorted: Vec<(K, V)>) {
+        let branch = self.branch();
+        let count = sorted.len();
+        let nodes_lock = self.nodes.write().await;
+        // Our first leaf takes over the index of our empty root
+        self.index.store(1, Ordering::SeqCst);
+
+        let mut entries = sorted.into_iter();
+        let mut level: Vec<Node<K, V>> = vec![];
+        for size in node_sizes(count, branch) {
+            let (keys, values) = entries.by_ref().take(size).unzip();
+            let mut leaf = Node::leaf(branch, None, keys, values);
+            leaf.set_index(self.index.fetch_add(1, Ordering::SeqCst));
+            if let Some(previous) = level.last_mut() {
+                previous.set_sibling(Direction::Ascending, Some(leaf.index()));
+                leaf.set_sibling(Direction::Descending, Some(previous.index()));
+            }
+            level.push(leaf);
+        }
+
+        let mut depth = 1;
+        while level.len() > 1 {
+            let mut parents = vec![];
+            let sizes = node_sizes(level.len(), branch);
+            let mut children = level.into_iter();
+            for size in sizes {
+                let idx = self.index.fetch_add(1, Ordering::SeqCst);
+                let mut keys = Vec::with_capacity(size);
+                let mut indices = Vec::with_capacity(size);
+                for mut child in children.by_ref().take(size) {
+                    keys.push(child.max_key().clone());
+                    indices.push(child.index());
+                    child.set_parent(Some(idx));
+                    nodes_lock.insert(child.index(), child);
+                }
+                let mut parent = Node::internal(branch, None, keys, indices);
+                parent.set_index(idx);
+                parents.push(parent);
+            }
+            level = parents;
+            depth += 1;
+        }
+
+        let root = level.pop().expect("THERE IS A ROOT");
+        *self.root.lock().await = root.index();
+        nodes_lock.insert(root.index(), root);
+        self.set_stats(count, depth);
+    }
+
+    /// Insert all the supplied entries, replacing the values of any existing keys.
+    pub async fn extend<I>(&self, entries: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        for (key, value) in entries {
+            self.insert(key, value).await?;
+        }
+        Ok(())
+    }
+
+    /// Copy all our entries into a map.
+    pub async fn to_btreemap(&self) -> BTreeMap<K, V> {
+        self.entries(Direction::Ascending).await.collect().await
+    }
+}
+
+/// Return the sizes of the nodes needed to hold a level of the specified length.
+///
+/// Sizes are spread evenly, so that the last node isn't left below minimum.
+fn node_sizes(len: usize, branch: u64) -> Vec<usize> {
+    let branch = branch as usize;
+    let fill = (branch * BULK_LOAD_FILL / 100).max(2);
+    let mut nodes = len.div_ceil(fill).max(1);
+    while nodes > 1 && len / nodes < branch / 2 {
+        nodes -= 1;
+    }
+    (0..nodes)
+        .map(|node| len / nodes + usize::from(node < len % nodes))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_converts_btreemap() {
+        let map = (0..200)
+            .map(|i| (i, i * 2))
+            .collect::<BTreeMap<usize, usize>>();
+        let tree = Baildon::from_btreemap("convert_btreemap.db", 5, map.clone())
+            .await
+            .expect("creates tree file");
+        assert_eq!(tree.count().await, 200);
+        assert_eq!(tree.to_btreemap().await, map);
+
+        tree.extend((200..300).map(|i| (i, i * 2)))
+            .await
+            .expect("extends tree");
+        assert_eq!(tree.to_btreemap().await.len(), 300);
+        tree.close().await.expect("closes tree");
+        std::fs::remove_file("convert_btreemap.db").expect("cleanup");
+    }
+
+    #[tokio::test]
+    async fn it_bulk_loads_entries() {
+        for branch in [2, 5, 64] {
+            for count in [0, 1, 2, 3, 7, 100, 1000] {
+                let tree =
+                    Baildon::bulk_load("bulk_load.db", branch, (0..count).map(|i| (i, i * 2)))
+                        .await
+                        .expect("bulk loads tree");
+                assert_eq!(tree.count().await, count);
+                assert!(tree
+                    .verify(Direction::Ascending)
+                    .await
+                    .expect("verifies tree")
+                    .is_ok());
+                tree.close().await.expect("closes tree");
+
+                let tree = Baildon::<usize, usize>::try_open("bulk_load.db")
+                    .await
+                    .expect("opens tree file");
+                assert_eq!(tree.count().await, count);
+                assert_eq!(
+                    tree.get(&(count / 2)).await,
+                    (count > 0).then_some(count / 2 * 2)
+                );
+                let keys = tree
+                    .keys(Direction::Descending)
+                    .await
+                    .collect::<Vec<usize>>()
+                    .await;
+                assert_eq!(keys, (0..count).rev().collect::<Vec<usize>>());
+                // Our tree is an ordinary tree
+                tree.extend((count..count + 50).map(|i| (i, i * 2)))
+                    .await
+                    .expect("extends tree");
+                for i in (0..count + 50).step_by(3) {
+                    tree.delete(&i).await.expect("delete worked");
+                }
+                assert!(tree
+                    .verify(Direction::Ascending)
+                    .await
+                    .expect("verifies tree")
+                    .is_ok());
+                tree.close().await.expect("closes tree");
+                std::fs::remove_file("bulk_load.db").expect("cleanup");
+            }
+        }
+
+        let err = Baildon::bulk_load("bulk_load_unsorted.db", 5, [(1, 1), (3, 3), (2, 2)])
+            .await
+            .err()
+            .expect("entries are unsorted");
+        assert!(matches!(
+            err.downcast_ref::<BaildonError>(),
+            Some(BaildonError::UnsortedEntries(2))
+        ));
+        // No files are left behind, so the path can still be loaded
+        for path in ["bulk_load_unsorted.db", "bulk_load_unsorted.wal"] {
+            assert!(!Path::new(path).exists(), "{path} exists");
+        }
+        let tree = Baildon::bulk_load("bulk_load_unsorted.db", 5, [(1, 1), (2, 2)])
+            .await
+            .expect("loads tree");
+        assert_eq!(tree.count().await, 2);
+        tree.close().await.expect("closes tree");
+        std::fs::remove_file("bulk_load_unsorted.db").expect("cleanup");
+    }
+
+    #[test]
+    fn it_sizes_nodes() {
+        assert_eq!(node_sizes(0, 5), vec![0]);
+        assert_eq!(node_sizes(3, 2), vec![2, 1]);
+        assert_eq!(node_sizes(3, 5), vec![3]);
+        assert_eq!(node_sizes(5, 5), vec![3, 2]);
+        assert_eq!(node_sizes(90, 100), vec![90]);
+        assert_eq!(node_sizes(100, 100), vec![50, 50]);
+        assert_eq!(node_sizes(101, 100), vec![51, 50]);
+        for len in 1..500 {
+            let sizes = node_sizes(len, 64);
+            assert_eq!(sizes.iter().sum::<usize>(), len);
+            assert!(sizes.iter().all(|size| *size <= 64));
+            assert!(sizes.len() == 1 || sizes.iter().all(|size| *size >= 32));
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Crash Points
+//!
+//! Named points at which a tree can be made to fail mid-operation, as if the process had crashed,
+//! so that tests can check what is recovered when the tree is next opened.
+//!
+//! Crash points can only be armed in tests, or when the `crash-points` feature is enabled.
+//!
+//! Once a crash point has been triggered, the tree must be dropped without being closed and then
+//! opened again. Recovery guarantees, by crash point:
+//!  - `AfterWalAppend`: every modification appended to the WAL is recovered
+//!  - `AfterNodeWrite`, `BeforeFooterWrite`: a flush which only updates entries in place is
+//!    recovered. A flush which splits or merges nodes may leave a tree which can only be
+//!    recovered with [`Baildon::repair`](crate::btree::Baildon::repair).
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use strum::Display;
+
+use super::baildon::BaildonError;
+#[cfg(any(test, feature = "crash-points"))]
+use super::baildon::{Baildon, BaildonKey, BaildonValue};
+
+const CRASH_POINTS_ENABLED: bool = cfg!(any(test, feature = "crash-points"));
+
+/// A point at which a tree may be made to crash.
+#[derive(Clone, Copy, Debug, Display, Eq, Hash, PartialEq)]
+pub enum CrashPoint {
+    /// After a modification is appended to the WAL, but before it is applied to the tree.
+    AfterWalAppend,
+    /// During a flush, after a node, or a run of nodes in adjacent blocks, is written to disk.
+    AfterNodeWrite,
+    /// During a flush, after all nodes are written to disk, but before the header and footer.
+    BeforeFooterWrite,
+}
+
+/// The crash points armed for a tree.
+#[derive(Debug, Default)]
+pub(crate) struct CrashPoints {
+    armed: Mutex<HashSet<CrashPoint>>,
+}
+
+impl CrashPoints {
+    #[cfg(any(test, feature = "crash-points"))]
+    fn arm(&self, point: CrashPoint) {
+        self.lock().insert(point);
+    }
+
+    #[cfg(any(test, feature = "crash-points"))]
+    fn disarm(&self, point: CrashPoint) {
+        self.lock().remove(&point);
+    }
+
+    /// Fail if our crash point is armed. A crash point is disarmed once it is triggered.
+    pub(crate) fn trigger(&self, point: CrashPoint) -> Result<()> {
+        if CRASH_POINTS_ENABLED && self.lock().remove(&point) {
+            tracing::warn!("triggered crash point: {point}");
+            return Err(BaildonError::Crashed(point).into());
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashSet<CrashPoint>> {
+        self.armed.lock().expect("crash points lock poisoned")
+    }
+}
+
+#[cfg(any(test, feature = "crash-points"))]
+impl<K, V> Baildon<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    /// Arm a crash point. The next operation to reach it fails with [`BaildonError::Crashed`].
+    pub fn arm_crash_point(&self, point: CrashPoint) {
+        self.crash_points.arm(point);
+    }
+
+    /// Disarm a crash point.
+    pub fn disarm_crash_point(&self, point: CrashPoint) {
+        self.crash_points.disarm(point);
+    }
+}
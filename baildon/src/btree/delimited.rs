@@ -0,0 +1,124 @@
+//! Delimited Files
+//!
+//! Import and export entries as CSV, or any other delimited format supported by the csv crate.
+//! Readers and writers are supplied by the caller, so delimiters, quoting and headers can be
+//! configured with [`csv::ReaderBuilder`] and [`csv::WriterBuilder`].
+//!
+//! Keys and values are converted with serde, so a key is read from a single field and a value may
+//! be read from one field (e.g.: a `String`) or several (e.g.: a tuple or a struct).
+
+use std::io::Read;
+use std::io::Write;
+
+use anyhow::Result;
+use csv::StringRecord;
+use futures::StreamExt;
+
+use super::baildon::Baildon;
+use super::baildon::BaildonError;
+use super::baildon::BaildonKey;
+use super::baildon::BaildonValue;
+use super::baildon::Direction;
+
+impl<K, V> Baildon<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    /// Insert an entry for each record, returning the number of records read.
+    ///
+    /// The key is read from the `key_col` field of each record and the value from the
+    /// `value_cols` fields, in the order specified. Fields are numbered from 0.
+    ///
+    /// Entries are inserted as they are read, so if a record is invalid, some entries may already
+    /// have been inserted.
+    pub async fn import_csv<R: Read>(
+        &self,
+        reader: &mut csv::Reader<R>,
+        key_col: usize,
+        value_cols: &[usize],
+    ) -> Result<u64> {
+        let mut count = 0;
+        for record in reader.records() {
+            let record = record?;
+            let field = |col: usize| {
+                record.get(col).ok_or_else(|| {
+                    BaildonError::InvalidRecord(format!(
+                        "{}: has no field: {col}",
+                        describe(&record)
+                    ))
+                })
+            };
+            let key: K = StringRecord::from(vec![field(key_col)?]).deserialize(None)?;
+            let value: V = value_cols
+                .iter()
+                .map(|col| field(*col))
+                .collect::<Result<StringRecord, BaildonError>>()?
+                .deserialize(None)?;
+            self.insert(key, value).await?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Write a record for each of our entries, returning the number of entries written.
+    ///
+    /// Each record contains the fields of the key, followed by the fields of the value.
+    pub async fn export_csv<W: Write>(&self, writer: &mut csv::Writer<W>) -> Result<u64> {
+        let mut count = 0;
+        let mut entries = self.entries(Direction::Ascending).await;
+        while let Some(entry) = entries.next().await {
+            writer.serialize(entry)?;
+            count += 1;
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+}
+
+/// Describe the position of a record, for errors.
+fn describe(record: &StringRecord) -> String {
+    match record.position() {
+        Some(position) => format!("record at line: {}", position.line()),
+        None => "record".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_imports_and_exports_csv() {
+        let tree = Baildon::<String, (String, u32)>::temporary(5)
+            .await
+            .expect("creates tree file");
+        let input = "id,name,team,age\n\
+                     2,alice,red,31\n\
+                     1,bob,blue,42\n";
+        let mut reader = csv::Reader::from_reader(input.as_bytes());
+        let count = tree
+            .import_csv(&mut reader, 0, &[1, 3])
+            .await
+            .expect("imports csv");
+        assert_eq!(count, 2);
+        assert_eq!(
+            tree.get(&"2".to_string()).await,
+            Some(("alice".to_string(), 31))
+        );
+
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(vec![]);
+        let count = tree.export_csv(&mut writer).await.expect("exports csv");
+        assert_eq!(count, 2);
+        let output = String::from_utf8(writer.into_inner().expect("flushed")).expect("utf8");
+        assert_eq!(output, "1,bob,42\n2,alice,31\n");
+
+        // Records may have fewer fields than our header
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .from_reader("id,name\n3\n".as_bytes());
+        assert!(tree.import_csv(&mut reader, 0, &[1]).await.is_err());
+    }
+}
@@ -0,0 +1,357 @@
+//! Column Families
+//!
+//! Multiple independent keyspaces, stored in one tree. Each key is prefixed by the name of its
+//! family, so all of the families share a data file, a WAL and a node cache.
+
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::Result;
+use futures::Stream;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use super::baildon::Baildon;
+use super::baildon::BaildonKey;
+use super::baildon::BaildonValue;
+use super::baildon::Direction;
+
+/// A key within a column family.
+///
+/// Keys are ordered by family and then by key, so the keys of a family are adjacent.
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(bound(serialize = "K: Serialize", deserialize = "K: Deserialize<'de>"))]
+pub struct FamilyKey<K> {
+    family: String,
+    // Only the bounds of families, which are never stored, don't have a key
+    #[serde(with = "stored_key")]
+    key: Option<K>,
+}
+
+impl<K> FamilyKey<K> {
+    /// Create a key within a family.
+    pub fn new(family: &str, key: K) -> Self {
+        Self {
+            family: family.to_string(),
+            key: Some(key),
+        }
+    }
+
+    /// The keys of a family lie between the start of the family and the start of the next
+    /// possible family.
+    fn bounds(family: &str) -> Range<Self> {
+        let start = |family: String| Self { family, key: None };
+        start(family.to_string())..start(format!("{family}\0"))
+    }
+
+    /// Return the name of our family.
+    pub fn family(&self) -> &str {
+        &self.family
+    }
+
+    /// Return a reference to our key.
+    pub fn key(&self) -> &K {
+        self.key.as_ref().expect("FAMILY KEY HAS KEY")
+    }
+
+    /// Unwrap our key.
+    pub fn into_key(self) -> K {
+        self.key.expect("FAMILY KEY HAS KEY")
+    }
+}
+
+/// Keys are stored without an option, since only keys with a key are stored.
+mod stored_key {
+    use serde::de::Deserializer;
+    use serde::ser::Error;
+    use serde::ser::Serializer;
+    use serde::Deserialize;
+    use serde::Serialize;
+
+    pub(super) fn serialize<K: Serialize, S: Serializer>(
+        key: &Option<K>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match key {
+            Some(key) => key.serialize(serializer),
+            None => Err(S::Error::custom("family bounds can't be stored")),
+        }
+    }
+
+    pub(super) fn deserialize<'de, K: Deserialize<'de>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<K>, D::Error> {
+        K::deserialize(deserializer).map(Some)
+    }
+}
+
+/// A persistent collection of named keyspaces, which share one tree.
+pub struct BaildonFamilies<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    tree: Baildon<FamilyKey<K>, V>,
+}
+
+impl<K, V> BaildonFamilies<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    /// Create new families at the specified path with the specified branching factor.
+    pub async fn try_new<P: AsRef<Path>>(origin: P, branch: u64) -> Result<Self> {
+        Baildon::try_new(origin, branch).await.map(Self::from)
+    }
+
+    /// Open existing families at the specified path.
+    pub async fn try_open<P: AsRef<Path>>(origin: P) -> Result<Self> {
+        Baildon::try_open(origin).await.map(Self::from)
+    }
+
+    /// Open existing families at the specified path, creating them if they don't exist.
+    pub async fn open_or_create<P: AsRef<Path>>(origin: P, branch: u64) -> Result<Self> {
+        Baildon::open_or_create(origin, branch)
+            .await
+            .map(Self::from)
+    }
+
+    /// Create new families, with the specified branching factor, in the temporary directory.
+    pub async fn temporary(branch: u64) -> Result<Self> {
+        Baildon::temporary(branch).await.map(Self::from)
+    }
+
+    /// Close our families, flushing all our updated nodes to disk.
+    pub async fn close(self) -> Result<()> {
+        self.tree.close().await
+    }
+
+    /// Return the tree which stores our families.
+    pub fn tree(&self) -> &Baildon<FamilyKey<K>, V> {
+        &self.tree
+    }
+
+    /// Unwrap the tree which stores our families.
+    pub fn into_tree(self) -> Baildon<FamilyKey<K>, V> {
+        self.tree
+    }
+
+    /// Open the named column family. Families don't need to be created: a family without any
+    /// entries is empty.
+    pub fn open_cf(&self, name: &str) -> ColumnFamily<'_, K, V> {
+        ColumnFamily {
+            tree: &self.tree,
+            name: name.to_string(),
+        }
+    }
+}
+
+impl<K, V> From<Baildon<FamilyKey<K>, V>> for BaildonFamilies<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    fn from(tree: Baildon<FamilyKey<K>, V>) -> Self {
+        Self { tree }
+    }
+}
+
+/// A named keyspace within a [`BaildonFamilies`].
+pub struct ColumnFamily<'a, K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    tree: &'a Baildon<FamilyKey<K>, V>,
+    name: String,
+}
+
+impl<K, V> ColumnFamily<'_, K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    /// Return the name of our family.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Insert a key and value, returning the previous value of the key.
+    pub async fn insert(&self, key: K, value: V) -> Result<Option<V>> {
+        self.tree
+            .insert(FamilyKey::new(&self.name, key), value)
+            .await
+    }
+
+    /// Return the value of a key.
+    pub async fn get(&self, key: &K) -> Option<V> {
+        self.tree
+            .get(&FamilyKey::new(&self.name, key.clone()))
+            .await
+    }
+
+    /// Does our family contain this key?
+    pub async fn contains(&self, key: &K) -> bool {
+        self.tree
+            .contains(&FamilyKey::new(&self.name, key.clone()))
+            .await
+    }
+
+    /// Delete a key, returning its value.
+    pub async fn delete(&self, key: &K) -> Result<Option<V>> {
+        self.tree
+            .delete(&FamilyKey::new(&self.name, key.clone()))
+            .await
+    }
+
+    /// Return a stream of our entries.
+    ///
+    /// Streaming starts from the leaf containing the start of our family (or the end, when
+    /// descending), so entries of other families aren't visited.
+    pub async fn entries(&self, direction: Direction) -> impl Stream<Item = (K, V)> + '_ {
+        self.tree
+            .range(FamilyKey::bounds(&self.name), direction)
+            .await
+            .map(|(key, value)| (key.into_key(), value))
+    }
+
+    /// Return a stream of our keys.
+    pub async fn keys(&self, direction: Direction) -> impl Stream<Item = K> + '_ {
+        self.tree
+            .range_keys(FamilyKey::bounds(&self.name), direction)
+            .await
+            .map(FamilyKey::into_key)
+    }
+
+    /// Return the number of entries in our family.
+    ///
+    /// Keys are counted while streaming.
+    pub async fn len(&self) -> usize {
+        self.keys(Direction::Ascending).await.count().await
+    }
+
+    /// Is our family empty?
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// Delete all of our entries, returning how many were deleted.
+    pub async fn clear(&self) -> Result<usize> {
+        let keys = self
+            .keys(Direction::Ascending)
+            .await
+            .collect::<Vec<K>>()
+            .await;
+        for key in &keys {
+            self.delete(key).await?;
+        }
+        Ok(keys.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_separates_families() {
+        let families = BaildonFamilies::<usize, String>::temporary(5)
+            .await
+            .expect("creates families file");
+        let odd = families.open_cf("odd");
+        let even = families.open_cf("even");
+        for i in 0..100 {
+            let family = if i % 2 == 0 { &even } else { &odd };
+            family
+                .insert(i, i.to_string())
+                .await
+                .expect("insert worked");
+        }
+        assert_eq!(odd.len().await, 50);
+        assert_eq!(even.get(&42).await, Some("42".to_string()));
+        assert!(!odd.contains(&42).await);
+        assert!(families.open_cf("none").is_empty().await);
+
+        let keys = odd
+            .keys(Direction::Descending)
+            .await
+            .take(3)
+            .collect::<Vec<usize>>()
+            .await;
+        assert_eq!(keys, vec![99, 97, 95]);
+        let keys = even
+            .keys(Direction::Ascending)
+            .await
+            .take(3)
+            .collect::<Vec<usize>>()
+            .await;
+        assert_eq!(keys, vec![0, 2, 4]);
+
+        assert_eq!(
+            even.delete(&42).await.expect("delete worked"),
+            Some("42".to_string())
+        );
+        assert_eq!(even.clear().await.expect("clear worked"), 49);
+        assert!(even.is_empty().await);
+        assert_eq!(odd.len().await, 50);
+        assert_eq!(families.tree().count().await, 50);
+    }
+
+    #[tokio::test]
+    async fn it_seeks_to_families() {
+        let families = BaildonFamilies::<usize, usize>::temporary(4)
+            .await
+            .expect("creates families file");
+        let names = ["a", "b", "c", "d"];
+        for name in names {
+            let family = families.open_cf(name);
+            for i in 0..100 {
+                family.insert(i, i).await.expect("insert worked");
+            }
+        }
+        // Family names which extend others sort between them and the next family
+        families
+            .open_cf("a0")
+            .insert(0, 0)
+            .await
+            .expect("insert worked");
+
+        let visited = |families: &BaildonFamilies<usize, usize>| {
+            let metrics = families.tree().metrics();
+            metrics.cache_hits + metrics.cache_misses
+        };
+        let before = visited(&families);
+        let count = families
+            .tree()
+            .keys(Direction::Ascending)
+            .await
+            .count()
+            .await;
+        assert_eq!(count, 401);
+        let all = visited(&families) - before;
+
+        // Streaming our last family doesn't visit the leaves of the others
+        let last = families.open_cf("d");
+        let before = visited(&families);
+        assert_eq!(last.len().await, 100);
+        assert!(visited(&families) - before < all / 2);
+        let before = visited(&families);
+        let keys = last
+            .keys(Direction::Descending)
+            .await
+            .take(2)
+            .collect::<Vec<usize>>()
+            .await;
+        assert_eq!(keys, vec![99, 98]);
+        assert!(visited(&families) - before < all / 4);
+
+        assert_eq!(families.open_cf("a").len().await, 100);
+        assert_eq!(families.open_cf("a0").len().await, 1);
+        assert_eq!(
+            families.open_cf("c").clear().await.expect("clear worked"),
+            100
+        );
+        assert_eq!(families.open_cf("b").len().await, 100);
+        assert!(families.open_cf("c").is_empty().await);
+    }
+}
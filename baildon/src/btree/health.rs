@@ -0,0 +1,186 @@
+//! Health Checks
+//!
+//! A quick check of a tree, suitable for readiness probes. Unlike verification, which visits
+//! every node, a health check only reads our file header and footer and our root node.
+
+use std::fmt;
+
+use anyhow::Result;
+use thiserror::Error;
+
+use super::baildon::Baildon;
+use super::baildon::BaildonKey;
+use super::baildon::BaildonValue;
+use super::baildon::CachePolicy;
+
+/// Overall health of a tree, from best to worst.
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+pub enum HealthStatus {
+    /// No issues were found.
+    #[default]
+    Healthy,
+    /// Our tree is usable, but has issues which will be resolved by flushing.
+    Degraded,
+    /// Our tree can't be relied on.
+    Unhealthy,
+}
+
+/// An issue found by a health check.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum HealthIssue {
+    /// Our file header or footer can't be read, or doesn't describe our file
+    #[error("invalid header: {0}")]
+    InvalidHeader(String),
+
+    /// Data blocks in our file are corrupt
+    #[error("corrupt blocks: {0}")]
+    CorruptBlocks(String),
+
+    /// Our root node can't be read
+    #[error("root: {root} can't be read: {reason}")]
+    UnreachableRoot {
+        /// Root index
+        root: usize,
+        /// Why the root can't be read
+        reason: String,
+    },
+
+    /// Our root node has a parent
+    #[error("root: {0} has a parent")]
+    RootHasParent(usize),
+
+    /// An update of our data file was started, but hasn't been completed
+    #[error("update of data file is incomplete")]
+    UpdateIncomplete,
+}
+
+impl HealthIssue {
+    /// How serious is this issue?
+    pub fn status(&self) -> HealthStatus {
+        match self {
+            HealthIssue::UpdateIncomplete => HealthStatus::Degraded,
+            _ => HealthStatus::Unhealthy,
+        }
+    }
+}
+
+/// The result of a health check.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HealthReport {
+    /// Overall health, which is the status of our most serious issue.
+    pub status: HealthStatus,
+    /// Is there a WAL, containing modifications which haven't been flushed?
+    pub wal_present: bool,
+    /// Issues found.
+    pub issues: Vec<HealthIssue>,
+}
+
+impl HealthReport {
+    /// Was our tree healthy?
+    pub fn is_healthy(&self) -> bool {
+        self.status == HealthStatus::Healthy
+    }
+
+    fn push(&mut self, issue: HealthIssue) {
+        self.status = self.status.max(issue.status());
+        self.issues.push(issue);
+    }
+}
+
+impl fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "status: {:?}, wal present: {}",
+            self.status, self.wal_present
+        )?;
+        for issue in &self.issues {
+            write!(f, "\n  {issue}")?;
+        }
+        Ok(())
+    }
+}
+
+impl<K, V> Baildon<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    /// Check the health of our tree, without traversing it.
+    ///
+    /// Issues are reported, not treated as errors. An error is only returned if the presence of
+    /// our WAL can't be determined.
+    pub async fn health_check(&self) -> Result<HealthReport> {
+        let mut report = HealthReport::default();
+
+        {
+            let mut file_lock = self.file.lock().await;
+            if let Err(e) = file_lock.check_header().await {
+                report.push(HealthIssue::InvalidHeader(e.to_string()));
+            }
+            if let Err(e) = file_lock.validate() {
+                report.push(HealthIssue::CorruptBlocks(e.to_string()));
+            }
+            // Readers may see a writer's update in progress
+            if !self.read_only() && file_lock.update_incomplete() {
+                report.push(HealthIssue::UpdateIncomplete);
+            }
+        }
+
+        let root = *self.root.lock().await;
+        let nodes_lock = self.nodes.read().await;
+        match self
+            .find_node_with_lock_and_policy(&nodes_lock, root, CachePolicy::Bypass)
+            .await
+        {
+            Ok(node) if node.parent().is_some() => report.push(HealthIssue::RootHasParent(root)),
+            Ok(_node) => (),
+            Err(e) => report.push(HealthIssue::UnreachableRoot {
+                root,
+                reason: e.to_string(),
+            }),
+        }
+        drop(nodes_lock);
+
+        let mut wal_path = self.path.clone();
+        wal_path.set_extension("wal");
+        report.wal_present = tokio::fs::try_exists(&wal_path).await?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn it_checks_health() {
+        let tree = Baildon::<usize, usize>::temporary(5)
+            .await
+            .expect("creates tree file");
+        for i in 0..100 {
+            tree.insert(i, i).await.expect("insert worked");
+        }
+        let report = tree.health_check().await.expect("checks health");
+        assert!(report.is_healthy(), "{report}");
+        assert!(report.wal_present);
+
+        tree.flush_to_disk().await.expect("flushes tree");
+        let report = tree.health_check().await.expect("checks health");
+        assert!(report.is_healthy(), "{report}");
+        assert!(!report.wal_present);
+
+        // Overwrite our header
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&tree.path)
+            .expect("opens data file");
+        file.write_all(&[0xff; 16]).expect("corrupts header");
+        drop(file);
+        let report = tree.health_check().await.expect("checks health");
+        assert_eq!(report.status, HealthStatus::Unhealthy);
+        assert!(matches!(report.issues[0], HealthIssue::InvalidHeader(_)));
+    }
+}
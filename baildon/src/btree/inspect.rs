@@ -0,0 +1,151 @@
+//! WAL Inspection
+//!
+//! List the records of a WAL without replaying them, to diagnose what a tree will recover when it
+//! is opened. A WAL contains every modification which hasn't been flushed to our data file.
+
+use std::io;
+use std::path::Path;
+
+use anyhow::Result;
+
+use super::baildon::Baildon;
+use super::baildon::BaildonKey;
+use super::baildon::BaildonValue;
+use crate::command::Command;
+use crate::io::wal::WalFile;
+
+/// Each record is preceded by its length.
+const RECORD_HEADER_SIZE: u64 = std::mem::size_of::<u64>() as u64;
+
+/// A modification recorded in a WAL.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WalRecord<K, V> {
+    /// Position of the record in the WAL, in bytes
+    pub offset: u64,
+    /// Size of the serialized modification, in bytes
+    pub size: u64,
+    /// Key which was modified
+    pub key: K,
+    /// Value which was inserted or moved, or `None` if the key was deleted (or renamed by an
+    /// older version)
+    pub value: Option<V>,
+    /// Key to which the key was renamed, for renames
+    pub new_key: Option<K>,
+}
+
+impl<K, V> WalRecord<K, V> {
+    /// Name of the operation which was recorded.
+    pub fn operation(&self) -> &'static str {
+        match (&self.value, &self.new_key) {
+            (_, Some(_)) => "rename",
+            (Some(_), None) => "upsert",
+            (None, None) => "delete",
+        }
+    }
+}
+
+impl<K, V> Baildon<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    /// Return the records of the WAL of the tree at the specified path, in the order they will be
+    /// replayed. If there is no WAL, there are no records.
+    ///
+    /// The WAL isn't locked, so it may be inspected while its tree is open. As when replaying, a
+    /// final record which was only partially written is ignored.
+    pub async fn inspect_wal<P: AsRef<Path>>(origin: P) -> Result<Vec<WalRecord<K, V>>> {
+        let wal_path = origin.as_ref().with_extension("wal");
+        let mut wal = match WalFile::try_open(&wal_path).await {
+            Ok(wal) => wal,
+            Err(err) => {
+                if let Some(io_error) = err.downcast_ref::<io::Error>() {
+                    if io_error.kind() == io::ErrorKind::NotFound {
+                        return Ok(vec![]);
+                    }
+                }
+                return Err(err);
+            }
+        };
+
+        let mut records = vec![];
+        let mut offset = 0;
+        loop {
+            let data = match wal.read_data().await {
+                Ok(data) => data,
+                Err(e) => {
+                    if let Some(io_error) = e.downcast_ref::<io::Error>() {
+                        if io_error.kind() == io::ErrorKind::UnexpectedEof {
+                            break;
+                        }
+                    }
+                    return Err(e);
+                }
+            };
+            let size = data.len() as u64;
+            let (key, value, new_key) = match Command::<K, V>::deserialize(&data)? {
+                Command::Upsert(key, value) => (key, Some(value), None),
+                Command::Delete(key) => (key, None, None),
+                Command::Rename(from, to) => (from, None, Some(to)),
+                Command::Move(from, to, value) => (from, Some(value), Some(to)),
+            };
+            records.push(WalRecord {
+                offset,
+                size,
+                key,
+                value,
+                new_key,
+            });
+            offset += RECORD_HEADER_SIZE + size;
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_inspects_wal_records() {
+        let tree = Baildon::<String, String>::try_new("inspect_wal.db", 5)
+            .await
+            .expect("creates tree file");
+        for key in ["apple", "banana", "cherry"] {
+            tree.insert(key.to_string(), key.to_uppercase())
+                .await
+                .expect("insert worked");
+        }
+        tree.delete(&"banana".to_string())
+            .await
+            .expect("delete worked");
+
+        let records = Baildon::<String, String>::inspect_wal(tree.path())
+            .await
+            .expect("inspects wal");
+        let operations = records
+            .iter()
+            .map(|record| (record.operation(), record.key.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            operations,
+            vec![
+                ("upsert", "apple"),
+                ("upsert", "banana"),
+                ("upsert", "cherry"),
+                ("delete", "banana")
+            ]
+        );
+        assert_eq!(records[0].value, Some("APPLE".to_string()));
+        assert_eq!(records[0].offset, 0);
+        assert_eq!(records[1].offset, RECORD_HEADER_SIZE + records[0].size);
+
+        // Closing flushes our tree, which removes our WAL
+        tree.close().await.expect("closes tree");
+        let records = Baildon::<String, String>::inspect_wal("inspect_wal.db")
+            .await
+            .expect("inspects wal");
+        assert!(records.is_empty());
+        std::fs::remove_file("inspect_wal.db").expect("cleanup");
+    }
+}
@@ -0,0 +1,106 @@
+//! JSON Lines
+//!
+//! A human readable format for exchanging the entries of a tree. Each line is a JSON object
+//! containing one entry:
+//!
+//! ```text
+//! {"key":1,"value":"one"}
+//! ```
+//!
+//! Entries are exported in ascending order of key, so exports of similar trees can be diffed.
+
+use anyhow::Result;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::baildon::Baildon;
+use super::baildon::BaildonError;
+use super::baildon::BaildonKey;
+use super::baildon::BaildonValue;
+use super::baildon::Direction;
+
+#[derive(Deserialize, Serialize)]
+struct JsonEntry<K, V> {
+    key: K,
+    value: V,
+}
+
+impl<K, V> Baildon<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    /// Write a line for each of our entries, returning the number of entries written.
+    pub async fn export_jsonl<W: AsyncWrite + Unpin>(&self, mut writer: W) -> Result<u64> {
+        let mut count = 0;
+        let mut entries = self.entries(Direction::Ascending).await;
+        while let Some((key, value)) = entries.next().await {
+            let mut line = serde_json::to_vec(&JsonEntry { key, value })?;
+            line.push(b'\n');
+            writer.write_all(&line).await?;
+            count += 1;
+        }
+        writer.flush().await?;
+        Ok(count)
+    }
+
+    /// Insert the entry from each line, returning the number of entries read. Blank lines are
+    /// skipped.
+    ///
+    /// Entries are inserted as they are read, so if a line is invalid, some entries may already
+    /// have been inserted.
+    pub async fn import_jsonl<R: AsyncBufRead + Unpin>(&self, reader: R) -> Result<u64> {
+        let mut count = 0;
+        let mut lines = reader.lines();
+        let mut number = 0;
+        while let Some(line) = lines.next_line().await? {
+            number += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: JsonEntry<K, V> = serde_json::from_str(&line)
+                .map_err(|e| BaildonError::InvalidRecord(format!("line: {number}: {e}")))?;
+            self.insert(entry.key, entry.value).await?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_imports_and_exports_jsonl() {
+        let tree = Baildon::<usize, String>::temporary(5)
+            .await
+            .expect("creates tree file");
+        for i in (0..100).rev() {
+            tree.insert(i, i.to_string()).await.expect("insert worked");
+        }
+        let mut output = vec![];
+        let count = tree.export_jsonl(&mut output).await.expect("exports jsonl");
+        assert_eq!(count, 100);
+        let output = String::from_utf8(output).expect("utf8");
+        assert!(output.starts_with("{\"key\":0,\"value\":\"0\"}\n{\"key\":1,"));
+
+        let copy = Baildon::<usize, String>::temporary(5)
+            .await
+            .expect("creates tree file");
+        let count = copy
+            .import_jsonl(output.as_bytes())
+            .await
+            .expect("imports jsonl");
+        assert_eq!(count, 100);
+        assert_eq!(copy.to_btreemap().await, tree.to_btreemap().await);
+
+        let invalid = "{\"key\":1,\"value\":\"1\"}\n\n{\"key\":\"two\"}\n";
+        let err = copy
+            .import_jsonl(invalid.as_bytes())
+            .await
+            .expect_err("line 3 is invalid");
+        assert!(err.to_string().starts_with("invalid record: line: 3:"));
+    }
+}
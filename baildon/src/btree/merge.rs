@@ -0,0 +1,244 @@
+//! Merging
+//!
+//! Combine the entries of two trees, either by streaming one tree into another or by building a
+//! third tree from both.
+
+use std::cmp::Ordering;
+use std::path::Path;
+
+use anyhow::Result;
+use futures::StreamExt;
+
+use super::baildon::Baildon;
+use super::baildon::BaildonError;
+use super::baildon::BaildonKey;
+use super::baildon::BaildonValue;
+use super::baildon::Direction;
+
+type Resolver<K, V> = Box<dyn Fn(&K, V, V) -> V + Send + Sync>;
+
+/// How to resolve keys which are present in both trees when merging.
+pub enum ConflictPolicy<K, V> {
+    /// Keep our value.
+    KeepSelf,
+    /// Replace our value with the value from the other tree.
+    KeepOther,
+    /// Call a closure with the key, our value and the other value, keeping the value it returns.
+    Resolve(Resolver<K, V>),
+}
+
+impl<K, V> ConflictPolicy<K, V> {
+    /// Resolve conflicts with the supplied closure.
+    pub fn resolve_with<F>(f: F) -> Self
+    where
+        F: Fn(&K, V, V) -> V + Send + Sync + 'static,
+    {
+        ConflictPolicy::Resolve(Box::new(f))
+    }
+
+    fn resolve(&self, key: &K, ours: V, theirs: V) -> V {
+        match self {
+            ConflictPolicy::KeepSelf => ours,
+            ConflictPolicy::KeepOther => theirs,
+            ConflictPolicy::Resolve(f) => f(key, ours, theirs),
+        }
+    }
+}
+
+/// The result of merging one tree into another.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MergeReport {
+    /// Number of entries read from the other tree.
+    pub entries: usize,
+    /// Number of keys which were only in the other tree, and so were inserted.
+    pub inserted: usize,
+    /// Number of keys which were in both trees, and so were resolved by our policy.
+    pub conflicts: usize,
+}
+
+impl<K, V> Baildon<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    /// Stream all the entries of another tree into our tree, resolving keys which are present in
+    /// both trees with the supplied policy.
+    ///
+    /// Entries are inserted one at a time, so they are written to our WAL.
+    pub async fn merge_from(
+        &self,
+        other: &Baildon<K, V>,
+        policy: ConflictPolicy<K, V>,
+    ) -> Result<MergeReport> {
+        self.check_writable()?;
+        if std::ptr::eq(self, other) {
+            return Err(BaildonError::SelfMerge(self.path.clone()).into());
+        }
+        let mut report = MergeReport::default();
+        let mut entries = other.entries(Direction::Ascending).await;
+        while let Some((key, theirs)) = entries.next().await {
+            report.entries += 1;
+            let value = match &policy {
+                // We don't need our value, the insert tells us if there was one
+                ConflictPolicy::KeepOther => theirs,
+                _ => match self.get(&key).await {
+                    Some(_ours) if matches!(policy, ConflictPolicy::KeepSelf) => {
+                        report.conflicts += 1;
+                        continue;
+                    }
+                    Some(ours) => {
+                        report.conflicts += 1;
+                        policy.resolve(&key, ours, theirs)
+                    }
+                    None => theirs,
+                },
+            };
+            match self.insert(key, value).await? {
+                None => report.inserted += 1,
+                Some(_previous) if matches!(policy, ConflictPolicy::KeepOther) => {
+                    report.conflicts += 1
+                }
+                Some(_previous) => (),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Create a new store at the specified path, with the specified branching factor, containing
+    /// the entries of two trees. Keys which are present in both trees are resolved with the
+    /// supplied policy, with `first` as "self" and `second` as "other".
+    ///
+    /// Both trees are streamed in order and the new store is bulk loaded (see
+    /// [`Baildon::bulk_load`]), so this is much faster than [`Baildon::merge_from`]. Neither tree
+    /// is modified.
+    pub async fn merge<P: AsRef<Path>>(
+        origin: P,
+        branch: u64,
+        first: &Baildon<K, V>,
+        second: &Baildon<K, V>,
+        policy: ConflictPolicy<K, V>,
+    ) -> Result<Self> {
+        let mut firsts = first.entries(Direction::Ascending).await;
+        let mut seconds = second.entries(Direction::Ascending).await;
+        let mut merged = vec![];
+        let (mut ours, mut theirs) = (firsts.next().await, seconds.next().await);
+        loop {
+            match (ours.take(), theirs.take()) {
+                (Some(entry), Some(other)) => match entry.0.cmp(&other.0) {
+                    Ordering::Less => {
+                        merged.push(entry);
+                        ours = firsts.next().await;
+                        theirs = Some(other);
+                    }
+                    Ordering::Greater => {
+                        merged.push(other);
+                        ours = Some(entry);
+                        theirs = seconds.next().await;
+                    }
+                    Ordering::Equal => {
+                        let (key, value) = entry;
+                        let value = policy.resolve(&key, value, other.1);
+                        merged.push((key, value));
+                        ours = firsts.next().await;
+                        theirs = seconds.next().await;
+                    }
+                },
+                (Some(entry), None) => {
+                    merged.push(entry);
+                    ours = firsts.next().await;
+                }
+                (None, Some(other)) => {
+                    merged.push(other);
+                    theirs = seconds.next().await;
+                }
+                (None, None) => break,
+            }
+        }
+        Self::bulk_load(origin, branch, merged).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn tree_of(range: std::ops::Range<usize>, tag: &str) -> Baildon<usize, String> {
+        let tree = Baildon::<usize, String>::temporary(5)
+            .await
+            .expect("creates tree file");
+        for i in range {
+            tree.insert(i, format!("{tag}{i}"))
+                .await
+                .expect("insert worked");
+        }
+        tree
+    }
+
+    #[tokio::test]
+    async fn it_merges_trees() {
+        let other = tree_of(50..150, "other").await;
+
+        let tree = tree_of(0..100, "self").await;
+        let report = tree
+            .merge_from(&other, ConflictPolicy::KeepSelf)
+            .await
+            .expect("merge worked");
+        assert_eq!(
+            report,
+            MergeReport {
+                entries: 100,
+                inserted: 50,
+                conflicts: 50
+            }
+        );
+        assert_eq!(tree.count().await, 150);
+        assert_eq!(tree.get(&75).await, Some("self75".to_string()));
+        assert_eq!(tree.get(&125).await, Some("other125".to_string()));
+
+        let tree = tree_of(0..100, "self").await;
+        let report = tree
+            .merge_from(&other, ConflictPolicy::KeepOther)
+            .await
+            .expect("merge worked");
+        assert_eq!(report.inserted, 50);
+        assert_eq!(report.conflicts, 50);
+        assert_eq!(tree.get(&75).await, Some("other75".to_string()));
+
+        let tree = tree_of(0..100, "self").await;
+        tree.merge_from(
+            &other,
+            ConflictPolicy::resolve_with(|_key, ours, theirs| format!("{ours}+{theirs}")),
+        )
+        .await
+        .expect("merge worked");
+        assert_eq!(tree.get(&75).await, Some("self75+other75".to_string()));
+        assert_eq!(tree.get(&25).await, Some("self25".to_string()));
+
+        assert!(tree
+            .merge_from(&tree, ConflictPolicy::KeepSelf)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn it_merges_into_new_tree() {
+        let first = tree_of(0..100, "first").await;
+        let second = tree_of(50..150, "second").await;
+        let merged = Baildon::merge(
+            "merged_tree.db",
+            5,
+            &first,
+            &second,
+            ConflictPolicy::KeepOther,
+        )
+        .await
+        .expect("merge worked");
+        assert_eq!(merged.count().await, 150);
+        assert_eq!(merged.get(&25).await, Some("first25".to_string()));
+        assert_eq!(merged.get(&75).await, Some("second75".to_string()));
+        assert_eq!(merged.get(&125).await, Some("second125".to_string()));
+        assert_eq!(first.count().await, 100);
+        merged.close().await.expect("closes tree");
+        std::fs::remove_file("merged_tree.db").expect("cleanup");
+    }
+}
@@ -2,10 +2,61 @@
 //!
 
 // Re-export
+pub use self::backup::BackupProgress;
 pub use self::baildon::Baildon;
+pub use self::baildon::CachePolicy;
 pub use self::baildon::Direction;
+pub use self::baildon::Recovery;
+pub use self::baildon::SizeLimits;
+pub use self::compaction::CompactionOptions;
+pub use self::compaction::CompactionProgress;
+pub use self::crash::CrashPoint;
+pub use self::family::BaildonFamilies;
+pub use self::family::ColumnFamily;
+pub use self::family::FamilyKey;
+pub use self::health::HealthIssue;
+pub use self::health::HealthReport;
+pub use self::health::HealthStatus;
+pub use self::inspect::WalRecord;
+pub use self::merge::ConflictPolicy;
+pub use self::merge::MergeReport;
+pub use self::order::Descending;
+pub use self::order::KeyOrder;
+pub use self::order::Ordered;
+pub use self::order::Reversed;
+pub use self::queue::WriteQueue;
+pub use self::queue::WriteQueueStats;
+pub use self::repair::RepairReport;
+pub use self::set::BaildonSet;
+pub use self::stats::FillStats;
+pub use self::stats::TreeStats;
+pub use self::verify::VerifyReport;
+pub use self::verify::Violation;
 
+mod backup;
 pub mod baildon;
+mod bloom;
+mod cache;
+mod compaction;
+mod convert;
+mod crash;
+#[cfg(feature = "csv")]
+mod delimited;
+mod family;
+mod health;
+mod inspect;
+#[cfg(feature = "json")]
+mod jsonl;
+mod merge;
 mod node;
+mod order;
+mod queue;
+mod repair;
+mod set;
+mod snapshot;
 mod sparse;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod stats;
 mod stream;
+mod verify;
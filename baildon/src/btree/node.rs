@@ -1,93 +1,363 @@
 //! B+Tree Node Types
 
-use std::cmp::Ordering;
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::OnceLock;
 
 use anyhow::Error;
 use anyhow::Result;
 use bincode::Options;
-use serde::{Deserialize, Serialize};
+use serde::de::{self, Visitor};
+use serde::ser::{Error as _, SerializeStruct};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+use super::baildon::BaildonError;
 use super::baildon::BaildonKey;
 use super::baildon::BaildonValue;
+use super::baildon::Direction;
 use crate::BINCODER;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct KeyPair<K, V> {
-    key: K,
-    value: V,
+/// Parallel keys and values, serialized exactly like a sequence of pairs, so the layout of our
+/// nodes doesn't depend on how they are stored in memory.
+struct Pairs<'a, K, V> {
+    keys: &'a [K],
+    values: &'a [V],
 }
 
-impl<K, V> PartialOrd for KeyPair<K, V>
+impl<K, V> Serialize for Pairs<'_, K, V>
 where
-    K: BaildonKey,
-    V: BaildonValue,
+    K: Serialize,
+    V: Serialize,
 {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.keys.iter().zip(self.values))
     }
 }
 
-impl<K, V> Ord for KeyPair<K, V>
+#[derive(Clone, Debug)]
+pub(crate) enum Node<K, V> {
+    Internal(NodeInternal<K>),
+    Leaf(NodeLeaf<K, V>),
+}
+
+// Serialized nodes are identified by their variant index. Leaves which store their values inline
+// are still read from older files, but are always written with lazy values.
+const INTERNAL_VARIANT: u32 = 0;
+const LEAF_VARIANT: u32 = 2;
+
+impl<K, V> Serialize for Node<K, V>
 where
-    K: BaildonKey,
-    V: BaildonValue,
+    K: Serialize,
+    V: Serialize,
 {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.key.cmp(&other.key)
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Node::Internal(node) => {
+                serializer.serialize_newtype_variant("Node", INTERNAL_VARIANT, "Internal", node)
+            }
+            Node::Leaf(node) => {
+                serializer.serialize_newtype_variant("Node", LEAF_VARIANT, "Leaf", node)
+            }
+        }
     }
 }
 
-impl<K, V> PartialEq for KeyPair<K, V>
+// Keys are stored apart from values, so that searches only touch keys
+#[derive(Clone, Debug)]
+pub(crate) struct NodeLeaf<K, V> {
+    keys: Vec<K>,
+    values: Vec<LazyValue<V>>,
+    branch: u64,
+    parent: Option<usize>,
+    idx: usize,
+    clean: bool,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+impl<K, V> Serialize for NodeLeaf<K, V>
 where
-    K: BaildonKey,
-    V: BaildonValue,
+    K: Serialize,
+    V: Serialize,
 {
-    fn eq(&self, other: &Self) -> bool {
-        self.key == other.key
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let pairs = Pairs {
+            keys: &self.keys,
+            values: &self.values,
+        };
+        let mut state = serializer.serialize_struct("NodeLeaf", 7)?;
+        state.serialize_field("pairs", &pairs)?;
+        state.serialize_field("branch", &self.branch)?;
+        state.serialize_field("parent", &self.parent)?;
+        state.serialize_field("idx", &self.idx)?;
+        state.serialize_field("clean", &self.clean)?;
+        // Sibling links are last, so leaves written with links can also be read without them
+        state.serialize_field("prev", &self.prev)?;
+        state.serialize_field("next", &self.next)?;
+        state.end()
     }
 }
 
-impl<K, V> Eq for KeyPair<K, V>
-where
-    K: BaildonKey,
-    V: BaildonValue,
-{
+/// A leaf value which is only deserialized when it is first accessed.
+///
+/// Values are serialized as length prefixed bytes, so that they can be skipped when a leaf is
+/// read. Keys are always deserialized, since they are searched. Once a value is deserialized, its
+/// bytes are dropped.
+pub(crate) struct LazyValue<V> {
+    bytes: Mutex<Option<Vec<u8>>>,
+    value: OnceLock<V>,
 }
 
-impl<K, V> KeyPair<K, V>
+impl<V> LazyValue<V>
 where
-    K: BaildonKey,
     V: BaildonValue,
 {
-    pub(crate) fn new(key: K, value: V) -> Self {
-        Self { key, value }
+    fn new(value: V) -> Self {
+        Self {
+            bytes: Mutex::new(None),
+            value: OnceLock::from(value),
+        }
+    }
+
+    /// Return our value, deserializing it if this is its first access. Values are only corrupt
+    /// if our file is, which repair checks for.
+    fn get(&self) -> Result<&V> {
+        if let Some(value) = self.value.get() {
+            return Ok(value);
+        }
+        let mut bytes = self.bytes.lock().expect("LAZY VALUE LOCK");
+        // Another thread may have deserialized us while we waited
+        if let Some(value) = self.value.get() {
+            return Ok(value);
+        }
+        let value = decode(bytes.as_deref().expect("UNDECODED VALUE HAS BYTES"))?;
+        let value = self.value.get_or_init(|| value);
+        *bytes = None;
+        Ok(value)
+    }
+
+    fn into_inner(self) -> Result<V> {
+        match self.value.into_inner() {
+            Some(value) => Ok(value),
+            None => {
+                let bytes = self.bytes.into_inner().expect("LAZY VALUE LOCK");
+                decode(bytes.as_deref().expect("UNDECODED VALUE HAS BYTES"))
+            }
+        }
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub(crate) enum Node<K, V> {
-    Internal(NodeInternal<K>),
-    Leaf(NodeLeaf<K, V>),
+fn decode<V: BaildonValue>(bytes: &[u8]) -> Result<V> {
+    BINCODER
+        .deserialize(bytes)
+        .map_err(|e| BaildonError::CorruptValue(e.to_string()).into())
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub(crate) struct NodeLeaf<K, V> {
-    pairs: Vec<KeyPair<K, V>>,
+impl<V: Clone> Clone for LazyValue<V> {
+    fn clone(&self) -> Self {
+        Self {
+            bytes: Mutex::new(self.bytes.lock().expect("LAZY VALUE LOCK").clone()),
+            value: self.value.clone(),
+        }
+    }
+}
+
+impl<V: fmt::Debug> fmt::Debug for LazyValue<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(value) = self.value.get() {
+            return value.fmt(f);
+        }
+        match &*self.bytes.lock().expect("LAZY VALUE LOCK") {
+            Some(bytes) => write!(f, "<{} bytes>", bytes.len()),
+            None => write!(f, "<empty>"),
+        }
+    }
+}
+
+impl<V: Serialize> Serialize for LazyValue<V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Our bytes are dropped once we are deserialized, so check our value while we hold our lock
+        let bytes = self.bytes.lock().expect("LAZY VALUE LOCK");
+        match (self.value.get(), &*bytes) {
+            (Some(value), _) => {
+                let bytes = BINCODER.serialize(value).map_err(S::Error::custom)?;
+                serializer.serialize_bytes(&bytes)
+            }
+            (None, Some(bytes)) => serializer.serialize_bytes(bytes),
+            (None, None) => Err(S::Error::custom("lazy value has no value")),
+        }
+    }
+}
+
+impl<'de, V> Deserialize<'de> for LazyValue<V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer
+            .deserialize_byte_buf(LazyValueVisitor)
+            .map(|bytes| Self {
+                bytes: Mutex::new(Some(bytes)),
+                value: OnceLock::new(),
+            })
+    }
+}
+
+struct LazyValueVisitor;
+
+impl<'de> Visitor<'de> for LazyValueVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("serialized value bytes")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+        Ok(bytes.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(bytes)
+    }
+}
+
+// The layouts of nodes in older files. Internal nodes and leaves with lazy values are unchanged,
+// but leaves may store their values inline, with or without links to their siblings.
+#[derive(Deserialize)]
+enum StoredNode<K, V, L> {
+    Internal(StoredNodeInternal<K>),
+    InlineLeaf(L),
+    Leaf(LinkedNodeLeaf<K, LazyValue<V>>),
+}
+
+#[derive(Deserialize)]
+struct StoredNodeInternal<K> {
+    pairs: Vec<(K, usize)>,
+    branch: u64,
+    parent: Option<usize>,
+    idx: usize,
+    clean: bool,
+}
+
+#[cfg_attr(test, derive(Serialize))]
+#[derive(Deserialize)]
+struct LinkedNodeLeaf<K, V> {
+    pairs: Vec<(K, V)>,
+    branch: u64,
+    parent: Option<usize>,
+    idx: usize,
+    clean: bool,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+#[cfg_attr(test, derive(Serialize))]
+#[derive(Deserialize)]
+struct UnlinkedNodeLeaf<K, V> {
+    pairs: Vec<(K, V)>,
     branch: u64,
     parent: Option<usize>,
     idx: usize,
     clean: bool,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+impl<K> From<StoredNodeInternal<K>> for NodeInternal<K> {
+    fn from(node: StoredNodeInternal<K>) -> Self {
+        let (keys, children) = node.pairs.into_iter().unzip();
+        NodeInternal {
+            keys,
+            children,
+            branch: node.branch,
+            parent: node.parent,
+            idx: node.idx,
+            clean: node.clean,
+        }
+    }
+}
+
+impl<K, V> LinkedNodeLeaf<K, V> {
+    fn into_leaf<W>(self, lazy: impl FnMut(V) -> LazyValue<W>) -> NodeLeaf<K, W> {
+        let (keys, values): (Vec<K>, Vec<V>) = self.pairs.into_iter().unzip();
+        NodeLeaf {
+            keys,
+            values: values.into_iter().map(lazy).collect(),
+            branch: self.branch,
+            parent: self.parent,
+            idx: self.idx,
+            clean: self.clean,
+            prev: self.prev,
+            next: self.next,
+        }
+    }
+}
+
+impl<K, V> From<LinkedNodeLeaf<K, V>> for NodeLeaf<K, V>
+where
+    V: BaildonValue,
+{
+    fn from(node: LinkedNodeLeaf<K, V>) -> Self {
+        node.into_leaf(LazyValue::new)
+    }
+}
+
+impl<K, V> From<UnlinkedNodeLeaf<K, V>> for NodeLeaf<K, V>
+where
+    V: BaildonValue,
+{
+    fn from(node: UnlinkedNodeLeaf<K, V>) -> Self {
+        let (keys, values): (Vec<K>, Vec<V>) = node.pairs.into_iter().unzip();
+        NodeLeaf {
+            keys,
+            values: values.into_iter().map(LazyValue::new).collect(),
+            branch: node.branch,
+            parent: node.parent,
+            idx: node.idx,
+            clean: node.clean,
+            prev: None,
+            next: None,
+        }
+    }
+}
+
+impl<K, V, L> From<StoredNode<K, V, L>> for Node<K, V>
+where
+    L: Into<NodeLeaf<K, V>>,
+{
+    fn from(node: StoredNode<K, V, L>) -> Self {
+        match node {
+            StoredNode::Internal(node) => Node::Internal(node.into()),
+            StoredNode::InlineLeaf(node) => Node::Leaf(node.into()),
+            StoredNode::Leaf(node) => Node::Leaf(node.into_leaf(|value| value)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub(crate) struct NodeInternal<K> {
-    pairs: Vec<KeyPair<K, usize>>,
+    keys: Vec<K>,
+    children: Vec<usize>,
     branch: u64,
     parent: Option<usize>,
     idx: usize,
     clean: bool,
 }
 
+impl<K> Serialize for NodeInternal<K>
+where
+    K: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let pairs = Pairs {
+            keys: &self.keys,
+            values: &self.children,
+        };
+        let mut state = serializer.serialize_struct("NodeInternal", 5)?;
+        state.serialize_field("pairs", &pairs)?;
+        state.serialize_field("branch", &self.branch)?;
+        state.serialize_field("parent", &self.parent)?;
+        state.serialize_field("idx", &self.idx)?;
+        state.serialize_field("clean", &self.clean)?;
+        state.end()
+    }
+}
+
 impl<K, V> Node<K, V>
 where
     K: BaildonKey,
@@ -105,68 +375,50 @@ where
         root
     }
 
-    fn leaf(branch: u64, parent: Option<usize>, keys: Vec<K>, values: Vec<V>) -> Self {
+    pub(crate) fn leaf(branch: u64, parent: Option<usize>, keys: Vec<K>, values: Vec<V>) -> Self {
         assert!(branch >= 2);
+        assert!(keys.len() == values.len());
 
-        let mut pairs = Vec::with_capacity(branch as usize);
-        for pair in std::iter::zip(keys, values) {
-            pairs.push(KeyPair::new(pair.0, pair.1));
-        }
+        let mut lazy = Vec::with_capacity(branch as usize);
+        lazy.extend(values.into_iter().map(LazyValue::new));
 
-        Node::Leaf(NodeLeaf {
-            branch,
-            parent,
-            pairs,
-            idx: 0,
-            clean: false,
-        })
+        Node::leaf_from_parts(branch, parent, keys, lazy)
     }
 
-    fn leaf_from_pairs(branch: u64, parent: Option<usize>, pairs: Vec<KeyPair<K, V>>) -> Self {
-        assert!(branch >= 2);
-
-        Node::Leaf(NodeLeaf {
-            branch,
-            parent,
-            pairs,
-            idx: 0,
-            clean: false,
-        })
-    }
-    pub(crate) fn internal(
+    fn leaf_from_parts(
         branch: u64,
         parent: Option<usize>,
         keys: Vec<K>,
-        children: Vec<usize>,
+        values: Vec<LazyValue<V>>,
     ) -> Self {
         assert!(branch >= 2);
-        assert!(keys.len() == children.len());
-
-        let mut pairs = Vec::with_capacity(branch as usize);
-        for pair in std::iter::zip(keys, children) {
-            pairs.push(KeyPair::new(pair.0, pair.1));
-        }
 
-        Node::Internal(NodeInternal {
+        Node::Leaf(NodeLeaf {
+            keys,
+            values,
             branch,
             parent,
-            pairs,
             idx: 0,
             clean: false,
+            prev: None,
+            next: None,
         })
     }
 
-    fn internal_from_pairs(
+    pub(crate) fn internal(
         branch: u64,
         parent: Option<usize>,
-        pairs: Vec<KeyPair<K, usize>>,
+        keys: Vec<K>,
+        children: Vec<usize>,
     ) -> Self {
         assert!(branch >= 2);
+        assert!(keys.len() == children.len());
 
         Node::Internal(NodeInternal {
+            keys,
+            children,
             branch,
             parent,
-            pairs,
             idx: 0,
             clean: false,
         })
@@ -177,7 +429,19 @@ where
     }
 
     pub(crate) fn deserialize(bytes: &[u8]) -> Result<Self> {
-        BINCODER.deserialize(bytes).map_err(Error::new)
+        BINCODER
+            .deserialize::<StoredNode<K, V, LinkedNodeLeaf<K, V>>>(bytes)
+            .map(Node::from)
+            .map_err(Error::new)
+    }
+
+    /// Deserialize a node from a file which doesn't link leaves to their siblings. Leaves which
+    /// store their values inline may also have been written with links, but their links are lost.
+    pub(crate) fn deserialize_unlinked(bytes: &[u8]) -> Result<Self> {
+        BINCODER
+            .deserialize::<StoredNode<K, V, UnlinkedNodeLeaf<K, V>>>(bytes)
+            .map(Node::from)
+            .map_err(Error::new)
     }
 
     pub(crate) fn branch(&self) -> u64 {
@@ -196,14 +460,8 @@ where
 
     pub(crate) fn key_index(&self, key: &K) -> Option<usize> {
         match self {
-            Node::Internal(node) => match node.pairs.binary_search_by(|pair| pair.key.cmp(key)) {
-                Ok(idx) => Some(idx),
-                Err(_idx) => None,
-            },
-            Node::Leaf(node) => match node.pairs.binary_search_by(|pair| pair.key.cmp(key)) {
-                Ok(idx) => Some(idx),
-                Err(_idx) => None,
-            },
+            Node::Internal(node) => node.keys.binary_search(key).ok(),
+            Node::Leaf(node) => node.keys.binary_search(key).ok(),
         }
     }
 
@@ -223,8 +481,8 @@ where
 
     pub(crate) fn len(&self) -> usize {
         match self {
-            Node::Internal(node) => node.pairs.len(),
-            Node::Leaf(node) => node.pairs.len(),
+            Node::Internal(node) => node.keys.len(),
+            Node::Leaf(node) => node.keys.len(),
         }
     }
 
@@ -261,6 +519,30 @@ where
         }
     }
 
+    /// Return the index of the neighbouring leaf in the specified direction.
+    pub(crate) fn sibling(&self, direction: Direction) -> Option<usize> {
+        match self {
+            Node::Internal(_node) => panic!("Internal nodes do not have siblings"),
+            Node::Leaf(node) => match direction {
+                Direction::Ascending => node.next,
+                Direction::Descending => node.prev,
+            },
+        }
+    }
+
+    pub(crate) fn set_sibling(&mut self, direction: Direction, sibling: Option<usize>) {
+        match self {
+            Node::Internal(_node) => panic!("Internal nodes do not have siblings"),
+            Node::Leaf(node) => {
+                node.clean = false;
+                match direction {
+                    Direction::Ascending => node.next = sibling,
+                    Direction::Descending => node.prev = sibling,
+                }
+            }
+        }
+    }
+
     pub(crate) fn is_leaf(&self) -> bool {
         match self {
             Node::Internal(_) => false,
@@ -268,40 +550,80 @@ where
         }
     }
 
-    pub(crate) fn keys(&self) -> Box<dyn DoubleEndedIterator<Item = &K> + '_> {
+    pub(crate) fn keys(&self) -> std::slice::Iter<'_, K> {
         match self {
-            Node::Internal(node) => Box::new(node.keys()),
-            Node::Leaf(node) => Box::new(node.keys()),
+            Node::Internal(node) => node.keys(),
+            Node::Leaf(node) => node.keys(),
         }
     }
 
     pub(crate) fn update_child_key(&mut self, idx: usize, new: K) -> Option<K> {
         match self {
-            Node::Internal(node) => match node.pairs.iter().position(|x| x.value == idx) {
-                Some(idx) => Some(std::mem::replace(&mut node.pairs[idx].key, new)),
+            Node::Internal(node) => match node.children.iter().position(|x| *x == idx) {
+                Some(idx) => Some(std::mem::replace(&mut node.keys[idx], new)),
                 None => None,
             },
             Node::Leaf(_node) => panic!("Leaf nodes do not contain children"),
         }
     }
 
-    pub(crate) fn values(&self) -> impl DoubleEndedIterator<Item = &V> + '_ {
+    /// Return a leaf's pairs, failing if any of its values are corrupt.
+    pub(crate) fn pairs(&self) -> Result<Vec<(&K, &V)>> {
+        match self {
+            Node::Internal(_node) => panic!("Internal nodes do not contain values"),
+            Node::Leaf(node) => node
+                .keys
+                .iter()
+                .zip(&node.values)
+                .map(|(key, value)| Ok((key, value.get()?)))
+                .collect(),
+        }
+    }
+
+    /// Consume a leaf, returning its pairs in the specified order, failing if any of its values
+    /// are corrupt.
+    pub(crate) fn into_pairs(self, direction: Direction) -> Result<Vec<(K, V)>> {
         match self {
             Node::Internal(_node) => panic!("Internal nodes do not contain values"),
-            Node::Leaf(node) => node.pairs.iter().map(|pair| &pair.value),
+            Node::Leaf(node) => {
+                let pairs = node
+                    .keys
+                    .into_iter()
+                    .zip(node.values)
+                    .map(|(key, value)| Ok((key, value.into_inner()?)));
+                match direction {
+                    Direction::Ascending => pairs.collect(),
+                    Direction::Descending => pairs.rev().collect(),
+                }
+            }
         }
     }
 
-    pub(crate) fn pairs(&self) -> impl DoubleEndedIterator<Item = (&K, &V)> + '_ {
+    /// Return a leaf's keys in the specified order, without deserializing any of its values.
+    pub(crate) fn leaf_keys(&self, direction: Direction) -> Vec<K> {
         match self {
             Node::Internal(_node) => panic!("Internal nodes do not contain values"),
-            Node::Leaf(node) => node.pairs.iter().map(|pair| (&pair.key, &pair.value)),
+            Node::Leaf(node) => match direction {
+                Direction::Ascending => node.keys.clone(),
+                Direction::Descending => node.keys.iter().rev().cloned().collect(),
+            },
+        }
+    }
+
+    /// Deserialize all of a leaf's values, failing if any of them are corrupt.
+    pub(crate) fn decode_values(&self) -> Result<()> {
+        match self {
+            Node::Internal(_node) => panic!("Internal nodes do not contain values"),
+            Node::Leaf(node) => node
+                .values
+                .iter()
+                .try_for_each(|value| value.get().map(|_| ())),
         }
     }
 
     pub(crate) fn children(&self) -> impl DoubleEndedIterator<Item = usize> + '_ {
         match self {
-            Node::Internal(node) => node.pairs.iter().map(|pair| pair.value),
+            Node::Internal(node) => node.children(),
             Node::Leaf(_node) => panic!("Leaf nodes do not contain children"),
         }
     }
@@ -310,13 +632,13 @@ where
     /// always returns a child node (as long as there are children).
     pub(crate) fn child(&self, key: &K) -> Option<usize> {
         match self {
-            Node::Internal(node) => match node.pairs.binary_search_by(|pair| pair.key.cmp(key)) {
-                Ok(idx) => Some(node.pairs[idx].value),
+            Node::Internal(node) => match node.keys.binary_search(key) {
+                Ok(idx) => Some(node.children[idx]),
                 Err(idx) => {
-                    if idx == node.pairs.len() {
-                        Some(node.pairs[idx - 1].value)
+                    if idx == node.children.len() {
+                        Some(node.children[idx - 1])
                     } else {
-                        Some(node.pairs[idx].value)
+                        Some(node.children[idx])
                     }
                 }
             },
@@ -326,10 +648,11 @@ where
 
     pub(crate) fn remove_child(&mut self, idx: usize) -> Option<usize> {
         match self {
-            Node::Internal(node) => match node.pairs.iter().position(|x| x.value == idx) {
+            Node::Internal(node) => match node.children.iter().position(|x| *x == idx) {
                 Some(idx) => {
                     node.clean = false;
-                    Some(node.pairs.remove(idx).value)
+                    node.keys.remove(idx);
+                    Some(node.children.remove(idx))
                 }
                 None => None,
             },
@@ -339,25 +662,25 @@ where
 
     pub(crate) fn set_child(&mut self, key: &K, child: usize) -> Option<usize> {
         match self {
-            Node::Internal(node) => match node.pairs.binary_search_by(|pair| pair.key.cmp(key)) {
+            Node::Internal(node) => match node.keys.binary_search(key) {
                 Ok(idx) => {
-                    let old = Some(node.pairs[idx].value);
-                    node.pairs[idx].value = child;
+                    let old = Some(node.children[idx]);
+                    node.children[idx] = child;
                     node.clean = false;
                     old
                 }
                 Err(idx) => {
                     // If we can't find the index or the index value doesn't match our child,
-                    // allocate new KeyPair and insert it.
-                    if idx == 0 || idx == node.pairs.len() || node.pairs[idx].value != child {
-                        let pair = KeyPair::new(key.clone(), child);
-                        node.pairs.insert(idx, pair);
+                    // insert a new key and child.
+                    if idx == 0 || idx == node.children.len() || node.children[idx] != child {
+                        node.keys.insert(idx, key.clone());
+                        node.children.insert(idx, child);
                         node.clean = false;
                         None
                     } else {
-                        let old = Some(node.pairs[idx].value);
-                        node.pairs[idx].key = key.clone();
-                        node.pairs[idx].value = child;
+                        let old = Some(node.children[idx]);
+                        node.keys[idx] = key.clone();
+                        node.children[idx] = child;
                         node.clean = false;
                         old
                     }
@@ -367,40 +690,50 @@ where
         }
     }
 
-    pub(crate) fn value(&self, key: &K) -> Option<V> {
+    /// Return the value of a key, failing if it is corrupt.
+    pub(crate) fn value(&self, key: &K) -> Result<Option<V>> {
         match self {
             Node::Internal(_node) => panic!("Internal nodes do not contain values"),
-            Node::Leaf(node) => node
-                .pairs
-                .iter()
-                .find(|pair| pair.key == *key)
-                .map(|x| x.value.clone()),
+            Node::Leaf(node) => match node.keys.binary_search(key) {
+                Ok(idx) => node.values[idx].get().map(|value| Some(value.clone())),
+                Err(_) => Ok(None),
+            },
         }
     }
 
-    pub(crate) fn remove_value(&mut self, key: &K) -> Option<V> {
+    /// Remove a key, returning its old value. The key is removed even if its value is corrupt.
+    pub(crate) fn remove_value(&mut self, key: &K) -> Option<Result<V>> {
         match self {
             Node::Internal(_node) => panic!("Internal nodes do not contain values"),
-            Node::Leaf(node) => match node.pairs.binary_search_by(|pair| pair.key.cmp(key)) {
+            Node::Leaf(node) => match node.keys.binary_search(key) {
                 Ok(idx) => {
                     node.clean = false;
-                    Some(node.pairs.remove(idx).value)
+                    node.keys.remove(idx);
+                    Some(node.values.remove(idx).into_inner())
                 }
                 Err(_) => None,
             },
         }
     }
 
-    pub(crate) fn set_value(&mut self, key: &K, value: V) -> Option<V> {
+    /// Set the value of a key, returning its old value. The old value is replaced even if it is
+    /// corrupt.
+    pub(crate) fn set_value(&mut self, key: &K, value: V) -> Option<Result<V>> {
+        self.set_lazy_value(key, LazyValue::new(value))
+            .map(LazyValue::into_inner)
+    }
+
+    /// Set the value of a key to a value taken from another leaf, which isn't deserialized.
+    pub(crate) fn set_lazy_value(&mut self, key: &K, value: LazyValue<V>) -> Option<LazyValue<V>> {
         match self {
             Node::Internal(_node) => panic!("Internal nodes do not contain values"),
             Node::Leaf(node) => {
                 node.clean = false;
-                match node.pairs.binary_search_by(|pair| pair.key.cmp(key)) {
-                    Ok(idx) => Some(std::mem::replace(&mut node.pairs[idx].value, value)),
+                match node.keys.binary_search(key) {
+                    Ok(idx) => Some(std::mem::replace(&mut node.values[idx], value)),
                     Err(idx) => {
-                        let pair = KeyPair::new(key.clone(), value);
-                        node.pairs.insert(idx, pair);
+                        node.keys.insert(idx, key.clone());
+                        node.values.insert(idx, value);
                         None
                     }
                 }
@@ -410,15 +743,15 @@ where
 
     pub(crate) fn max_key(&self) -> &K {
         match self {
-            Node::Internal(node) => &node.pairs.last().unwrap().key,
-            Node::Leaf(node) => &node.pairs.last().unwrap().key,
+            Node::Internal(node) => node.keys.last().unwrap(),
+            Node::Leaf(node) => node.keys.last().unwrap(),
         }
     }
 
     // Internal nodes must have children, so this is ok
     pub(crate) fn first_child(&self) -> usize {
         match self {
-            Node::Internal(node) => node.pairs.first().expect("THERE IS A FIRST").value,
+            Node::Internal(node) => *node.children.first().expect("THERE IS A FIRST"),
             Node::Leaf(_node) => panic!("Leaf does not contain children"),
         }
     }
@@ -426,15 +759,15 @@ where
     // Internal nodes must have children, so this is ok
     pub(crate) fn last_child(&self) -> usize {
         match self {
-            Node::Internal(node) => node.pairs.last().expect("THERE IS A LAST").value,
+            Node::Internal(node) => *node.children.last().expect("THERE IS A LAST"),
             Node::Leaf(_node) => panic!("Leaf does not contain children"),
         }
     }
 
     pub(crate) fn is_full(&self) -> bool {
         match self {
-            Node::Internal(node) => node.pairs.len() as u64 > node.branch,
-            Node::Leaf(node) => node.pairs.len() as u64 > node.branch,
+            Node::Internal(node) => node.keys.len() as u64 > node.branch,
+            Node::Leaf(node) => node.keys.len() as u64 > node.branch,
         }
     }
 
@@ -442,16 +775,16 @@ where
         match self {
             Node::Internal(node) => {
                 if node.parent.is_none() {
-                    node.pairs.is_empty()
+                    node.keys.is_empty()
                 } else {
-                    (node.pairs.len() as u64) < node.branch / 2
+                    (node.keys.len() as u64) < node.branch / 2
                 }
             }
             Node::Leaf(node) => {
                 if node.parent.is_none() {
-                    node.pairs.is_empty()
+                    node.keys.is_empty()
                 } else {
-                    (node.pairs.len() as u64) < node.branch / 2
+                    (node.keys.len() as u64) < node.branch / 2
                 }
             }
         }
@@ -462,27 +795,31 @@ where
             Node::Internal(node) => {
                 let split = (node.branch / 2 + node.branch % 2) as usize;
 
-                tracing::debug!("Splitting internal node: {:?}, split: {}", node, split);
-                let new = Node::internal_from_pairs(
+                tracing::debug!(idx = node.idx, split, "splitting internal node");
+                let new = Node::internal(
                     node.branch,
                     node.parent,
-                    node.pairs.split_off(split),
+                    node.keys.split_off(split),
+                    node.children.split_off(split),
                 );
                 node.clean = false;
-                tracing::debug!("After split: node: {:?}", node);
-                tracing::debug!("After split: new: {:?}", new);
-                assert!((node.pairs.len() as u64) >= node.branch / 2);
+                assert!((node.keys.len() as u64) >= node.branch / 2);
                 new
             }
             Node::Leaf(node) => {
                 let split = (node.branch / 2 + node.branch % 2) as usize;
 
-                tracing::debug!("SPLITTING LEAF NODE: {:?}, split: {}", node, split);
-                let new =
-                    Node::leaf_from_pairs(node.branch, node.parent, node.pairs.split_off(split));
+                tracing::debug!(idx = node.idx, split, "splitting leaf node");
+                let mut new = Node::leaf_from_parts(
+                    node.branch,
+                    node.parent,
+                    node.keys.split_off(split),
+                    node.values.split_off(split),
+                );
+                // The new leaf follows us. We can't link to it until it has an index.
+                new.set_sibling(Direction::Descending, Some(node.idx));
+                new.set_sibling(Direction::Ascending, node.next);
                 node.clean = false;
-                tracing::debug!("After split: node: {:?}", node);
-                tracing::debug!("After split: new: {:?}", new);
                 new
             }
         }
@@ -493,10 +830,12 @@ where
             Node::Internal(node) => match other {
                 Node::Internal(node_other) => {
                     assert_eq!(node.branch, node_other.branch);
-                    if node.pairs[0].key < node_other.pairs[0].key {
-                        node.pairs.extend(node_other.pairs);
+                    if node.keys[0] < node_other.keys[0] {
+                        node.keys.extend(node_other.keys);
+                        node.children.extend(node_other.children);
                     } else {
-                        node.pairs.splice(0..0, node_other.pairs);
+                        node.keys.splice(0..0, node_other.keys);
+                        node.children.splice(0..0, node_other.children);
                     }
                     node.clean = false;
                 }
@@ -510,10 +849,12 @@ where
                 }
                 Node::Leaf(node_other) => {
                     assert_eq!(node.branch, node_other.branch);
-                    if node.pairs[0].key < node_other.pairs[0].key {
-                        node.pairs.extend(node_other.pairs);
+                    if node.keys[0] < node_other.keys[0] {
+                        node.keys.extend(node_other.keys);
+                        node.values.extend(node_other.values);
                     } else {
-                        node.pairs.splice(0..0, node_other.pairs);
+                        node.keys.splice(0..0, node_other.keys);
+                        node.values.splice(0..0, node_other.values);
                     }
                     node.clean = false;
                 }
@@ -522,34 +863,19 @@ where
         assert!(!self.is_full());
     }
 
-    pub(crate) fn verify_keys(&self) {
-        let mut previous = None;
-        match self {
-            Node::Internal(node) => {
-                for pair in &node.pairs {
-                    let key = &pair.key;
-                    match previous {
-                        Some(k) => {
-                            assert!(k < key);
-                            previous = Some(key);
-                        }
-                        None => previous = Some(key),
-                    }
-                }
-            }
-            Node::Leaf(node) => {
-                for pair in &node.pairs {
-                    let key = &pair.key;
-                    match previous {
-                        Some(k) => {
-                            assert!(k < key);
-                            previous = Some(key);
-                        }
-                        None => previous = Some(key),
-                    }
-                }
+    /// Are our keys in strictly ascending order?
+    pub(crate) fn keys_sorted(&self) -> bool {
+        let mut keys = self.keys();
+        let Some(mut previous) = keys.next() else {
+            return true;
+        };
+        for key in keys {
+            if previous >= key {
+                return false;
             }
+            previous = key;
         }
+        true
     }
 }
 
@@ -557,8 +883,8 @@ impl<K> NodeInternal<K>
 where
     K: BaildonKey,
 {
-    pub(crate) fn keys(&self) -> impl DoubleEndedIterator<Item = &K> + '_ {
-        self.pairs.iter().map(|pair| &pair.key)
+    pub(crate) fn keys(&self) -> std::slice::Iter<'_, K> {
+        self.keys.iter()
     }
 
     pub(crate) fn index(&self) -> usize {
@@ -566,17 +892,16 @@ where
     }
 
     pub(crate) fn len(&self) -> usize {
-        self.pairs.len()
+        self.keys.len()
     }
 
     pub(crate) fn children(&self) -> impl DoubleEndedIterator<Item = usize> + '_ {
-        self.pairs.iter().map(|pair| pair.value)
+        self.children.iter().copied()
     }
 
     pub(crate) fn remove_pair(&mut self, idx: usize) -> (K, usize) {
-        let pair = self.pairs.remove(idx);
         self.clean = false;
-        (pair.key, pair.value)
+        (self.keys.remove(idx), self.children.remove(idx))
     }
 }
 
@@ -585,8 +910,8 @@ where
     K: BaildonKey,
     V: BaildonValue,
 {
-    pub(crate) fn keys(&self) -> impl DoubleEndedIterator<Item = &K> + '_ {
-        self.pairs.iter().map(|pair| &pair.key)
+    pub(crate) fn keys(&self) -> std::slice::Iter<'_, K> {
+        self.keys.iter()
     }
 
     pub(crate) fn index(&self) -> usize {
@@ -594,13 +919,13 @@ where
     }
 
     pub(crate) fn len(&self) -> usize {
-        self.pairs.len()
+        self.keys.len()
     }
 
-    pub(crate) fn remove_pair(&mut self, idx: usize) -> (K, V) {
-        let pair = self.pairs.remove(idx);
+    /// Remove a pair, to be moved to another leaf, without deserializing its value.
+    pub(crate) fn remove_pair(&mut self, idx: usize) -> (K, LazyValue<V>) {
         self.clean = false;
-        (pair.key, pair.value)
+        (self.keys.remove(idx), self.values.remove(idx))
     }
 }
 
@@ -631,6 +956,17 @@ mod tests {
         }
     }
 
+    #[test]
+    fn it_finds_a_value() {
+        let keys = (0..157).map(|i| i * 2).collect::<Vec<usize>>();
+        let values = keys.iter().map(|key| key * 10).collect::<Vec<usize>>();
+        let target = Node::leaf(157, None, keys, values);
+        for key in 0..314 {
+            let expected = (key % 2 == 0).then_some(key * 10);
+            assert_eq!(target.value(&key).expect("decodes"), expected);
+        }
+    }
+
     #[test]
     fn it_merges_leaf_nodes() {
         let mut target = Node::leaf(
@@ -651,11 +987,122 @@ mod tests {
             vec!["a", "b", "c", "d", "e", "f"]
         );
         assert_eq!(
-            target.values().cloned().collect::<Vec<usize>>(),
+            target
+                .pairs()
+                .expect("decodes")
+                .into_iter()
+                .map(|(_key, value)| *value)
+                .collect::<Vec<usize>>(),
             vec![1usize, 3, 5, 2, 4, 6]
         );
     }
 
+    #[test]
+    fn it_reads_inline_leaf_nodes() {
+        let pairs = vec![(1usize, 10usize), (2, 20)];
+        let expected = vec![(&1, &10), (&2, &20)];
+
+        // Older leaves are the second variant of a node
+        let leaf = UnlinkedNodeLeaf {
+            pairs: pairs.clone(),
+            branch: 8,
+            parent: Some(3),
+            idx: 7,
+            clean: true,
+        };
+        let s_leaf = BINCODER.serialize(&(1u32, leaf)).expect("serializes");
+        let unlinked = Node::<usize, usize>::deserialize_unlinked(&s_leaf).expect("deserializes");
+        assert_eq!(unlinked.index(), 7);
+        assert_eq!(unlinked.parent(), Some(3));
+        assert_eq!(unlinked.pairs().expect("decodes"), expected);
+        assert_eq!(unlinked.sibling(Direction::Ascending), None);
+
+        // Linked leaves may be read without their links
+        let leaf = LinkedNodeLeaf {
+            pairs,
+            branch: 8,
+            parent: Some(3),
+            idx: 7,
+            clean: true,
+            prev: None,
+            next: Some(9),
+        };
+        let s_leaf = BINCODER.serialize(&(1u32, leaf)).expect("serializes");
+        let unlinked = Node::<usize, usize>::deserialize_unlinked(&s_leaf).expect("deserializes");
+        assert_eq!(unlinked.pairs().expect("decodes"), expected);
+        assert_eq!(unlinked.sibling(Direction::Ascending), None);
+
+        let linked = Node::<usize, usize>::deserialize(&s_leaf).expect("deserializes");
+        assert_eq!(linked.pairs().expect("decodes"), expected);
+        assert_eq!(linked.sibling(Direction::Ascending), Some(9));
+        assert_eq!(linked.sibling(Direction::Descending), None);
+
+        // Leaves with lazy values keep their links, whatever the layout of their file
+        let s_leaf = linked.serialize().expect("serializes");
+        let lazy = Node::<usize, usize>::deserialize_unlinked(&s_leaf).expect("deserializes");
+        assert_eq!(lazy.pairs().expect("decodes"), expected);
+        assert_eq!(lazy.sibling(Direction::Ascending), Some(9));
+    }
+
+    #[test]
+    fn it_reads_values_lazily() {
+        let keys = vec![1usize, 2, 3];
+        let values = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let leaf = Node::leaf(8, None, keys, values);
+        let s_leaf = leaf.serialize().expect("serializes");
+
+        let node = Node::<usize, String>::deserialize(&s_leaf).expect("deserializes");
+        let decoded = |node: &Node<usize, String>| match node {
+            Node::Internal(_node) => unreachable!(),
+            Node::Leaf(node) => node
+                .values
+                .iter()
+                .filter(|value| value.value.get().is_some())
+                .count(),
+        };
+        assert_eq!(decoded(&node), 0);
+        assert_eq!(node.value(&2).expect("decodes"), Some("b".to_string()));
+        assert_eq!(decoded(&node), 1);
+
+        // Values which haven't been decoded are written unchanged
+        assert_eq!(node.serialize().expect("serializes"), s_leaf);
+        assert_eq!(node.leaf_keys(Direction::Descending), vec![3, 2, 1]);
+        assert_eq!(decoded(&node), 1);
+        assert_eq!(
+            node.into_pairs(Direction::Descending).expect("decodes"),
+            vec![
+                (3, "c".to_string()),
+                (2, "b".to_string()),
+                (1, "a".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn it_reports_corrupt_values() {
+        // Invalid UTF-8 can't be read as a String
+        let leaf = Node::leaf(8, None, vec![1usize, 2], vec![vec![0x61u8], vec![0xff]]);
+        let s_leaf = leaf.serialize().expect("serializes");
+
+        let mut node = Node::<usize, String>::deserialize(&s_leaf).expect("deserializes");
+        assert_eq!(node.value(&1).expect("decodes"), Some("a".to_string()));
+        let err = node.value(&2).expect_err("corrupt");
+        assert!(matches!(
+            err.downcast_ref::<BaildonError>(),
+            Some(BaildonError::CorruptValue(_))
+        ));
+        assert!(node.pairs().is_err());
+        assert!(node.decode_values().is_err());
+        assert_eq!(node.leaf_keys(Direction::Ascending), vec![1, 2]);
+
+        // Corrupt values can still be replaced
+        assert!(node
+            .set_value(&2, "b".to_string())
+            .expect("replaced")
+            .is_err());
+        assert_eq!(node.value(&2).expect("decodes"), Some("b".to_string()));
+    }
+
     #[test]
     #[should_panic]
     fn it_wont_merge_leaf_nodes_when_full() {
@@ -677,7 +1124,12 @@ mod tests {
             vec!["a", "b", "c", "d", "e", "f"]
         );
         assert_eq!(
-            target.values().cloned().collect::<Vec<usize>>(),
+            target
+                .pairs()
+                .expect("decodes")
+                .into_iter()
+                .map(|(_key, value)| *value)
+                .collect::<Vec<usize>>(),
             vec![1usize, 3, 5, 2, 4, 6]
         );
     }
@@ -0,0 +1,371 @@
+//! Key Ordering
+//!
+//! Keys are normally ordered by their `Ord` implementation. Wrapping keys in [`Ordered`] orders
+//! them with a [`KeyOrder`] instead, e.g.: locale aware collation, or ordering by an embedded
+//! field.
+//!
+//! Trees created with [`Baildon::try_new_ordered`] record the name of their ordering in their
+//! header and [`Baildon::try_open_ordered`] refuses to open a tree with a different ordering,
+//! since the tree's nodes would be out of order. Each way of opening a tree has an `_ordered`
+//! variant; the others only open trees which use the natural ordering of their keys.
+//!
+//! [`Reversed`] keys are stored in descending order, so that latest-first workloads (e.g.:
+//! timestamps) scan in the cheaper ascending direction.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::marker::PhantomData;
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::baildon::Baildon;
+use super::baildon::BaildonKey;
+use super::baildon::BaildonValue;
+use super::baildon::Recovery;
+
+/// Identifies the natural (`Ord`) ordering of keys in a file header.
+pub(crate) const NATURAL_ORDER: u64 = 0;
+
+/// An ordering of keys.
+pub trait KeyOrder<K>: 'static {
+    /// Uniquely identifies this ordering. Changing the name of an ordering prevents existing
+    /// trees which use it from being opened.
+    const NAME: &'static str;
+
+    /// Compare two keys.
+    fn compare(a: &K, b: &K) -> Ordering;
+}
+
+/// The reverse of the natural ordering of keys.
+pub struct Descending;
+
+impl<K: Ord> KeyOrder<K> for Descending {
+    const NAME: &'static str = "descending";
+
+    fn compare(a: &K, b: &K) -> Ordering {
+        b.cmp(a)
+    }
+}
+
+/// A key which is stored in descending order.
+pub type Reversed<K> = Ordered<K, Descending>;
+
+/// Identify an ordering in a file header.
+pub(crate) fn key_order_id<K, O: KeyOrder<K>>() -> u64 {
+    // FNV-1a, so that identities are stable across builds
+    let id = O::NAME
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+    // Never collide with our natural ordering
+    id.max(NATURAL_ORDER + 1)
+}
+
+/// A key which is ordered by a [`KeyOrder`], rather than by its own `Ord` implementation.
+///
+/// Ordered keys are serialized exactly like the key they wrap.
+#[derive(Deserialize, Serialize)]
+#[serde(
+    transparent,
+    bound(serialize = "K: Serialize", deserialize = "K: Deserialize<'de>")
+)]
+pub struct Ordered<K, O> {
+    key: K,
+    #[serde(skip)]
+    order: PhantomData<fn() -> O>,
+}
+
+impl<K, O> Ordered<K, O> {
+    /// Wrap a key.
+    pub fn new(key: K) -> Self {
+        Self {
+            key,
+            order: PhantomData,
+        }
+    }
+
+    /// Return a reference to our key.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Unwrap our key.
+    pub fn into_key(self) -> K {
+        self.key
+    }
+}
+
+impl<K, O> From<K> for Ordered<K, O> {
+    fn from(key: K) -> Self {
+        Self::new(key)
+    }
+}
+
+impl<K: Clone, O> Clone for Ordered<K, O> {
+    fn clone(&self) -> Self {
+        Self::new(self.key.clone())
+    }
+}
+
+impl<K: fmt::Debug, O> fmt::Debug for Ordered<K, O> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.key.fmt(f)
+    }
+}
+
+impl<K, O: KeyOrder<K>> PartialEq for Ordered<K, O> {
+    fn eq(&self, other: &Self) -> bool {
+        O::compare(&self.key, &other.key) == Ordering::Equal
+    }
+}
+
+impl<K, O: KeyOrder<K>> Eq for Ordered<K, O> {}
+
+impl<K, O: KeyOrder<K>> PartialOrd for Ordered<K, O> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K, O: KeyOrder<K>> Ord for Ordered<K, O> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        O::compare(&self.key, &other.key)
+    }
+}
+
+impl<K, O, V> Baildon<Ordered<K, O>, V>
+where
+    K: BaildonKey + Send + Sync,
+    O: KeyOrder<K>,
+    V: BaildonValue + Send + Sync,
+{
+    /// Create a new store at the specified path with the specified branching factor, recording
+    /// the ordering of our keys.
+    pub async fn try_new_ordered<P: AsRef<Path>>(origin: P, branch: u64) -> Result<Self> {
+        Self::create(origin.as_ref(), branch, key_order_id::<K, O>()).await
+    }
+
+    /// Open an existing store at the specified path, which must have been created with the
+    /// ordering of our keys.
+    pub async fn try_open_ordered<P: AsRef<Path>>(origin: P) -> Result<Self> {
+        Self::open(
+            origin.as_ref(),
+            false,
+            Recovery::ReplayWal,
+            key_order_id::<K, O>(),
+        )
+        .await
+    }
+
+    /// Open an existing store at the specified path, which must have been created with the
+    /// ordering of our keys, choosing what to do with any WAL.
+    pub async fn try_open_with_recovery_ordered<P: AsRef<Path>>(
+        origin: P,
+        recovery: Recovery,
+    ) -> Result<Self> {
+        Self::open(origin.as_ref(), false, recovery, key_order_id::<K, O>()).await
+    }
+
+    /// Open an existing store at the specified path, which must have been created with the
+    /// ordering of our keys, for read only access. See [`Baildon::try_open_shared`].
+    pub async fn try_open_shared_ordered<P: AsRef<Path>>(origin: P) -> Result<Self> {
+        Self::open(
+            origin.as_ref(),
+            true,
+            Recovery::ReplayWal,
+            key_order_id::<K, O>(),
+        )
+        .await
+    }
+
+    /// Open an existing store at the specified path, which must have been created with the
+    /// ordering of our keys, or create a new store with the specified branching factor if the
+    /// store doesn't exist.
+    pub async fn open_or_create_ordered<P: AsRef<Path>>(origin: P, branch: u64) -> Result<Self> {
+        Self::open_or_create_with_order(origin.as_ref(), branch, key_order_id::<K, O>()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::btree::baildon::BaildonError;
+    use crate::btree::Direction;
+    use futures::StreamExt;
+
+    struct CaseInsensitive;
+
+    impl KeyOrder<String> for CaseInsensitive {
+        const NAME: &'static str = "case-insensitive";
+
+        fn compare(a: &String, b: &String) -> Ordering {
+            a.to_lowercase().cmp(&b.to_lowercase())
+        }
+    }
+
+    struct Reverse;
+
+    impl KeyOrder<String> for Reverse {
+        const NAME: &'static str = "reverse";
+
+        fn compare(a: &String, b: &String) -> Ordering {
+            b.cmp(a)
+        }
+    }
+
+    #[tokio::test]
+    async fn it_orders_keys() {
+        let tree = Baildon::<Ordered<String, CaseInsensitive>, usize>::try_new_ordered(
+            "ordered_keys.db",
+            5,
+        )
+        .await
+        .expect("creates tree file");
+        for (i, key) in ["b", "C", "a", "D"].into_iter().enumerate() {
+            tree.insert(Ordered::new(key.to_string()), i)
+                .await
+                .expect("insert worked");
+        }
+        // Keys which compare as equal are the same key
+        tree.insert(Ordered::new("A".to_string()), 4)
+            .await
+            .expect("insert worked");
+        assert_eq!(tree.count().await, 4);
+        tree.close().await.expect("closes tree");
+
+        let tree =
+            Baildon::<Ordered<String, CaseInsensitive>, usize>::try_open_ordered("ordered_keys.db")
+                .await
+                .expect("opens tree file");
+        let keys = tree
+            .keys(Direction::Ascending)
+            .await
+            .map(Ordered::into_key)
+            .collect::<Vec<String>>()
+            .await;
+        assert_eq!(keys, vec!["a", "b", "C", "D"]);
+        tree.close().await.expect("closes tree");
+
+        // Other orderings are refused
+        let err = Baildon::<Ordered<String, Reverse>, usize>::try_open_ordered("ordered_keys.db")
+            .await
+            .err()
+            .expect("wrong ordering");
+        assert!(matches!(
+            err.downcast_ref::<BaildonError>(),
+            Some(BaildonError::KeyOrderMismatch(_))
+        ));
+        let err = Baildon::<String, usize>::try_open("ordered_keys.db")
+            .await
+            .err()
+            .expect("wrong ordering");
+        assert!(matches!(
+            err.downcast_ref::<BaildonError>(),
+            Some(BaildonError::KeyOrderMismatch(_))
+        ));
+        std::fs::remove_file("ordered_keys.db").expect("cleanup");
+    }
+
+    #[tokio::test]
+    async fn it_reverses_keys() {
+        let tree = Baildon::<Reversed<u64>, usize>::try_new_ordered("reversed_keys.db", 5)
+            .await
+            .expect("creates tree file");
+        for i in 0..100 {
+            tree.insert(Reversed::new(i), i as usize)
+                .await
+                .expect("insert worked");
+        }
+        tree.close().await.expect("closes tree");
+
+        let tree = Baildon::<Reversed<u64>, usize>::try_open_ordered("reversed_keys.db")
+            .await
+            .expect("opens tree file");
+        let keys = tree
+            .keys(Direction::Ascending)
+            .await
+            .map(Ordered::into_key)
+            .collect::<Vec<u64>>()
+            .await;
+        assert_eq!(keys, (0..100).rev().collect::<Vec<u64>>());
+        assert_eq!(tree.get(&Reversed::from(42)).await, Some(42));
+        tree.close().await.expect("closes tree");
+        std::fs::remove_file("reversed_keys.db").expect("cleanup");
+    }
+
+    #[tokio::test]
+    async fn it_opens_reversed_keys_every_way() {
+        type Tree = Baildon<Reversed<u64>, usize>;
+
+        async fn assert_reversed(tree: Tree) {
+            let keys = tree
+                .keys(Direction::Ascending)
+                .await
+                .map(Ordered::into_key)
+                .collect::<Vec<u64>>()
+                .await;
+            assert_eq!(keys, vec![2, 1, 0]);
+            tree.close().await.expect("closes tree");
+        }
+
+        let tree = Tree::open_or_create_ordered("reversed_open.db", 5)
+            .await
+            .expect("creates tree file");
+        for i in 0..3 {
+            tree.insert(Reversed::new(i), i as usize)
+                .await
+                .expect("insert worked");
+        }
+        assert_reversed(tree).await;
+
+        assert_reversed(
+            Tree::open_or_create_ordered("reversed_open.db", 5)
+                .await
+                .expect("opens tree file"),
+        )
+        .await;
+        assert_reversed(
+            Tree::try_open_with_recovery_ordered("reversed_open.db", Recovery::IgnoreWal)
+                .await
+                .expect("opens tree file"),
+        )
+        .await;
+        assert_reversed(
+            Tree::try_open_shared_ordered("reversed_open.db")
+                .await
+                .expect("opens tree file"),
+        )
+        .await;
+
+        // The constructors for naturally ordered keys refuse our tree, rather than creating one
+        let mismatch = |result: Result<Baildon<u64, usize>>| {
+            matches!(
+                result
+                    .err()
+                    .expect("wrong ordering")
+                    .downcast_ref::<BaildonError>(),
+                Some(BaildonError::KeyOrderMismatch(_))
+            )
+        };
+        assert!(mismatch(
+            Baildon::open_or_create("reversed_open.db", 5).await
+        ));
+        assert!(mismatch(
+            Baildon::try_open_with_recovery("reversed_open.db", Recovery::IgnoreWal).await
+        ));
+        assert!(mismatch(Baildon::try_open_shared("reversed_open.db").await));
+        let err = crate::compat::sled::open("reversed_open.db")
+            .await
+            .err()
+            .expect("wrong ordering");
+        assert!(matches!(
+            err.downcast_ref::<BaildonError>(),
+            Some(BaildonError::KeyOrderMismatch(_))
+        ));
+        std::fs::remove_file("reversed_open.db").expect("cleanup");
+    }
+}
@@ -0,0 +1,216 @@
+//! Write Queue
+//!
+//! A bounded queue of modifications which a background task applies to a tree. When the queue is
+//! full, producers wait for space, so bursty producers experience backpressure rather than
+//! unbounded growth in latency when the WAL or disk can't keep up.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::sync::{mpsc, oneshot};
+
+use super::baildon::Baildon;
+use super::baildon::BaildonError;
+use super::baildon::BaildonKey;
+use super::baildon::BaildonValue;
+use crate::command::Command;
+
+enum Request<K, V> {
+    Command(Command<K, V>),
+    Flush(oneshot::Sender<Result<()>>),
+}
+
+/// Write queue statistics.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WriteQueueStats {
+    /// Number of requests currently queued.
+    pub depth: usize,
+    /// Maximum number of requests which may be queued.
+    pub capacity: usize,
+    /// Highest number of requests queued at once.
+    pub max_depth: usize,
+    /// Number of modifications applied to the tree.
+    pub applied: u64,
+    /// Number of modifications which failed.
+    pub failed: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    max_depth: AtomicUsize,
+    applied: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// A bounded queue of modifications to a tree.
+///
+/// Queues may be cloned to share them between producers. The background task which applies
+/// modifications stops once every clone has been dropped and the queue is empty.
+pub struct WriteQueue<K, V> {
+    sender: mpsc::Sender<Request<K, V>>,
+    counters: Arc<Counters>,
+}
+
+impl<K, V> Clone for WriteQueue<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            counters: self.counters.clone(),
+        }
+    }
+}
+
+impl<K, V> Baildon<K, V>
+where
+    K: BaildonKey + Send + Sync + 'static,
+    V: BaildonValue + Send + Sync + 'static,
+{
+    /// Create a write queue which holds at most capacity modifications.
+    ///
+    /// The queue holds a reference to our tree until it is dropped.
+    pub fn write_queue(self: &Arc<Self>, capacity: usize) -> WriteQueue<K, V> {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let counters = Arc::new(Counters::default());
+        tokio::spawn(Self::write_queue_task(
+            self.clone(),
+            receiver,
+            counters.clone(),
+        ));
+        WriteQueue { sender, counters }
+    }
+
+    async fn write_queue_task(
+        tree: Arc<Self>,
+        mut receiver: mpsc::Receiver<Request<K, V>>,
+        counters: Arc<Counters>,
+    ) {
+        // Remember the first failure, so we can report it when the queue is next flushed
+        let mut failure = None;
+        while let Some(request) = receiver.recv().await {
+            let result = match request {
+                Request::Command(Command::Upsert(key, value)) => {
+                    tree.insert(key, value).await.map(|_| ())
+                }
+                Request::Command(Command::Delete(key)) => tree.delete(&key).await.map(|_| ()),
+                Request::Command(Command::Rename(from, to) | Command::Move(from, to, _)) => {
+                    tree.rename(&from, to, true).await.map(|_| ())
+                }
+                Request::Flush(reply) => {
+                    let _ = reply.send(failure.take().map_or(Ok(()), Err));
+                    continue;
+                }
+            };
+            match result {
+                Ok(()) => {
+                    counters.applied.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    counters.failed.fetch_add(1, Ordering::Relaxed);
+                    tracing::error!("queued write failed: {e}");
+                    failure.get_or_insert(e);
+                }
+            }
+        }
+    }
+}
+
+impl<K, V> WriteQueue<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    /// Queue the insertion of a Key and Value, waiting for space if the queue is full.
+    pub async fn insert(&self, key: K, value: V) -> Result<()> {
+        self.send(Request::Command(Command::Upsert(key, value)))
+            .await
+    }
+
+    /// Queue the deletion of a Key, waiting for space if the queue is full.
+    pub async fn delete(&self, key: K) -> Result<()> {
+        self.send(Request::Command(Command::Delete(key))).await
+    }
+
+    /// Wait until all previously queued modifications have been applied to our tree.
+    ///
+    /// If any modifications failed since the queue was last flushed, the first failure is
+    /// returned.
+    pub async fn flush_queue(&self) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(Request::Flush(sender)).await?;
+        receiver.await.map_err(|_| BaildonError::QueueClosed)?
+    }
+
+    /// Return write queue statistics.
+    pub fn stats(&self) -> WriteQueueStats {
+        WriteQueueStats {
+            depth: self.depth(),
+            capacity: self.sender.max_capacity(),
+            max_depth: self.counters.max_depth.load(Ordering::Relaxed),
+            applied: self.counters.applied.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+        }
+    }
+
+    fn depth(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    async fn send(&self, request: Request<K, V>) -> Result<()> {
+        self.sender
+            .send(request)
+            .await
+            .map_err(|_| BaildonError::QueueClosed)?;
+        self.counters
+            .max_depth
+            .fetch_max(self.depth(), Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn it_applies_queued_writes() {
+        let tree = Arc::new(
+            Baildon::<usize, usize>::temporary(7)
+                .await
+                .expect("creates tree file"),
+        );
+        let queue = tree.write_queue(4);
+
+        let producer = queue.clone();
+        tokio::spawn(async move {
+            for i in 0..100 {
+                producer.insert(i, i).await.expect("queues insert");
+            }
+            for i in (0..100).step_by(2) {
+                producer.delete(i).await.expect("queues delete");
+            }
+        })
+        .await
+        .expect("producer finished");
+
+        queue.flush_queue().await.expect("queue flushed");
+        assert_eq!(tree.count().await, 50);
+        let stats = queue.stats();
+        assert_eq!(stats.depth, 0);
+        assert_eq!(stats.capacity, 4);
+        assert!(stats.max_depth <= 4);
+        assert_eq!(stats.applied, 150);
+        assert_eq!(stats.failed, 0);
+
+        // Dropping our queue stops the task, releasing its reference to our tree
+        drop(queue);
+        while Arc::strong_count(&tree) > 1 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        Arc::into_inner(tree)
+            .expect("only reference")
+            .close()
+            .await
+            .expect("closes tree");
+    }
+}
@@ -0,0 +1,314 @@
+//! Repair
+//!
+//! Rebuild a tree from the entries in its leaf nodes, ignoring the (possibly corrupt) internal
+//! structure of the tree.
+//!
+//! If the file header or footer is destroyed, leaf nodes can still be salvaged by scanning every
+//! block in the file for data which deserializes as a leaf.
+
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use futures::stream;
+use futures::Stream;
+use futures::StreamExt;
+
+use super::baildon::locked_error;
+use super::baildon::Baildon;
+use super::baildon::BaildonError;
+use super::baildon::BaildonKey;
+use super::baildon::BaildonValue;
+use super::baildon::Direction;
+use super::bloom::remove_bloom_file;
+use super::node::Node;
+use super::order::NATURAL_ORDER;
+use crate::io::file::BTreeFile;
+use crate::io::file::BlockScanner;
+
+/// How much data we try to deserialize as a leaf node when salvaging.
+const SALVAGE_WINDOW: u64 = 1024 * 1024;
+
+/// The result of repairing a tree.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RepairReport {
+    /// Number of nodes read.
+    pub nodes: usize,
+    /// Number of nodes which couldn't be read.
+    pub unreadable: usize,
+    /// Number of entries recovered.
+    pub entries: usize,
+}
+
+impl<K, V> Baildon<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    /// Rebuild the store at the specified path from the entries in its leaf nodes.
+    ///
+    /// Every node which can be read is examined and a fresh store is built from the entries in
+    /// the leaf nodes. The rebuilt store then replaces the original. Any WAL is left in place, so
+    /// it will be replayed when the store is next opened.
+    ///
+    /// If the store can't be opened at all, the entries are salvaged instead (see
+    /// [`Baildon::salvage`]).
+    ///
+    /// The store must not be open.
+    pub async fn repair<P: AsRef<Path>>(origin: P) -> Result<RepairReport> {
+        let path: &Path = origin.as_ref();
+
+        tracing::info!("Repairing B+Tree at: {}", path.display());
+
+        let mut file = match BTreeFile::try_open(path).await {
+            Ok(file) => file,
+            Err(e) => {
+                let e = locked_error(path, e);
+                let missing = matches!(
+                    e.downcast_ref::<std::io::Error>(),
+                    Some(io_error) if io_error.kind() == ErrorKind::NotFound
+                );
+                if missing || matches!(e.downcast_ref(), Some(BaildonError::Locked(_))) {
+                    return Err(e);
+                }
+                tracing::warn!("could not open B+Tree, salvaging instead: {e}");
+                return Self::repair_from_salvage(path).await;
+            }
+        };
+
+        let mut report = RepairReport::default();
+        let mut branch = None;
+        let mut entries = BTreeMap::new();
+        // Only entries are recovered, which every version of our leaves contains
+        for index in file.indices() {
+            let node = file
+                .read_data(index)
+                .await
+                .and_then(|buf| Node::<K, V>::deserialize_unlinked(&buf));
+            match node {
+                Ok(node) if node.is_leaf() => match node.pairs() {
+                    Ok(pairs) => {
+                        report.nodes += 1;
+                        branch.get_or_insert(node.branch());
+                        entries.extend(pairs.into_iter().map(|(k, v)| (k.clone(), v.clone())));
+                    }
+                    Err(e) => {
+                        tracing::warn!("could not read values of node with index {index}: {e}");
+                        report.unreadable += 1;
+                    }
+                },
+                Ok(node) => {
+                    report.nodes += 1;
+                    branch.get_or_insert(node.branch());
+                }
+                Err(e) => {
+                    tracing::warn!("could not read node with index {index}: {e}");
+                    report.unreadable += 1;
+                }
+            }
+        }
+        let branch = branch.ok_or_else(|| BaildonError::Unrecoverable(path.into()))?;
+        report.entries = entries.len();
+
+        let tmp_path = Self::rebuild(path, branch, file.get_key_order(), entries).await?;
+        // Release our lock before we replace our file
+        drop(file);
+        // Our filter describes our old file
+        remove_bloom_file(path).await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+
+        tracing::info!(
+            nodes = report.nodes,
+            unreadable = report.unreadable,
+            entries = report.entries,
+            "Repaired B+Tree"
+        );
+        Ok(report)
+    }
+
+    async fn repair_from_salvage(path: &Path) -> Result<RepairReport> {
+        let mut report = RepairReport::default();
+        let mut branch = None;
+        let mut entries = BTreeMap::new();
+        let mut leaves = Box::pin(Self::salvage_leaves(path).await?);
+        while let Some(leaf) = leaves.next().await {
+            report.nodes += 1;
+            branch.get_or_insert(leaf.branch());
+            // Salvaged leaves are plausible, so their values have already been decoded
+            entries.extend(leaf.into_pairs(Direction::Ascending).unwrap_or_default());
+        }
+        let branch = branch.ok_or_else(|| BaildonError::Unrecoverable(path.into()))?;
+        report.entries = entries.len();
+
+        // Our header is lost, so we have to assume our keys have their natural ordering
+        let tmp_path = Self::rebuild(path, branch, NATURAL_ORDER, entries).await?;
+        // Our filter describes our old file
+        remove_bloom_file(path).await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+
+        tracing::info!(
+            nodes = report.nodes,
+            entries = report.entries,
+            "Repaired B+Tree from salvage"
+        );
+        Ok(report)
+    }
+
+    /// Scan the file at the specified path for leaf nodes, streaming the entries they contain.
+    ///
+    /// The header and footer of the file are ignored, so entries can be recovered even if they
+    /// are destroyed. Since freed blocks can't be told apart from blocks in use, the stream may
+    /// contain old versions of entries, or entries which were deleted. Leaf nodes larger than
+    /// 1MB aren't recovered.
+    ///
+    /// The recovered entries may be inserted into a new store.
+    pub async fn salvage<P: AsRef<Path>>(origin: P) -> Result<impl Stream<Item = (K, V)>> {
+        let leaves = Self::salvage_leaves(origin.as_ref()).await?;
+        Ok(leaves.flat_map(|leaf| {
+            stream::iter(leaf.into_pairs(Direction::Ascending).unwrap_or_default())
+        }))
+    }
+
+    async fn salvage_leaves(path: &Path) -> Result<impl Stream<Item = Node<K, V>>> {
+        tracing::info!("Salvaging B+Tree at: {}", path.display());
+
+        let scanner = BlockScanner::try_open(path).await?;
+
+        Ok(stream::unfold(scanner, |mut scanner| async move {
+            loop {
+                let buf = match scanner.next_block(SALVAGE_WINDOW).await {
+                    Ok(Some(buf)) => buf,
+                    Ok(None) => return None,
+                    Err(e) => {
+                        tracing::error!("salvage stopped, could not read block: {e}");
+                        return None;
+                    }
+                };
+                // Our header is lost, so we can't tell if our leaves are linked
+                let Ok(node) = Node::<K, V>::deserialize_unlinked(&buf) else {
+                    continue;
+                };
+                if !Self::plausible_leaf(&node) {
+                    continue;
+                }
+                // Don't look for leaves inside the leaf we just found
+                if let Ok(s_node) = node.serialize() {
+                    scanner.skip(s_node.len() as u64);
+                }
+                return Some((node, scanner));
+            }
+        }))
+    }
+
+    /// Could this node have been written by us, or is it just noise?
+    fn plausible_leaf(node: &Node<K, V>) -> bool {
+        node.is_leaf()
+            && node.branch() >= 2
+            && node.len() as u64 <= node.branch()
+            && node.index() > 0
+            && node.keys_sorted()
+            && node.decode_values().is_ok()
+    }
+
+    /// Build a new store alongside the store at the specified path, returning its path.
+    async fn rebuild(
+        path: &Path,
+        branch: u64,
+        key_order: u64,
+        entries: BTreeMap<K, V>,
+    ) -> Result<PathBuf> {
+        let mut tmp_path = path.to_path_buf();
+        tmp_path.set_extension("repair.db");
+        let mut tmp_wal_path = tmp_path.clone();
+        tmp_wal_path.set_extension("wal");
+        // Clean up after any previous failed attempt
+        for stale in [&tmp_path, &tmp_wal_path] {
+            if let Err(e) = tokio::fs::remove_file(stale).await {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    return Err(e.into());
+                }
+            }
+        }
+
+        let tree = Baildon::<K, V>::create(&tmp_path, branch, key_order).await?;
+        tree.extend(entries).await?;
+        tree.close().await?;
+        Ok(tmp_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::btree::Direction;
+
+    #[tokio::test]
+    async fn it_repairs_tree() {
+        let tree = Baildon::<usize, usize>::try_new("repair_tree.db", 5)
+            .await
+            .expect("creates tree file");
+        for i in 0..200 {
+            tree.insert(i, i).await.expect("insert worked");
+        }
+        tree.close().await.expect("closes tree");
+
+        let report = Baildon::<usize, usize>::repair("repair_tree.db")
+            .await
+            .expect("repairs tree");
+        assert_eq!(report.entries, 200);
+        assert_eq!(report.unreadable, 0);
+
+        let tree = Baildon::<usize, usize>::try_open("repair_tree.db")
+            .await
+            .expect("opens tree file");
+        assert_eq!(tree.count().await, 200);
+        assert!(tree
+            .verify(Direction::Ascending)
+            .await
+            .expect("verifies tree")
+            .is_ok());
+        tree.close().await.expect("closes tree");
+        std::fs::remove_file("repair_tree.db").expect("cleanup");
+    }
+
+    #[tokio::test]
+    async fn it_salvages_tree() {
+        let tree = Baildon::<usize, usize>::try_new("salvage_tree.db", 5)
+            .await
+            .expect("creates tree file");
+        for i in 0..200 {
+            tree.insert(i, i).await.expect("insert worked");
+        }
+        tree.close().await.expect("closes tree");
+
+        // Destroy our header
+        let mut data = std::fs::read("salvage_tree.db").expect("reads file");
+        data[..512].fill(0xff);
+        std::fs::write("salvage_tree.db", data).expect("writes file");
+        assert!(Baildon::<usize, usize>::try_open("salvage_tree.db")
+            .await
+            .is_err());
+
+        let mut entries = Baildon::<usize, usize>::salvage("salvage_tree.db")
+            .await
+            .expect("salvages tree")
+            .collect::<Vec<(usize, usize)>>()
+            .await;
+        entries.sort();
+        entries.dedup();
+        assert_eq!(entries, (0..200).map(|i| (i, i)).collect::<Vec<_>>());
+
+        let report = Baildon::<usize, usize>::repair("salvage_tree.db")
+            .await
+            .expect("repairs tree");
+        assert_eq!(report.entries, 200);
+        let tree = Baildon::<usize, usize>::try_open("salvage_tree.db")
+            .await
+            .expect("opens tree file");
+        assert_eq!(tree.count().await, 200);
+        tree.close().await.expect("closes tree");
+        std::fs::remove_file("salvage_tree.db").expect("cleanup");
+    }
+}
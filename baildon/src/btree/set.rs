@@ -0,0 +1,200 @@
+//! Sets
+//!
+//! A persistent ordered set of keys, stored as a tree with unit values. Unit values occupy no
+//! space when serialized, so a set's nodes only contain keys.
+
+use std::ops::Bound;
+use std::ops::RangeBounds;
+use std::path::Path;
+
+use anyhow::Result;
+use futures::future;
+use futures::Stream;
+use futures::StreamExt;
+
+use super::baildon::Baildon;
+use super::baildon::BaildonKey;
+use super::baildon::Direction;
+
+/// A persistent ordered set of keys.
+pub struct BaildonSet<K>
+where
+    K: BaildonKey + Send + Sync,
+{
+    tree: Baildon<K, ()>,
+}
+
+impl<K> BaildonSet<K>
+where
+    K: BaildonKey + Send + Sync,
+{
+    /// Create a new set at the specified path with the specified branching factor.
+    pub async fn try_new<P: AsRef<Path>>(origin: P, branch: u64) -> Result<Self> {
+        Baildon::try_new(origin, branch).await.map(Self::from)
+    }
+
+    /// Open an existing set at the specified path.
+    pub async fn try_open<P: AsRef<Path>>(origin: P) -> Result<Self> {
+        Baildon::try_open(origin).await.map(Self::from)
+    }
+
+    /// Create a new set, with the specified branching factor, in the temporary directory.
+    pub async fn temporary(branch: u64) -> Result<Self> {
+        Baildon::temporary(branch).await.map(Self::from)
+    }
+
+    /// Close our set, flushing all our updated nodes to disk.
+    pub async fn close(self) -> Result<()> {
+        self.tree.close().await
+    }
+
+    /// Return the tree which stores our keys.
+    pub fn tree(&self) -> &Baildon<K, ()> {
+        &self.tree
+    }
+
+    /// Unwrap the tree which stores our keys.
+    pub fn into_tree(self) -> Baildon<K, ()> {
+        self.tree
+    }
+
+    /// Insert a key, returning true if it wasn't already present.
+    pub async fn insert(&self, key: K) -> Result<bool> {
+        Ok(self.tree.insert(key, ()).await?.is_none())
+    }
+
+    /// Does the set contain this key?
+    pub async fn contains(&self, key: &K) -> bool {
+        self.tree.contains(key).await
+    }
+
+    /// Remove a key, returning true if it was present.
+    pub async fn remove(&self, key: &K) -> Result<bool> {
+        Ok(self.tree.delete(key).await?.is_some())
+    }
+
+    /// Return the number of keys in our set.
+    pub async fn len(&self) -> usize {
+        self.tree.count().await
+    }
+
+    /// Is our set empty?
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// Return a stream of our keys.
+    pub async fn iter(&self, direction: Direction) -> impl Stream<Item = K> + '_ {
+        self.tree.keys(direction).await
+    }
+
+    /// Return a stream of the keys within a range.
+    ///
+    /// Keys outside the range are skipped while streaming, so the cost depends on where the range
+    /// starts.
+    pub async fn range<R: RangeBounds<K>>(
+        &self,
+        range: R,
+        direction: Direction,
+    ) -> impl Stream<Item = K> + '_ {
+        self.bounded(
+            range.start_bound().cloned(),
+            range.end_bound().cloned(),
+            direction,
+        )
+        .await
+    }
+
+    // Not generic over the type of range, so our stream doesn't capture it
+    async fn bounded(
+        &self,
+        start: Bound<K>,
+        end: Bound<K>,
+        direction: Direction,
+    ) -> impl Stream<Item = K> + '_ {
+        let ascending = direction == Direction::Ascending;
+        let (skip_start, skip_end) = (start.clone(), end.clone());
+        self.iter(direction)
+            .await
+            .skip_while(move |key| {
+                future::ready(if ascending {
+                    !after_start(&skip_start, key)
+                } else {
+                    !before_end(&skip_end, key)
+                })
+            })
+            .take_while(move |key| {
+                future::ready(if ascending {
+                    before_end(&end, key)
+                } else {
+                    after_start(&start, key)
+                })
+            })
+    }
+}
+
+impl<K> From<Baildon<K, ()>> for BaildonSet<K>
+where
+    K: BaildonKey + Send + Sync,
+{
+    fn from(tree: Baildon<K, ()>) -> Self {
+        Self { tree }
+    }
+}
+
+fn after_start<K: Ord>(start: &Bound<K>, key: &K) -> bool {
+    match start {
+        Bound::Included(start) => key >= start,
+        Bound::Excluded(start) => key > start,
+        Bound::Unbounded => true,
+    }
+}
+
+fn before_end<K: Ord>(end: &Bound<K>, key: &K) -> bool {
+    match end {
+        Bound::Included(end) => key <= end,
+        Bound::Excluded(end) => key < end,
+        Bound::Unbounded => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_stores_keys() {
+        let set = BaildonSet::<usize>::temporary(5)
+            .await
+            .expect("creates set file");
+        assert!(set.is_empty().await);
+        for i in (0..100).rev() {
+            assert!(set.insert(i).await.expect("insert worked"));
+        }
+        assert!(!set.insert(42).await.expect("insert worked"));
+        assert_eq!(set.len().await, 100);
+        assert!(set.contains(&42).await);
+        assert!(set.remove(&42).await.expect("remove worked"));
+        assert!(!set.remove(&42).await.expect("remove worked"));
+        assert!(!set.contains(&42).await);
+
+        let keys = set
+            .range(40..=45, Direction::Ascending)
+            .await
+            .collect::<Vec<usize>>()
+            .await;
+        assert_eq!(keys, vec![40, 41, 43, 44, 45]);
+        let keys = set
+            .range(..3, Direction::Descending)
+            .await
+            .collect::<Vec<usize>>()
+            .await;
+        assert_eq!(keys, vec![2, 1, 0]);
+        let keys = set
+            .range(97.., Direction::Descending)
+            .await
+            .collect::<Vec<usize>>()
+            .await;
+        assert_eq!(keys, vec![99, 98, 97]);
+    }
+}
@@ -0,0 +1,177 @@
+//! Snapshots
+//!
+//! A portable format for backing up the entries of a tree. Snapshots only contain entries, so
+//! they don't depend on the node or block layout of our data file and can be imported by trees
+//! using any file format version.
+//!
+//! Snapshot layout (integers are big endian):
+//!  - Magic `BAILDON\0`
+//!  - Version (u8)
+//!  - Branching factor of the exported tree (u64)
+//!  - Entries, each: tag 1 (u8), length (u64), bincode serialized (Key, Value)
+//!  - End: tag 0 (u8), number of entries (u64)
+
+use anyhow::Result;
+use bincode::Options;
+use futures::StreamExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::baildon::Baildon;
+use super::baildon::BaildonError;
+use super::baildon::BaildonKey;
+use super::baildon::BaildonValue;
+use super::baildon::Direction;
+use crate::BINCODER;
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"BAILDON\0";
+
+const SNAPSHOT_VERSION_1: u8 = 1;
+
+const ENTRY_TAG: u8 = 1;
+
+const END_TAG: u8 = 0;
+
+impl<K, V> Baildon<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    /// Write a snapshot of all our entries, returning the number of entries written.
+    pub async fn export_snapshot<W: AsyncWrite + Unpin>(&self, mut writer: W) -> Result<u64> {
+        writer.write_all(SNAPSHOT_MAGIC).await?;
+        writer.write_u8(SNAPSHOT_VERSION_1).await?;
+        writer.write_u64(self.branch()).await?;
+
+        let mut count = 0;
+        let mut entries = self.entries(Direction::Ascending).await;
+        while let Some(entry) = entries.next().await {
+            let s_entry = BINCODER.serialize(&entry)?;
+            writer.write_u8(ENTRY_TAG).await?;
+            writer.write_u64(s_entry.len() as u64).await?;
+            writer.write_all(&s_entry).await?;
+            count += 1;
+        }
+
+        writer.write_u8(END_TAG).await?;
+        writer.write_u64(count).await?;
+        writer.flush().await?;
+        Ok(count)
+    }
+
+    /// Insert all the entries from a snapshot, returning the number of entries read.
+    ///
+    /// Entries are inserted as they are read, so if the snapshot is invalid, some entries may
+    /// already have been inserted.
+    pub async fn import_snapshot<R: AsyncRead + Unpin>(&self, mut reader: R) -> Result<u64> {
+        let mut magic = [0; SNAPSHOT_MAGIC.len()];
+        reader.read_exact(&mut magic).await?;
+        if magic != *SNAPSHOT_MAGIC {
+            return Err(BaildonError::InvalidSnapshot("not a snapshot".to_string()).into());
+        }
+        let version = reader.read_u8().await?;
+        if version != SNAPSHOT_VERSION_1 {
+            return Err(
+                BaildonError::InvalidSnapshot(format!("unsupported version: {version}")).into(),
+            );
+        }
+        let _branch = reader.read_u64().await?;
+
+        let mut count = 0;
+        loop {
+            match reader.read_u8().await? {
+                ENTRY_TAG => {
+                    let len = reader.read_u64().await?;
+                    // Our length is untrusted, so only allocate what we can actually read
+                    let mut buf = vec![];
+                    (&mut reader).take(len).read_to_end(&mut buf).await?;
+                    if buf.len() as u64 != len {
+                        return Err(BaildonError::InvalidSnapshot(format!(
+                            "expected {len} byte entry, read {}",
+                            buf.len()
+                        ))
+                        .into());
+                    }
+                    let (key, value): (K, V) = BINCODER.deserialize(&buf)?;
+                    self.insert(key, value).await?;
+                    count += 1;
+                }
+                END_TAG => {
+                    let expected = reader.read_u64().await?;
+                    if expected != count {
+                        return Err(BaildonError::InvalidSnapshot(format!(
+                            "expected {expected} entries, read {count}"
+                        ))
+                        .into());
+                    }
+                    return Ok(count);
+                }
+                tag => {
+                    return Err(BaildonError::InvalidSnapshot(format!("unknown tag: {tag}")).into())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_exports_and_imports_snapshot() {
+        let tree = Baildon::<usize, String>::temporary(5)
+            .await
+            .expect("creates tree file");
+        for i in 0..200 {
+            tree.insert(i, i.to_string()).await.expect("insert worked");
+        }
+        let mut snapshot = vec![];
+        let exported = tree
+            .export_snapshot(&mut snapshot)
+            .await
+            .expect("exports snapshot");
+        assert_eq!(exported, 200);
+
+        let copy = Baildon::<usize, String>::temporary(7)
+            .await
+            .expect("creates tree file");
+        let imported = copy
+            .import_snapshot(snapshot.as_slice())
+            .await
+            .expect("imports snapshot");
+        assert_eq!(imported, 200);
+        assert_eq!(copy.to_btreemap().await, tree.to_btreemap().await);
+
+        // Truncated snapshots are rejected
+        let err = copy
+            .import_snapshot(&snapshot[..snapshot.len() - 9])
+            .await
+            .expect_err("snapshot is truncated");
+        assert!(err.downcast_ref::<std::io::Error>().is_some());
+
+        // Entry lengths aren't trusted
+        let header = SNAPSHOT_MAGIC.len() + 1 + 8;
+        let mut oversized = snapshot[..header].to_vec();
+        oversized.push(ENTRY_TAG);
+        oversized.extend_from_slice(&u64::MAX.to_be_bytes());
+        oversized.extend_from_slice(b"short");
+        let err = copy
+            .import_snapshot(oversized.as_slice())
+            .await
+            .expect_err("entry is truncated");
+        assert!(matches!(
+            err.downcast_ref::<BaildonError>(),
+            Some(BaildonError::InvalidSnapshot(_))
+        ));
+
+        snapshot[0] = b'X';
+        let err = copy
+            .import_snapshot(snapshot.as_slice())
+            .await
+            .expect_err("snapshot is invalid");
+        assert!(matches!(
+            err.downcast_ref::<BaildonError>(),
+            Some(BaildonError::InvalidSnapshot(_))
+        ));
+    }
+}
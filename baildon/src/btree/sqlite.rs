@@ -0,0 +1,145 @@
+//! SQLite
+//!
+//! Import the rows of SQLite tables, so that lookup tables can be migrated to a tree.
+
+use std::path::Path;
+
+use anyhow::Result;
+use rusqlite::types::FromSql;
+use rusqlite::Connection;
+use rusqlite::OpenFlags;
+
+use super::baildon::Baildon;
+use super::baildon::BaildonKey;
+use super::baildon::BaildonValue;
+
+impl<K, V> Baildon<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    /// Create a new store at the specified path, with the specified branching factor, containing
+    /// the key and value columns of every row of a SQLite table.
+    ///
+    /// Rows are read in the order of their keys and bulk loaded (see [`Baildon::bulk_load`]).
+    /// Keys are ordered by SQLite, so if its ordering doesn't match the ordering of our keys (or
+    /// keys aren't unique), an error is returned.
+    pub async fn from_sqlite<P, Q>(
+        origin: P,
+        branch: u64,
+        database: Q,
+        table: &str,
+        key_col: &str,
+        value_col: &str,
+    ) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+        K: FromSql + 'static,
+        V: FromSql + 'static,
+    {
+        let rows = read_table(database.as_ref(), table, key_col, value_col).await?;
+        Self::bulk_load(origin, branch, rows).await
+    }
+
+    /// Insert the key and value columns of every row of a SQLite table, replacing the values of
+    /// any existing keys. Returns the number of rows read.
+    pub async fn import_sqlite<Q: AsRef<Path>>(
+        &self,
+        database: Q,
+        table: &str,
+        key_col: &str,
+        value_col: &str,
+    ) -> Result<u64>
+    where
+        K: FromSql + 'static,
+        V: FromSql + 'static,
+    {
+        let rows = read_table(database.as_ref(), table, key_col, value_col).await?;
+        let count = rows.len() as u64;
+        self.extend(rows).await?;
+        Ok(count)
+    }
+}
+
+/// Read the key and value columns of a table, in order of key.
+async fn read_table<K, V>(
+    database: &Path,
+    table: &str,
+    key_col: &str,
+    value_col: &str,
+) -> Result<Vec<(K, V)>>
+where
+    K: FromSql + Send + 'static,
+    V: FromSql + Send + 'static,
+{
+    let database = database.to_path_buf();
+    let query = format!(
+        "SELECT {}, {} FROM {} ORDER BY 1",
+        quote(key_col),
+        quote(value_col),
+        quote(table)
+    );
+    // rusqlite is synchronous
+    tokio::task::spawn_blocking(move || {
+        let connection = Connection::open_with_flags(database, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let mut statement = connection.prepare(&query)?;
+        let rows = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(K, V)>>>()?;
+        Ok::<_, anyhow::Error>(rows)
+    })
+    .await?
+}
+
+/// Quote an identifier, so that any name can be used.
+fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_imports_sqlite_tables() {
+        let connection = Connection::open("import_sqlite.sqlite").expect("creates database");
+        connection
+            .execute_batch(
+                "CREATE TABLE \"lookup table\" (id INTEGER, name TEXT, code TEXT);
+                 INSERT INTO \"lookup table\" VALUES (3, 'three', 'c'), (1, 'one', 'a'),
+                     (2, 'two', 'b');",
+            )
+            .expect("creates table");
+        drop(connection);
+
+        let tree = Baildon::<i64, String>::from_sqlite(
+            "import_sqlite.db",
+            5,
+            "import_sqlite.sqlite",
+            "lookup table",
+            "id",
+            "name",
+        )
+        .await
+        .expect("imports table");
+        assert_eq!(tree.count().await, 3);
+        assert_eq!(tree.get(&2).await, Some("two".to_string()));
+
+        let count = tree
+            .import_sqlite("import_sqlite.sqlite", "lookup table", "id", "code")
+            .await
+            .expect("imports table");
+        assert_eq!(count, 3);
+        assert_eq!(tree.get(&2).await, Some("b".to_string()));
+
+        assert!(tree
+            .import_sqlite("import_sqlite.sqlite", "lookup table", "id", "none")
+            .await
+            .is_err());
+
+        tree.close().await.expect("closes tree");
+        std::fs::remove_file("import_sqlite.db").expect("cleanup");
+        std::fs::remove_file("import_sqlite.sqlite").expect("cleanup");
+    }
+}
@@ -0,0 +1,211 @@
+//! Statistics
+//!
+//! Describe the shape of a tree and the resources it is using.
+
+use std::fmt;
+
+use anyhow::Result;
+
+use super::baildon::Baildon;
+use super::baildon::BaildonKey;
+use super::baildon::BaildonValue;
+use super::baildon::CachePolicy;
+
+/// Number of buckets in our fill histogram, each covering a tenth of a node's capacity.
+const FILL_BUCKETS: usize = 10;
+
+/// Statistics describing a tree.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TreeStats {
+    /// Branching factor.
+    pub branch: u64,
+    /// Number of levels of nodes.
+    pub depth: usize,
+    /// Number of nodes at each level, starting with the root.
+    pub nodes_per_level: Vec<usize>,
+    /// Number of entries.
+    pub entries: usize,
+    /// Fraction of the capacity of our leaf nodes which is used.
+    pub utilization: f64,
+    /// Fill of the nodes at each level, starting with the root.
+    pub fill_per_level: Vec<FillStats>,
+    /// Number of leaf nodes whose fill is within each tenth of their capacity. The last bucket
+    /// includes full nodes.
+    pub fill_histogram: Vec<usize>,
+    /// Shallowest level at which a leaf node was found. Levels are numbered from 1 (the root).
+    pub min_leaf_depth: usize,
+    /// Deepest level at which a leaf node was found. All leaves should be at the same depth.
+    pub max_leaf_depth: usize,
+    /// Number of nodes in our cache.
+    pub cached_nodes: usize,
+    /// Size of our data file, in bytes.
+    pub file_size: u64,
+}
+
+/// How full the nodes of a level are, as fractions of their capacity.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FillStats {
+    /// Fill of the emptiest node.
+    pub min: f64,
+    /// Mean fill.
+    pub mean: f64,
+    /// Fill of the fullest node.
+    pub max: f64,
+}
+
+impl fmt::Display for FillStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.1}%/{:.1}%/{:.1}%",
+            100.0 * self.min,
+            100.0 * self.mean,
+            100.0 * self.max
+        )
+    }
+}
+
+impl TreeStats {
+    /// Total number of nodes.
+    pub fn nodes(&self) -> usize {
+        self.nodes_per_level.iter().sum()
+    }
+}
+
+impl fmt::Display for TreeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "branching: {}", self.branch)?;
+        writeln!(f, "entries: {}", self.entries)?;
+        writeln!(f, "depth: {}", self.depth)?;
+        let levels = self
+            .nodes_per_level
+            .iter()
+            .map(|count| count.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+        writeln!(f, "nodes: {} (per level: {levels})", self.nodes())?;
+        writeln!(f, "utilization: {:.1}%", 100.0 * self.utilization)?;
+        let fills = self
+            .fill_per_level
+            .iter()
+            .map(|fill| fill.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+        writeln!(f, "fill min/mean/max per level: {fills}")?;
+        let histogram = self
+            .fill_histogram
+            .iter()
+            .enumerate()
+            .map(|(bucket, count)| format!("{}%: {count}", (bucket + 1) * 100 / FILL_BUCKETS))
+            .collect::<Vec<String>>()
+            .join(", ");
+        writeln!(f, "leaf fill histogram: {histogram}")?;
+        writeln!(
+            f,
+            "leaf depth: {} to {}",
+            self.min_leaf_depth, self.max_leaf_depth
+        )?;
+        writeln!(f, "cached nodes: {}", self.cached_nodes)?;
+        write!(f, "file size: {} bytes", self.file_size)
+    }
+}
+
+impl<K, V> Baildon<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    /// Return statistics describing our tree.
+    ///
+    /// Every node is visited, so this is as expensive as a full traversal.
+    pub async fn stats(&self) -> Result<TreeStats> {
+        let mut stats = TreeStats {
+            branch: self.branch(),
+            file_size: self.file.lock().await.size().await?,
+            ..Default::default()
+        };
+
+        let nodes_lock = self.nodes.read().await;
+        let branch = self.branch() as f64;
+        let mut leaves = 0;
+        stats.fill_histogram = vec![0; FILL_BUCKETS];
+        let mut level = vec![*self.root.lock().await];
+        while !level.is_empty() {
+            let depth = stats.nodes_per_level.len() + 1;
+            stats.nodes_per_level.push(level.len());
+            let mut fill = FillStats {
+                min: f64::MAX,
+                ..Default::default()
+            };
+            let mut next = vec![];
+            for idx in &level {
+                let node = self
+                    .find_node_with_lock_and_policy(&nodes_lock, *idx, CachePolicy::Bypass)
+                    .await?;
+                let node_fill = node.len() as f64 / branch;
+                fill.min = fill.min.min(node_fill);
+                fill.max = fill.max.max(node_fill);
+                fill.mean += node_fill;
+                if node.is_leaf() {
+                    leaves += 1;
+                    stats.entries += node.len();
+                    let bucket = (node_fill * FILL_BUCKETS as f64) as usize;
+                    stats.fill_histogram[bucket.min(FILL_BUCKETS - 1)] += 1;
+                    if stats.min_leaf_depth == 0 {
+                        stats.min_leaf_depth = depth;
+                    }
+                    stats.max_leaf_depth = depth;
+                } else {
+                    next.extend(node.children());
+                }
+            }
+            fill.mean /= level.len() as f64;
+            stats.fill_per_level.push(fill);
+            level = next;
+        }
+        stats.cached_nodes = nodes_lock.len();
+        stats.depth = stats.nodes_per_level.len();
+        stats.utilization = stats.entries as f64 / (leaves as f64 * branch);
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_reports_stats() {
+        let tree = Baildon::<usize, usize>::temporary(5)
+            .await
+            .expect("creates tree file");
+        let stats = tree.stats().await.expect("reports stats");
+        assert_eq!(stats.depth, 1);
+        assert_eq!(stats.nodes_per_level, vec![1]);
+        assert_eq!(stats.entries, 0);
+
+        for i in 0..200 {
+            tree.insert(i, i).await.expect("insert worked");
+        }
+        let stats = tree.stats().await.expect("reports stats");
+        assert_eq!(stats.branch, 5);
+        assert_eq!(stats.entries, 200);
+        assert_eq!(stats.depth, tree.depth().await);
+        assert_eq!(stats.nodes_per_level[0], 1);
+        assert!(stats.nodes_per_level.windows(2).all(|w| w[0] < w[1]));
+        assert!(stats.utilization > 0.0 && stats.utilization <= 1.0);
+        assert_eq!(stats.fill_per_level.len(), stats.depth);
+        let leaf_fill = stats.fill_per_level[stats.depth - 1];
+        assert!(leaf_fill.min <= leaf_fill.mean && leaf_fill.mean <= leaf_fill.max);
+        assert!((leaf_fill.mean - stats.utilization).abs() < 1e-9);
+        assert_eq!(
+            stats.fill_histogram.iter().sum::<usize>(),
+            stats.nodes_per_level[stats.depth - 1]
+        );
+        assert_eq!(stats.min_leaf_depth, stats.depth);
+        assert_eq!(stats.max_leaf_depth, stats.depth);
+        assert!(stats.cached_nodes > 0);
+        assert!(stats.file_size > 0);
+        assert!(stats.to_string().contains("entries: 200"));
+    }
+}
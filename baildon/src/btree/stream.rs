@@ -1,12 +1,19 @@
-use std::sync::atomic::Ordering;
+use std::ops::Bound;
+use std::ops::RangeBounds;
+use std::sync::Arc;
 
 use super::baildon::Baildon;
+use super::baildon::CachePolicy;
 use super::baildon::Direction;
 use super::node::Node;
 
+use futures::future;
 use futures::stream;
 use futures::Stream;
 use futures::StreamExt;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
@@ -17,166 +24,304 @@ where
 {
     /// Return a stream of entries
     pub async fn entries(&self, direction: Direction) -> impl Stream<Item = (K, V)> + '_ {
-        let mut streamer = self.stream_all_leaf_nodes(direction).await;
-        let index = 0;
-        let leaf_opt = streamer.next().await;
-
-        // Each node contains a number of values, we must read all the values from the current node
-        // before advancing. (i.e.: a loop within a loop)
-        Box::pin(stream::unfold(
-            (streamer, leaf_opt, index),
-            move |mut triplet| async move {
-                loop {
-                    match &triplet.1 {
-                        Some(leaf) => {
-                            let pair_opt = if direction == Direction::Ascending {
-                                leaf.pairs().nth(triplet.2)
-                            } else {
-                                leaf.pairs().rev().nth(triplet.2)
-                            };
-                            match pair_opt {
-                                Some(pair) => {
-                                    triplet.2 += 1;
-                                    break Some(((pair.0.clone(), pair.1.clone()), triplet));
-                                }
-                                None => {
-                                    triplet.1 = triplet.0.next().await;
-                                    triplet.2 = 0;
-                                    continue;
-                                }
-                            }
-                        }
-                        None => break None,
-                    };
-                }
-            },
-        ))
+        self.entries_with_policy(direction, CachePolicy::Populate)
+            .await
+    }
+
+    /// Return a stream of entries, using the supplied cache policy for leaf nodes
+    pub async fn entries_with_policy(
+        &self,
+        direction: Direction,
+        policy: CachePolicy,
+    ) -> impl Stream<Item = (K, V)> + '_ {
+        // Each leaf is drained in turn, so every entry is only visited once
+        Box::pin(
+            self.stream_all_leaf_nodes(direction, policy)
+                .await
+                .flat_map(move |leaf| stream::iter(leaf_pairs(leaf, direction))),
+        )
     }
 
     /// Return a stream of keys
     pub async fn keys(&self, direction: Direction) -> impl Stream<Item = K> + '_ {
-        let mut streamer = self.stream_all_leaf_nodes(direction).await;
-        let index = 0;
-        let leaf_opt = streamer.next().await;
-
-        // Each node contains a number of keys, we must read all the keys from the current node
-        // before advancing. (i.e.: a loop within a loop)
-        Box::pin(stream::unfold(
-            (streamer, leaf_opt, index),
-            move |mut triplet| async move {
-                loop {
-                    match &triplet.1 {
-                        Some(leaf) => {
-                            let key_opt = if direction == Direction::Ascending {
-                                leaf.keys().nth(triplet.2)
-                            } else {
-                                leaf.keys().rev().nth(triplet.2)
-                            };
-                            match key_opt {
-                                Some(key) => {
-                                    triplet.2 += 1;
-                                    break Some(((*key).clone(), triplet));
-                                }
-                                None => {
-                                    triplet.1 = triplet.0.next().await;
-                                    triplet.2 = 0;
-                                    continue;
-                                }
-                            }
-                        }
-                        None => break None,
-                    }
-                }
-            },
-        ))
+        self.keys_with_policy(direction, CachePolicy::Populate)
+            .await
+    }
+
+    /// Return a stream of keys, using the supplied cache policy for leaf nodes
+    pub async fn keys_with_policy(
+        &self,
+        direction: Direction,
+        policy: CachePolicy,
+    ) -> impl Stream<Item = K> + '_ {
+        // Only keys are drained, so values are never deserialized
+        Box::pin(
+            self.stream_all_leaf_nodes(direction, policy)
+                .await
+                .flat_map(move |leaf| stream::iter(leaf.leaf_keys(direction))),
+        )
     }
 
     /// Return a stream of values
     pub async fn values(&self, direction: Direction) -> impl Stream<Item = V> + '_ {
-        let mut streamer = self.stream_all_leaf_nodes(direction).await;
-        let index = 0;
-        let leaf_opt = streamer.next().await;
-
-        // Each node contains a number of values, we must read all the values from the current node
-        // before advancing. (i.e.: a loop within a loop)
-        Box::pin(stream::unfold(
-            (streamer, leaf_opt, index),
-            move |mut triplet| async move {
-                loop {
-                    match &triplet.1 {
-                        Some(leaf) => {
-                            let value_opt = if direction == Direction::Ascending {
-                                leaf.values().nth(triplet.2)
-                            } else {
-                                leaf.values().rev().nth(triplet.2)
-                            };
-                            match value_opt {
-                                Some(value) => {
-                                    triplet.2 += 1;
-                                    break Some(((*value).clone(), triplet));
-                                }
-                                None => {
-                                    triplet.1 = triplet.0.next().await;
-                                    triplet.2 = 0;
-                                    continue;
-                                }
-                            }
-                        }
-                        None => break None,
-                    };
+        self.values_with_policy(direction, CachePolicy::Populate)
+            .await
+    }
+
+    /// Return a stream of values, using the supplied cache policy for leaf nodes
+    pub async fn values_with_policy(
+        &self,
+        direction: Direction,
+        policy: CachePolicy,
+    ) -> impl Stream<Item = V> + '_ {
+        self.entries_with_policy(direction, policy)
+            .await
+            .map(|(_key, value)| value)
+    }
+
+    /// Return a stream of the entries within a range
+    ///
+    /// Streaming starts from the leaf containing the start of the range (or the end, when
+    /// descending), so earlier leaves aren't visited.
+    pub async fn range<R: RangeBounds<K>>(
+        &self,
+        range: R,
+        direction: Direction,
+    ) -> impl Stream<Item = (K, V)> + '_ {
+        self.bounded(
+            range.start_bound().cloned(),
+            range.end_bound().cloned(),
+            direction,
+            leaf_pairs,
+            |(key, _value)| key,
+        )
+        .await
+    }
+
+    /// Return a stream of the keys within a range, as for [`Baildon::range`]
+    pub async fn range_keys<R: RangeBounds<K>>(
+        &self,
+        range: R,
+        direction: Direction,
+    ) -> impl Stream<Item = K> + '_ {
+        self.bounded(
+            range.start_bound().cloned(),
+            range.end_bound().cloned(),
+            direction,
+            |leaf, direction| leaf.leaf_keys(direction),
+            |key| key,
+        )
+        .await
+    }
+
+    /// Return a stream of entries, starting at a key
+    ///
+    /// The stream starts at the first key at or after the start key (at or before it, when
+    /// descending), whether or not the start key is present. Its leaf is found by searching the
+    /// tree, so pages of entries can be streamed from the last key of the previous page.
+    pub async fn entries_from(
+        &self,
+        start_key: K,
+        direction: Direction,
+    ) -> impl Stream<Item = (K, V)> + '_ {
+        let (start, end) = from_bounds(start_key, direction);
+        self.bounded(start, end, direction, leaf_pairs, |(key, _value)| key)
+            .await
+    }
+
+    /// Return a stream of keys, starting at a key, as for [`Baildon::entries_from`]
+    pub async fn keys_from(
+        &self,
+        start_key: K,
+        direction: Direction,
+    ) -> impl Stream<Item = K> + '_ {
+        let (start, end) = from_bounds(start_key, direction);
+        self.bounded(
+            start,
+            end,
+            direction,
+            |leaf, direction| leaf.leaf_keys(direction),
+            |key| key,
+        )
+        .await
+    }
+
+    /// Return a stream of the entries whose keys start with a prefix, in ascending order
+    pub async fn scan_prefix(&self, prefix: K) -> impl Stream<Item = (K, V)> + '_
+    where
+        K: AsRef<[u8]>,
+    {
+        self.range(prefix.clone().., Direction::Ascending)
+            .await
+            .take_while(move |(key, _value)| {
+                future::ready(key.as_ref().starts_with(prefix.as_ref()))
+            })
+    }
+
+    /// Return up to n keys chosen at random, in ascending order
+    ///
+    /// Every key is visited once (reservoir sampling), bypassing the cache so that sampling a
+    /// large tree doesn't evict the nodes in use.
+    pub async fn sample_keys(&self, n: usize) -> Vec<K> {
+        let mut keys = self
+            .keys_with_policy(Direction::Ascending, CachePolicy::Bypass)
+            .await;
+        // Not thread_rng(), which isn't Send and would be held across our awaits
+        let mut rng = StdRng::from_entropy();
+        let mut sample = Vec::with_capacity(n);
+        let mut seen = 0;
+        while let Some(key) = keys.next().await {
+            seen += 1;
+            if sample.len() < n {
+                sample.push(key);
+            } else {
+                let i = rng.gen_range(0..seen);
+                if i < n {
+                    sample[i] = key;
+                }
+            }
+        }
+        sample.sort();
+        sample
+    }
+
+    // Not generic over the type of range, so our stream doesn't capture it. Each leaf is drained
+    // into items (pairs or keys), which are compared with our bounds by their keys.
+    async fn bounded<'a, T: 'a>(
+        &'a self,
+        start: Bound<K>,
+        end: Bound<K>,
+        direction: Direction,
+        drain: fn(Arc<Node<K, V>>, Direction) -> Vec<T>,
+        key_of: fn(&T) -> &K,
+    ) -> impl Stream<Item = T> + 'a {
+        let (first, last) = match direction {
+            Direction::Ascending => (start, end),
+            Direction::Descending => (end, start),
+        };
+        let seed = match &first {
+            Bound::Included(key) | Bound::Excluded(key) => match self.search_node(key).await {
+                Ok(node) => Some(node),
+                Err(e) => {
+                    tracing::error!("could not find leaf for range: {e}");
+                    None
                 }
             },
-        ))
+            Bound::Unbounded => None,
+        };
+        let seed = match seed {
+            Some(seed) => seed,
+            None if direction == Direction::Ascending => {
+                self.first_leaf(CachePolicy::Populate).await
+            }
+            None => self.last_leaf(CachePolicy::Populate).await,
+        };
+        Box::pin(
+            self.inner_stream_leaf_nodes(seed, direction, CachePolicy::Populate)
+                .flat_map(move |leaf| stream::iter(drain(leaf, direction)))
+                .skip_while(move |item| future::ready(before(key_of(item), &first, direction)))
+                .take_while(move |item| future::ready(!after(key_of(item), &last, direction))),
+        )
     }
 
-    pub(crate) async fn stream_all_nodes(
-        &self,
+    /// Return an owned stream of entries
+    ///
+    /// The stream holds a reference to the tree, so it can be spawned onto other tasks or stored.
+    pub async fn entries_owned(
+        self: Arc<Self>,
+        direction: Direction,
+    ) -> impl Stream<Item = (K, V)> + Send + 'static
+    where
+        K: 'static,
+        V: 'static,
+    {
+        self.stream_owned_leaf_nodes(direction)
+            .await
+            .flat_map(move |leaf| stream::iter(leaf_pairs(leaf, direction)))
+    }
+
+    /// Return an owned stream of keys
+    ///
+    /// The stream holds a reference to the tree, so it can be spawned onto other tasks or stored.
+    pub async fn keys_owned(
+        self: Arc<Self>,
+        direction: Direction,
+    ) -> impl Stream<Item = K> + Send + 'static
+    where
+        K: 'static,
+        V: 'static,
+    {
+        self.stream_owned_leaf_nodes(direction)
+            .await
+            .flat_map(move |leaf| stream::iter(leaf.leaf_keys(direction)))
+    }
+
+    /// Return an owned stream of values
+    ///
+    /// The stream holds a reference to the tree, so it can be spawned onto other tasks or stored.
+    pub async fn values_owned(
+        self: Arc<Self>,
         direction: Direction,
-    ) -> impl Stream<Item = Node<K, V>> + '_ {
+    ) -> impl Stream<Item = V> + Send + 'static
+    where
+        K: 'static,
+        V: 'static,
+    {
+        self.entries_owned(direction)
+            .await
+            .map(|(_key, value)| value)
+    }
+
+    async fn stream_owned_leaf_nodes(
+        self: Arc<Self>,
+        direction: Direction,
+    ) -> impl Stream<Item = Arc<Node<K, V>>> + Send + 'static
+    where
+        K: 'static,
+        V: 'static,
+    {
         let seed = if direction == Direction::Ascending {
-            1
+            self.first_leaf(CachePolicy::Populate).await
         } else {
-            self.index.load(Ordering::SeqCst)
+            self.last_leaf(CachePolicy::Populate).await
         };
-        self.inner_stream_nodes(seed, direction).await
+
+        stream::unfold((self, Some(seed)), move |(tree, node_opt)| async move {
+            let node = node_opt?;
+            let next = tree
+                .next_leaf(&node, direction, CachePolicy::Populate)
+                .await;
+            Some((node, (tree, next)))
+        })
     }
 
-    async fn inner_stream_nodes(
+    /// Stream every node in our tree, depth first from our root, visiting children in the
+    /// specified direction.
+    pub(crate) async fn stream_all_nodes(
         &self,
-        seed_idx: usize,
         direction: Direction,
-    ) -> impl Stream<Item = Node<K, V>> + '_ {
-        let node_count = self.index.load(Ordering::SeqCst);
-        Box::pin(stream::unfold(seed_idx, move |mut idx| async move {
-            let mut nodes_lock = self.nodes.lock().await;
-            match direction {
-                Direction::Descending => {
-                    // Needed to protect header record
-                    while idx > 0 {
-                        match self.find_node_with_lock(&mut nodes_lock, idx).await {
-                            Ok(node) => {
-                                return Some((node, idx - 1));
-                            }
-                            Err(_e) => {
-                                idx -= 1;
-                            }
-                        }
-                    }
-                    None
-                }
-                Direction::Ascending => {
-                    // Needed to protect header record
-                    while idx > 0 && idx < node_count {
-                        match self.find_node_with_lock(&mut nodes_lock, idx).await {
-                            Ok(node) => {
-                                return Some((node, idx + 1));
-                            }
-                            Err(_e) => {
-                                idx += 1;
+    ) -> impl Stream<Item = Arc<Node<K, V>>> + '_ {
+        if let Err(e) = self.ensure_current().await {
+            tracing::error!("could not refresh tree: {e}");
+        }
+        let root = *self.root.lock().await;
+        Box::pin(stream::unfold(vec![root], move |mut pending| async move {
+            loop {
+                let idx = pending.pop()?;
+                let nodes_lock = self.nodes.read().await;
+                match self.find_node_with_lock(&nodes_lock, idx).await {
+                    Ok(node) => {
+                        if !node.is_leaf() {
+                            // Push in reverse, so that children are visited in our direction
+                            match direction {
+                                Direction::Ascending => pending.extend(node.children().rev()),
+                                Direction::Descending => pending.extend(node.children()),
                             }
                         }
+                        return Some((node, pending));
                     }
-                    None
+                    // Skip missing nodes, so the rest of the tree can still be visited
+                    Err(e) => tracing::error!("could not find node with index {idx}: {e}"),
                 }
             }
         }))
@@ -185,24 +330,26 @@ where
     pub(crate) async fn stream_all_leaf_nodes(
         &self,
         direction: Direction,
-    ) -> impl Stream<Item = Node<K, V>> + '_ {
+        policy: CachePolicy,
+    ) -> impl Stream<Item = Arc<Node<K, V>>> + '_ {
         let seed = if direction == Direction::Ascending {
-            self.first_leaf().await
+            self.first_leaf(policy).await
         } else {
-            self.last_leaf().await
+            self.last_leaf(policy).await
         };
 
-        self.inner_stream_leaf_nodes(seed, direction)
+        self.inner_stream_leaf_nodes(seed, direction, policy)
     }
 
     fn inner_stream_leaf_nodes(
         &self,
-        seed: Node<K, V>,
+        seed: Arc<Node<K, V>>,
         direction: Direction,
-    ) -> impl Stream<Item = Node<K, V>> + '_ {
+        policy: CachePolicy,
+    ) -> impl Stream<Item = Arc<Node<K, V>>> + '_ {
         Box::pin(stream::unfold(Some(seed), move |node_opt| async move {
             match node_opt {
-                Some(node) => match self.neighbour(node.index(), direction).await {
+                Some(node) => match self.next_leaf(&node, direction, policy).await {
                     Some(ns) => Some((node, Some(ns))),
                     None => Some((node, None)),
                 },
@@ -212,10 +359,58 @@ where
     }
 }
 
+/// Drain a leaf of its pairs. Leaves with corrupt values are logged and skipped, as missing nodes
+/// are.
+fn leaf_pairs<K, V>(leaf: Arc<Node<K, V>>, direction: Direction) -> Vec<(K, V)>
+where
+    K: Clone + Ord + Serialize + DeserializeOwned + std::fmt::Debug + Send + Sync,
+    V: Clone + Serialize + DeserializeOwned + std::fmt::Debug + Send + Sync,
+{
+    let index = leaf.index();
+    match Arc::unwrap_or_clone(leaf).into_pairs(direction) {
+        Ok(pairs) => pairs,
+        Err(e) => {
+            tracing::error!("could not read values of node with index {index}: {e}");
+            vec![]
+        }
+    }
+}
+
+/// The bounds of a stream starting at a key, in a direction
+fn from_bounds<K>(start_key: K, direction: Direction) -> (Bound<K>, Bound<K>) {
+    match direction {
+        Direction::Ascending => (Bound::Included(start_key), Bound::Unbounded),
+        Direction::Descending => (Bound::Unbounded, Bound::Included(start_key)),
+    }
+}
+
+/// Does a key come before a bound, when streaming in a direction?
+fn before<K: Ord>(key: &K, bound: &Bound<K>, direction: Direction) -> bool {
+    match (bound, direction) {
+        (Bound::Included(bound), Direction::Ascending) => key < bound,
+        (Bound::Excluded(bound), Direction::Ascending) => key <= bound,
+        (Bound::Included(bound), Direction::Descending) => key > bound,
+        (Bound::Excluded(bound), Direction::Descending) => key >= bound,
+        (Bound::Unbounded, _) => false,
+    }
+}
+
+/// Does a key come after a bound, when streaming in a direction?
+fn after<K: Ord>(key: &K, bound: &Bound<K>, direction: Direction) -> bool {
+    match direction {
+        Direction::Ascending => before(key, bound, Direction::Descending),
+        Direction::Descending => before(key, bound, Direction::Ascending),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use futures::StreamExt;
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
 
     #[test_log::test(tokio::test)]
     async fn it_streams_leaf_nodes() {
@@ -231,7 +426,9 @@ mod tests {
         }
 
         // Test Ascending stream
-        let mut streamer = tree.stream_all_leaf_nodes(Direction::Ascending).await;
+        let mut streamer = tree
+            .stream_all_leaf_nodes(Direction::Ascending, CachePolicy::Populate)
+            .await;
 
         let mut slice_index = 0;
         while let Some(node) = streamer.next().await {
@@ -243,7 +440,9 @@ mod tests {
         }
 
         // Test Descending stream
-        let mut streamer = tree.stream_all_leaf_nodes(Direction::Descending).await;
+        let mut streamer = tree
+            .stream_all_leaf_nodes(Direction::Descending, CachePolicy::Populate)
+            .await;
 
         let mut slice_index = input.len();
         while let Some(node) = streamer.next().await {
@@ -255,6 +454,293 @@ mod tests {
         }
 
         // Delete test tree
+        tree.close().await.expect("closes tree");
         std::fs::remove_file("streams_tree.db").expect("cleanup");
     }
+
+    #[test_log::test(tokio::test)]
+    async fn it_streams_nodes_from_root() {
+        let tree = Baildon::<usize, usize>::temporary(4)
+            .await
+            .expect("creates tree file");
+        for i in 0..200 {
+            tree.insert(i, i).await.expect("insert worked");
+        }
+        // Deleting frees nodes, leaving gaps in our node indices
+        for i in 50..150 {
+            tree.delete(&i).await.expect("delete worked");
+        }
+        let stats = tree.stats().await.expect("reports stats");
+        let root = *tree.root.lock().await;
+
+        for direction in [Direction::Ascending, Direction::Descending] {
+            let nodes = tree
+                .stream_all_nodes(direction)
+                .await
+                .collect::<Vec<Arc<Node<usize, usize>>>>()
+                .await;
+            assert_eq!(nodes.len(), stats.nodes());
+            assert_eq!(nodes[0].index(), root);
+            // Leaves are visited in our direction
+            let mut leaves = nodes
+                .iter()
+                .filter(|node| node.is_leaf())
+                .collect::<Vec<&Arc<Node<usize, usize>>>>();
+            if direction == Direction::Descending {
+                leaves.reverse();
+            }
+            let keys = leaves
+                .iter()
+                .flat_map(|node| node.keys().cloned())
+                .collect::<Vec<usize>>();
+            assert_eq!(keys, (0..50).chain(150..200).collect::<Vec<usize>>());
+        }
+    }
+
+    #[test_log::test(tokio::test(flavor = "multi_thread"))]
+    async fn it_streams_owned_keys_from_another_task() {
+        // Create test tree
+        let tree = Arc::new(
+            Baildon::<usize, usize>::try_new("streams_owned_tree.db", 4)
+                .await
+                .expect("creates tree file"),
+        );
+        let input = vec![
+            7, 8, 14, 20, 21, 27, 34, 42, 43, 47, 48, 52, 64, 72, 90, 91, 93, 94, 97,
+        ];
+        for i in &input {
+            tree.insert(*i, *i).await.expect("insert worked");
+        }
+
+        let streamer = tree.clone().keys_owned(Direction::Descending).await;
+        let keys = tokio::spawn(streamer.collect::<Vec<usize>>())
+            .await
+            .expect("task finished");
+        assert_eq!(input.into_iter().rev().collect::<Vec<usize>>(), keys);
+
+        // Delete test tree
+        Arc::into_inner(tree)
+            .expect("only reference")
+            .close()
+            .await
+            .expect("closes tree");
+        std::fs::remove_file("streams_owned_tree.db").expect("cleanup");
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn it_streams_entries_without_caching_leaves() {
+        // Create test tree
+        let tree = Baildon::<usize, usize>::try_new("streams_bypass_tree.db", 4)
+            .await
+            .expect("creates tree file");
+        let input = vec![
+            7, 8, 14, 20, 21, 27, 34, 42, 43, 47, 48, 52, 64, 72, 90, 91, 93, 94, 97,
+        ];
+        for i in &input {
+            tree.insert(*i, *i).await.expect("insert worked");
+        }
+        // Flushing empties our node cache
+        tree.flush_to_disk().await.expect("flushes");
+
+        let keys = tree
+            .keys_with_policy(Direction::Ascending, CachePolicy::Bypass)
+            .await
+            .collect::<Vec<usize>>()
+            .await;
+        assert_eq!(input, keys);
+
+        // Only internal nodes should have been cached
+        assert!(tree
+            .nodes
+            .read()
+            .await
+            .nodes()
+            .iter()
+            .all(|node| !node.is_leaf()));
+
+        // Delete test tree
+        tree.close().await.expect("closes tree");
+        std::fs::remove_file("streams_bypass_tree.db").expect("cleanup");
+    }
+
+    #[tokio::test]
+    async fn it_streams_ranges() {
+        let tree = Baildon::<usize, usize>::temporary(4)
+            .await
+            .expect("creates tree file");
+        for i in (0..100).step_by(2) {
+            tree.insert(i, i).await.expect("insert worked");
+        }
+        let keys = |entries: Vec<(usize, usize)>| {
+            entries
+                .into_iter()
+                .map(|(key, _value)| key)
+                .collect::<Vec<usize>>()
+        };
+
+        let entries = tree
+            .range(41..50, Direction::Ascending)
+            .await
+            .collect()
+            .await;
+        assert_eq!(keys(entries), vec![42, 44, 46, 48]);
+        let entries = tree
+            .range(41..=50, Direction::Descending)
+            .await
+            .collect()
+            .await;
+        assert_eq!(keys(entries), vec![50, 48, 46, 44, 42]);
+        let entries = tree.range(..5, Direction::Ascending).await.collect().await;
+        assert_eq!(keys(entries), vec![0, 2, 4]);
+        let entries = tree
+            .range(95.., Direction::Descending)
+            .await
+            .collect()
+            .await;
+        assert_eq!(keys(entries), vec![98, 96]);
+        let count = tree.range(.., Direction::Ascending).await.count().await;
+        assert_eq!(count, 50);
+        let count = tree.range(200.., Direction::Ascending).await.count().await;
+        assert_eq!(count, 0);
+        let keys = tree
+            .range_keys(41..=50, Direction::Descending)
+            .await
+            .collect::<Vec<usize>>()
+            .await;
+        assert_eq!(keys, vec![50, 48, 46, 44, 42]);
+    }
+
+    #[tokio::test]
+    async fn it_streams_from_keys() {
+        let tree = Baildon::<usize, usize>::temporary(4)
+            .await
+            .expect("creates tree file");
+        for i in (0..100).step_by(2) {
+            tree.insert(i, i).await.expect("insert worked");
+        }
+
+        let keys = tree
+            .keys_from(42, Direction::Ascending)
+            .await
+            .take(3)
+            .collect::<Vec<usize>>()
+            .await;
+        assert_eq!(keys, vec![42, 44, 46]);
+        let keys = tree
+            .keys_from(41, Direction::Ascending)
+            .await
+            .take(3)
+            .collect::<Vec<usize>>()
+            .await;
+        assert_eq!(keys, vec![42, 44, 46]);
+        let keys = tree
+            .keys_from(41, Direction::Descending)
+            .await
+            .take(3)
+            .collect::<Vec<usize>>()
+            .await;
+        assert_eq!(keys, vec![40, 38, 36]);
+        let entries = tree
+            .entries_from(4, Direction::Descending)
+            .await
+            .collect::<Vec<(usize, usize)>>()
+            .await;
+        assert_eq!(entries, vec![(4, 4), (2, 2), (0, 0)]);
+        let count = tree.keys_from(0, Direction::Ascending).await.count().await;
+        assert_eq!(count, 50);
+        let count = tree.keys_from(99, Direction::Ascending).await.count().await;
+        assert_eq!(count, 0);
+    }
+
+    #[tokio::test]
+    async fn it_scans_prefixes() {
+        let tree = Baildon::<String, usize>::temporary(4)
+            .await
+            .expect("creates tree file");
+        for (i, key) in ["a", "ab", "abc", "abd", "ac", "b", "ba"]
+            .iter()
+            .enumerate()
+        {
+            tree.insert(key.to_string(), i)
+                .await
+                .expect("insert worked");
+        }
+        let keys = tree
+            .scan_prefix("ab".to_string())
+            .await
+            .map(|(key, _value)| key)
+            .collect::<Vec<String>>()
+            .await;
+        assert_eq!(keys, vec!["ab", "abc", "abd"]);
+        assert_eq!(tree.scan_prefix("b".to_string()).await.count().await, 2);
+        assert_eq!(tree.scan_prefix("c".to_string()).await.count().await, 0);
+    }
+
+    static DECODED: AtomicUsize = AtomicUsize::new(0);
+
+    /// A value which counts how often it is deserialized
+    #[derive(Clone, Debug, Serialize)]
+    struct Counted(usize);
+
+    impl<'de> Deserialize<'de> for Counted {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            DECODED.fetch_add(1, Ordering::SeqCst);
+            usize::deserialize(deserializer).map(Counted)
+        }
+    }
+
+    #[tokio::test]
+    async fn it_streams_keys_without_decoding_values() {
+        let tree = Arc::new(
+            Baildon::<usize, Counted>::temporary(4)
+                .await
+                .expect("creates tree file"),
+        );
+        for i in 0..100 {
+            tree.insert(i, Counted(i)).await.expect("insert worked");
+        }
+        // Flushing empties our node cache, so leaves are read (lazily) from disk
+        tree.flush_to_disk().await.expect("flushes");
+
+        assert_eq!(tree.keys(Direction::Ascending).await.count().await, 100);
+        assert_eq!(
+            tree.keys_with_policy(Direction::Descending, CachePolicy::Bypass)
+                .await
+                .count()
+                .await,
+            100
+        );
+        assert_eq!(
+            tree.keys_from(50, Direction::Ascending).await.count().await,
+            50
+        );
+        assert_eq!(tree.sample_keys(10).await.len(), 10);
+        let keys = tree.clone().keys_owned(Direction::Ascending).await;
+        assert_eq!(keys.count().await, 100);
+        assert_eq!(DECODED.load(Ordering::SeqCst), 0);
+
+        // Streaming values decodes them
+        assert_eq!(tree.values(Direction::Ascending).await.count().await, 100);
+        assert_eq!(DECODED.load(Ordering::SeqCst), 100);
+    }
+
+    fn assert_send<T: Send>(_: T) {}
+
+    #[tokio::test]
+    async fn it_samples_keys() {
+        let tree = Baildon::<usize, usize>::temporary(4)
+            .await
+            .expect("creates tree file");
+        for i in 0..100 {
+            tree.insert(i, i).await.expect("insert worked");
+        }
+        let sample = tree.sample_keys(5).await;
+        assert_eq!(sample.len(), 5);
+        assert!(sample.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(sample.iter().all(|key| *key < 100));
+        assert_eq!(tree.sample_keys(200).await.len(), 100);
+        assert!(tree.sample_keys(0).await.is_empty());
+        // Sampling can be spawned
+        assert_send(tree.sample_keys(5));
+    }
 }
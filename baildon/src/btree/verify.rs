@@ -0,0 +1,404 @@
+//! Verification
+//!
+//! Check the structural invariants of a tree, reporting every violation rather than stopping at
+//! the first.
+
+use std::fmt;
+
+use anyhow::Result;
+use futures::StreamExt;
+use thiserror::Error;
+
+use super::baildon::Baildon;
+use super::baildon::BaildonKey;
+use super::baildon::BaildonValue;
+use super::baildon::CachePolicy;
+use super::baildon::Direction;
+
+/// A violated tree invariant.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum Violation {
+    /// Keys within a node aren't in strictly ascending order
+    #[error("node: {node} has keys out of order")]
+    UnsortedKeys {
+        /// Node index
+        node: usize,
+    },
+
+    /// A node's child couldn't be found
+    #[error("node: {node} has missing child: {child}")]
+    MissingChild {
+        /// Node index
+        node: usize,
+        /// Child index
+        child: usize,
+    },
+
+    /// A child doesn't refer back to its parent
+    #[error("node: {child} has parent: {parent:?}, expected: {node}")]
+    WrongParent {
+        /// Node index
+        node: usize,
+        /// Child index
+        child: usize,
+        /// Parent recorded by the child
+        parent: Option<usize>,
+    },
+
+    /// Keys in neighbouring leaves aren't in strictly ascending order
+    #[error("leaf: {node} has keys out of order with leaf: {previous}")]
+    UnsortedLeaves {
+        /// Node index
+        node: usize,
+        /// Index of the previous leaf
+        previous: usize,
+    },
+
+    /// A leaf isn't at the same depth as the other leaves
+    #[error("leaf: {node} is at depth: {depth}, expected: {expected}")]
+    UnbalancedLeaf {
+        /// Node index
+        node: usize,
+        /// Depth of the leaf
+        depth: usize,
+        /// Depth of the first leaf
+        expected: usize,
+    },
+
+    /// A leaf contains keys outside the range given by the separator keys of its ancestors
+    #[error("leaf: {node} has keys outside the range of its separators")]
+    KeyOutOfRange {
+        /// Node index
+        node: usize,
+    },
+
+    /// A leaf isn't linked to the leaves which precede and follow it
+    #[error("leaf: {node} isn't linked to its siblings")]
+    BrokenLeafLink {
+        /// Node index
+        node: usize,
+    },
+
+    /// The recorded number of entries doesn't match the number of entries in our leaves
+    #[error("recorded count: {recorded} doesn't match actual count: {actual}")]
+    CountMismatch {
+        /// Recorded count
+        recorded: usize,
+        /// Actual count
+        actual: usize,
+    },
+
+    /// The recorded depth doesn't match the depth of our leaves
+    #[error("recorded depth: {recorded} doesn't match actual depth: {actual}")]
+    DepthMismatch {
+        /// Recorded depth
+        recorded: usize,
+        /// Actual depth
+        actual: usize,
+    },
+
+    /// Data blocks in our file are corrupt
+    #[error("corrupt blocks: {0}")]
+    CorruptBlocks(String),
+}
+
+/// The result of verifying a tree.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VerifyReport {
+    /// Number of nodes checked.
+    pub nodes: usize,
+    /// Number of leaf nodes checked.
+    pub leaves: usize,
+    /// Violations found.
+    pub violations: Vec<Violation>,
+}
+
+impl VerifyReport {
+    /// Did our tree satisfy all invariants?
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "checked {} nodes ({} leaves), found {} violations",
+            self.nodes,
+            self.leaves,
+            self.violations.len()
+        )?;
+        for violation in &self.violations {
+            write!(f, "\n  {violation}")?;
+        }
+        Ok(())
+    }
+}
+
+impl<K, V> Baildon<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    /// Verify all the nodes in the tree.
+    ///
+    /// Violations are reported, not treated as errors. An error is only returned if the tree
+    /// can't be read.
+    pub async fn verify(&self, direction: Direction) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+
+        if let Err(e) = self.file.lock().await.validate() {
+            report
+                .violations
+                .push(Violation::CorruptBlocks(e.to_string()));
+        }
+
+        let mut nodes = self.stream_all_nodes(direction).await;
+        while let Some(node) = nodes.next().await {
+            report.nodes += 1;
+            if !node.keys_sorted() {
+                report
+                    .violations
+                    .push(Violation::UnsortedKeys { node: node.index() });
+            }
+            if node.is_leaf() {
+                continue;
+            }
+            let nodes_lock = self.nodes.read().await;
+            for child in node.children() {
+                match self.find_node_with_lock(&nodes_lock, child).await {
+                    Ok(child_node) => {
+                        if child_node.parent() != Some(node.index()) {
+                            report.violations.push(Violation::WrongParent {
+                                node: node.index(),
+                                child,
+                                parent: child_node.parent(),
+                            });
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!("could not find node: {e}");
+                        report.violations.push(Violation::MissingChild {
+                            node: node.index(),
+                            child,
+                        });
+                    }
+                }
+            }
+        }
+
+        // Keys must also be ordered across leaves
+        let mut leaves = self
+            .stream_all_leaf_nodes(direction, CachePolicy::Bypass)
+            .await;
+        let mut previous: Option<(usize, K)> = None;
+        let mut count = 0;
+        while let Some(leaf) = leaves.next().await {
+            report.leaves += 1;
+            count += leaf.len();
+            let (first, last) = match direction {
+                Direction::Ascending => (leaf.keys().next(), leaf.keys().next_back()),
+                Direction::Descending => (leaf.keys().next_back(), leaf.keys().next()),
+            };
+            if let (Some((previous_idx, previous_key)), Some(first)) = (&previous, first) {
+                let ordered = match direction {
+                    Direction::Ascending => previous_key < first,
+                    Direction::Descending => previous_key > first,
+                };
+                if !ordered {
+                    report.violations.push(Violation::UnsortedLeaves {
+                        node: leaf.index(),
+                        previous: *previous_idx,
+                    });
+                }
+            }
+            if let Some(last) = last {
+                previous = Some((leaf.index(), last.clone()));
+            }
+        }
+
+        // Our recorded count and depth must match our structure
+        let recorded = self.count().await;
+        if recorded != count {
+            report.violations.push(Violation::CountMismatch {
+                recorded,
+                actual: count,
+            });
+        }
+        let recorded = self.depth().await;
+        if let Some(actual) = self.verify_structure(&mut report).await? {
+            if recorded != actual {
+                report
+                    .violations
+                    .push(Violation::DepthMismatch { recorded, actual });
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Descend from our root, checking that all leaves are at the same depth and that every key in
+    /// a leaf is within the range given by its ancestors' separator keys.
+    ///
+    /// A search for a key follows the first child whose separator is >= the key, or the last
+    /// child, so a child's keys must be > the previous separator and <= its own separator (unless
+    /// it is the last child, when its parent's upper bound applies).
+    ///
+    /// Leaves are visited in ascending order, so we also check that each leaf is linked to the
+    /// leaves either side of it.
+    ///
+    /// Returns the depth of our first leaf.
+    async fn verify_structure(&self, report: &mut VerifyReport) -> Result<Option<usize>> {
+        let linked = self.file.lock().await.has_leaf_links();
+        let nodes_lock = self.nodes.read().await;
+        let mut leaf_depth = None;
+        let mut previous: Option<(usize, Option<usize>)> = None;
+        let root = *self.root.lock().await;
+        let mut pending: Vec<(usize, usize, Option<K>, Option<K>)> = vec![(root, 1, None, None)];
+        while let Some((idx, depth, lower, upper)) = pending.pop() {
+            // Missing nodes have already been reported
+            let Ok(node) = self
+                .find_node_with_lock_and_policy(&nodes_lock, idx, CachePolicy::Bypass)
+                .await
+            else {
+                continue;
+            };
+            if node.is_leaf() {
+                let expected = *leaf_depth.get_or_insert(depth);
+                if depth != expected {
+                    report.violations.push(Violation::UnbalancedLeaf {
+                        node: idx,
+                        depth,
+                        expected,
+                    });
+                }
+                let in_range = node.keys().all(|key| {
+                    lower.as_ref().map_or(true, |lower| key > lower)
+                        && upper.as_ref().map_or(true, |upper| key <= upper)
+                });
+                if !in_range {
+                    report
+                        .violations
+                        .push(Violation::KeyOutOfRange { node: idx });
+                }
+                if linked {
+                    let (previous_idx, previous_next) = previous.unzip();
+                    if node.sibling(Direction::Descending) != previous_idx
+                        || previous_next.is_some_and(|next| next != Some(idx))
+                    {
+                        report
+                            .violations
+                            .push(Violation::BrokenLeafLink { node: idx });
+                    }
+                    previous = Some((idx, node.sibling(Direction::Ascending)));
+                }
+                continue;
+            }
+            let separators = node.keys().cloned().collect::<Vec<K>>();
+            let last = separators.len().saturating_sub(1);
+            // Push in reverse, so that leaves are visited in ascending order
+            for (position, child) in node.children().enumerate().rev() {
+                let child_lower = match position {
+                    0 => lower.clone(),
+                    _ => Some(separators[position - 1].clone()),
+                };
+                let child_upper = if position == last {
+                    upper.clone()
+                } else {
+                    Some(separators[position].clone())
+                };
+                pending.push((child, depth + 1, child_lower, child_upper));
+            }
+        }
+        // Our last leaf mustn't have a successor
+        if let Some((idx, Some(_))) = previous {
+            report
+                .violations
+                .push(Violation::BrokenLeafLink { node: idx });
+        }
+        Ok(leaf_depth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_verifies_tree() {
+        let tree = Baildon::<usize, usize>::temporary(5)
+            .await
+            .expect("creates tree file");
+        for i in 0..200 {
+            tree.insert(i, i).await.expect("insert worked");
+        }
+        for direction in [Direction::Ascending, Direction::Descending] {
+            let report = tree.verify(direction).await.expect("verifies tree");
+            assert!(report.is_ok(), "{report}");
+            assert!(report.leaves > 1);
+            assert!(report.nodes > report.leaves);
+        }
+        for i in 0..150 {
+            tree.delete(&i).await.expect("delete worked");
+        }
+        let report = tree
+            .verify(Direction::Ascending)
+            .await
+            .expect("verifies tree");
+        assert!(report.is_ok(), "{report}");
+        tree.close().await.expect("closes tree");
+    }
+
+    #[tokio::test]
+    async fn it_detects_keys_out_of_range() {
+        let tree = Baildon::<usize, usize>::temporary(5)
+            .await
+            .expect("creates tree file");
+        for i in 1..200 {
+            tree.insert(i, i).await.expect("insert worked");
+        }
+        // Lower the separator for our first child below all of its keys
+        let root = *tree.root.lock().await;
+        tree.nodes.read().await.update(root, |node| {
+            let first = node.children().next().expect("has children");
+            node.update_child_key(first, 0)
+        });
+        let report = tree
+            .verify(Direction::Ascending)
+            .await
+            .expect("verifies tree");
+        assert!(report
+            .violations
+            .iter()
+            .any(|violation| matches!(violation, Violation::KeyOutOfRange { .. })));
+        tree.close().await.expect("closes tree");
+    }
+
+    #[tokio::test]
+    async fn it_detects_broken_leaf_links() {
+        let tree = Baildon::<usize, usize>::temporary(5)
+            .await
+            .expect("creates tree file");
+        for i in 0..200 {
+            tree.insert(i, i).await.expect("insert worked");
+        }
+        // Make our first leaf skip its successor, which no longer follows its predecessor
+        let first = tree.first_leaf(CachePolicy::Populate).await;
+        let skipped = tree
+            .next_leaf(&first, Direction::Ascending, CachePolicy::Populate)
+            .await
+            .expect("has a successor");
+        tree.nodes.read().await.update(first.index(), |node| {
+            node.set_sibling(Direction::Ascending, skipped.sibling(Direction::Ascending))
+        });
+        let report = tree
+            .verify(Direction::Descending)
+            .await
+            .expect("verifies tree");
+        assert!(report.violations.contains(&Violation::BrokenLeafLink {
+            node: skipped.index()
+        }));
+        tree.close().await.expect("closes tree");
+    }
+}
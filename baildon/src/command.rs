@@ -14,6 +14,20 @@ use crate::BINCODER;
 pub(crate) enum Command<K, V> {
     Upsert(K, V),
     Delete(K),
+    // Variants are only ever appended, so that existing WALs can still be replayed
+    Rename(K, K),
+    // A rename, with the value it moved, so that replaying it doesn't depend on the current
+    // value of its key. Renames are logged as moves, Rename is only replayed from older WALs.
+    Move(K, K, V),
+}
+
+// Serialized exactly like a Command, without needing to own its key or value
+#[derive(Serialize)]
+enum CommandRef<'a, K, V> {
+    Upsert(&'a K, &'a V),
+    Delete(&'a K),
+    Rename(&'a K, &'a K),
+    Move(&'a K, &'a K, &'a V),
 }
 
 impl<K, V> Command<K, V>
@@ -29,6 +43,31 @@ where
     pub(crate) fn deserialize(buf: &[u8]) -> Result<Self> {
         BINCODER.deserialize(buf).map_err(|e| e.into())
     }
+
+    /// Serialize an upsert of a borrowed key and value.
+    pub(crate) fn upsert_ref(key: &K, value: &V) -> Result<Vec<u8>> {
+        let s_cmd = BINCODER.serialize(&CommandRef::Upsert(key, value))?;
+        Ok(s_cmd)
+    }
+
+    /// Serialize a delete of a borrowed key.
+    pub(crate) fn delete_ref(key: &K) -> Result<Vec<u8>> {
+        let s_cmd = BINCODER.serialize(&CommandRef::<K, V>::Delete(key))?;
+        Ok(s_cmd)
+    }
+
+    /// Serialize a rename of a borrowed key.
+    #[cfg(test)]
+    pub(crate) fn rename_ref(from: &K, to: &K) -> Result<Vec<u8>> {
+        let s_cmd = BINCODER.serialize(&CommandRef::<K, V>::Rename(from, to))?;
+        Ok(s_cmd)
+    }
+
+    /// Serialize a move of a borrowed value from one key to another.
+    pub(crate) fn move_ref(from: &K, to: &K, value: &V) -> Result<Vec<u8>> {
+        let s_cmd = BINCODER.serialize(&CommandRef::Move(from, to, value))?;
+        Ok(s_cmd)
+    }
 }
 
 #[cfg(test)]
@@ -43,6 +82,32 @@ mod tests {
         assert_eq!(upsert, new_upsert);
     }
 
+    #[test]
+    fn it_serializes_borrowed_commands() {
+        let (key, value) = ("this".to_string(), "that".to_string());
+        let s_upsert = Command::upsert_ref(&key, &value).expect("serializes");
+        let upsert = Command::Upsert(key.clone(), value);
+        assert_eq!(s_upsert, upsert.serialize().expect("serializes"));
+        assert_eq!(
+            Command::deserialize(&s_upsert).expect("deserializes"),
+            upsert
+        );
+
+        let s_delete = Command::<String, usize>::delete_ref(&key).expect("serializes");
+        let delete_: Command<String, usize> = Command::Delete(key.clone());
+        assert_eq!(s_delete, delete_.serialize().expect("serializes"));
+
+        let to = "other".to_string();
+        let s_rename = Command::<String, usize>::rename_ref(&key, &to).expect("serializes");
+        let rename: Command<String, usize> = Command::Rename(key.clone(), to.clone());
+        assert_eq!(s_rename, rename.serialize().expect("serializes"));
+
+        let s_move = Command::move_ref(&key, &to, &7usize).expect("serializes");
+        let move_: Command<String, usize> = Command::Move(key, to, 7);
+        assert_eq!(s_move, move_.serialize().expect("serializes"));
+        assert_eq!(Command::deserialize(&s_move).expect("deserializes"), move_);
+    }
+
     #[test]
     fn it_serializes_delete_command() {
         let delete_: Command<String, usize> = Command::Delete("this".to_string());
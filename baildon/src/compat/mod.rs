@@ -0,0 +1,6 @@
+//! Compatibility
+//!
+//! Wrappers which mimic the APIs of other embedded stores, to reduce the cost of migrating to
+//! baildon.
+
+pub mod sled;
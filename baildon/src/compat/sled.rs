@@ -0,0 +1,213 @@
+//! sled
+//!
+//! A wrapper which mimics the API of [sled](https://docs.rs/sled), so that projects leaving sled
+//! can migrate by changing their imports and awaiting operations.
+//!
+//! Differences from sled:
+//!  - Operations are asynchronous and iterators are streams.
+//!  - Values are returned as `IVec`, which is a `Vec<u8>` rather than a shared buffer.
+//!  - Trees aren't flushed when dropped, so they should be closed with [`Tree::close`].
+//!  - A database has a single keyspace, so there is no `open_tree`.
+//!  - Databases use the natural ordering of their keys, so trees created with another
+//!    [`crate::btree::KeyOrder`] are refused.
+
+use std::ops::Bound;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::Stream;
+use futures::StreamExt;
+
+use crate::btree::Baildon;
+use crate::btree::Direction;
+
+/// Branching factor of created databases
+const DEFAULT_BRANCH: u64 = 13;
+
+/// Keys and values, as returned by sled.
+pub type IVec = Vec<u8>;
+
+/// A database, which has a single [`Tree`].
+pub type Db = Tree;
+
+/// Open the database at the specified path, creating it if it doesn't exist.
+pub async fn open<P: AsRef<Path>>(path: P) -> Result<Db> {
+    let tree = Baildon::open_or_create(path, DEFAULT_BRANCH).await?;
+    Ok(Tree {
+        tree: Arc::new(tree),
+    })
+}
+
+/// An ordered map of byte keys to byte values. Clones share the same tree.
+#[derive(Clone)]
+pub struct Tree {
+    tree: Arc<Baildon<IVec, IVec>>,
+}
+
+impl Tree {
+    /// Return the tree which stores our entries.
+    pub fn tree(&self) -> &Baildon<IVec, IVec> {
+        &self.tree
+    }
+
+    /// Insert a key and value, returning the previous value of the key.
+    pub async fn insert<K, V>(&self, key: K, value: V) -> Result<Option<IVec>>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.tree
+            .insert(key.as_ref().to_vec(), value.as_ref().to_vec())
+            .await
+    }
+
+    /// Return the value of a key.
+    pub async fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>> {
+        Ok(self.tree.get(&key.as_ref().to_vec()).await)
+    }
+
+    /// Delete a key, returning its value.
+    pub async fn remove<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>> {
+        self.tree.delete(&key.as_ref().to_vec()).await
+    }
+
+    /// Does our tree contain this key?
+    pub async fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+        Ok(self.tree.contains(&key.as_ref().to_vec()).await)
+    }
+
+    /// Return our first entry.
+    pub async fn first(&self) -> Result<Option<(IVec, IVec)>> {
+        Ok(self.tree.entries(Direction::Ascending).await.next().await)
+    }
+
+    /// Return our last entry.
+    pub async fn last(&self) -> Result<Option<(IVec, IVec)>> {
+        Ok(self.tree.entries(Direction::Descending).await.next().await)
+    }
+
+    /// Return a stream of all our entries, in ascending order.
+    pub async fn iter(&self) -> impl Stream<Item = Result<(IVec, IVec)>> + '_ {
+        self.tree.entries(Direction::Ascending).await.map(Ok)
+    }
+
+    /// Return a stream of the entries within a range, in ascending order.
+    pub async fn range<K, R>(&self, range: R) -> impl Stream<Item = Result<(IVec, IVec)>> + '_
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        self.bounded(
+            range.start_bound().map(|key| key.as_ref().to_vec()),
+            range.end_bound().map(|key| key.as_ref().to_vec()),
+        )
+        .await
+    }
+
+    // Not generic over the type of range, so our stream doesn't capture it
+    async fn bounded(
+        &self,
+        start: Bound<IVec>,
+        end: Bound<IVec>,
+    ) -> impl Stream<Item = Result<(IVec, IVec)>> + '_ {
+        self.tree
+            .range((start, end), Direction::Ascending)
+            .await
+            .map(Ok)
+    }
+
+    /// Return a stream of the entries whose keys start with a prefix, in ascending order.
+    pub async fn scan_prefix<P: AsRef<[u8]>>(
+        &self,
+        prefix: P,
+    ) -> impl Stream<Item = Result<(IVec, IVec)>> + '_ {
+        self.tree
+            .scan_prefix(prefix.as_ref().to_vec())
+            .await
+            .map(Ok)
+    }
+
+    /// Return the number of entries in our tree.
+    pub async fn len(&self) -> usize {
+        self.tree.count().await
+    }
+
+    /// Is our tree empty?
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// Delete all of our entries.
+    pub async fn clear(&self) -> Result<()> {
+        self.tree.clear().await
+    }
+
+    /// Flush all our updated nodes to disk.
+    pub async fn flush(&self) -> Result<()> {
+        self.tree.flush_to_disk().await
+    }
+
+    /// Close our tree, flushing all our updated nodes to disk.
+    ///
+    /// If our tree is shared with clones, it is flushed, but remains open until the last clone
+    /// is closed.
+    pub async fn close(self) -> Result<()> {
+        match Arc::try_unwrap(self.tree) {
+            Ok(tree) => tree.close().await,
+            Err(tree) => tree.flush_to_disk().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_mimics_sled() {
+        let db = open("sled_compat.db").await.expect("opens db");
+        db.clear().await.expect("clears db");
+        for key in ["apple", "apricot", "banana", "blueberry", "cherry"] {
+            db.insert(key, key.to_uppercase()).await.expect("inserts");
+        }
+        assert_eq!(
+            db.insert("apple", "green").await.expect("inserts"),
+            Some(b"APPLE".to_vec())
+        );
+        assert_eq!(
+            db.get("apple").await.expect("gets"),
+            Some(b"green".to_vec())
+        );
+        assert!(db.contains_key(b"cherry").await.expect("contains"));
+        assert_eq!(
+            db.remove("cherry").await.expect("removes"),
+            Some(b"CHERRY".to_vec())
+        );
+        assert_eq!(db.len().await, 4);
+
+        let keys = |entries: Vec<Result<(IVec, IVec)>>| {
+            entries
+                .into_iter()
+                .map(|entry| String::from_utf8(entry.expect("entry").0).expect("utf8"))
+                .collect::<Vec<String>>()
+        };
+        let entries = db.range("apricot".."blueberry").await.collect().await;
+        assert_eq!(keys(entries), vec!["apricot", "banana"]);
+        let entries = db.range::<&str, _>(..="banana").await.collect().await;
+        assert_eq!(keys(entries), vec!["apple", "apricot", "banana"]);
+        let entries = db.scan_prefix("b").await.collect().await;
+        assert_eq!(keys(entries), vec!["banana", "blueberry"]);
+        assert_eq!(
+            db.last().await.expect("last").map(|(key, _value)| key),
+            Some(b"blueberry".to_vec())
+        );
+
+        db.close().await.expect("closes db");
+        let db = open("sled_compat.db").await.expect("opens db");
+        assert_eq!(db.len().await, 4);
+        db.close().await.expect("closes db");
+        std::fs::remove_file("sled_compat.db").expect("cleanup");
+    }
+}
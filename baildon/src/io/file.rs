@@ -6,22 +6,31 @@
 //!   [Block]
 //! Footer
 //!
-//! The Header contains a couple of useful indices and the offeset of the Footer.
+//! The Header contains a couple of useful indices, the offeset of the Footer and the number of
+//! entries in, and depth of, the tree.
 //! The Footer contains:
 //!   Blocks are the blocks of data used to store Nodes. `VecDeque<Block>`
 //!   BlockMap associates an index with a Block `HashMap<Index, Block>`
+//!
+//! Files are protected from concurrent modification by other processes with an advisory lock,
+//! which is held for as long as the file is open for writing.
+//!
+//! Files may also be opened read only, without taking a lock. The Header contains a generation,
+//! which is odd while the file is being updated and even once the update is complete. Readers
+//! check the generation after every read, so they know if the data they read is stale.
 
 use std::cmp::Ordering;
 use std::collections::{HashMap, VecDeque};
 use std::io::SeekFrom;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use anyhow::Result;
 use bincode::Options;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::fs::{File, OpenOptions};
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 
 use crate::BINCODER;
 
@@ -29,13 +38,47 @@ const BLOCK_SIZE: u64 = 512;
 
 const FORMAT_VERSION_1: u8 = 1;
 
-const SUPPORTED_VERSIONS: &[u8] = &[FORMAT_VERSION_1];
+// Adds the entry count and depth of the tree to the header
+const FORMAT_VERSION_2: u8 = 2;
+
+// Leaf nodes link to their siblings
+const FORMAT_VERSION_3: u8 = 3;
+
+// Leaf values are serialized separately, so that they can be deserialized lazily
+const FORMAT_VERSION_4: u8 = 4;
+
+const SUPPORTED_VERSIONS: &[u8] = &[
+    FORMAT_VERSION_1,
+    FORMAT_VERSION_2,
+    FORMAT_VERSION_3,
+    FORMAT_VERSION_4,
+];
+
+/// Validate block allocation as blocks are allocated and freed. This is always done in debug
+/// builds, but is optional in release builds because every check scans our blocks.
+const VALIDATE_BLOCKS: bool = cfg!(any(debug_assertions, feature = "validate-blocks"));
+
+/// Writes to adjacent blocks are coalesced, up to this many bytes.
+const MAX_COALESCED_WRITE: usize = 1024 * 1024;
+
+/// Files are copied in chunks of this many bytes.
+const COPY_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// How many times a reader will try to read a consistent header and footer.
+const READ_ATTEMPTS: usize = 100;
+
+/// How long a reader waits for an update to complete before trying again.
+const READ_RETRY_DELAY: Duration = Duration::from_millis(10);
 
 #[derive(Debug)]
 pub(crate) struct BTreeFile {
     file: File,
+    path: PathBuf,
     header: BTreeFileHeader,
     footer: BTreeFileFooter,
+    read_only: bool,
+    // Number of blocks allocated since our file was opened
+    blocks_allocated: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -45,12 +88,21 @@ struct BTreeFileFooter {
     blocks_size: u64,
     blocks: VecDeque<Block>,
 }
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 struct BTreeFileHeader {
     version: u8,
     footer_offset: u64,
     root_index: usize,
     tree_index: usize,
+    // Files written before generations were tracked are zero filled after the header, so they
+    // read as generation 0.
+    generation: u64,
+    // Only valid from FORMAT_VERSION_2
+    count: usize,
+    depth: usize,
+    // Identifies how keys are ordered. Files written before orderings were recorded read as 0,
+    // which is the natural ordering of keys.
+    key_order: u64,
 }
 
 #[derive(Error, Debug)]
@@ -63,6 +115,16 @@ pub enum BTreeFileError {
     LostMapping(usize),
     #[error("file version not supported: {0}")]
     InvalidFileVersion(u8),
+    #[error("file has been updated since it was read")]
+    Stale,
+    #[error("index: {0} freed block at offset: {1}, which was already free")]
+    DoubleFree(usize, u64),
+    #[error("block at offset: {0} overlaps block at offset: {1}")]
+    OverlappingBlocks(u64, u64),
+    #[error("block at offset: {0} is outside the data region")]
+    BlockOutOfBounds(u64),
+    #[error("footer at offset: {0} is beyond the end of the file")]
+    FooterOutOfBounds(u64),
 }
 
 /// A Block of storage
@@ -74,7 +136,30 @@ pub(crate) struct Block {
     count: u64,
 }
 
+/// A single write, which may store the data of several adjacent blocks.
+#[derive(Debug)]
+pub(crate) struct CoalescedWrite {
+    offset: u64,
+    data: Vec<u8>,
+    indices: Vec<usize>,
+}
+
+impl CoalescedWrite {
+    /// The indices whose data is stored by this write.
+    pub(crate) fn indices(&self) -> &[usize] {
+        &self.indices
+    }
+}
+
 impl Block {
+    fn end(&self) -> u64 {
+        self.offset + self.count * BLOCK_SIZE
+    }
+
+    fn overlaps(&self, other: &Block) -> bool {
+        self.offset < other.end() && other.offset < self.end()
+    }
+
     fn split(&mut self, count: u64) -> Option<Block> {
         assert!(count <= self.count);
         if count < self.count {
@@ -117,6 +202,8 @@ impl BTreeFile {
             .open(path)
             .await?;
 
+        lock(&file)?;
+
         let header = BTreeFile::read_header(&mut file).await?;
 
         if !SUPPORTED_VERSIONS.contains(&header.version) {
@@ -127,11 +214,61 @@ impl BTreeFile {
 
         Ok(Self {
             file,
+            path: path.into(),
             header,
             footer,
+            read_only: false,
+            blocks_allocated: 0,
         })
     }
 
+    /// Open a file for reading, whilst another process may be writing to it.
+    ///
+    /// If the file is being updated, we wait for the update to complete. If it doesn't complete,
+    /// because the writer failed, we make do with whatever we can read.
+    pub(crate) async fn try_open_read_only(path: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(false)
+            .create(false)
+            .open(path)
+            .await?;
+
+        let mut attempt = 1;
+        loop {
+            let header = BTreeFile::read_header(&mut file).await?;
+
+            if !SUPPORTED_VERSIONS.contains(&header.version) {
+                return Err(BTreeFileError::InvalidFileVersion(header.version).into());
+            }
+
+            let last_attempt = attempt == READ_ATTEMPTS;
+            if header.generation % 2 == 0 || last_attempt {
+                let footer = BTreeFile::read_footer(&mut file, header.footer_offset).await;
+                let current = BTreeFile::read_header(&mut file).await?;
+                match footer {
+                    Ok(footer) if current.generation == header.generation || last_attempt => {
+                        if header.generation % 2 != 0 {
+                            tracing::warn!("{} is incomplete, writer failed?", path.display());
+                        }
+                        return Ok(Self {
+                            file,
+                            path: path.into(),
+                            header,
+                            footer,
+                            read_only: true,
+                            blocks_allocated: 0,
+                        });
+                    }
+                    Err(e) if last_attempt => return Err(e),
+                    _ => (),
+                }
+            }
+            attempt += 1;
+            tokio::time::sleep(READ_RETRY_DELAY).await;
+        }
+    }
+
     pub(crate) async fn try_new(path: &Path, size: u64) -> Result<Self> {
         let file = OpenOptions::new()
             .read(true)
@@ -140,6 +277,9 @@ impl BTreeFile {
             .open(path)
             .await?;
 
+        // Lock before we modify anything, in case another process has the file open
+        lock(&file)?;
+
         file.set_len(512_584).await?;
 
         let mut blocks = VecDeque::new();
@@ -159,12 +299,16 @@ impl BTreeFile {
 
         Ok(Self {
             file,
+            path: path.into(),
             header,
             footer,
+            read_only: false,
+            blocks_allocated: 0,
         })
     }
 
     pub(crate) async fn reset(&mut self, size: u64) -> Result<()> {
+        self.begin_update().await?;
         self.file.set_len(512_584).await?;
         self.footer.block_map.clear();
         self.footer.blocks.clear();
@@ -176,7 +320,12 @@ impl BTreeFile {
         self.footer.map_size = BINCODER.serialized_size(&self.footer.block_map)?;
         self.footer.blocks_size = BINCODER.serialized_size(&self.footer.blocks)?;
 
+        // Our generation must keep increasing, so readers notice the reset
+        let generation = self.header.generation;
+        let key_order = self.header.key_order;
         self.header = header;
+        self.header.generation = generation;
+        self.header.key_order = key_order;
 
         Ok(())
     }
@@ -191,27 +340,109 @@ impl BTreeFile {
                 let mut buf = vec![0; (BLOCK_SIZE * block.count) as usize];
                 self.file.seek(SeekFrom::Start(block.offset)).await?;
                 self.file.read_exact(&mut buf).await?;
+                // Only a reader can be racing with a writer
+                if self.read_only && !self.is_current().await? {
+                    return Err(BTreeFileError::Stale.into());
+                }
                 Ok(buf)
             }
             None => Err(BTreeFileError::LostMapping(index).into()),
         }
     }
 
+    /// Has our file been updated (by another process) since we read our header?
+    pub(crate) async fn is_current(&mut self) -> Result<bool> {
+        let header = BTreeFile::read_header(&mut self.file).await?;
+        Ok(header.generation == self.header.generation)
+    }
+
     pub(crate) fn free_data(&mut self, index: usize) -> Result<()> {
-        self.footer
+        let block = self
+            .footer
             .block_map
             .remove(&index)
-            .map(|block| {
-                let pos = self
-                    .footer
-                    .blocks
-                    .partition_point(|x| block.count <= x.count);
-                self.footer.blocks.insert(pos, block);
-            })
-            .ok_or(BTreeFileError::LostMapping(index).into())
+            .ok_or(BTreeFileError::LostMapping(index))?;
+        self.release_block(index, block)
+    }
+
+    /// Return a block to our free blocks.
+    fn release_block(&mut self, index: usize, block: Block) -> Result<()> {
+        if VALIDATE_BLOCKS {
+            if let Some(free) = self.footer.blocks.iter().find(|free| free.overlaps(&block)) {
+                return Err(BTreeFileError::DoubleFree(index, free.offset).into());
+            }
+        }
+        let pos = self
+            .footer
+            .blocks
+            .partition_point(|x| block.count <= x.count);
+        self.footer.blocks.insert(pos, block);
+        Ok(())
+    }
+
+    /// Make sure a newly allocated block doesn't overlap any block which is in use.
+    fn check_allocation(&self, block: &Block) -> Result<()> {
+        if VALIDATE_BLOCKS {
+            if let Some(used) = self
+                .footer
+                .block_map
+                .values()
+                .find(|used| used.overlaps(block))
+            {
+                return Err(BTreeFileError::OverlappingBlocks(block.offset, used.offset).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that none of our blocks overlap and that they are all within our data region.
+    pub(crate) fn validate(&self) -> Result<()> {
+        let mut blocks = self
+            .footer
+            .block_map
+            .values()
+            .chain(self.footer.blocks.iter())
+            .collect::<Vec<&Block>>();
+        blocks.sort_by_key(|block| block.offset);
+        for block in &blocks {
+            if block.offset < BLOCK_SIZE || block.end() > self.header.footer_offset {
+                return Err(BTreeFileError::BlockOutOfBounds(block.offset).into());
+            }
+        }
+        for pair in blocks.windows(2) {
+            if pair[0].overlaps(pair[1]) {
+                return Err(
+                    BTreeFileError::OverlappingBlocks(pair[1].offset, pair[0].offset).into(),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the header and footer on disk can be read and still describe our file.
+    pub(crate) async fn check_header(&mut self) -> Result<()> {
+        let header = BTreeFile::read_header(&mut self.file).await?;
+        if !SUPPORTED_VERSIONS.contains(&header.version) {
+            return Err(BTreeFileError::InvalidFileVersion(header.version).into());
+        }
+        if header.footer_offset > self.size().await? {
+            return Err(BTreeFileError::FooterOutOfBounds(header.footer_offset).into());
+        }
+        // Only a reader can be racing with a writer
+        if !self.read_only && header.generation != self.header.generation {
+            return Err(BTreeFileError::Stale.into());
+        }
+        BTreeFile::read_footer(&mut self.file, header.footer_offset).await?;
+        Ok(())
+    }
+
+    /// Was an update of our file started, but not completed?
+    pub(crate) fn update_incomplete(&self) -> bool {
+        self.header.generation % 2 != 0
     }
 
     pub(crate) async fn write_data(&mut self, index: usize, data: &[u8]) -> Result<()> {
+        self.begin_update().await?;
         // Somewhat unusual structure because we may have to migrate a data block
         let offset = match self.footer.block_map.get(&index) {
             Some(block) => {
@@ -219,6 +450,7 @@ impl BTreeFile {
                 if count > block.count {
                     // Need to migrate
                     let new_block = self.get_block(data.len() as u64).await?;
+                    self.check_allocation(&new_block)?;
                     let offset = new_block.offset;
                     let old_block = self
                         .footer
@@ -227,11 +459,7 @@ impl BTreeFile {
                         .ok_or(BTreeFileError::BlockReturn(index))?;
                     // .expect("must already be a value; qed");
                     // Return old block into blocks...
-                    let pos = self
-                        .footer
-                        .blocks
-                        .partition_point(|x| old_block.count <= x.count);
-                    self.footer.blocks.insert(pos, old_block);
+                    self.release_block(index, old_block)?;
                     offset
                 } else {
                     block.offset
@@ -239,6 +467,7 @@ impl BTreeFile {
             }
             None => {
                 let block = self.get_block(data.len() as u64).await?;
+                self.check_allocation(&block)?;
                 let offset = block.offset;
                 self.footer.block_map.insert(index, block);
                 offset
@@ -249,6 +478,210 @@ impl BTreeFile {
         Ok(())
     }
 
+    /// Allocate blocks for a batch of data, returning the writes which will store it.
+    ///
+    /// Data which doesn't fit in its current block is stored in a single region, so that data
+    /// which is written together is stored together. Writes to adjacent blocks are coalesced, with
+    /// the unused end of each block filled with zeros.
+    pub(crate) async fn prepare_writes(
+        &mut self,
+        batch: Vec<(usize, Vec<u8>)>,
+    ) -> Result<Vec<CoalescedWrite>> {
+        self.begin_update().await?;
+        let mut placed = Vec::with_capacity(batch.len());
+        let mut unplaced = vec![];
+        for (index, data) in batch {
+            let count = BTreeFile::blocks_needed(data.len() as u64);
+            match self.footer.block_map.get(&index) {
+                Some(block) if count <= block.count => {
+                    placed.push((block.offset, block.end(), index, data))
+                }
+                _ => unplaced.push((index, count, data)),
+            }
+        }
+
+        if !unplaced.is_empty() {
+            let total = unplaced
+                .iter()
+                .map(|(_index, count, _data)| count)
+                .sum::<u64>();
+            let mut region = Some(self.get_block(total * BLOCK_SIZE).await?);
+            // Split our region before releasing any blocks, so they can't overlap it
+            let mut blocks = vec![];
+            for (index, count, data) in unplaced {
+                let mut block = region.take().ok_or(BTreeFileError::LostBlock(index))?;
+                region = block.split(count);
+                self.check_allocation(&block)?;
+                blocks.push((index, block, data));
+            }
+            for (index, block, data) in blocks {
+                placed.push((block.offset, block.end(), index, data));
+                if let Some(old_block) = self.footer.block_map.insert(index, block) {
+                    self.release_block(index, old_block)?;
+                }
+            }
+        }
+
+        placed.sort_by_key(|(offset, _end, _index, _data)| *offset);
+        let mut writes: Vec<CoalescedWrite> = vec![];
+        let mut end = 0;
+        for (offset, block_end, index, data) in placed {
+            match writes.last_mut() {
+                Some(write)
+                    if offset == end && write.data.len() + data.len() <= MAX_COALESCED_WRITE =>
+                {
+                    write.data.resize((offset - write.offset) as usize, 0);
+                    write.data.extend(data);
+                    write.indices.push(index);
+                }
+                _ => writes.push(CoalescedWrite {
+                    offset,
+                    data,
+                    indices: vec![index],
+                }),
+            }
+            end = block_end;
+        }
+        Ok(writes)
+    }
+
+    /// Perform a write returned by [`BTreeFile::prepare_writes`].
+    pub(crate) async fn write_coalesced(&mut self, write: &CoalescedWrite) -> Result<()> {
+        self.file.seek(SeekFrom::Start(write.offset)).await?;
+        self.file.write_all(&write.data).await?;
+        Ok(())
+    }
+
+    /// Return the fraction of our data blocks which are free, ignoring any free space at the end of
+    /// our data blocks (which is available for growth, rather than lost to fragmentation).
+    pub(crate) fn fragmentation(&self) -> f64 {
+        let total = self.header.footer_offset - BLOCK_SIZE;
+        if total == 0 {
+            return 0.0;
+        }
+        let free: u64 = self
+            .footer
+            .blocks
+            .iter()
+            .filter(|block| block.offset + block.count * BLOCK_SIZE != self.header.footer_offset)
+            .map(|block| block.count * BLOCK_SIZE)
+            .sum();
+        free as f64 / total as f64
+    }
+
+    /// Rewrite our file so that all our data blocks are contiguous, releasing all free space.
+    ///
+    /// Indices are preserved, only the location of the blocks within the file changes. The
+    /// number of bytes reclaimed is returned.
+    pub(crate) async fn compact(&mut self) -> Result<u64> {
+        let mut tmp_path = self.path.clone();
+        tmp_path.set_extension("compact");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)
+            .await?;
+        lock(&file)?;
+
+        let mut compacted = Self {
+            file,
+            path: self.path.clone(),
+            header: self.header.clone(),
+            footer: BTreeFileFooter {
+                map_size: 0,
+                block_map: HashMap::new(),
+                blocks_size: 0,
+                blocks: VecDeque::new(),
+            },
+            read_only: false,
+            blocks_allocated: self.blocks_allocated,
+        };
+
+        let result = self.copy_blocks(&mut compacted).await;
+        if let Err(e) = result {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(e);
+        }
+
+        // Readers of our old file must notice that it has been replaced
+        self.begin_update().await?;
+        let old_len = self.file.metadata().await?.len();
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+        *self = compacted;
+        let new_len = self.file.metadata().await?.len();
+        Ok(old_len.saturating_sub(new_len))
+    }
+
+    /// Copy all our blocks, in file order, to the start of the target file.
+    async fn copy_blocks(&mut self, target: &mut BTreeFile) -> Result<()> {
+        let mut mapped = self
+            .footer
+            .block_map
+            .iter()
+            .map(|(index, block)| (*index, block.offset, block.count))
+            .collect::<Vec<(usize, u64, u64)>>();
+        mapped.sort_by_key(|(_index, offset, _count)| *offset);
+
+        let mut target_offset = BLOCK_SIZE;
+        for (index, offset, count) in mapped {
+            let mut buf = vec![0; (BLOCK_SIZE * count) as usize];
+            self.file.seek(SeekFrom::Start(offset)).await?;
+            self.file.read_exact(&mut buf).await?;
+            target.file.seek(SeekFrom::Start(target_offset)).await?;
+            target.file.write_all(&buf).await?;
+            target.footer.block_map.insert(
+                index,
+                Block {
+                    offset: target_offset,
+                    count,
+                },
+            );
+            target_offset += BLOCK_SIZE * count;
+        }
+        target.header.footer_offset = target_offset;
+        target.write_header_and_footer().await?;
+        target.end_update().await?;
+        target.flush().await
+    }
+
+    /// Copy our whole file to a writer, reporting the number of bytes copied (and the total) after
+    /// each chunk. Returns the number of bytes copied.
+    pub(crate) async fn copy_to<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<u64> {
+        let total = self.size().await?;
+        let mut buf = vec![0; COPY_CHUNK_SIZE];
+        let mut copied = 0;
+        self.file.seek(SeekFrom::Start(0)).await?;
+        while copied < total {
+            let len = buf.len().min((total - copied) as usize);
+            self.file.read_exact(&mut buf[..len]).await?;
+            writer.write_all(&buf[..len]).await?;
+            copied += len as u64;
+            progress(copied, total);
+        }
+        writer.flush().await?;
+        // Only a reader can be racing with a writer
+        if self.read_only && !self.is_current().await? {
+            return Err(BTreeFileError::Stale.into());
+        }
+        Ok(copied)
+    }
+
+    /// Return the size of our file in bytes.
+    pub(crate) async fn size(&self) -> Result<u64> {
+        Ok(self.file.metadata().await?.len())
+    }
+
+    /// Return the indices of all our data blocks.
+    pub(crate) fn indices(&self) -> Vec<usize> {
+        self.footer.block_map.keys().copied().collect()
+    }
+
     pub(crate) async fn get_root_index(&self) -> usize {
         self.header.root_index
     }
@@ -257,6 +690,59 @@ impl BTreeFile {
         self.header.tree_index
     }
 
+    /// Return the number of blocks allocated since our file was opened.
+    pub(crate) fn blocks_allocated(&self) -> u64 {
+        self.blocks_allocated
+    }
+
+    /// Return our generation, which changes whenever our file is updated.
+    pub(crate) fn get_generation(&self) -> u64 {
+        self.header.generation
+    }
+
+    /// Return the identity of the ordering of our keys.
+    pub(crate) fn get_key_order(&self) -> u64 {
+        self.header.key_order
+    }
+
+    /// Set the identity of the ordering of our keys. Written with our header.
+    pub(crate) fn set_key_order(&mut self, key_order: u64) {
+        self.header.key_order = key_order;
+    }
+
+    /// Return the number of entries in, and depth of, our tree, if our file records them.
+    ///
+    /// If an update was interrupted (e.g.: the writer crashed), our nodes may not match the
+    /// recorded values, so they aren't returned.
+    pub(crate) fn get_count_and_depth(&self) -> Option<(usize, usize)> {
+        (self.header.version >= FORMAT_VERSION_2 && self.header.generation % 2 == 0)
+            .then_some((self.header.count, self.header.depth))
+    }
+
+    /// Set the number of entries in, and depth of, our tree. Written with our header.
+    pub(crate) fn set_count_and_depth(&mut self, count: usize, depth: usize) {
+        // Our header now contains everything required by FORMAT_VERSION_2
+        self.header.version = self.header.version.max(FORMAT_VERSION_2);
+        self.header.count = count;
+        self.header.depth = depth;
+    }
+
+    /// Do the leaf nodes in our file link to their siblings?
+    pub(crate) fn has_leaf_links(&self) -> bool {
+        self.header.version >= FORMAT_VERSION_3
+    }
+
+    /// Was our file written by an earlier version?
+    pub(crate) fn needs_upgrade(&self) -> bool {
+        self.header.version < FORMAT_VERSION_4
+    }
+
+    /// Record that our file uses the latest format. All the leaf nodes in our file must link to
+    /// their siblings. Written with our header.
+    pub(crate) fn upgrade(&mut self) {
+        self.header.version = FORMAT_VERSION_4;
+    }
+
     async fn read_header(file: &mut File) -> Result<BTreeFileHeader> {
         let mut buf = vec![0; BLOCK_SIZE as usize];
 
@@ -302,13 +788,37 @@ impl BTreeFile {
     ) -> Result<()> {
         self.header.root_index = root_index;
         self.header.tree_index = tree_index;
-        self.write_header_and_footer().await
+        self.write_header_and_footer().await?;
+        self.end_update().await
     }
 
-    async fn write_header_and_footer(&mut self) -> Result<()> {
+    /// Mark our file as being updated, if it isn't already.
+    async fn begin_update(&mut self) -> Result<()> {
+        if self.header.generation % 2 == 0 {
+            self.header.generation += 1;
+            self.write_header().await?;
+        }
+        Ok(())
+    }
+
+    /// Mark our update as complete.
+    async fn end_update(&mut self) -> Result<()> {
+        if self.header.generation % 2 != 0 {
+            self.header.generation += 1;
+            self.write_header().await?;
+        }
+        Ok(())
+    }
+
+    async fn write_header(&mut self) -> Result<()> {
         let s_header = BINCODER.serialize(&self.header)?;
         self.file.seek(SeekFrom::Start(0)).await?;
         self.file.write_all(&s_header).await?;
+        Ok(())
+    }
+
+    async fn write_header_and_footer(&mut self) -> Result<()> {
+        self.begin_update().await?;
 
         let s_map = BINCODER.serialize(&self.footer.block_map)?;
         let s_blocks = BINCODER.serialize(&self.footer.blocks)?;
@@ -325,7 +835,7 @@ impl BTreeFile {
         self.file.write_all(&s_blocks_size).await?;
         self.file.write_all(&s_blocks).await?;
 
-        Ok(())
+        self.write_header().await
     }
 
     /// Initialise our file structure based on desired storage space
@@ -334,10 +844,14 @@ impl BTreeFile {
 
         // Add on a block to store the header in
         let hdr = BTreeFileHeader {
-            version: FORMAT_VERSION_1,
+            version: FORMAT_VERSION_4,
             footer_offset: (count + 1) * BLOCK_SIZE,
             root_index: 1,
             tree_index: 2,
+            generation: 0,
+            count: 0,
+            depth: 1,
+            key_order: 0,
         };
 
         let block = Block {
@@ -378,6 +892,7 @@ impl BTreeFile {
             self.footer.blocks.push_front(block);
             pos = self.footer.blocks.partition_point(|x| count <= x.count);
         }
+        self.blocks_allocated += 1;
         // Take 1 away from pos to get the last valid value (that's what we are looking for)
         pos -= 1;
         // At this point, pos must be a valid value. Let's split that block and then re-insert it
@@ -396,6 +911,73 @@ impl BTreeFile {
     }
 }
 
+/// Scans the blocks of a file without relying on its header or footer, so that data can be
+/// salvaged from a file which is corrupt.
+pub(crate) struct BlockScanner {
+    file: File,
+    offset: u64,
+    len: u64,
+}
+
+impl BlockScanner {
+    pub(crate) async fn try_open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(false)
+            .create(false)
+            .open(path)
+            .await?;
+        let len = file.metadata().await?.len();
+
+        // Skip our header block, data is never stored there
+        Ok(Self {
+            file,
+            offset: BLOCK_SIZE,
+            len,
+        })
+    }
+
+    /// Read up to window bytes from the start of the next block.
+    pub(crate) async fn next_block(&mut self, window: u64) -> Result<Option<Vec<u8>>> {
+        if self.offset >= self.len {
+            return Ok(None);
+        }
+        let mut buf = vec![0; window.min(self.len - self.offset) as usize];
+        self.file.seek(SeekFrom::Start(self.offset)).await?;
+        self.file.read_exact(&mut buf).await?;
+        self.offset += BLOCK_SIZE;
+        Ok(Some(buf))
+    }
+
+    /// Skip over the remainder of the data of the specified size, which started at our last
+    /// block.
+    pub(crate) fn skip(&mut self, size: u64) {
+        let count = BTreeFile::blocks_needed(size);
+        if count > 1 {
+            self.offset += (count - 1) * BLOCK_SIZE;
+        }
+    }
+}
+
+/// Take an exclusive advisory lock on a file, failing with `ErrorKind::WouldBlock` if another
+/// process holds the lock. The lock is released when the file is closed.
+#[cfg(unix)]
+fn lock(file: &File) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: The file descriptor is valid for as long as we hold a reference to file.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn lock(_file: &File) -> std::io::Result<()> {
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -424,6 +1006,107 @@ mod tests {
         std::fs::remove_file("file_open.db").expect("cleanup");
     }
 
+    #[tokio::test]
+    async fn it_locks_btree_file() {
+        let tree = BTreeFile::try_new(Path::new("file_lock.db"), 1_024)
+            .await
+            .expect("creates tree file");
+        let err = BTreeFile::try_open(Path::new("file_lock.db"))
+            .await
+            .expect_err("file is locked");
+        let io_error = err
+            .downcast_ref::<std::io::Error>()
+            .expect("is an io error");
+        assert_eq!(io_error.kind(), std::io::ErrorKind::WouldBlock);
+        drop(tree);
+        std::fs::remove_file("file_lock.db").expect("cleanup");
+    }
+
+    #[tokio::test]
+    async fn it_detects_stale_reads() {
+        let mut tree = BTreeFile::try_new(Path::new("file_stale.db"), 1_024)
+            .await
+            .expect("creates tree file");
+        tree.write_data(1, &[1; 600]).await.expect("writes data");
+        tree.write_header_with_indices(1, 2)
+            .await
+            .expect("header written");
+
+        let mut reader = BTreeFile::try_open_read_only(Path::new("file_stale.db"))
+            .await
+            .expect("opens tree file");
+        let data = reader.read_data(1).await.expect("reads data");
+        assert_eq!(&data[..600], &[1; 600][..]);
+
+        tree.write_data(1, &[2; 600]).await.expect("writes data");
+        let err = reader.read_data(1).await.expect_err("is stale");
+        assert!(matches!(
+            err.downcast_ref::<BTreeFileError>(),
+            Some(BTreeFileError::Stale)
+        ));
+        tree.write_header_with_indices(1, 2)
+            .await
+            .expect("header written");
+        assert!(!reader.is_current().await.expect("reads header"));
+
+        let mut reader = BTreeFile::try_open_read_only(Path::new("file_stale.db"))
+            .await
+            .expect("opens tree file");
+        let data = reader.read_data(1).await.expect("reads data");
+        assert_eq!(&data[..600], &[2; 600][..]);
+        std::fs::remove_file("file_stale.db").expect("cleanup");
+    }
+
+    #[tokio::test]
+    async fn it_compacts_btree_file() {
+        let mut tree = BTreeFile::try_new(Path::new("file_compact.db"), 1_024)
+            .await
+            .expect("creates tree file");
+        for index in 1..20 {
+            let data = vec![index as u8; 600];
+            tree.write_data(index, &data).await.expect("writes data");
+        }
+        for index in (1..20).step_by(2) {
+            tree.free_data(index).expect("frees data");
+        }
+        assert!(tree.fragmentation() > 0.0);
+        let reclaimed = tree.compact().await.expect("compacts");
+        assert!(reclaimed > 0);
+        assert_eq!(tree.fragmentation(), 0.0);
+        for index in (2..20).step_by(2) {
+            let data = tree.read_data(index).await.expect("reads data");
+            assert_eq!(&data[..600], &vec![index as u8; 600][..]);
+        }
+        std::fs::remove_file("file_compact.db").expect("cleanup");
+    }
+
+    #[tokio::test]
+    async fn it_detects_double_free() {
+        let mut tree = BTreeFile::try_new(Path::new("file_double_free.db"), 1_024)
+            .await
+            .expect("creates tree file");
+        tree.write_data(1, &[1; 600]).await.expect("writes data");
+        tree.write_data(2, &[2; 600]).await.expect("writes data");
+        tree.validate().expect("blocks are valid");
+
+        // Simulate a bug which leaves two indices referring to the same block
+        let offset = tree.footer.block_map[&1].offset;
+        tree.footer.block_map.get_mut(&2).expect("is mapped").offset = offset;
+        let err = tree.validate().expect_err("blocks overlap");
+        assert!(matches!(
+            err.downcast_ref::<BTreeFileError>(),
+            Some(BTreeFileError::OverlappingBlocks(_, _))
+        ));
+
+        tree.free_data(1).expect("frees data");
+        let err = tree.free_data(2).expect_err("double free");
+        assert!(matches!(
+            err.downcast_ref::<BTreeFileError>(),
+            Some(BTreeFileError::DoubleFree(2, _))
+        ));
+        std::fs::remove_file("file_double_free.db").expect("cleanup");
+    }
+
     #[tokio::test]
     async fn it_finds_block() {
         let mut tree = BTreeFile::try_new(Path::new("file_find_valid_block.db"), 1_024)
@@ -433,4 +1116,41 @@ mod tests {
         tree.get_block(513).await.expect("gets a block");
         std::fs::remove_file("file_find_valid_block.db").expect("cleanup");
     }
+
+    #[tokio::test]
+    async fn it_coalesces_writes() {
+        let mut tree = BTreeFile::try_new(Path::new("file_coalesce.db"), 1_024)
+            .await
+            .expect("creates tree file");
+        let batch = (2..6)
+            .map(|index| (index, vec![index as u8; 100]))
+            .collect::<Vec<(usize, Vec<u8>)>>();
+        let writes = tree.prepare_writes(batch).await.expect("prepares writes");
+        // New data is stored together
+        assert_eq!(writes.len(), 1);
+        assert_eq!(writes[0].indices(), &[2, 3, 4, 5]);
+        for write in &writes {
+            tree.write_coalesced(write).await.expect("writes data");
+        }
+        tree.validate().expect("blocks are valid");
+
+        // Data which outgrows its block is moved, so it is no longer adjacent
+        let batch = vec![(3, vec![3; 600]), (4, vec![4; 100]), (5, vec![5; 100])];
+        let writes = tree.prepare_writes(batch).await.expect("prepares writes");
+        assert_eq!(writes.len(), 2);
+        assert_eq!(writes[0].indices(), &[3]);
+        assert_eq!(writes[1].indices(), &[4, 5]);
+        for write in &writes {
+            tree.write_coalesced(write).await.expect("writes data");
+        }
+        tree.validate().expect("blocks are valid");
+        for (index, len) in [(2, 100), (3, 600), (4, 100), (5, 100)] {
+            let data = tree.read_data(index).await.expect("reads data");
+            assert!(data[..len].iter().all(|byte| *byte == index as u8));
+        }
+        // Gaps between coalesced data are zero filled
+        let data = tree.read_data(4).await.expect("reads data");
+        assert!(data[100..].iter().all(|byte| *byte == 0));
+        std::fs::remove_file("file_coalesce.db").expect("cleanup");
+    }
 }
@@ -1,6 +1,7 @@
 //! WAL functions
 //!
 
+use std::io::SeekFrom;
 use std::path::Path;
 use std::time::Duration;
 
@@ -8,7 +9,7 @@ use anyhow::Result;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokio::fs::{File, OpenOptions};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
 #[derive(Debug)]
 pub(crate) struct WalFile {
@@ -70,9 +71,20 @@ impl WalFile {
     pub(crate) async fn write_data(&mut self, data: &[u8]) -> Result<()> {
         self.file.write_u64(data.len() as u64).await?;
         self.file.write_all(data).await?;
+        // Make sure our write has reached the OS, even if we don't sync it to disk
+        self.file.flush().await?;
         self.flush().await
     }
 
+    /// Read every record in our file. Records are always appended, so reading doesn't affect
+    /// subsequent writes.
+    pub(crate) async fn read_all(&mut self) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        self.file.seek(SeekFrom::Start(0)).await?;
+        self.file.read_to_end(&mut buf).await?;
+        Ok(buf)
+    }
+
     pub(crate) async fn read_data(&mut self) -> Result<Vec<u8>> {
         let len = self.file.read_u64().await?;
         let mut buf = vec![0; len as usize];
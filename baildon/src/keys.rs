@@ -0,0 +1,627 @@
+//! Order Preserving Key Encoding
+//!
+//! Encode values as byte keys whose lexicographic order matches the logical order of the values,
+//! so that multi-column (composite) keys can be built from tuples and stored as `Vec<u8>`.
+//!
+//! Encodings:
+//!  - Unsigned integers: big endian
+//!  - Signed integers: big endian, with the sign bit flipped
+//!  - Floats: IEEE total ordering (negative values have all bits flipped, others the sign bit)
+//!  - Strings and byte strings: zero bytes are escaped as `0x00 0xFF` and the value is terminated
+//!    by `0x00 0x00`, so a string sorts before any longer string it prefixes, even when followed
+//!    by other columns
+//!  - Tuples: the concatenation of their elements
+//!
+//! Floats don't implement `Ord`, so they can't be used as tree keys directly. [`TotalF64`] and
+//! [`TotalF32`] wrap them with IEEE total ordering (`-NaN < -inf < ... < -0.0 < 0.0 < ... < inf <
+//! NaN`), so they can.
+//!
+//! Encoded keys may be stored as [`RawKey`], which is serialized as a single block of bytes
+//! rather than byte by byte.
+//!
+//! ```
+//! use baildon::keys;
+//!
+//! let a = keys::encode(&("apple", -1i32));
+//! let b = keys::encode(&("apple", 2i32));
+//! let c = keys::encode(&("banana", -5i32));
+//! assert!(a < b && b < c);
+//! assert_eq!(keys::decode::<(String, i32)>(&b).unwrap(), ("apple".to_string(), 2));
+//! ```
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+
+use serde::de::{SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+/// Key decoding errors.
+#[derive(Debug, Error, PartialEq)]
+pub enum KeyError {
+    /// Key ended before a value was complete
+    #[error("key is truncated")]
+    Truncated,
+
+    /// A zero byte in a string wasn't followed by a valid escape
+    #[error("invalid escape: {0:#04x}")]
+    InvalidEscape(u8),
+
+    /// A string wasn't valid UTF-8
+    #[error("invalid UTF-8 in key")]
+    InvalidUtf8,
+
+    /// Key contained more bytes than the decoded value
+    #[error("key has {0} trailing bytes")]
+    TrailingBytes(usize),
+}
+
+/// Values which can be encoded as order preserving byte keys.
+pub trait KeyEncode {
+    /// Append our encoding to a key.
+    fn encode_key(&self, out: &mut Vec<u8>);
+}
+
+/// Values which can be decoded from order preserving byte keys.
+pub trait KeyDecode: Sized {
+    /// Decode a value from the start of a key, advancing the key past it.
+    fn decode_key(input: &mut &[u8]) -> Result<Self, KeyError>;
+}
+
+/// Encode a value as an order preserving key.
+pub fn encode<T: KeyEncode + ?Sized>(value: &T) -> Vec<u8> {
+    let mut out = vec![];
+    value.encode_key(&mut out);
+    out
+}
+
+/// Decode a value from an entire key.
+pub fn decode<T: KeyDecode>(mut key: &[u8]) -> Result<T, KeyError> {
+    let value = T::decode_key(&mut key)?;
+    if !key.is_empty() {
+        return Err(KeyError::TrailingBytes(key.len()));
+    }
+    Ok(value)
+}
+
+fn take<const N: usize>(input: &mut &[u8]) -> Result<[u8; N], KeyError> {
+    if input.len() < N {
+        return Err(KeyError::Truncated);
+    }
+    let (head, tail) = input.split_at(N);
+    *input = tail;
+    Ok(head.try_into().expect("split at N; qed"))
+}
+
+macro_rules! unsigned_key {
+    ($($t:ty),*) => {
+        $(
+            impl KeyEncode for $t {
+                fn encode_key(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_be_bytes());
+                }
+            }
+
+            impl KeyDecode for $t {
+                fn decode_key(input: &mut &[u8]) -> Result<Self, KeyError> {
+                    Ok(<$t>::from_be_bytes(take(input)?))
+                }
+            }
+        )*
+    };
+}
+
+unsigned_key!(u8, u16, u32, u64, u128);
+
+macro_rules! signed_key {
+    ($(($t:ty, $u:ty)),*) => {
+        $(
+            impl KeyEncode for $t {
+                fn encode_key(&self, out: &mut Vec<u8>) {
+                    // Flipping the sign bit orders negative values before positive values
+                    ((*self as $u) ^ (1 << (<$u>::BITS - 1))).encode_key(out);
+                }
+            }
+
+            impl KeyDecode for $t {
+                fn decode_key(input: &mut &[u8]) -> Result<Self, KeyError> {
+                    Ok((<$u>::decode_key(input)? ^ (1 << (<$u>::BITS - 1))) as $t)
+                }
+            }
+        )*
+    };
+}
+
+signed_key!((i8, u8), (i16, u16), (i32, u32), (i64, u64), (i128, u128));
+
+macro_rules! float_key {
+    ($(($t:ty, $u:ty)),*) => {
+        $(
+            impl KeyEncode for $t {
+                fn encode_key(&self, out: &mut Vec<u8>) {
+                    let bits = self.to_bits();
+                    let sign = 1 << (<$u>::BITS - 1);
+                    let ordered = if bits & sign != 0 { !bits } else { bits ^ sign };
+                    ordered.encode_key(out);
+                }
+            }
+
+            impl KeyDecode for $t {
+                fn decode_key(input: &mut &[u8]) -> Result<Self, KeyError> {
+                    let ordered = <$u>::decode_key(input)?;
+                    let sign = 1 << (<$u>::BITS - 1);
+                    let bits = if ordered & sign != 0 { ordered ^ sign } else { !ordered };
+                    Ok(<$t>::from_bits(bits))
+                }
+            }
+        )*
+    };
+}
+
+float_key!((f32, u32), (f64, u64));
+
+// usize and isize are always encoded as 64 bits, so keys are portable
+impl KeyEncode for usize {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        (*self as u64).encode_key(out);
+    }
+}
+
+impl KeyDecode for usize {
+    fn decode_key(input: &mut &[u8]) -> Result<Self, KeyError> {
+        Ok(u64::decode_key(input)? as usize)
+    }
+}
+
+impl KeyEncode for isize {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        (*self as i64).encode_key(out);
+    }
+}
+
+impl KeyDecode for isize {
+    fn decode_key(input: &mut &[u8]) -> Result<Self, KeyError> {
+        Ok(i64::decode_key(input)? as isize)
+    }
+}
+
+impl KeyEncode for bool {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+}
+
+impl KeyDecode for bool {
+    fn decode_key(input: &mut &[u8]) -> Result<Self, KeyError> {
+        Ok(u8::decode_key(input)? != 0)
+    }
+}
+
+impl KeyEncode for [u8] {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        for byte in self {
+            out.push(*byte);
+            if *byte == 0 {
+                out.push(0xFF);
+            }
+        }
+        out.extend_from_slice(&[0, 0]);
+    }
+}
+
+impl KeyEncode for Vec<u8> {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        self.as_slice().encode_key(out);
+    }
+}
+
+impl KeyDecode for Vec<u8> {
+    fn decode_key(input: &mut &[u8]) -> Result<Self, KeyError> {
+        let mut bytes = vec![];
+        loop {
+            let [byte] = take::<1>(input)?;
+            if byte != 0 {
+                bytes.push(byte);
+                continue;
+            }
+            match take::<1>(input)? {
+                [0] => return Ok(bytes),
+                [0xFF] => bytes.push(0),
+                [other] => return Err(KeyError::InvalidEscape(other)),
+            }
+        }
+    }
+}
+
+impl KeyEncode for str {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        self.as_bytes().encode_key(out);
+    }
+}
+
+impl KeyEncode for String {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        self.as_str().encode_key(out);
+    }
+}
+
+impl KeyDecode for String {
+    fn decode_key(input: &mut &[u8]) -> Result<Self, KeyError> {
+        String::from_utf8(Vec::<u8>::decode_key(input)?).map_err(|_| KeyError::InvalidUtf8)
+    }
+}
+
+impl<T: KeyEncode + ?Sized> KeyEncode for &T {
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        (**self).encode_key(out);
+    }
+}
+
+macro_rules! tuple_key {
+    ($(($($name:ident),+)),*) => {
+        $(
+            impl<$($name: KeyEncode),+> KeyEncode for ($($name,)+) {
+                #[allow(non_snake_case)]
+                fn encode_key(&self, out: &mut Vec<u8>) {
+                    let ($($name,)+) = self;
+                    $($name.encode_key(out);)+
+                }
+            }
+
+            impl<$($name: KeyDecode),+> KeyDecode for ($($name,)+) {
+                fn decode_key(input: &mut &[u8]) -> Result<Self, KeyError> {
+                    Ok(($($name::decode_key(input)?,)+))
+                }
+            }
+        )*
+    };
+}
+
+tuple_key!(
+    (A),
+    (A, B),
+    (A, B, C),
+    (A, B, C, D),
+    (A, B, C, D, E),
+    (A, B, C, D, E, F)
+);
+
+macro_rules! total_float {
+    ($(($name:ident, $t:ty)),*) => {
+        $(
+            #[doc = concat!(
+                "An `", stringify!($t), "` which is ordered by IEEE total ordering, so it may be ",
+                "used as a key."
+            )]
+            ///
+            /// Unlike the wrapped float, `-0.0` and `0.0` are different keys and `NaN` is equal to
+            /// itself.
+            #[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+            #[serde(transparent)]
+            pub struct $name(pub $t);
+
+            impl $name {
+                /// Return our float.
+                pub fn get(self) -> $t {
+                    self.0
+                }
+            }
+
+            impl From<$t> for $name {
+                fn from(value: $t) -> Self {
+                    Self(value)
+                }
+            }
+
+            impl From<$name> for $t {
+                fn from(value: $name) -> Self {
+                    value.0
+                }
+            }
+
+            impl PartialEq for $name {
+                fn eq(&self, other: &Self) -> bool {
+                    self.cmp(other) == Ordering::Equal
+                }
+            }
+
+            impl Eq for $name {}
+
+            impl PartialOrd for $name {
+                fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                    Some(self.cmp(other))
+                }
+            }
+
+            impl Ord for $name {
+                fn cmp(&self, other: &Self) -> Ordering {
+                    self.0.total_cmp(&other.0)
+                }
+            }
+
+            // Consistent with Eq, since floats are only equal in total ordering if their bits match
+            impl Hash for $name {
+                fn hash<H: Hasher>(&self, state: &mut H) {
+                    self.0.to_bits().hash(state);
+                }
+            }
+
+            impl KeyEncode for $name {
+                fn encode_key(&self, out: &mut Vec<u8>) {
+                    self.0.encode_key(out);
+                }
+            }
+
+            impl KeyDecode for $name {
+                fn decode_key(input: &mut &[u8]) -> Result<Self, KeyError> {
+                    <$t>::decode_key(input).map(Self)
+                }
+            }
+        )*
+    };
+}
+
+total_float!((TotalF64, f64), (TotalF32, f32));
+
+/// A key of raw bytes, which are compared lexicographically.
+///
+/// `Vec<u8>` is serialized one byte at a time, whereas a `RawKey` is copied as a single block. The
+/// serialized forms are identical, so trees with `Vec<u8>` keys may be opened with `RawKey` keys.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RawKey(Vec<u8>);
+
+impl RawKey {
+    /// Encode a value as a key.
+    pub fn encode<T: KeyEncode + ?Sized>(value: &T) -> Self {
+        Self(encode(value))
+    }
+
+    /// Decode a value from our key.
+    pub fn decode<T: KeyDecode>(&self) -> Result<T, KeyError> {
+        decode(&self.0)
+    }
+
+    /// Unwrap our bytes.
+    pub fn into_vec(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl Deref for RawKey {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for RawKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for RawKey {
+    fn from(value: Vec<u8>) -> Self {
+        Self(value)
+    }
+}
+
+impl From<&[u8]> for RawKey {
+    fn from(value: &[u8]) -> Self {
+        Self(value.to_vec())
+    }
+}
+
+impl From<RawKey> for Vec<u8> {
+    fn from(value: RawKey) -> Self {
+        value.0
+    }
+}
+
+impl Serialize for RawKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for RawKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_byte_buf(RawKeyVisitor)
+    }
+}
+
+struct RawKeyVisitor;
+
+impl<'de> Visitor<'de> for RawKeyVisitor {
+    type Value = RawKey;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a byte array")
+    }
+
+    fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<RawKey, E> {
+        Ok(RawKey::from(v))
+    }
+
+    fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<RawKey, E> {
+        Ok(RawKey(v))
+    }
+
+    // Self describing formats may represent bytes as a sequence
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<RawKey, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(RawKey(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bincode::Options;
+    use futures::StreamExt;
+
+    fn assert_ordered<T: KeyEncode + KeyDecode + PartialEq + std::fmt::Debug>(values: &[T]) {
+        let encoded = values.iter().map(encode).collect::<Vec<Vec<u8>>>();
+        assert!(encoded.windows(2).all(|w| w[0] < w[1]), "{values:?}");
+        for (value, key) in values.iter().zip(&encoded) {
+            assert_eq!(&decode::<T>(key).expect("decodes key"), value);
+        }
+    }
+
+    #[test]
+    fn it_orders_integers() {
+        assert_ordered(&[0u8, 1, 127, 128, 255]);
+        assert_ordered(&[i32::MIN, -256, -1, 0, 1, 256, i32::MAX]);
+        assert_ordered(&[i64::MIN, -1, 0, i64::MAX]);
+        assert_ordered(&[0usize, 1, usize::MAX]);
+    }
+
+    #[test]
+    fn it_orders_floats() {
+        assert_ordered(&[
+            f64::NEG_INFINITY,
+            -1e10,
+            -1.5,
+            -0.0,
+            0.0,
+            f64::MIN_POSITIVE,
+            1.5,
+            f64::INFINITY,
+        ]);
+        assert_ordered(&[-2.5f32, -0.0, 0.0, 3.25]);
+    }
+
+    #[test]
+    fn it_orders_strings() {
+        assert_ordered(&[
+            "".to_string(),
+            "a".to_string(),
+            "a\0".to_string(),
+            "a\0b".to_string(),
+            "ab".to_string(),
+            "b".to_string(),
+        ]);
+        assert_ordered(&[vec![], vec![0u8], vec![0, 0], vec![1]]);
+    }
+
+    #[test]
+    fn it_orders_tuples() {
+        assert_ordered(&[
+            ("a".to_string(), 255u8, 1u8),
+            ("a\0".to_string(), 0, 0),
+            ("b".to_string(), 0, 0),
+        ]);
+        assert_ordered(&[(-1i32, "z".to_string()), (0, "a".to_string())]);
+    }
+
+    #[test]
+    fn it_orders_total_floats() {
+        let mut values = [
+            f64::NAN,
+            1.0,
+            f64::INFINITY,
+            -0.0,
+            0.0,
+            -f64::NAN,
+            f64::NEG_INFINITY,
+            -1.0,
+        ]
+        .map(TotalF64);
+        values.sort();
+        let sorted = values.map(|value| value.get().to_bits());
+        let expected = [
+            -f64::NAN,
+            f64::NEG_INFINITY,
+            -1.0,
+            -0.0,
+            0.0,
+            1.0,
+            f64::INFINITY,
+            f64::NAN,
+        ]
+        .map(f64::to_bits);
+        assert_eq!(sorted, expected);
+        assert_ne!(TotalF32(-0.0), TotalF32(0.0));
+        assert_eq!(TotalF32(f32::NAN), TotalF32(f32::NAN));
+
+        // Our serialized form is just the float
+        let s_value = crate::BINCODER
+            .serialize(&TotalF64(1.5))
+            .expect("serializes");
+        assert_eq!(
+            s_value,
+            crate::BINCODER.serialize(&1.5f64).expect("serializes")
+        );
+        assert_eq!(
+            crate::BINCODER
+                .deserialize::<TotalF64>(&s_value)
+                .expect("deserializes"),
+            TotalF64(1.5)
+        );
+        assert_ordered(&values);
+    }
+
+    #[tokio::test]
+    async fn it_stores_float_keys() {
+        let tree = crate::btree::Baildon::<TotalF64, usize>::temporary(5)
+            .await
+            .expect("creates tree file");
+        for (i, key) in [2.5, -1.0, 0.0, -0.0].into_iter().enumerate() {
+            tree.insert(TotalF64(key), i).await.expect("insert worked");
+        }
+        assert_eq!(tree.get(&TotalF64(-0.0)).await, Some(3));
+        assert_eq!(tree.get(&TotalF64(0.0)).await, Some(2));
+        assert_eq!(tree.count().await, 4);
+    }
+
+    #[tokio::test]
+    async fn it_stores_raw_keys() {
+        let key = RawKey::encode(&("apple", 2i32));
+        assert_eq!(key.decode::<(String, i32)>(), Ok(("apple".to_string(), 2)));
+
+        // Our serialized form is the same as a Vec<u8>
+        let s_key = crate::BINCODER.serialize(&key).expect("serializes");
+        assert_eq!(s_key, crate::BINCODER.serialize(&*key).expect("serializes"));
+        assert_eq!(
+            crate::BINCODER
+                .deserialize::<RawKey>(&s_key)
+                .expect("deserializes"),
+            key
+        );
+
+        let tree = crate::btree::Baildon::<RawKey, usize>::temporary(5)
+            .await
+            .expect("creates tree file");
+        for i in (0..100u32).rev() {
+            tree.insert(RawKey::encode(&i), i as usize)
+                .await
+                .expect("insert worked");
+        }
+        let keys = tree
+            .keys(crate::btree::Direction::Ascending)
+            .await
+            .map(|key| key.decode::<u32>().expect("decodes"))
+            .collect::<Vec<u32>>()
+            .await;
+        assert_eq!(keys, (0..100).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn it_rejects_invalid_keys() {
+        assert_eq!(decode::<u32>(&[0, 0]), Err(KeyError::Truncated));
+        assert_eq!(decode::<u8>(&[0, 0]), Err(KeyError::TrailingBytes(1)));
+        assert_eq!(
+            decode::<String>(b"a\0\x01"),
+            Err(KeyError::InvalidEscape(1))
+        );
+        assert_eq!(decode::<String>(b"a"), Err(KeyError::Truncated));
+        assert_eq!(decode::<String>(b"\xFF\0\0"), Err(KeyError::InvalidUtf8));
+    }
+}
@@ -27,11 +27,16 @@
 //!
 //! At load/store to disk, a node is serialized/deserialized using bincode.
 //!
-//! When a reference to a B+Tree is dropped, dirty data is flushed to disk. Alternatively, a flush
-//! can also be manually triggered if desired.
+//! When a B+Tree is closed, dirty data is flushed to disk. Alternatively, a flush can also be
+//! manually triggered if desired. A B+Tree which is dropped without being closed does not flush.
 //!
 //! If the process fails in any way before data is safely on disk, then the Write Ahead Log (WAL)
-//! will be used when the B+Tree is next opened to recover lost modifications.
+//! will be used when the B+Tree is next opened to recover lost modifications. The guarantees this
+//! provides are exercised by crashing trees at the points described by [`btree::CrashPoint`].
+//!
+//! Operations are traced with [`tracing`] spans (`baildon.get`, `baildon.insert`,
+//! `baildon.delete`, `baildon.flush` and `wal.append`), which record the size of keys, the index
+//! of the leaf node which was used and how long the operation took, in microseconds.
 //!
 //! Note: Most of these details (nodes, file access, serialization format) are tucked away inside
 //! the implementation.  The user experience should be similar to working with a BTreeMap, but
@@ -40,7 +45,14 @@
 
 pub mod btree;
 mod command;
+pub mod compat;
 mod io;
+pub mod keys;
+pub mod metrics;
+
+/// The csv crate, used to configure the readers and writers of delimited files.
+#[cfg(feature = "csv")]
+pub use csv;
 
 use bincode::config::AllowTrailing;
 use bincode::config::FixintEncoding;
@@ -0,0 +1,215 @@
+//! Metrics
+//!
+//! Trees count their operations and record how long they take, so that their performance can be
+//! monitored. Metrics are always collected, since recording them only updates a few atomic
+//! counters, and are read with [`Baildon::metrics`].
+//!
+//! Latencies are recorded in histograms, with buckets whose bounds are powers of two
+//! microseconds.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::btree::baildon::Baildon;
+use crate::btree::baildon::BaildonKey;
+use crate::btree::baildon::BaildonValue;
+
+/// Number of buckets in a histogram. The last bucket counts every latency above 2^30µs.
+const BUCKETS: usize = 32;
+
+/// A snapshot of the metrics of a tree.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metrics {
+    /// Latencies of gets.
+    pub get: Histogram,
+    /// Latencies of inserts.
+    pub insert: Histogram,
+    /// Latencies of deletes.
+    pub delete: Histogram,
+    /// Latencies of flushes to disk.
+    pub flush: Histogram,
+    /// Number of bytes of commands appended to our WAL.
+    pub wal_bytes: u64,
+    /// Number of nodes found in our node cache.
+    pub cache_hits: u64,
+    /// Number of nodes read from disk, because they weren't cached.
+    pub cache_misses: u64,
+    /// Number of blocks allocated in our data file.
+    pub blocks_allocated: u64,
+}
+
+/// A histogram of latencies.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histogram {
+    /// Number of operations.
+    pub count: u64,
+    /// Total duration of all operations.
+    pub sum: Duration,
+    /// Number of operations in each bucket. Bucket `n` counts operations which took less than
+    /// 2^n microseconds (and at least 2^(n-1) microseconds).
+    pub buckets: Vec<u64>,
+}
+
+impl Histogram {
+    /// Mean duration of our operations.
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos((self.sum.as_nanos() / self.count as u128) as u64)
+    }
+
+    /// Upper bound of the duration within which the specified fraction (0.0 to 1.0) of our
+    /// operations completed.
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        let target = (quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target.max(1) {
+                return Duration::from_micros(1 << bucket);
+            }
+        }
+        Duration::MAX
+    }
+}
+
+/// A histogram which may be updated concurrently.
+#[derive(Debug, Default)]
+pub(crate) struct AtomicHistogram {
+    sum_nanos: AtomicU64,
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl AtomicHistogram {
+    pub(crate) fn record(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Start timing an operation, which is recorded when the returned timer is dropped.
+    pub(crate) fn time(&self) -> Timer<'_> {
+        Timer {
+            histogram: self,
+            start: Instant::now(),
+        }
+    }
+
+    fn snapshot(&self) -> Histogram {
+        let buckets = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect::<Vec<u64>>();
+        Histogram {
+            count: buckets.iter().sum(),
+            sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)),
+            buckets,
+        }
+    }
+}
+
+/// Records the duration of an operation when dropped, so that failed operations are recorded.
+pub(crate) struct Timer<'a> {
+    histogram: &'a AtomicHistogram,
+    start: Instant,
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        self.histogram.record(self.start.elapsed());
+    }
+}
+
+/// Collects the metrics of a tree.
+#[derive(Debug, Default)]
+pub(crate) struct MetricsRecorder {
+    pub(crate) get: AtomicHistogram,
+    pub(crate) insert: AtomicHistogram,
+    pub(crate) delete: AtomicHistogram,
+    pub(crate) flush: AtomicHistogram,
+    pub(crate) wal_bytes: AtomicU64,
+    pub(crate) cache_hits: AtomicU64,
+    pub(crate) cache_misses: AtomicU64,
+    pub(crate) blocks_allocated: AtomicU64,
+}
+
+impl MetricsRecorder {
+    /// Add to a counter.
+    pub(crate) fn count(counter: &AtomicU64, count: u64) {
+        counter.fetch_add(count, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Metrics {
+        Metrics {
+            get: self.get.snapshot(),
+            insert: self.insert.snapshot(),
+            delete: self.delete.snapshot(),
+            flush: self.flush.snapshot(),
+            wal_bytes: self.wal_bytes.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            blocks_allocated: self.blocks_allocated.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<K, V> Baildon<K, V>
+where
+    K: BaildonKey + Send + Sync,
+    V: BaildonValue + Send + Sync,
+{
+    /// Return a snapshot of our metrics, which have been collected since our tree was opened.
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_buckets_latencies() {
+        let histogram = AtomicHistogram::default();
+        for micros in [0, 1, 3, 100, 100, 5000] {
+            histogram.record(Duration::from_micros(micros));
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 6);
+        assert_eq!(snapshot.buckets[0], 1);
+        assert_eq!(snapshot.buckets[1], 1);
+        assert_eq!(snapshot.buckets[2], 1);
+        assert_eq!(snapshot.buckets[7], 2);
+        assert_eq!(snapshot.buckets[13], 1);
+        assert_eq!(snapshot.mean(), Duration::from_micros(5204) / 6);
+        assert_eq!(snapshot.quantile(0.5), Duration::from_micros(4));
+        assert_eq!(snapshot.quantile(1.0), Duration::from_micros(8192));
+    }
+
+    #[tokio::test]
+    async fn it_collects_metrics() {
+        let tree = Baildon::<usize, usize>::temporary(5)
+            .await
+            .expect("creates tree file");
+        for i in 0..100 {
+            tree.insert(i, i).await.expect("insert worked");
+        }
+        tree.delete(&42).await.expect("delete worked");
+        tree.flush_to_disk().await.expect("flushes tree");
+        assert_eq!(tree.get(&7).await, Some(7));
+
+        let metrics = tree.metrics();
+        assert_eq!(metrics.insert.count, 100);
+        assert_eq!(metrics.delete.count, 1);
+        assert_eq!(metrics.get.count, 1);
+        // Our tree was flushed when it was created
+        assert_eq!(metrics.flush.count, 2);
+        assert!(metrics.wal_bytes > 0);
+        assert!(metrics.cache_misses > 0);
+        assert!(metrics.blocks_allocated > 0);
+    }
+}